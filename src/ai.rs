@@ -1,52 +1,393 @@
-use reqwest::Client;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use reqwest::{Client, StatusCode};
+use tokio::sync::mpsc;
 use serde_json::{json, Value};
 use anyhow::{Result, anyhow};
 use log::{info, error, debug};
+use crate::chat::{ChatMessage, Role};
+use crate::config::Config;
 
-const GEMINI_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models/gemini-flash-latest:generateContent";
+const GEMINI_API: &str = "https://generativelanguage.googleapis.com/v1beta/models";
 
-pub async fn request_gemini(api_key: String, current_code: String, filename: String, user_instruction: String) -> Result<String> {
-    let client = Client::new();
-    
-    info!("Preparing Gemini API request for file: {}", filename);
+/// Received bytes between progress notes of a streamed answer.
+const PROGRESS_STEP: usize = 64 * 1024;
+
+/// Overloaded or rate limited: worth trying again. Auth and request errors
+/// aren't.
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// How transient failures are retried: up to `max_attempts` tries in all,
+/// waiting `base_delay` doubled each time plus some jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `attempt` (1-based).
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay * 2u32.saturating_pow(attempt - 1);
+        // Spread clients out without pulling in a RNG.
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        backoff + self.base_delay.mul_f64(nanos as f64 / 2e9)
+    }
+}
+
+/// Sampling settings for `generationConfig`; None leaves Gemini's default.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: Option<u32>,
+    pub stop_sequences: Vec<String>,
+}
 
-    let system_prompt = format!(
-        "You are an intelligent text editor engine. I will provide a file named \"{}\" with the following content. The user wants to: \"{}\". RULES:
+impl GenerationParams {
+    /// Add the set parameters to `config`, keeping any it already has.
+    fn fill(&self, config: &mut serde_json::Map<String, Value>) {
+        let mut set = |key: &str, value: Value| {
+            config.entry(key).or_insert(value);
+        };
+        if let Some(temperature) = self.temperature {
+            set("temperature", json!(temperature));
+        }
+        if let Some(top_p) = self.top_p {
+            set("topP", json!(top_p));
+        }
+        if let Some(max) = self.max_output_tokens {
+            set("maxOutputTokens", json!(max));
+        }
+        if !self.stop_sequences.is_empty() {
+            set("stopSequences", json!(self.stop_sequences));
+        }
+    }
+}
+
+/// What every request needs to reach Gemini.
+#[derive(Clone)]
+pub struct Gemini {
+    pub api_key: String,
+    /// Models to try in order; see `send`.
+    pub models: Vec<String>,
+    pub retry: RetryPolicy,
+    pub params: GenerationParams,
+    /// User template replacing the built-in instructions of rewrite
+    /// requests; see `render_prompt`.
+    pub system_prompt: Option<String>,
+    /// Language for comments and explanations the AI writes (e.g.
+    /// "Spanish"); unset leaves it to the model.
+    pub response_language: Option<String>,
+    /// Where to report progress like "retrying (2/3)…", if anyone listens.
+    pub progress: Option<mpsc::UnboundedSender<String>>,
+    /// Requests bigger than this aren't sent.
+    pub max_request_bytes: usize,
+    /// Streamed answers are cut off past this.
+    pub max_response_bytes: usize,
+    /// Stream whole-file answers to a temp file rather than memory. Off for
+    /// text that mustn't touch the disk.
+    pub spool: bool,
+}
+
+/// Token counts Gemini reports for a request (`usageMetadata`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub output_tokens: u64,
+}
+
+pub struct Answer {
+    pub text: String,
+    pub usage: Usage,
+    /// Temp file holding the answer instead of `text` (see `Gemini::spool`).
+    pub spool: Option<PathBuf>,
+    /// The model that answered, when it wasn't the first choice.
+    pub fallback: Option<String>,
+}
+
+impl Answer {
+    /// The answer's lines, read from the spool file (which is then removed)
+    /// if it went to disk.
+    pub fn into_lines(self) -> Result<Vec<String>> {
+        let Some(path) = self.spool else { return Ok(self.text.lines().map(String::from).collect()) };
+        let lines = File::open(&path).and_then(|file| BufReader::new(file).lines().collect::<std::io::Result<Vec<_>>>());
+        let _ = fs::remove_file(&path);
+        let mut lines = lines?;
+        // The same fence stripping as `clean_markdown`.
+        if lines.first().is_some_and(|l| l.trim().starts_with("```")) {
+            lines.remove(0);
+        }
+        if lines.last().is_some_and(|l| l.trim().starts_with("```")) {
+            lines.pop();
+        }
+        Ok(lines)
+    }
+}
+
+/// Rough token count for text not yet sent: about four chars a token.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+const DEFAULT_SYSTEM_PROMPT: &str = "You are an intelligent text editor engine. I will provide a file named \"{filename}\" with the following content. The user wants to: \"{instruction}\". RULES:
 
 Return ONLY the fully updated file content. No markdown code blocks. No conversational text.
 
-If the user asks for explanations, insert them as COMMENTS inside the code (using correct syntax for {}).
+If the user asks for explanations, insert them as COMMENTS inside the code (using correct syntax for {language}).
 
-Preserve indentation.",
-        filename, user_instruction, filename
-    );
+Preserve indentation.";
 
-    let body = json!({
-        "contents": [{
-            "parts": [{
-                "text": format!("{}\n\nCODE:\n{}", system_prompt, current_code)
-            }]
-        }]
-    });
+const DEFAULT_SELECTION_PROMPT: &str = "You are an intelligent text editor engine. I will provide an excerpt of a file named \"{filename}\". Only the part between SELECTION START and SELECTION END may change; the text around it is context. The user wants to: \"{instruction}\". RULES:
 
-    debug!("Payload: {}", body);
+Return ONLY the replacement for the selected text. Do not repeat the context. No markdown code blocks. No conversational text.
+
+If the user asks for explanations, insert them as COMMENTS inside the code (using correct syntax for {language}).
+
+Preserve indentation.";
+
+/// Template file in the config directory replacing the built-in rewrite
+/// instructions (`--edit-prompt`).
+pub fn system_prompt_path() -> PathBuf {
+    Config::dir().join("system_prompt.txt")
+}
+
+/// The template file's contents, unless it's missing or blank.
+pub fn load_system_prompt() -> Option<String> {
+    fs::read_to_string(system_prompt_path()).ok().filter(|text| !text.trim().is_empty())
+}
+
+/// Write the built-in instructions to the template file, to start editing
+/// from or to go back to the default.
+pub fn write_default_system_prompt() -> Result<PathBuf> {
+    let path = system_prompt_path();
+    fs::create_dir_all(Config::dir())?;
+    fs::write(&path, format!("{}\n", DEFAULT_SYSTEM_PROMPT))?;
+    Ok(path)
+}
+
+/// Appended to a user template for selection rewrites, which need these
+/// rules to splice the answer back in.
+const SELECTION_RULES: &str = "Only the part between SELECTION START and SELECTION END may change; the text around it is context. Return ONLY the replacement for the selected text, without the context.";
+
+/// Add the configured response language to `prompt`.
+fn with_response_language(prompt: String, gemini: &Gemini) -> String {
+    match &gemini.response_language {
+        Some(language) => format!("{}\n\nWrite all comments and explanations in {}.", prompt, language),
+        None => prompt,
+    }
+}
 
-    let url = format!("{}?key={}", GEMINI_URL, api_key);
-    info!("Sending request to Gemini Flash Latest...");
+/// Fill in the `{filename}`, `{instruction}` and `{language}` placeholders.
+fn render_prompt(template: &str, filename: &str, language: &str, instruction: &str) -> String {
+    template
+        .replace("{filename}", filename)
+        .replace("{language}", language)
+        .replace("{instruction}", instruction)
+}
 
-    let response = client.post(&url)
-        .json(&body)
+/// Look `model` up, to tell whether the key and the connection work. The
+/// key goes in a header, so errors (which quote the URL) can't show it.
+pub async fn check_model(api_key: &str, model: &str) -> Result<()> {
+    let response = Client::new()
+        .get(format!("{}/{}", GEMINI_API, model))
+        .header("x-goog-api-key", api_key)
+        .timeout(Duration::from_secs(10))
         .send()
         .await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body: Value = response.json().await.unwrap_or_default();
+    Err(anyhow!("{} {}", status, body["error"]["message"].as_str().unwrap_or_default()))
+}
+
+pub async fn request_gemini(
+    gemini: Gemini,
+    current_code: String,
+    filename: String,
+    language: String,
+    user_instruction: String,
+) -> Result<Answer> {
+    info!("Preparing Gemini API request for file: {}", filename);
+
+    let template = gemini.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
+    let system_prompt = with_response_language(render_prompt(template, &filename, &language, &user_instruction), &gemini);
+
+    let answer = generate_streaming(&gemini, single_turn(format!("{}\n\nCODE:\n{}", system_prompt, current_code))).await?;
+    Ok(Answer { text: clean_markdown(&answer.text), ..answer })
+}
+
+/// Like `request_gemini`, but only `selection` gets rewritten; the lines
+/// around it are sent as read-only context.
+pub async fn request_gemini_selection(
+    gemini: Gemini,
+    before: String,
+    selection: String,
+    after: String,
+    filename: String,
+    language: String,
+    user_instruction: String,
+) -> Result<Answer> {
+    info!("Preparing Gemini API request for a selection in: {}", filename);
+
+    let system_prompt = match &gemini.system_prompt {
+        Some(template) => format!("{}\n\n{}", render_prompt(template, &filename, &language, &user_instruction), SELECTION_RULES),
+        None => render_prompt(DEFAULT_SELECTION_PROMPT, &filename, &language, &user_instruction),
+    };
+    let system_prompt = with_response_language(system_prompt, &gemini);
+
+    let answer = generate(
+        &gemini,
+        single_turn(format!(
+            "{}\n\nCONTEXT BEFORE:\n{}\n\nSELECTION START\n{}\nSELECTION END\n\nCONTEXT AFTER:\n{}",
+            system_prompt, before, selection, after
+        )),
+    )
+    .await?;
+    Ok(Answer { text: clean_markdown(&answer.text), ..answer })
+}
+
+/// Answer the last message of a conversation about the file. The answer is
+/// returned as-is (Markdown, code fences included) for the chat panel.
+pub async fn request_gemini_chat(gemini: Gemini, messages: Vec<ChatMessage>, current_code: String, filename: String) -> Result<Answer> {
+    info!("Preparing Gemini chat request for file: {}", filename);
+
+    let instructions = with_response_language(
+        format!("You are a helpful programming assistant inside a text editor. The user is editing a file named \"{}\" and asks questions about it. Answer concisely. Put any code in fenced code blocks; do not rewrite the whole file unless asked.", filename),
+        &gemini,
+    );
+    let instruction = format!("{}\n\nFILE:\n{}", instructions, current_code);
+    let contents: Vec<Value> = messages
+        .iter()
+        .filter(|m| m.role != Role::Error)
+        .map(|m| {
+            let role = if m.role == Role::User { "user" } else { "model" };
+            json!({ "role": role, "parts": [{ "text": m.text }] })
+        })
+        .collect();
+
+    generate(&gemini, json!({
+        "system_instruction": { "parts": [{ "text": instruction }] },
+        "contents": contents
+    }))
+    .await
+}
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-        error!("API Error: Status {}, Body: {}", status, error_text);
-        return Err(anyhow!("Gemini API Error {}: {}", status, error_text));
+/// Plain-language explanation of `selection`, for reading rather than for
+/// the buffer.
+pub async fn request_explanation(gemini: Gemini, before: String, selection: String, after: String, filename: String) -> Result<Answer> {
+    info!("Requesting explanation of a selection in: {}", filename);
+
+    let instructions = with_response_language(
+        format!("You are a patient programming teacher. Explain what the selected code from the file \"{}\" does, how it works and anything surprising about it. The text around the selection is only context. Answer in plain text suitable for a terminal; keep it concise.", filename),
+        &gemini,
+    );
+    let prompt = format!(
+        "{}\n\nCONTEXT BEFORE:\n{}\n\nSELECTION START\n{}\nSELECTION END\n\nCONTEXT AFTER:\n{}",
+        instructions, before, selection, after
+    );
+    generate(&gemini, single_turn(prompt)).await
+}
+
+/// A commit message for the staged `diff`: a short subject line, a blank
+/// line and, when the change needs it, a body.
+pub async fn request_commit_message(gemini: Gemini, diff: String, filename: String) -> Result<Answer> {
+    info!("Requesting a commit message for: {}", filename);
+
+    let instructions = with_response_language(
+        "Write a git commit message for the following diff. Use an imperative subject line of at most 72 characters, then a blank line and a short body explaining what changed and why, wrapped at 72 columns. Leave out the body if the subject says it all. Return ONLY the commit message: no markdown, no quotes, no code blocks.".to_string(),
+        &gemini,
+    );
+    let prompt = format!("{}\n\nDIFF:\n{}", instructions, diff);
+    let answer = generate(&gemini, single_turn(prompt)).await?;
+    Ok(Answer { text: clean_markdown(&answer.text).trim().to_string(), ..answer })
+}
+
+/// A short continuation of the text at the cursor, for inline completion.
+/// Only the text around the cursor is sent, and the answer is capped so it
+/// comes back quickly.
+pub async fn request_completion(gemini: Gemini, before: String, after: String, filename: String) -> Result<Answer> {
+    info!("Requesting completion for file: {}", filename);
+
+    let prompt = format!(
+        "You are a code completion engine for a file named \"{}\". Continue the text at <CURSOR>. Return ONLY the text to insert at the cursor: no explanations, no markdown code blocks, and do not repeat the text before or after the cursor. Keep it short (at most a few lines).\n\n{}<CURSOR>{}",
+        filename, before, after
+    );
+    let mut body = single_turn(prompt);
+    body["generationConfig"] = json!({ "maxOutputTokens": 64, "temperature": 0.2 });
+
+    let answer = generate(&gemini, body).await?;
+    Ok(Answer { text: clean_markdown(&answer.text).trim_end().to_string(), ..answer })
+}
+
+/// `--ask`: say where `question` is handled, from the best-ranked
+/// excerpts of the project (`file:first-last` header and text each).
+pub async fn request_codebase_answer(gemini: Gemini, question: String, excerpts: Vec<(String, String)>) -> Result<Answer> {
+    info!("Asking about the codebase: {}", question);
+
+    let instructions = with_response_language(
+        format!("You are helping someone find their way around a codebase. They ask: \"{}\". Below are the excerpts of the project that seem most relevant, best first. Say where this is handled, citing file:line, and briefly explain how. If the excerpts don't answer it, say so. Answer in plain text suitable for a terminal; keep it concise.", question),
+        &gemini,
+    );
+    let mut prompt = instructions;
+    for (header, text) in excerpts {
+        prompt.push_str(&format!("\n\n--- {}\n{}", header, text));
+    }
+    generate(&gemini, single_turn(prompt)).await
+}
+
+/// Embedding vectors for `texts`, in order, from `model`. `task` is
+/// Gemini's task type, e.g. "RETRIEVAL_DOCUMENT" for the text searched and
+/// "RETRIEVAL_QUERY" for the question.
+pub async fn embed(gemini: &Gemini, model: &str, texts: &[String], task: &str, dimensions: usize) -> Result<Vec<Vec<f32>>> {
+    let requests: Vec<Value> = texts
+        .iter()
+        .map(|text| {
+            json!({
+                "model": format!("models/{}", model),
+                "content": { "parts": [{ "text": text }] },
+                "taskType": task,
+                "outputDimensionality": dimensions,
+            })
+        })
+        .collect();
+    let url = format!("{}/{}:batchEmbedContents?key={}", GEMINI_API, model, gemini.api_key);
+    let response = send_to(gemini, &Client::new(), model, &url, &json!({ "requests": requests })).await.map_err(|(e, _)| e)?;
+    let json_resp: Value = response.json().await?;
+    let vectors: Vec<Vec<f32>> = json_resp["embeddings"]
+        .as_array()
+        .map(|embeddings| {
+            embeddings
+                .iter()
+                .map(|e| e["values"].as_array().map(|v| v.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect()).unwrap_or_default())
+                .collect()
+        })
+        .unwrap_or_default();
+    if vectors.len() != texts.len() {
+        error!("Invalid embedding response: {:?}", json_resp);
+        return Err(anyhow!("Invalid embedding response ({} vectors for {} texts)", vectors.len(), texts.len()));
     }
+    Ok(vectors)
+}
 
-    info!("Gemini API request successful.");
+fn single_turn(text: String) -> Value {
+    json!({
+        "contents": [{
+            "parts": [{
+                "text": text
+            }]
+        }]
+    })
+}
+
+async fn generate(gemini: &Gemini, body: Value) -> Result<Answer> {
+    let (response, fallback) = send(gemini, false, body).await?;
 
     let json_resp: Value = response.json().await?;
     
@@ -58,7 +399,197 @@ Preserve indentation.",
         })?
         .to_string();
 
-    Ok(clean_markdown(&text))
+    Ok(Answer { text, usage: usage_of(&json_resp), spool: None, fallback })
+}
+
+/// Like `generate`, but the answer is read as it's produced: progress goes
+/// to the popup, it's written to a temp file when `spool` is set, and it's
+/// cut off past `max_response_bytes`.
+async fn generate_streaming(gemini: &Gemini, body: Value) -> Result<Answer> {
+    let (mut response, fallback) = send(gemini, true, body).await?;
+
+    let mut spool = if gemini.spool { Some(Spool::create()?) } else { None };
+    let mut text = String::new();
+    let mut usage = Usage::default();
+    let mut pending = Vec::new();
+    let (mut received, mut reported) = (0, 0);
+    while let Some(chunk) = response.chunk().await? {
+        pending.extend_from_slice(&chunk);
+        // Server-sent events: one `data: {json}` line per piece.
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let Some(data) = line.strip_prefix(b"data:") else { continue };
+            let event: Value = serde_json::from_slice(data)?;
+            if event.get("usageMetadata").is_some() {
+                usage = usage_of(&event);
+            }
+            let parts = event["candidates"][0]["content"]["parts"].as_array().cloned().unwrap_or_default();
+            for piece in parts.iter().filter_map(|p| p["text"].as_str()) {
+                received += piece.len();
+                if received > gemini.max_response_bytes {
+                    return Err(anyhow!("Answer cut off past {} (ai_max_response_bytes)", format_bytes(gemini.max_response_bytes)));
+                }
+                match &mut spool {
+                    Some(spool) => spool.file.write_all(piece.as_bytes())?,
+                    None => text.push_str(piece),
+                }
+            }
+            if received - reported >= PROGRESS_STEP {
+                reported = received;
+                if let Some(progress) = &gemini.progress {
+                    let _ = progress.send(format!("receiving {}…", format_bytes(received)));
+                }
+            }
+        }
+    }
+    info!("Streamed answer complete: {} bytes.", received);
+    let spool = spool.map(Spool::keep).transpose()?;
+    Ok(Answer { text, usage, spool, fallback })
+}
+
+/// The temp file a streamed answer goes to; removed if the answer doesn't
+/// make it (an error, the cutoff or a cancelled request).
+struct Spool {
+    path: PathBuf,
+    file: File,
+    keep: bool,
+}
+
+impl Spool {
+    fn create() -> Result<Self> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let path = std::env::temp_dir().join(format!("neuronano-answer-{}-{}.txt", std::process::id(), nanos));
+        let file = File::create(&path)?;
+        Ok(Self { path, file, keep: false })
+    }
+
+    fn keep(mut self) -> Result<PathBuf> {
+        self.file.flush()?;
+        self.keep = true;
+        Ok(self.path.clone())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn usage_of(response: &Value) -> Usage {
+    let usage = &response["usageMetadata"];
+    Usage {
+        prompt_tokens: usage["promptTokenCount"].as_u64().unwrap_or(0),
+        output_tokens: usage["candidatesTokenCount"].as_u64().unwrap_or(0),
+    }
+}
+
+/// "512 B", "1.2 MB".
+fn format_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// POST `body` with the configured generation settings to the first model
+/// that answers, retrying transient failures before falling back to the
+/// next one. Returns the response, and the model if it was a fallback.
+async fn send(gemini: &Gemini, stream: bool, mut body: Value) -> Result<(reqwest::Response, Option<String>)> {
+    let client = Client::new();
+
+    // Settings a request picks itself (e.g. completions) win over the config.
+    if let Some(body) = body.as_object_mut() {
+        if let Some(config) = body.entry("generationConfig").or_insert_with(|| json!({})).as_object_mut() {
+            gemini.params.fill(config);
+            if config.is_empty() {
+                body.remove("generationConfig");
+            }
+        }
+    }
+
+    debug!("Payload: {}", body);
+
+    let size = body.to_string().len();
+    if size > gemini.max_request_bytes {
+        return Err(anyhow!(
+            "Request too large ({}, ai_max_request_bytes is {}); select less text",
+            format_bytes(size),
+            format_bytes(gemini.max_request_bytes)
+        ));
+    }
+
+    let mut error = anyhow!("No AI model configured (ai_models)");
+    for (i, model) in gemini.models.iter().enumerate() {
+        if i > 0 {
+            if let Some(progress) = &gemini.progress {
+                let _ = progress.send(format!("falling back to {}…", model));
+            }
+        }
+        let method = if stream { "streamGenerateContent?alt=sse&" } else { "generateContent?" };
+        let url = format!("{}/{}:{}key={}", GEMINI_API, model, method, gemini.api_key);
+        match send_to(gemini, &client, model, &url, &body).await {
+            Ok(response) => return Ok((response, (i > 0).then(|| model.clone()))),
+            Err((e, fall_back)) => {
+                if !fall_back {
+                    return Err(e);
+                }
+                error = e;
+            }
+        }
+    }
+    Err(error)
+}
+
+/// One model's attempts at a request. Errors say whether another model
+/// is worth a try: not for requests that are wrong everywhere (bad key,
+/// malformed body).
+async fn send_to(gemini: &Gemini, client: &Client, model: &str, url: &str, body: &Value) -> Result<reqwest::Response, (anyhow::Error, bool)> {
+    let max_attempts = gemini.retry.max_attempts.max(1);
+    let mut attempt = 1;
+    let response = loop {
+        info!("Sending request to {} (attempt {}/{})...", model, attempt, max_attempts);
+        let (error, retry_after) = match client.post(url).json(body).send().await {
+            Ok(response) if response.status().is_success() => break response,
+            Ok(response) => {
+                let status = response.status();
+                let retry_after = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok())
+                    .map(Duration::from_secs);
+                let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+                error!("API Error: Status {}, Body: {}", status, error_text);
+                let error = anyhow!("Gemini API Error {} from {}: {}", status, model, error_text);
+                if !is_retryable_status(status) {
+                    // An unknown model name is the config's fault, not the request's.
+                    return Err((error, status == StatusCode::NOT_FOUND));
+                }
+                (error, retry_after)
+            }
+            Err(e) if e.is_timeout() || e.is_connect() => {
+                error!("Request failed: {}", e);
+                (e.into(), None)
+            }
+            Err(e) => return Err((e.into(), false)),
+        };
+        if attempt >= max_attempts {
+            return Err((error, true));
+        }
+        let delay = retry_after.unwrap_or_else(|| gemini.retry.delay(attempt));
+        attempt += 1;
+        if let Some(progress) = &gemini.progress {
+            let _ = progress.send(format!("retrying ({}/{})…", attempt, max_attempts));
+        }
+        tokio::time::sleep(delay).await;
+    };
+
+    info!("Gemini API request to {} successful.", model);
+    Ok(response)
 }
 
 fn clean_markdown(text: &str) -> String {
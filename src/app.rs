@@ -1,8 +1,59 @@
-use tui_textarea::TextArea;
-use crate::config::Config;
-use tokio::sync::mpsc;
+use tui_textarea::{CursorMove, TextArea};
+use crate::config::{Config, KeySource, LineLengthRule};
+use crate::crypto::{self, Cipher};
+use crate::diff;
+use ropey::Rope;
+use crate::editor::Editor;
+use crate::fileio::{self, DiskStamp, LineEnding};
+use crate::frontmatter::{self, FrontMatter};
+use crate::graphics::{self, Protocol};
+use crate::keymap::{Action, KeyMap};
+use crate::history::PromptHistory;
+use crate::indent::{self, Indent};
+use crate::input::{self, PromptInput};
+use crate::journal::{Journal, Op};
+use crate::killring::KillRing;
+use crate::large::{self, Loaded, Loading};
+use crate::links;
+use crate::lsp;
+use crate::lists;
+use crate::markdown::{self, Heading};
+use crate::notify;
+use crate::patch;
+use crate::ai;
+use crate::block::{self, Block};
+use crate::bookmarks::{self, Bookmark};
+use crate::brackets;
+use crate::buffer::{Buffer, Change};
+use crate::cells;
+use crate::comment;
+use crate::cursors;
+use crate::fold::{self, Fold};
+use crate::git;
+use crate::grep::{self, Found, Hit};
+use crate::stats::Stats;
+use crate::swap;
+use crate::chat::{self, ChatMessage, Role};
+use crate::profile::{self, Profile};
+use crate::prefs::{self, Prefs};
+use crate::prose;
+use crate::recent;
+use crate::replace::{self, Replaced};
+use crate::semantic;
+use crate::session::{self, SessionFile};
+use crate::shell;
+use crate::share::{self, Session};
+use crate::spell::{self, Dictionary, Scope};
+use crate::table;
+use crate::vim::VimState;
+use crate::wrap;
+use crate::theme::{ColorSupport, Theme};
+use crate::ui::{self, CursorShape};
+use tokio::sync::{mpsc, oneshot};
 use syntect::parsing::SyntaxSet;
-use syntect::highlighting::ThemeSet;
+use similar::TextDiff;
+use zeroize::Zeroize;
+use encoding_rs::{Encoding, UTF_8};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AppMode {
@@ -13,61 +64,804 @@ pub enum AppMode {
     Search,
     SaveAs,
     ConfirmQuit,
+    FileChanged,
+    Outline,
+    FrontMatter,
+    /// Typing into the chat panel.
+    Chat,
+    /// Read-only popup with an explanation of the selection.
+    Explain,
+    /// List of buffer snapshots taken before AI answers.
+    AiSnapshots,
+    /// Bookmark picker.
+    Bookmarks,
+    /// Suggestions for a misspelled word.
+    Spelling,
+    /// List of open buffers.
+    Buffers,
+    /// What a command over several buffers couldn't do.
+    Report,
+    /// Idle lock screen, or choosing its passphrase.
+    Locked,
+    /// Start screen when no file was given.
+    Welcome,
+    /// Asking for a file to open.
+    OpenFile,
+    /// Moving through the list of matches of the last search.
+    SearchResults,
+    /// The diagnostics of the cursor line in full.
+    DiagnosticDetails,
+    /// Typing a regex replace, with a preview of what it does.
+    Replace,
+    /// Offering back changes a crashed session left in a swap file.
+    Recover,
+    /// Editing the message of a git commit of the file.
+    GitCommit,
+    /// How the buffer differs from git HEAD.
+    GitDiff,
+    /// Asking for a shell command whose output is inserted.
+    RunCommand,
+    /// Asking for a command to filter the selected lines through.
+    Filter,
+    /// Asking what to find in the project's files, then the hits.
+    Grep,
+    GrepResults,
+    /// Image or diagram preview.
+    Preview,
+    /// Three-way merge, hunk by hunk.
+    Merge,
+    /// Asking for a patch file to apply, or where to export one.
+    ApplyPatch,
+    ExportPatch,
+}
+
+/// `--merge` state: the merge and the hunk being looked at.
+pub struct MergeView {
+    pub merge: diff::Merge,
+    pub selected: usize,
+}
+
+/// Follow state for `--tail`: how far into the file we have read.
+pub struct TailState {
+    pub offset: u64,
+    pub ends_with_newline: bool,
+    /// Signalled when the file changes, by the watcher kept with it.
+    /// Without one the file is checked on every poll.
+    changed: Option<(::notify::RecommendedWatcher, mpsc::Receiver<()>)>,
+}
+
+/// Watch the directory of `path` for changes to the file, so it being
+/// replaced (log rotation) is seen as well as writes to it.
+fn watch_file(path: &Path) -> Option<(::notify::RecommendedWatcher, mpsc::Receiver<()>)> {
+    use ::notify::Watcher;
+    let path = std::path::absolute(path).ok()?;
+    let name = path.file_name()?.to_os_string();
+    let (tx, rx) = mpsc::channel(1);
+    let mut watcher = ::notify::recommended_watcher(move |event: ::notify::Result<::notify::Event>| {
+        // Reading the file is reported too.
+        let changed = event.map_or(true, |e| !e.kind.is_access() && e.paths.iter().any(|p| p.file_name() == Some(&name)));
+        if changed {
+            // One waiting already covers this.
+            let _ = tx.try_send(());
+        }
+    })
+    .ok()?;
+    watcher.watch(path.parent()?, ::notify::RecursiveMode::NonRecursive).ok()?;
+    Some((watcher, rx))
+}
+
+/// Results pane for the last executed cell.
+pub struct CellOutput {
+    pub text: String,
+    pub running: bool,
+    pub success: bool,
+}
+
+/// A shell command started with Ctrl+T or as a filter, and where its
+/// output goes.
+pub struct RunningCommand {
+    pub command: String,
+    target: CommandTarget,
+    /// The buffer it was started from; output meant for it goes to a new
+    /// buffer (or, for a filter, nowhere) if that's no longer the one shown.
+    buffer: (usize, String),
+    task: tokio::task::AbortHandle,
+}
+
+/// A formatter run for a save, and the text it was given: the file is
+/// written once it's done.
+pub struct Formatting {
+    pub formatter: String,
+    file: String,
+    text: Rope,
+}
+
+enum CommandTarget {
+    Cursor,
+    NewBuffer,
+    /// Replace these rows, as long as they still read `old`.
+    Rows { rows: std::ops::Range<usize>, old: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Hint,
+}
+
+/// A problem reported against a buffer position, listed in the
+/// diagnostics panel.
+pub struct Diagnostic {
+    pub row: usize,
+    pub col: usize,
+    /// Where the underlined text ends (row, char column).
+    pub end: (usize, usize),
+    pub severity: Severity,
+    pub message: String,
+    /// What reported it, from a language server ("rustc", "Pyright");
+    /// None for link checks.
+    pub source: Option<String>,
+}
+
+impl Diagnostic {
+    /// A warning about the text at (row, col), from the editor itself.
+    pub fn at(row: usize, col: usize, message: String) -> Self {
+        Self { row, col, end: (row, col), severity: Severity::Warning, message, source: None }
+    }
+}
+
+/// Quick-edit popup for the title/date/tags front matter fields.
+pub struct FrontMatterForm<'a> {
+    pub inputs: Vec<PromptInput<'a>>,
+    pub focus: usize,
+}
+
+/// A prose auto-correction that ^Z turns back into what was typed.
+pub struct SmartEdit {
+    /// Cursor position right after the replacement.
+    pub at: (usize, usize),
+    pub replacement_len: usize,
+    pub literal: String,
 }
 
 pub struct App<'a> {
-    pub textarea: TextArea<'a>,
-    pub prompt_textarea: TextArea<'a>,
-    pub setup_textarea: TextArea<'a>,
-    pub search_textarea: TextArea<'a>,
-    pub filename_input: TextArea<'a>,
+    pub buffer: Buffer<'a>,
+    pub prompt_input: PromptInput<'a>,
+    pub setup_input: PromptInput<'a>,
+    /// When a key was last pressed (or the mouse used), for the idle lock.
+    pub last_input: Instant,
+    /// The passphrase being chosen or typed to unlock.
+    pub lock_input: PromptInput<'a>,
+    /// What unlocks the idle lock in `--secure` mode; any key otherwise.
+    lock_passphrase: Option<String>,
+    /// The lock screen is asking for a new passphrase, not checking one.
+    pub choosing_lock_passphrase: bool,
+    pub search_input: PromptInput<'a>,
+    pub filename_input: PromptInput<'a>,
+    pub patch_input: PromptInput<'a>,
     pub should_quit: bool,
-    pub mode: AppMode,
+    /// Base mode plus the dialogs opened on top of it. Input goes to the
+    /// top layer; every layer is drawn, bottom to top. Never empty.
+    modes: Vec<AppMode>,
     pub filename: String,
     pub config: Config,
-    pub ai_response_tx: mpsc::Sender<String>,
-    pub ai_response_rx: Option<mpsc::Receiver<String>>,
-    pub is_modified: bool,
+    pub ai_response_tx: mpsc::Sender<AiResult>,
+    pub ai_response_rx: Option<mpsc::Receiver<AiResult>>,
+    /// The in-flight Gemini request, so Esc can abort it.
+    pub ai_task: Option<tokio::task::AbortHandle>,
+    /// Selection the in-flight request rewrites, as (start, end) cursor
+    /// positions; None rewrites the whole buffer.
+    pub ai_target: Option<((usize, usize), (usize, usize))>,
+    /// Retry notes from the in-flight request, and the latest one.
+    ai_progress_tx: mpsc::UnboundedSender<String>,
+    pub ai_progress_rx: Option<mpsc::UnboundedReceiver<String>>,
+    pub ai_progress: Option<String>,
+    /// Prompt of the in-flight request, for its snapshot.
+    ai_prompt: String,
+    /// Buffer contents from before each applied AI answer, oldest first,
+    /// and the one highlighted in the snapshot list.
+    pub ai_snapshots: Vec<AiSnapshot>,
+    /// The file as committed in git, and how each row differs from it.
+    pub git_head: Option<Arc<Vec<String>>>,
+    pub git_marks: Vec<Option<diff::Mark>>,
+    pub ai_snapshot_selected: usize,
+    pub merge: Option<MergeView>,
+    /// Encoding the file was read in (and is written back in).
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+    /// Line ending and final newline of the file, reproduced on save.
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
     pub status_message: Option<String>,
     pub syntax_set: SyntaxSet,
-    pub theme_set: ThemeSet,
+    /// Mirror of the editor viewport's top-left corner (row, col), kept in sync
+    /// with the editor widget so overlays can map buffer positions to screen cells.
+    pub editor_scroll: (u16, u16),
+    /// Screen area of the editor text (inside the border), from the last frame.
+    pub editor_area: ratatui::layout::Rect,
+    /// With soft wrap: which screen row of the top buffer row is at the
+    /// top, and what each screen row showed in the last frame.
+    pub wrap_top: usize,
+    pub screen_rows: Vec<wrap::ScreenRow>,
+    /// The terminal speaks the kitty keyboard protocol (set at startup).
+    pub keyboard_enhanced: bool,
+    /// Terminal cursor shape picked by the last frame.
+    pub cursor_shape: CursorShape,
+    /// Edits and saves are refused while set.
+    pub read_only: bool,
+    /// Read-only for a reason the view toggle can't undo (tail, following
+    /// a shared session, a file that couldn't be decrypted).
+    pub locked: bool,
+    /// Why the file can't be saved, found when it was opened; shown as a
+    /// banner while set.
+    pub write_protected: Option<String>,
+    /// `--secure`: nothing but the file itself is written (no autosave,
+    /// swap files, backups or input history), and text is wiped from memory on exit.
+    pub secure: bool,
+    /// Session journal, when `journal` is on.
+    journal: Option<Journal>,
+    pub tail: Option<TailState>,
+    /// Opened past `large_file_mb`: whitespace, line length, spelling and
+    /// bracket marks are left out.
+    pub large_file: bool,
+    /// The rest of a large file, still being read in.
+    pub loading: Option<Loading>,
+    /// Show CSV/TSV buffers as an aligned table instead of raw text.
+    pub table_view: bool,
+    pub keymap: KeyMap,
+    pub theme: Theme,
+    pub colors: ColorSupport,
+    pub cell_output: Option<CellOutput>,
+    /// Alt+O hides the output pane; the output is kept to bring it back.
+    pub show_cell_output: bool,
+    pub cell_result_tx: mpsc::Sender<(String, bool)>,
+    pub cell_result_rx: Option<mpsc::Receiver<(String, bool)>>,
+    /// Shell command prompt; Tab picks between the cursor and a new buffer.
+    pub command_input: PromptInput<'a>,
+    pub command_to_new_buffer: bool,
+    pub running_command: Option<RunningCommand>,
+    command_tx: mpsc::Sender<CommandResult>,
+    pub command_rx: Option<mpsc::Receiver<CommandResult>>,
+    /// The profile's formatter, run on Ctrl+S before the file is written.
+    pub formatting: Option<Formatting>,
+    format_tx: mpsc::Sender<Result<String, String>>,
+    format_rx: Option<mpsc::Receiver<Result<String, String>>>,
+    /// Settings resolved for the current file from `config.profiles`.
+    pub profile: Profile,
+    /// Indentation style of the buffer, applied to Tab and AI answers.
+    pub indent: Indent,
+    /// Vim-style modal layer, when enabled.
+    pub vim: Option<VimState>,
+    /// When the autosave timer last ran, and when it last wrote the file.
+    pub autosave_checked: Instant,
+    pub autosaved_at: Option<Instant>,
+    /// Set while an autosave is being written: whether the buffer has
+    /// been edited since (so it stays modified).
+    autosave_edited: Option<bool>,
+    autosave_tx: mpsc::Sender<AutosaveResult>,
+    pub autosave_rx: Option<mpsc::Receiver<AutosaveResult>>,
+    pub kill_ring: KillRing,
+    pub smart_edit: Option<SmartEdit>,
+    /// Every open buffer, in the order opened. The shown one's state lives
+    /// in the fields above, its slot here is stale until switched away.
+    pub buffers: Vec<BufferState<'a>>,
+    pub active_buffer: usize,
+    /// Entry highlighted in the buffer list, and the dirty one whose close
+    /// is waiting for a second press.
+    pub buffer_selected: usize,
+    pub buffer_close_pending: Option<usize>,
+    /// Buffers whose changes were given up on while quitting, as it goes
+    /// on to ask about the next one.
+    quit_discarded: Vec<usize>,
+    /// Title and lines of the report popup (what a save all or close
+    /// others couldn't do).
+    pub report: Option<(String, Vec<String>)>,
+    /// Extra cursors (Ctrl+D, Alt+Click) that typed keys also go to; the
+    /// editor's own cursor is the main one.
+    pub cursors: Vec<(usize, usize)>,
+    /// Block selection being extended with Alt+arrows or typed into.
+    pub block: Option<Block>,
+    /// Keys of the macro being recorded, and of the last one recorded.
+    pub recording: Option<Vec<crossterm::event::KeyEvent>>,
+    pub macro_keys: Vec<crossterm::event::KeyEvent>,
+    /// Times to run the macro, typed with Alt+digits before running it.
+    pub repeat_count: Option<usize>,
+    /// A macro run asked for, replayed by the event loop.
+    macro_run: Option<usize>,
+    pub disk_stamp: Option<DiskStamp>,
+    disk_checked: Instant,
+    /// Edited since the swap file was last written, and the swap file
+    /// written for this buffer.
+    swap_dirty: bool,
+    swap_file: Option<PathBuf>,
+    swap_checked: Instant,
+    /// When the git gutter marks went out of date; they are redone once
+    /// typing pauses, as diffing a big file on every key lags.
+    git_stale: Option<Instant>,
+    /// HEAD's copy of a file, or gutter marks, worked out in the background.
+    git_tx: mpsc::Sender<GitUpdate>,
+    pub git_rx: Option<mpsc::Receiver<GitUpdate>>,
+    /// Marks are being worked out; one diff runs at a time.
+    git_marks_busy: bool,
+    /// Swap file found when opening, being offered back.
+    pub recovery: Option<swap::Found>,
+    /// Buffer vs. disk diff shown in the "file changed" prompt, once asked for.
+    pub disk_diff: Option<Vec<diff::DiffLine>>,
+    /// Buffer vs. git HEAD diff view, its layout and scroll row.
+    pub head_diff: Option<Vec<diff::DiffLine>>,
+    pub head_diff_side_by_side: bool,
+    pub head_diff_scroll: usize,
+    /// Headings listed by the Markdown outline popup, and the highlighted one.
+    pub outline: Vec<Heading>,
+    pub outline_selected: usize,
+    /// Bookmarks of the file, by number, and the one highlighted in the
+    /// picker.
+    pub bookmarks: Vec<Bookmark>,
+    /// Folded blocks, by first row; they don't overlap.
+    pub folds: Vec<Fold>,
+    pub bookmark_selected: usize,
+    /// Spell checking turned on or off for this buffer with Shift+F7,
+    /// over the config and profile.
+    pub spell_check: Option<bool>,
+    /// Loaded the first time a buffer is spell checked; why it couldn't be.
+    pub dictionary: Option<Dictionary>,
+    dictionary_error: Option<String>,
+    /// The word the spelling popup is about.
+    pub spelling: Option<Spelling>,
+    pub grep_input: PromptInput<'a>,
+    /// The last find in files, kept until its list is closed.
+    pub grep: Option<Grep>,
+    /// Files listed on the welcome screen, and the highlighted entry (the
+    /// actions come after the files).
+    pub recent_files: Vec<String>,
+    pub welcome_selected: usize,
+    pub diagnostics: Vec<Diagnostic>,
+    pub diagnostic_selected: Option<usize>,
+    pub show_diagnostics: bool,
+    /// Every match of the last search, shown under the editor until closed.
+    pub search_results: Option<SearchResults>,
+    /// The selection when the search bar opened, and whether the search is
+    /// limited to it.
+    pub search_scope: Option<((usize, usize), (usize, usize))>,
+    pub search_in_selection: bool,
+    pub replace_find: PromptInput<'a>,
+    pub replace_with: PromptInput<'a>,
+    pub replace: Option<ReplaceState>,
+    /// Remote link checks still in flight; each sends one result tagged
+    /// with the run it belongs to, so a rerun can drop stale ones.
+    pub links_pending: usize,
+    link_run: u64,
+    link_tasks: Vec<tokio::task::AbortHandle>,
+    link_result_tx: mpsc::Sender<(u64, Option<Diagnostic>)>,
+    pub link_result_rx: Option<mpsc::Receiver<(u64, Option<Diagnostic>)>>,
+    /// Language servers by command line, the ones that failed to start or
+    /// stopped (not retried), and when the buffer last changed without the
+    /// server being told.
+    lsp: HashMap<String, lsp::Client>,
+    lsp_failed: Vec<String>,
+    lsp_tx: mpsc::UnboundedSender<lsp::Event>,
+    pub lsp_rx: Option<mpsc::UnboundedReceiver<lsp::Event>>,
+    lsp_changed: Option<Instant>,
+    /// Language server completions shown under the cursor, and the
+    /// request for them.
+    pub completion: Option<CompletionMenu>,
+    completion_request: Option<CompletionRequest>,
+    pub front_matter_form: Option<FrontMatterForm<'a>>,
+    /// AI chat side panel: the conversation, whether it's shown, and how
+    /// many rows it's scrolled up from the newest message.
+    pub chat: Vec<ChatMessage>,
+    pub chat_input: PromptInput<'a>,
+    pub show_chat: bool,
+    pub prefs: Prefs,
+    pub chat_scroll: usize,
+    pub chat_task: Option<tokio::task::AbortHandle>,
+    chat_response_tx: mpsc::Sender<AiResult>,
+    pub chat_response_rx: Option<mpsc::Receiver<AiResult>>,
+    /// Inline completion shown after the cursor, and the request for one.
+    pub ghost: Option<Ghost>,
+    ghost_request: Option<(Ghost, tokio::task::AbortHandle)>,
+    ghost_tx: mpsc::Sender<AiResult>,
+    pub ghost_rx: Option<mpsc::Receiver<AiResult>>,
+    /// Text of the explain popup (None while waiting) and its scroll row.
+    pub explanation: Option<String>,
+    pub explain_scroll: usize,
+    explain_task: Option<tokio::task::AbortHandle>,
+    explain_tx: mpsc::Sender<AiResult>,
+    pub explain_rx: Option<mpsc::Receiver<AiResult>>,
+    /// Message being written in the git commit popup (None while the AI
+    /// drafts it), and the request for the draft.
+    pub commit_message: Option<TextArea<'a>>,
+    commit_task: Option<tokio::task::AbortHandle>,
+    /// git is committing the confirmed message.
+    committing: bool,
+    commit_tx: mpsc::Sender<CommitStep>,
+    pub commit_rx: Option<mpsc::Receiver<CommitStep>>,
+    /// How the terminal shows images, if it can.
+    pub graphics: Option<Protocol>,
+    /// Whether the terminal has focus, once it has said so.
+    pub focused: Option<bool>,
+    /// Bells and notifications to write to the terminal.
+    alerts: Vec<u8>,
+    pub preview: Option<Preview>,
+    /// Screen area for the preview image, from the last frame.
+    pub preview_area: ratatui::layout::Rect,
+    preview_task: Option<tokio::task::AbortHandle>,
+    preview_tx: mpsc::Sender<Result<Vec<u8>, String>>,
+    pub preview_rx: Option<mpsc::Receiver<Result<Vec<u8>, String>>>,
+    /// Repaint the whole screen next frame (images drawn outside ratatui
+    /// have to be painted over).
+    pub repaint: bool,
+    /// Shared editing session (`--share`/`--join`).
+    pub share: Option<Session>,
+    /// Saved prompt highlighted in the AI popup (index into `saved_prompts`).
+    pub prompt_pick: Option<usize>,
+}
+
+/// What the preview popup shows. `image` is ready for the terminal (see
+/// `graphics::prepare`); until then, or without graphics support, `text`
+/// is shown instead.
+pub struct Preview {
+    pub title: String,
+    pub text: String,
+    pub image: Option<Vec<u8>>,
+    /// The image is on screen; it's written once, not every frame.
+    pub drawn: bool,
+}
+
+/// What is kept per open file while another one is shown; the same
+/// fields as in `App`.
+pub struct BufferState<'a> {
+    pub buffer: Buffer<'a>,
+    pub filename: String,
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+    pub final_newline: bool,
+    pub read_only: bool,
+    pub write_protected: Option<String>,
+    pub large_file: bool,
+    pub loading: Option<Loading>,
+    pub table_view: bool,
+    pub disk_stamp: Option<DiskStamp>,
+    pub swap_dirty: bool,
+    pub swap_file: Option<PathBuf>,
+    pub bookmarks: Vec<Bookmark>,
+    pub folds: Vec<Fold>,
+    pub spell_check: Option<bool>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub ai_snapshots: Vec<AiSnapshot>,
+    /// The file as committed in git, and how each row differs from it.
+    pub git_head: Option<Arc<Vec<String>>>,
+    pub git_marks: Vec<Option<diff::Mark>>,
+    pub editor_scroll: (u16, u16),
+    pub wrap_top: usize,
+}
+
+impl<'a> BufferState<'a> {
+    /// An empty, unnamed buffer.
+    fn new(soft_wrap: bool) -> Self {
+        let mut buffer = Buffer::new(App::new_editor(""));
+        buffer.soft_wrap = soft_wrap;
+        Self {
+            buffer,
+            filename: String::from("[No Name]"),
+            encoding: UTF_8,
+            bom: false,
+            line_ending: LineEnding::Lf,
+            final_newline: true,
+            read_only: false,
+            write_protected: None,
+            large_file: false,
+            loading: None,
+            table_view: false,
+            disk_stamp: None,
+            swap_dirty: false,
+            swap_file: None,
+            bookmarks: Vec::new(),
+            folds: Vec::new(),
+            spell_check: None,
+            diagnostics: Vec::new(),
+            ai_snapshots: Vec::new(),
+            git_head: None,
+            git_marks: Vec::new(),
+            editor_scroll: (0, 0),
+            wrap_top: 0,
+        }
+    }
+
+    fn wipe(&mut self) {
+        self.buffer.wipe();
+        for mut snapshot in self.ai_snapshots.drain(..) {
+            snapshot.lines.zeroize();
+        }
+    }
+}
+
+/// A misspelled word, as a char range of its row, and what it could be.
+pub struct Spelling {
+    pub row: usize,
+    pub start: usize,
+    pub end: usize,
+    pub word: String,
+    pub suggestions: Vec<String>,
+    pub selected: usize,
+}
+
+/// Where the last search matches, as (row, char column), kept up to date
+/// as the buffer changes.
+pub struct SearchResults {
+    pub query: String,
+    pub matches: Vec<(usize, usize)>,
+    pub selected: usize,
+    /// Only matches inside this (start, end) range count.
+    pub scope: Option<((usize, usize), (usize, usize))>,
+}
+
+/// A regex replace being typed: the selection it may be limited to, and
+/// which input has the focus.
+pub struct ReplaceState {
+    pub scope: Option<replace::Scope>,
+    pub in_selection: bool,
+    pub editing_with: bool,
+}
+
+/// Matches of a `chars`-long query that fit inside `scope`.
+fn in_scope(matches: &mut Vec<(usize, usize)>, chars: usize, scope: Option<((usize, usize), (usize, usize))>) {
+    if let Some((start, end)) = scope {
+        matches.retain(|&(row, col)| (row, col) >= start && (row, col + chars) <= end);
+    }
+}
+
+/// Every place `query` starts in the editor's text.
+fn find_all(editor: &Editor, query: &str) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    for row in 0..editor.len_lines() {
+        let line = editor.line(row);
+        let mut from = 0;
+        while let Some(at) = line[from..].find(query) {
+            let at = from + at;
+            matches.push((row, line[..at].chars().count()));
+            from = at + query.len().max(1);
+        }
+    }
+    matches
+}
+
+/// A find in files: the hits so far and the highlighted one.
+pub struct Grep {
+    pub query: String,
+    pub hits: Vec<Hit>,
+    pub selected: usize,
+    /// How many files were searched, once it's done.
+    pub files: Option<usize>,
+    rx: Option<mpsc::UnboundedReceiver<Found>>,
+}
+
+/// Write one buffer's swap file if it was edited since the last one, or
+/// drop it once the buffer is clean again.
+fn write_swap(filename: &str, buffer: &Buffer, large_file: bool, dirty: &mut bool, swap_file: &mut Option<PathBuf>) {
+    if !buffer.modified {
+        if let Some(path) = swap_file.take() {
+            swap::remove(&path);
+        }
+        return;
+    }
+    // Encrypted text stays off the disk; large files are too slow to copy.
+    if !*dirty || large_file || filename == "[No Name]" || Cipher::for_path(Path::new(filename)).is_some() {
+        return;
+    }
+    *dirty = false;
+    let path = Path::new(filename);
+    match swap::write(path, &buffer.editor.text().to_string()) {
+        Ok(()) => *swap_file = Some(swap::path_for(path)),
+        Err(e) => log::error!("Failed to write the swap file of {}: {}", filename, e),
+    }
+}
+
+/// Pipe `text` through `formatter`; it's killed if it runs past
+/// `FORMAT_TIMEOUT`.
+async fn format_text(formatter: &str, text: String) -> Result<String, String> {
+    match tokio::time::timeout(FORMAT_TIMEOUT, shell::run(formatter, Some(text))).await {
+        Err(_) => Err(format!("{} took over {}s", formatter, FORMAT_TIMEOUT.as_secs())),
+        Ok(Err(e)) => Err(format!("{} failed: {}", formatter, e)),
+        Ok(Ok(output)) if !output.success => Err(match output.complaint() {
+            Some(complaint) => format!("{} failed: {}", formatter, complaint),
+            None => format!("{} failed", formatter),
+        }),
+        Ok(Ok(output)) => Ok(output.stdout),
+    }
+}
+
+/// The buffer as it was before an AI answer was applied.
+pub struct AiSnapshot {
+    pub prompt: String,
+    pub taken: Instant,
+    pub lines: Vec<String>,
+    pub cursor: (usize, usize),
+}
+
+/// Suggested text to insert at (row, col); `line` is that row's text when
+/// it was asked for, so a stale suggestion can be dropped.
+pub struct Ghost {
+    pub row: usize,
+    pub col: usize,
+    line: String,
+    pub text: String,
+}
+
+/// Where language server completions were asked for: the word being
+/// completed starts at `start` on `row`, and the cursor was at `col` of
+/// that row reading `line`.
+struct CompletionRequest {
+    row: usize,
+    start: usize,
+    col: usize,
+    line: String,
+    encoding: lsp::Encoding,
+    /// Asked for with Ctrl+Space rather than by typing.
+    manual: bool,
+    response: oneshot::Receiver<serde_json::Value>,
+}
+
+/// The completion menu: the server's items for the word at `start` of
+/// `row`, and which of them match what's typed of it so far.
+pub struct CompletionMenu {
+    pub row: usize,
+    pub start: usize,
+    col: usize,
+    line: String,
+    encoding: lsp::Encoding,
+    incomplete: bool,
+    pub items: Vec<lsp::CompletionItem>,
+    /// Indices into `items`, best match first.
+    pub shown: Vec<usize>,
+    pub selected: usize,
+    /// The typed word `shown` was matched against.
+    typed: Option<String>,
 }
 
+/// Rows of the completion menu shown at a time.
+pub const COMPLETION_ROWS: usize = 8;
+
+use std::collections::HashMap;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::io::{Read, Seek, SeekFrom};
+
+/// What the AI tasks send back: the answer, or the error message.
+pub type AiResult = Result<ai::Answer, String>;
+
+/// What the background side of a git commit sends back.
+pub enum CommitStep {
+    /// The AI's draft of the message, from the file's diff.
+    Draft(AiResult),
+    /// No draft: nothing to commit, or git couldn't tell.
+    NoDiff(String),
+    /// git's summary line of the commit, or its error.
+    Committed(Result<String, String>),
+}
+
+/// What the background git gutter work sends back, for the file named.
+pub enum GitUpdate {
+    /// The file's lines as committed in HEAD.
+    Head { file: String, lines: Option<Arc<Vec<String>>> },
+    Marks { file: String, marks: Vec<Option<diff::Mark>> },
+}
+
+/// What an autosave write sends back: the file, and what it holds now.
+pub type AutosaveResult = (String, DiskStamp, Result<(), String>);
+
+/// What a shell command task sends back.
+pub type CommandResult = Result<shell::Output, String>;
+
+/// How long a formatter gets before the file is saved without it.
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lines on each side of a selection sent along with it to the AI.
+const AI_CONTEXT_LINES: usize = 5;
+/// Chunks `--ask` lists, and how many of them go to the AI.
+const ASK_RESULTS: usize = 10;
+const ASK_EXCERPTS: usize = 6;
+/// Most times a macro can be repeated in one go.
+const MAX_MACRO_REPEAT: usize = 10_000;
+/// AI snapshots kept for reverting.
+const MAX_AI_SNAPSHOTS: usize = 20;
+/// Chars before the cursor sent for an inline completion.
+const COMPLETION_CONTEXT: usize = 2000;
+/// Welcome screen entries after the recent files, with their keys.
+pub const WELCOME_ACTIONS: [(char, &str); 3] = [('o', "Open file…"), ('n', "New file"), ('s', "Settings")];
+/// Welcome screen tips when the config has none.
+const TIPS: &[&str] = &[
+    "Ctrl+P asks the AI to rewrite the selection, or the whole file.",
+    "Alt+I opens a chat about the file; Alt+W explains the selection.",
+    "Ctrl+D adds a cursor at the next match of the word under the cursor.",
+    "Alt+arrows select a block; typing then edits every row of it.",
+    "Alt+: records a macro and Alt+; plays it back; Alt+digits repeat it.",
+    "Ctrl+B bookmarks a line and F2 lists the bookmarks of the file.",
+    "Alt+Z goes back to the buffer as it was before any AI answer.",
+    "--ask \"question\" searches the project by meaning from the shell.",
+    "Keys can be rebound per action in config.json under \"keybindings\".",
+];
 
 impl<'a> App<'a> {
     pub fn new(filename: Option<String>) -> Self {
-        let textarea = if let Some(ref file) = filename {
-            if let Ok(content) = fs::read_to_string(file) {
-                let mut textarea = TextArea::from(content.lines().map(|s| s.to_string()));
-                textarea.set_line_number_style(ratatui::style::Style::default().fg(ratatui::style::Color::DarkGray));
-                textarea
-            } else {
-                let mut textarea = TextArea::default();
-                textarea.set_line_number_style(ratatui::style::Style::default().fg(ratatui::style::Color::DarkGray));
-                textarea
+        let config = Config::load().unwrap_or_default();
+        let large = filename.as_deref().and_then(|file| Self::open_large(Path::new(file), &config));
+        let (read, loading) = match large {
+            Some((decoded, loading)) => (Some(Ok(decoded)), Some(loading)),
+            None => (filename.as_deref().map(|file| Self::read_file(Path::new(file), &config, false)), None),
+        };
+        let encrypted = filename.as_deref().and_then(|file| Cipher::for_path(Path::new(file))).is_some();
+        let mut error = None;
+        let decoded = match read {
+            Some(Ok(decoded)) => Some(decoded),
+            // A missing file is a new one; an encrypted file we couldn't
+            // open must not be overwritten with an empty buffer.
+            Some(Err(e)) if encrypted && filename.as_deref().is_some_and(|f| Path::new(f).exists()) => {
+                error = Some(e);
+                None
             }
-        } else {
-            let mut textarea = TextArea::default();
-            textarea.set_line_number_style(ratatui::style::Style::default().fg(ratatui::style::Color::DarkGray));
-            textarea
+            _ => None,
         };
-        
-        let mut prompt_textarea = TextArea::default();
-        prompt_textarea.set_placeholder_text("Describe your wish (e.g., 'Refactor this function')...");
-
-        let mut setup_textarea = TextArea::default();
-        setup_textarea.set_placeholder_text("Paste your Google Gemini API Key here...");
+        let mut app = Self::with_config(filename, decoded, config);
+        app.repaint = encrypted;
+        if let Some(loading) = loading {
+            app.start_loading(loading);
+        }
+        app.load_git_head();
+        if let Some(e) = error {
+            app.read_only = true;
+            app.locked = true;
+            app.set_status(&format!("Couldn't decrypt: {}", e));
+        } else {
+            app.check_write_access();
+        }
+        app
+    }
 
-        let mut search_textarea = TextArea::default();
-        search_textarea.set_placeholder_text("Search...");
-        search_textarea.set_block(ratatui::widgets::Block::default().borders(ratatui::widgets::Borders::ALL).title(" Search "));
+    /// Open `decoded` (e.g. piped in) as the buffer of `filename`.
+    pub fn with_contents(filename: Option<String>, decoded: Option<fileio::Decoded>) -> Self {
+        Self::with_config(filename, decoded, Config::load().unwrap_or_default())
+    }
 
-        let mut filename_input = TextArea::default();
-        filename_input.set_placeholder_text("Enter filename...");
-        filename_input.set_block(ratatui::widgets::Block::default().borders(ratatui::widgets::Borders::ALL).title(" Save As "));
+    fn with_config(filename: Option<String>, decoded: Option<fileio::Decoded>, mut config: Config) -> Self {
+        let (encoding, bom) = decoded.as_ref().map(|d| (d.encoding, d.bom)).unwrap_or((UTF_8, false));
+        let content = decoded.map(|d| d.text);
+        let (line_ending, final_newline) = match &content {
+            Some(content) => (LineEnding::detect(content), content.is_empty() || content.ends_with('\n')),
+            None => (LineEnding::Lf, true),
+        };
+        let editor = App::new_editor(content.as_deref().unwrap_or(""));
+        
+        let prompt_input = PromptInput::new("✨ AI Magic Prompt", "Describe your wish (e.g., 'Refactor this function')...")
+            .with_history(PromptHistory::load("prompt_history.json"))
+            .with_validation(input::validate_not_empty)
+            .multiline();
+        let setup_input = PromptInput::new(" API Key ", "Paste your Google Gemini API Key here...")
+            .masked()
+            .with_validation(input::validate_api_key);
+        let search_input = PromptInput::new(" Search ", "Search...")
+            .with_history(PromptHistory::load("search_history.json"))
+            .with_validation(input::validate_not_empty);
+        let filename_input = PromptInput::new(" Save As ", "Enter filename...")
+            .with_completion(input::complete_path)
+            .with_validation(input::validate_filename);
+        let patch_input = PromptInput::new(" Patch ", "Patch file...")
+            .with_completion(input::complete_path)
+            .with_validation(input::validate_filename);
+        let grep_input = PromptInput::new(" Find in files ", "Text to find...")
+            .with_history(PromptHistory::load("grep_history.json"))
+            .with_validation(input::validate_not_empty);
+        let chat_input = PromptInput::new(" Ask ", "Ask about this file...")
+            .with_validation(input::validate_not_empty);
+        let command_input = PromptInput::new(" Run command ", "Shell command, e.g. date or ls -l...")
+            .with_history(PromptHistory::load("command_history.json"))
+            .with_validation(input::validate_not_empty);
 
-        let config = Config::load().unwrap_or(Config::default());
         let mode = if config.api_key.is_empty() {
             AppMode::Setup
         } else {
@@ -77,37 +871,228 @@ impl<'a> App<'a> {
 
 
         let syntax_set = SyntaxSet::load_defaults_newlines();
-        let theme_set = ThemeSet::load_defaults();
 
         let (tx, rx) = mpsc::channel(1);
+        let (cell_tx, cell_rx) = mpsc::channel(1);
+        let (command_tx, command_rx) = mpsc::channel(1);
+        let (format_tx, format_rx) = mpsc::channel(1);
+        let (lsp_tx, lsp_rx) = mpsc::unbounded_channel();
+        let (link_tx, link_rx) = mpsc::channel(16);
+        let (chat_tx, chat_rx) = mpsc::channel(1);
+        let (progress_tx, progress_rx) = mpsc::unbounded_channel();
+        let (ghost_tx, ghost_rx) = mpsc::channel(1);
+        let (explain_tx, explain_rx) = mpsc::channel(1);
+        let (autosave_tx, autosave_rx) = mpsc::channel(1);
+        let (commit_tx, commit_rx) = mpsc::channel(1);
+        let (git_tx, git_rx) = mpsc::channel(4);
+        let (preview_tx, preview_rx) = mpsc::channel(1);
+        let keymap = KeyMap::new(&config.keybindings, false);
+        // What was last picked in the editor wins over the config.
+        let prefs = prefs::load();
+        if let Some(theme) = prefs.theme.clone().filter(|t| Theme::names(&config.themes).contains(t)) {
+            config.theme = theme;
+        }
+        if let Some(show) = prefs.show_whitespace {
+            config.show_whitespace = show;
+        }
+        let colors = config.colors.unwrap_or_else(ColorSupport::detect);
+        let theme = Theme::resolve(&config.theme, &config.themes).fit(colors);
 
-        Self {
-            textarea,
-            prompt_textarea,
-            setup_textarea,
-            search_textarea,
+        let mut app = Self {
+            buffer: Buffer::new(editor),
+            prompt_input,
+            setup_input,
+            last_input: Instant::now(),
+            lock_input: PromptInput::new(" Passphrase ", "").masked(),
+            lock_passphrase: None,
+            choosing_lock_passphrase: false,
+            search_input,
             filename_input,
+            patch_input,
             should_quit: false,
-            mode,
+            modes: vec![mode],
             filename: filename.unwrap_or_else(|| String::from("[No Name]")),
             config,
             ai_response_tx: tx,
             ai_response_rx: Some(rx),
-            is_modified: false,
+            ai_task: None,
+            ai_target: None,
+            ai_progress_tx: progress_tx,
+            ai_progress_rx: Some(progress_rx),
+            ai_progress: None,
+            ai_prompt: String::new(),
+            ai_snapshots: Vec::new(),
+            git_head: None,
+            git_marks: Vec::new(),
+            ai_snapshot_selected: 0,
+            encoding,
+            bom,
+            line_ending,
+            final_newline,
             status_message: None,
             syntax_set,
-            theme_set,
+            editor_scroll: (0, 0),
+            editor_area: ratatui::layout::Rect::default(),
+            wrap_top: 0,
+            merge: None,
+            screen_rows: Vec::new(),
+            keyboard_enhanced: false,
+            cursor_shape: CursorShape::Hidden,
+            read_only: false,
+            write_protected: None,
+            locked: false,
+            tail: None,
+            large_file: false,
+            loading: None,
+            secure: false,
+            journal: None,
+            table_view: false,
+            keymap,
+            theme,
+            colors,
+            cell_output: None,
+            show_cell_output: false,
+            cell_result_tx: cell_tx,
+            cell_result_rx: Some(cell_rx),
+            command_input,
+            command_to_new_buffer: false,
+            running_command: None,
+            command_tx,
+            command_rx: Some(command_rx),
+            formatting: None,
+            format_tx,
+            format_rx: Some(format_rx),
+            profile: Profile::default(),
+            vim: None,
+            autosave_checked: Instant::now(),
+            autosaved_at: None,
+            autosave_edited: None,
+            autosave_tx,
+            autosave_rx: Some(autosave_rx),
+            kill_ring: KillRing::default(),
+            smart_edit: None,
+            buffers: vec![BufferState::new(false)],
+            active_buffer: 0,
+            buffer_selected: 0,
+            buffer_close_pending: None,
+            quit_discarded: Vec::new(),
+            report: None,
+            cursors: Vec::new(),
+            block: None,
+            recording: None,
+            macro_keys: Vec::new(),
+            repeat_count: None,
+            macro_run: None,
+            disk_stamp: None,
+            disk_checked: Instant::now(),
+            swap_dirty: false,
+            swap_file: None,
+            swap_checked: Instant::now(),
+            git_stale: None,
+            git_tx,
+            git_rx: Some(git_rx),
+            git_marks_busy: false,
+            recovery: None,
+            disk_diff: None,
+            head_diff: None,
+            head_diff_side_by_side: false,
+            head_diff_scroll: 0,
+            outline: Vec::new(),
+            outline_selected: 0,
+            bookmarks: Vec::new(),
+            folds: Vec::new(),
+            bookmark_selected: 0,
+            spell_check: None,
+            dictionary: None,
+            dictionary_error: None,
+            spelling: None,
+            grep_input,
+            grep: None,
+            recent_files: Vec::new(),
+            welcome_selected: 0,
+            diagnostics: Vec::new(),
+            diagnostic_selected: None,
+            show_diagnostics: false,
+            search_results: None,
+            search_scope: None,
+            search_in_selection: false,
+            replace_find: PromptInput::new(" Replace (regex) ", "Pattern..."),
+            replace_with: PromptInput::new(" With ", "Replacement ($1 for groups)..."),
+            replace: None,
+            links_pending: 0,
+            link_run: 0,
+            link_tasks: Vec::new(),
+            link_result_tx: link_tx,
+            link_result_rx: Some(link_rx),
+            lsp: HashMap::new(),
+            lsp_failed: Vec::new(),
+            lsp_tx,
+            lsp_rx: Some(lsp_rx),
+            lsp_changed: None,
+            completion: None,
+            completion_request: None,
+            front_matter_form: None,
+            chat: Vec::new(),
+            chat_input,
+            show_chat: prefs.show_chat,
+            prefs,
+            chat_scroll: 0,
+            chat_task: None,
+            chat_response_tx: chat_tx,
+            chat_response_rx: Some(chat_rx),
+            ghost: None,
+            ghost_request: None,
+            ghost_tx,
+            ghost_rx: Some(ghost_rx),
+            explanation: None,
+            explain_scroll: 0,
+            explain_task: None,
+            explain_tx,
+            explain_rx: Some(explain_rx),
+            commit_message: None,
+            commit_task: None,
+            committing: false,
+            commit_tx,
+            commit_rx: Some(commit_rx),
+            graphics: Protocol::detect(),
+            focused: None,
+            alerts: Vec::new(),
+            preview: None,
+            preview_area: ratatui::layout::Rect::default(),
+            preview_task: None,
+            preview_tx,
+            preview_rx: Some(preview_rx),
+            repaint: false,
+            share: None,
+            prompt_pick: None,
+            indent: Indent { hard_tabs: false, width: 4 },
+        };
+        app.disk_stamp = DiskStamp::read(Path::new(&app.filename));
+        if app.disk_stamp.is_some() {
+            let rows = app.buffer.editor.len_lines();
+            app.bookmarks = bookmarks::load(Path::new(&app.filename)).into_iter().filter(|b| b.row < rows).collect();
+        }
+        app.apply_profile();
+        app.buffer.soft_wrap = app.soft_wrap_pref();
+        if app.config.vim_mode {
+            app.vim = Some(VimState::new());
         }
+        app
     }
 
     pub fn save_config(&mut self) {
-        if let Some(key) = self.setup_textarea.lines().first() {
+        if let Some(key) = self.setup_input.submit() {
             self.config.api_key = key.trim().to_string();
+            self.config.api_key_source = if self.config.use_keychain {
+                KeySource::Keychain
+            } else {
+                KeySource::File
+            };
             if let Err(e) = self.config.save() {
                 // In a real app we might want to show an error message
                 eprintln!("Failed to save config: {}", e);
             } else {
-                self.mode = AppMode::Normal;
+                self.set_mode(AppMode::Normal);
             }
         }
     }
@@ -116,69 +1101,4905 @@ impl<'a> App<'a> {
         self.should_quit = true;
     }
 
+    /// The layer that gets input.
+    pub fn mode(&self) -> AppMode {
+        *self.modes.last().unwrap_or(&AppMode::Normal)
+    }
+
+    pub fn modes(&self) -> &[AppMode] {
+        &self.modes
+    }
+
+    /// Open a dialog over whatever is showing.
+    pub fn push_mode(&mut self, mode: AppMode) {
+        // Dialogs that open while locked wait under the lock screen.
+        if self.modes.last() == Some(&AppMode::Locked) {
+            self.modes.insert(self.modes.len() - 1, mode);
+        } else {
+            self.modes.push(mode);
+        }
+    }
+
+    /// Close the top dialog, going back to the one below.
+    pub fn pop_mode(&mut self) {
+        self.modes.pop();
+        if self.modes.is_empty() {
+            self.modes.push(AppMode::Normal);
+        }
+    }
+
+    /// Drop `mode` wherever it is in the stack.
+    pub fn remove_mode(&mut self, mode: AppMode) {
+        self.modes.retain(|m| *m != mode);
+        if self.modes.is_empty() {
+            self.modes.push(AppMode::Normal);
+        }
+    }
+
+    /// Turn the top layer into `mode` (e.g. the prompt into its progress popup).
+    pub fn set_mode(&mut self, mode: AppMode) {
+        self.modes.pop();
+        self.modes.push(mode);
+    }
+
+    /// Names of the saved prompts, in the order listed.
+    pub fn saved_prompts(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.config.prompts.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// Move the highlight in the saved prompt list; past either end it goes
+    /// back to the typed prompt.
+    pub fn move_prompt_pick(&mut self, delta: isize) {
+        let count = self.config.prompts.len();
+        if count == 0 {
+            return;
+        }
+        let slots = count as isize + 1;
+        let current = self.prompt_pick.map_or(count as isize, |i| i as isize);
+        let next = (current + delta).rem_euclid(slots) as usize;
+        self.prompt_pick = (next < count).then_some(next);
+    }
+
+    /// The highlighted saved prompt with its variables filled in; `input`
+    /// is what was typed.
+    pub fn picked_prompt(&self, input: &str) -> Option<String> {
+        let name = self.saved_prompts().get(self.prompt_pick?).copied()?;
+        let template = &self.config.prompts[name];
+        let language = self.detect_language().unwrap_or_else(|| self.filename.clone());
+        Some(
+            template
+                .replace("{filename}", &self.filename)
+                .replace("{language}", &language)
+                .replace("{input}", input.trim())
+                .trim()
+                .to_string(),
+        )
+    }
+
     pub fn enter_prompt_mode(&mut self) {
-        self.mode = AppMode::Prompting;
+        self.prompt_pick = None;
+        let title = if self.buffer.editor.is_selecting() { "✨ AI Magic Prompt (selection)" } else { "✨ AI Magic Prompt" };
+        self.prompt_input.set_title(title);
+        self.push_mode(AppMode::Prompting);
     }
 
     pub fn exit_prompt_mode(&mut self) {
-        self.mode = AppMode::Normal;
-        // Optional: Clear prompt on exit or keep history? For now, let's keep it simple.
+        self.pop_mode();
+    }
+
+    /// Send `prompt` to Gemini with the selection (plus a few lines around
+    /// it), or the whole buffer when nothing is selected; the answer arrives
+    /// on `ai_response_rx`.
+    pub fn submit_prompt(&mut self, prompt: String) {
+        self.prompt_input.reset();
+
+        let gemini = self.gemini(true);
+        let filename = self.filename.clone();
+        let language = self.detect_language().unwrap_or_else(|| filename.clone());
+        let tx = self.ai_response_tx.clone();
+        self.ai_target = self.buffer.editor.selection_range().filter(|(start, end)| start != end);
+        self.ai_prompt = prompt.clone();
+        let selection = self.ai_target.map(|(start, end)| self.selection_with_context(start, end));
+        let current_code = if selection.is_some() { String::new() } else { self.buffer.text().to_string() };
+
+        self.set_processing(true);
+
+        let task = tokio::spawn(async move {
+            let result = match selection {
+                Some((before, selection, after)) => {
+                    ai::request_gemini_selection(gemini, before, selection, after, filename, language, prompt).await
+                }
+                None => ai::request_gemini(gemini, current_code, filename, language, prompt).await,
+            };
+            match result {
+                Ok(answer) => {
+                    log::info!("Response received successfully.");
+                    let _ = tx.send(Ok(answer)).await;
+                }
+                Err(e) => {
+                    log::error!("Gemini Request Failed: {}", e);
+                    let _ = tx.send(Err(e.to_string())).await;
+                }
+            }
+        });
+        self.ai_task = Some(task.abort_handle());
+    }
+
+    /// Rewrite the whole buffer with `instruction` and wait for the answer,
+    /// for `--apply`. Returns a unified diff of the change (empty if the
+    /// answer changed nothing) and the usage summary.
+    pub async fn apply_instruction(&mut self, instruction: &str) -> anyhow::Result<(String, String)> {
+        if self.config.api_key.is_empty() {
+            return Err(anyhow::anyhow!("No API key configured (set GEMINI_API_KEY or run neuronano once)"));
+        }
+        let language = self.detect_language().unwrap_or_else(|| self.filename.clone());
+        let before = self.file_contents();
+        let current_code = self.buffer.text().to_string();
+        let answer =
+            ai::request_gemini(self.gemini(false), current_code, self.filename.clone(), language, instruction.to_string())
+                .await?;
+        let summary = self.record_usage(&answer);
+        let lines = answer.into_lines()?;
+        self.replace_lines(lines.iter().map(|l| indent::normalize(l, self.indent)).collect());
+        self.sync_buffer();
+        let after = self.file_contents();
+        if after == before {
+            return Ok((String::new(), summary));
+        }
+        self.buffer.modified = true;
+        let diff = TextDiff::from_lines(&before, &after)
+            .unified_diff()
+            .header(&format!("a/{}", self.filename), &format!("b/{}", self.filename))
+            .to_string();
+        Ok((diff, summary))
+    }
+
+    /// `--ask`: bring the embeddings index of the project at `root` up to
+    /// date, then rank its chunks against `question` and have the AI say
+    /// where it's handled from the best ones. Returns the ranked chunks
+    /// and the answer.
+    pub async fn ask_codebase(&mut self, root: &Path, question: &str) -> anyhow::Result<(Vec<semantic::Hit>, ai::Answer)> {
+        if self.config.api_key.is_empty() {
+            return Err(anyhow::anyhow!("No API key configured (set GEMINI_API_KEY or run neuronano once)"));
+        }
+        let gemini = self.gemini(false);
+        let model = self.config.ai_embedding_model.clone();
+        let mut index = semantic::Index::load(root);
+        let embedded = index.update(root, &gemini, &model).await?;
+        index.save(root)?;
+        log::info!("Embedded {} files; the index has {} chunks", embedded, index.chunks());
+
+        let query = ai::embed(&gemini, &model, &[question.to_string()], "RETRIEVAL_QUERY", semantic::DIMENSIONS).await?;
+        let hits = index.search(&query[0], ASK_RESULTS);
+        if hits.is_empty() {
+            return Err(anyhow::anyhow!("Nothing to search in {}", root.display()));
+        }
+        let excerpts = hits
+            .iter()
+            .take(ASK_EXCERPTS)
+            .map(|hit| (format!("{}:{}-{}", hit.file, hit.start, hit.end), hit.text(root)))
+            .collect();
+        let answer = ai::request_codebase_answer(gemini, question.to_string(), excerpts).await?;
+        Ok((hits, answer))
+    }
+
+    /// Text before the selection (from `AI_CONTEXT_LINES` rows up), the
+    /// selection itself and the text after it.
+    fn selection_with_context(&mut self, start: (usize, usize), end: (usize, usize)) -> (String, String, String) {
+        let last = (end.0 + AI_CONTEXT_LINES).min(self.buffer.editor.len_lines() - 1);
+        let after_end = (last, self.buffer.editor.line_len(last));
+        let before = self.buffer.range((start.0.saturating_sub(AI_CONTEXT_LINES), 0), start);
+        (before, self.buffer.range(start, end), self.buffer.range(end, after_end))
+    }
+
+    /// Put an AI answer into the buffer: over the selection it was asked
+    /// about, or as the whole new content. Errors only go to the status bar.
+    pub fn apply_ai_response(&mut self, response: AiResult) {
+        self.ai_task = None;
+        self.set_processing(false);
+        let target = self.ai_target.take();
+        let answer = match response {
+            Ok(answer) => {
+                let summary = self.record_usage(&answer);
+                self.set_status(&summary);
+                self.alert("ai", "AI answer applied");
+                answer
+            }
+            Err(e) => {
+                self.set_status(&format!("AI error: {}", e));
+                self.alert("error", "AI request failed");
+                return;
+            }
+        };
+        // Whole-file answers may have been streamed to disk.
+        let (content, lines) = if target.is_some() {
+            (indent::normalize(&answer.text, self.indent), Vec::new())
+        } else {
+            match answer.into_lines() {
+                Ok(lines) => (String::new(), lines.iter().map(|l| indent::normalize(l, self.indent)).collect()),
+                Err(e) => {
+                    self.set_status(&format!("AI error: {}", e));
+                    return;
+                }
+            }
+        };
+
+        // Journal the answer as one edit of its own.
+        self.sync_buffer();
+        self.record(Op::Ai { prompt: self.ai_prompt.clone() });
+        self.ai_snapshots.push(AiSnapshot {
+            prompt: std::mem::take(&mut self.ai_prompt),
+            taken: Instant::now(),
+            lines: self.buffer.editor.lines(),
+            cursor: self.buffer.editor.cursor(),
+        });
+        if self.ai_snapshots.len() > MAX_AI_SNAPSHOTS {
+            self.ai_snapshots.remove(0);
+        }
+
+        self.buffer.begin_group();
+        match target {
+            Some((start, end)) => {
+                let mut content = content;
+                // A selection of whole lines ends on the next row's column 0;
+                // keep the line break the answer usually drops.
+                if end.1 == 0 && end.0 > start.0 && !content.ends_with('\n') {
+                    content.push('\n');
+                }
+                self.buffer.editor.cancel_selection();
+                self.buffer.editor.jump((start.0, start.1));
+                self.buffer.editor.start_selection();
+                self.buffer.editor.jump((end.0, end.1));
+                if content.is_empty() {
+                    self.buffer.editor.delete_char();
+                } else {
+                    self.buffer.editor.insert_str(&content);
+                }
+            }
+            None => self.replace_lines(lines),
+        }
+        self.buffer.end_group();
+        self.sync_buffer();
+        let status = self.status_message.take();
+        self.mark_dirty();
+        self.status_message = status;
+    }
+
+    /// Put back the buffer from before the last AI answer.
+    pub fn revert_ai_change(&mut self) {
+        match self.ai_snapshots.len() {
+            0 => self.set_status("No AI change to revert"),
+            n => self.restore_ai_snapshot(n - 1),
+        }
+    }
+
+    pub fn open_ai_snapshots(&mut self) {
+        if self.ai_snapshots.is_empty() {
+            self.set_status("No AI changes yet");
+            return;
+        }
+        self.ai_snapshot_selected = 0;
+        self.push_mode(AppMode::AiSnapshots);
+    }
+
+    /// Move the highlight in the snapshot list, which shows newest first.
+    pub fn move_ai_snapshot(&mut self, delta: isize) {
+        let last = self.ai_snapshots.len().saturating_sub(1);
+        self.ai_snapshot_selected = self.ai_snapshot_selected.saturating_add_signed(delta).min(last);
+    }
+
+    pub fn restore_selected_ai_snapshot(&mut self) {
+        self.pop_mode();
+        if let Some(index) = self.ai_snapshots.len().checked_sub(self.ai_snapshot_selected + 1) {
+            self.restore_ai_snapshot(index);
+        }
+    }
+
+    /// Restore snapshot `index` as an undoable edit. Later snapshots were
+    /// taken on top of it, so they go too.
+    fn restore_ai_snapshot(&mut self, index: usize) {
+        let snapshot = self.ai_snapshots.remove(index);
+        self.ai_snapshots.truncate(index);
+        let rows = self.buffer.editor.len_lines();
+        self.replace_rows(0..rows, &snapshot.lines);
+        self.buffer.editor.jump((snapshot.cursor.0, snapshot.cursor.1));
+        self.mark_dirty();
+        self.set_status(&format!("Reverted AI change: {}", snapshot.prompt));
+    }
+
+    /// The rewrite template: `ai_system_prompt` from the config, else the
+    /// template file.
+    fn system_prompt(&self) -> Option<String> {
+        self.config.ai_system_prompt.clone().or_else(ai::load_system_prompt)
+    }
+
+    /// Request settings from the config; `progress` reports retries to the
+    /// Processing popup.
+    fn gemini(&self, progress: bool) -> ai::Gemini {
+        ai::Gemini {
+            api_key: self.config.api_key.clone(),
+            models: self.config.ai_models.clone(),
+            retry: ai::RetryPolicy { max_attempts: self.config.ai_max_attempts, base_delay: Duration::from_secs(1) },
+            params: ai::GenerationParams {
+                temperature: self.config.ai_temperature,
+                top_p: self.config.ai_top_p,
+                max_output_tokens: self.config.ai_max_output_tokens,
+                stop_sequences: self.config.ai_stop_sequences.clone(),
+            },
+            system_prompt: self.system_prompt(),
+            response_language: self.config.ai_response_language.clone(),
+            progress: progress.then(|| self.ai_progress_tx.clone()),
+            max_request_bytes: self.config.ai_max_request_bytes,
+            max_response_bytes: self.config.ai_max_response_bytes,
+            spool: !self.secure && Cipher::for_path(Path::new(&self.filename)).is_none(),
+        }
+    }
+
+    /// Show the latest progress note of the in-flight request.
+    pub fn poll_ai_progress(&mut self) {
+        let Some(rx) = &mut self.ai_progress_rx else { return };
+        while let Ok(note) = rx.try_recv() {
+            self.ai_progress = Some(note);
+        }
+    }
+
+    /// Estimated USD cost of a request.
+    pub fn ai_cost(&self, prompt_tokens: u64, output_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.config.ai_input_price + output_tokens as f64 * self.config.ai_output_price) / 1_000_000.0
+    }
+
+    /// Add a request to the persisted totals; returns a summary for the
+    /// status bar. An answer from a fallback model is noted there right
+    /// away, since not every caller shows the summary.
+    pub fn record_usage(&mut self, answer: &ai::Answer) -> String {
+        let usage = answer.usage;
+        let cost = self.ai_cost(usage.prompt_tokens, usage.output_tokens);
+        let mut stats = Stats::load();
+        stats.add(usage, cost);
+        if let Err(e) = stats.save() {
+            log::error!("Failed to save {}: {}", Stats::path().display(), e);
+        }
+        let mut summary = format!(
+            "AI: {} in / {} out tokens, ~${:.4} (total ~${:.2})",
+            usage.prompt_tokens, usage.output_tokens, cost, stats.cost
+        );
+        if let Some(model) = &answer.fallback {
+            summary.push_str(&format!(", answered by fallback {}", model));
+            self.set_status(&summary);
+        }
+        summary
+    }
+
+    /// Tokens the AI prompt would send as typed: the instruction plus the
+    /// selection (with context) or the whole buffer.
+    pub fn estimate_prompt_tokens(&mut self) -> usize {
+        let context = match self.buffer.editor.selection_range().filter(|(start, end)| start != end) {
+            Some((start, end)) => {
+                let (before, selection, after) = self.selection_with_context(start, end);
+                ai::estimate_tokens(&before) + ai::estimate_tokens(&selection) + ai::estimate_tokens(&after)
+            }
+            None => {
+                let editor = &self.buffer.editor;
+                (0..editor.len_lines()).map(|row| ai::estimate_tokens(&editor.line(row)) + 1).sum()
+            }
+        };
+        // Plus the instructions around them.
+        let instructions = self.system_prompt().as_deref().map_or(100, ai::estimate_tokens);
+        context + ai::estimate_tokens(&self.prompt_input.text()) + instructions
     }
 
     pub fn set_processing(&mut self, is_processing: bool) {
+        self.ai_progress = None;
         if is_processing {
-            self.mode = AppMode::Processing;
+            self.set_mode(AppMode::Processing);
         } else {
-            self.mode = AppMode::Normal;
+            self.remove_mode(AppMode::Processing);
         }
     }
 
-    pub fn enter_search_mode(&mut self) {
-        self.mode = AppMode::Search;
+    /// Abort the in-flight AI request and drop any answer that raced in.
+    pub fn cancel_ai_request(&mut self) {
+        if let Some(task) = self.ai_task.take() {
+            task.abort();
+        }
+        self.ai_target = None;
+        if let Some(rx) = &mut self.ai_response_rx {
+            while rx.try_recv().is_ok() {}
+        }
+        self.set_processing(false);
+        self.set_status("AI request cancelled");
     }
 
-    pub fn exit_search_mode(&mut self) {
-        self.mode = AppMode::Normal;
-        // Clear search text on exit? Maybe keep it for next time.
+    /// Show the chat panel and move the keyboard focus to it.
+    pub fn open_chat(&mut self) {
+        self.show_chat = true;
+        if self.mode() != AppMode::Chat {
+            self.push_mode(AppMode::Chat);
+        }
+        self.remember_chat();
     }
 
-    pub fn save_file(&mut self) -> anyhow::Result<()> {
-        if self.filename == "[No Name]" || self.filename.is_empty() {
-            return Err(anyhow::anyhow!("No filename specified"));
+    pub fn close_chat(&mut self) {
+        self.show_chat = false;
+        self.remove_mode(AppMode::Chat);
+        self.remember_chat();
+    }
+
+    fn remember_chat(&mut self) {
+        if self.prefs.show_chat != self.show_chat {
+            self.prefs.show_chat = self.show_chat;
+            self.save_prefs();
         }
+    }
 
-        let content = self.textarea.lines().join("\n");
-        fs::write(&self.filename, content)?;
-        
-        self.is_modified = false;
-        self.set_status("File Saved!");
-        Ok(())
+    /// Alt+. and Alt+,: make the chat panel wider or narrower by `delta`
+    /// percent of the screen.
+    pub fn resize_chat(&mut self, delta: i16) {
+        if !self.show_chat {
+            self.set_status("The chat panel is closed");
+            return;
+        }
+        let width = self.prefs.chat_width.saturating_add_signed(delta).clamp(prefs::MIN_CHAT_WIDTH, prefs::MAX_CHAT_WIDTH);
+        self.prefs.chat_width = width;
+        self.set_status(&format!("Chat panel: {}% of the width", width));
+        self.save_prefs();
     }
 
-    pub fn set_status(&mut self, msg: &str) {
-        self.status_message = Some(msg.to_string());
+    /// Ask `question` about the buffer; the answer is added to the
+    /// conversation by `poll_chat`. The buffer itself is never touched.
+    pub fn send_chat(&mut self, question: String) {
+        if self.chat_task.is_some() {
+            self.set_status("Still waiting for the last answer");
+            return;
+        }
+        self.chat_input.reset();
+        self.chat.push(ChatMessage::new(Role::User, &question));
+        self.chat_scroll = 0;
+
+        let gemini = self.gemini(false);
+        let messages = self.chat.clone();
+        let current_code = self.buffer.text().to_string();
+        let filename = self.filename.clone();
+        let tx = self.chat_response_tx.clone();
+        let task = tokio::spawn(async move {
+            let result = ai::request_gemini_chat(gemini, messages, current_code, filename).await;
+            if let Err(e) = &result {
+                log::error!("Gemini chat request failed: {}", e);
+            }
+            let _ = tx.send(result.map_err(|e| e.to_string())).await;
+        });
+        self.chat_task = Some(task.abort_handle());
     }
 
-    pub fn prompt_save_as(&mut self) {
-        self.mode = AppMode::SaveAs;
-        // Pre-fill with current filename if it's not [No Name]
-        if self.filename != "[No Name]" {
-            self.filename_input = TextArea::from(vec![self.filename.clone()]);
+    pub fn poll_chat(&mut self) {
+        let Some(rx) = &mut self.chat_response_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        self.chat_task = None;
+        self.chat_scroll = 0;
+        let message = match result {
+            Ok(answer) => {
+                self.record_usage(&answer);
+                self.alert("chat", "Chat answer ready");
+                ChatMessage::new(Role::Model, &answer.text)
+            }
+            Err(e) => {
+                self.alert("error", "Chat request failed");
+                ChatMessage::new(Role::Error, &e)
+            }
+        };
+        self.chat.push(message);
+    }
+
+    /// Insert the last code block of the newest answer at the cursor (over
+    /// the selection, if any) and go back to the editor.
+    pub fn apply_chat_code(&mut self) {
+        if self.read_only {
+            self.set_status("Read-only buffer");
+            return;
+        }
+        let answer = self.chat.iter().rev().find(|m| m.role == Role::Model);
+        let Some(code) = answer.and_then(|m| chat::code_blocks(&m.text).pop()) else {
+            self.set_status("No code block in the last answer");
+            return;
+        };
+        self.buffer.editor.insert_str(&code);
+        self.mark_dirty();
+        self.remove_mode(AppMode::Chat);
+        self.set_status("Inserted code from chat");
+    }
+
+    /// Ask the AI to explain the selection; the answer opens in a popup
+    /// and the buffer is left alone.
+    pub fn explain_selection(&mut self) {
+        let Some((start, end)) = self.buffer.editor.selection_range().filter(|(start, end)| start != end) else {
+            self.set_status("Select some code to explain");
+            return;
+        };
+        let (before, selection, after) = self.selection_with_context(start, end);
+        let gemini = self.gemini(false);
+        let filename = self.filename.clone();
+        let tx = self.explain_tx.clone();
+        let task = tokio::spawn(async move {
+            let result = ai::request_explanation(gemini, before, selection, after, filename).await;
+            let _ = tx.send(result.map_err(|e| e.to_string())).await;
+        });
+        self.explain_task = Some(task.abort_handle());
+        self.explanation = None;
+        self.explain_scroll = 0;
+        self.push_mode(AppMode::Explain);
+    }
+
+    pub fn poll_explanation(&mut self) {
+        let Some(rx) = &mut self.explain_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        self.explain_task = None;
+        self.explanation = Some(match result {
+            Ok(answer) => {
+                self.record_usage(&answer);
+                self.alert("explain", "Explanation ready");
+                answer.text
+            }
+            Err(e) => {
+                self.alert("error", "Explanation request failed");
+                format!("Error: {}", e)
+            }
+        });
+    }
+
+    /// Preview the diagram block under the cursor, the image linked on the
+    /// cursor line, or the file itself if it's an image.
+    pub fn open_preview(&mut self) {
+        let (row, col) = self.buffer.editor.cursor();
+        let lines = &self.buffer.editor.lines();
+        let base = Path::new(&self.filename)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty() && self.filename != "[No Name]")
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let image = links::find_links(lines)
+            .into_iter()
+            .filter(|l| l.row == row && graphics::is_image(&l.target) && !links::is_remote(&l.target))
+            .min_by_key(|l| l.col.abs_diff(col))
+            .map(|l| base.join(&l.target))
+            .or_else(|| graphics::is_image(&self.filename).then(|| Path::new(&self.filename).to_path_buf()));
+
+        let (title, text, source) = if let Some((lang, code)) = graphics::diagram_at(lines, row) {
+            let text = format!("{} diagram, {} lines", lang, code.lines().count());
+            (format!(" {} ", lang), text, Err((lang, code)))
+        } else if let Some(path) = image {
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    self.set_status(&format!("Can't read {}: {}", path.display(), e));
+                    return;
+                }
+            };
+            let size = graphics::png_size(&data).map(|(w, h)| format!(", {}x{}", w, h)).unwrap_or_default();
+            let text = format!("{} ({} KB{})", path.display(), data.len().div_ceil(1024), size);
+            (format!(" {} ", path.display()), text, Ok(data))
         } else {
-             self.filename_input = TextArea::default();
+            self.set_status("Nothing to preview: put the cursor on an image link or a mermaid/dot block");
+            return;
+        };
+
+        let Some(protocol) = self.graphics else {
+            let text = format!("{}\n\nThis terminal can't show images (needs kitty, iTerm2 or sixel graphics).", text);
+            self.preview = Some(Preview { title, text, image: None, drawn: false });
+            self.push_mode(AppMode::Preview);
+            return;
+        };
+        // About the popup's inner size, for tools that need pixels.
+        let max_px = match crossterm::terminal::window_size() {
+            Ok(size) if size.width > 0 && size.columns > 0 => (size.width as u32 * 3 / 4, size.height as u32 * 3 / 4),
+            _ => crossterm::terminal::size().map(|(w, h)| (w as u32 * 6, h as u32 * 12)).unwrap_or((640, 480)),
+        };
+        let tx = self.preview_tx.clone();
+        let task = tokio::spawn(async move {
+            let data = match source {
+                Ok(data) => Ok(data),
+                Err((lang, code)) => graphics::render_diagram(&lang, code).await,
+            };
+            let result = match data {
+                Ok(data) => graphics::prepare(protocol, data, max_px).await,
+                Err(e) => Err(e),
+            };
+            let _ = tx.send(result.map_err(|e| e.to_string())).await;
+        });
+        self.preview_task = Some(task.abort_handle());
+        self.preview = Some(Preview { title, text: format!("{}\n\nRendering...", text), image: None, drawn: false });
+        self.push_mode(AppMode::Preview);
+    }
+
+    pub fn poll_preview(&mut self) {
+        let Some(rx) = &mut self.preview_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        self.preview_task = None;
+        let Some(preview) = &mut self.preview else { return };
+        match result {
+            Ok(image) => {
+                preview.text.clear();
+                preview.image = Some(image);
+            }
+            Err(e) => preview.text = format!("Error: {}", e),
         }
-        self.filename_input.set_block(ratatui::widgets::Block::default().borders(ratatui::widgets::Borders::ALL).title(" Save As "));
     }
 
-    pub fn mark_dirty(&mut self) {
-        self.is_modified = true;
-        self.status_message = None; // Clear status on edit
+    /// Bytes that draw the preview image over the popup, once it's ready
+    /// and not on screen yet. The caller moves the cursor to `preview_area`.
+    /// Ring the bell or send a notification for `event` as configured in
+    /// `notify`, unless the terminal says it has focus.
+    pub fn alert(&mut self, event: &str, message: &str) {
+        let Some(&alert) = self.config.notify.get(event) else { return };
+        if self.focused == Some(true) {
+            return;
+        }
+        // Notifications can end up in logs; keep secure mode's file names out.
+        let title = if self.secure { "neuronano".to_string() } else { format!("neuronano: {}", self.filename) };
+        self.alerts.extend(notify::escape(alert, &title, message));
     }
 
-    pub fn detect_language(&self) -> Option<String> {
-        if let Some(syntax) = self.syntax_set.find_syntax_for_file(&self.filename).ok().flatten() {
-            return Some(syntax.name.clone());
+    pub fn take_alerts(&mut self) -> Option<Vec<u8>> {
+        (!self.alerts.is_empty()).then(|| std::mem::take(&mut self.alerts))
+    }
+
+    pub fn take_preview_image(&mut self) -> Option<Vec<u8>> {
+        let protocol = self.graphics?;
+        let area = self.preview_area;
+        let preview = self.preview.as_mut().filter(|p| !p.drawn && self.modes.last() == Some(&AppMode::Preview))?;
+        let image = preview.image.as_ref()?;
+        preview.drawn = true;
+        Some(graphics::escape(protocol, image, area.width, area.height))
+    }
+
+    pub fn close_preview(&mut self) {
+        if let Some(task) = self.preview_task.take() {
+            task.abort();
+        }
+        if let Some(rx) = &mut self.preview_rx {
+            while rx.try_recv().is_ok() {}
+        }
+        if self.preview.take().is_some_and(|p| p.drawn) {
+            self.repaint = true;
+        }
+        self.remove_mode(AppMode::Preview);
+    }
+
+    pub fn close_explanation(&mut self) {
+        if let Some(task) = self.explain_task.take() {
+            task.abort();
+        }
+        if let Some(rx) = &mut self.explain_rx {
+            while rx.try_recv().is_ok() {}
+        }
+        self.remove_mode(AppMode::Explain);
+    }
+
+    /// Ask for a short continuation at the cursor, shown as ghost text.
+    pub fn request_completion(&mut self) {
+        self.dismiss_ghost();
+        let (row, col) = self.buffer.editor.cursor();
+        let text = self.buffer.text();
+        let cursor = text.line_to_char(row) + col;
+        let before = text.slice(cursor.saturating_sub(COMPLETION_CONTEXT)..cursor).to_string();
+        let editor = &self.buffer.editor;
+        let line = editor.line(row);
+        let at = line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+        let mut after = line[at..].to_string();
+        for next in row + 1..editor.len_lines() {
+            if after.len() > COMPLETION_CONTEXT / 4 {
+                break;
+            }
+            after.push('\n');
+            after.push_str(&editor.line(next));
+        }
+
+        let gemini = self.gemini(false);
+        let filename = self.filename.clone();
+        let tx = self.ghost_tx.clone();
+        let task = tokio::spawn(async move {
+            let result = ai::request_completion(gemini, before, after, filename).await;
+            let _ = tx.send(result.map_err(|e| e.to_string())).await;
+        });
+        let ghost = Ghost { row, col, line: line.into_owned(), text: String::new() };
+        self.ghost_request = Some((ghost, task.abort_handle()));
+        self.set_status("Completing...");
+    }
+
+    /// Show an arrived completion, unless the cursor or its line moved on.
+    pub fn poll_completion(&mut self) {
+        let Some(rx) = &mut self.ghost_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        let Some((mut ghost, _)) = self.ghost_request.take() else { return };
+        self.status_message = None;
+        match result {
+            Ok(answer) => {
+                self.record_usage(&answer);
+                let text = answer.text;
+                let (row, col) = self.buffer.editor.cursor();
+                let current = self.buffer.editor.get_line(row);
+                if text.is_empty() || (row, col) != (ghost.row, ghost.col) || current.as_deref() != Some(&*ghost.line) {
+                    return;
+                }
+                ghost.text = text;
+                self.ghost = Some(ghost);
+            }
+            Err(e) => self.set_status(&format!("Completion failed: {}", e)),
         }
-        None
     }
-}
\ No newline at end of file
+
+    /// Drop the ghost text and any completion still on its way.
+    pub fn dismiss_ghost(&mut self) {
+        self.ghost = None;
+        if let Some((_, task)) = self.ghost_request.take() {
+            task.abort();
+            self.status_message = None;
+        }
+    }
+
+    /// Tab accepts the ghost text and Esc dismisses it; any other key
+    /// dismisses it and is handled as usual. Returns whether the key was
+    /// used up.
+    pub fn ghost_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::KeyCode;
+        if self.ghost.is_none() && self.ghost_request.is_none() {
+            return false;
+        }
+        match (key.code, self.ghost.take()) {
+            (KeyCode::Tab, Some(ghost)) => {
+                self.buffer.editor.insert_str(&ghost.text);
+                self.mark_dirty();
+                true
+            }
+            (KeyCode::Esc, _) => {
+                self.dismiss_ghost();
+                true
+            }
+            _ => {
+                self.dismiss_ghost();
+                false
+            }
+        }
+    }
+
+    /// Back to the list of matches while it's open, else a new search.
+    pub fn enter_search_mode(&mut self) {
+        if self.search_results.is_some() {
+            self.push_mode(AppMode::SearchResults);
+        } else {
+            let selection = self.buffer.editor.selection_range();
+            self.open_search_bar(selection);
+        }
+    }
+
+    /// Ask what to search for; with a `scope`, only inside it to begin
+    /// with.
+    fn open_search_bar(&mut self, scope: Option<((usize, usize), (usize, usize))>) {
+        self.search_scope = scope.filter(|(start, end)| start != end);
+        self.search_in_selection = self.search_scope.is_some();
+        self.update_search_title();
+        self.push_mode(AppMode::Search);
+    }
+
+    /// Ask for a regex and its replacement, within the selection if
+    /// there is one.
+    pub fn open_replace(&mut self) {
+        let scope = self.buffer.editor.selection_range().filter(|(start, end)| start != end);
+        self.replace_find.reset();
+        self.replace_find.set_note(None);
+        self.replace_with.reset();
+        self.replace = Some(ReplaceState { scope, in_selection: scope.is_some(), editing_with: false });
+        self.update_replace_title();
+        self.push_mode(AppMode::Replace);
+    }
+
+    fn update_replace_title(&mut self) {
+        let in_selection = self.replace.as_ref().is_some_and(|r| r.in_selection);
+        self.replace_find.set_title(if in_selection { " Replace in selection (regex) " } else { " Replace (regex) " });
+    }
+
+    /// The pattern typed so far, compiled; None while it's empty.
+    pub fn replace_regex(&self) -> Option<Result<regex::Regex, String>> {
+        let pattern = self.replace_find.text();
+        if pattern.is_empty() {
+            return None;
+        }
+        // The error ends with its one-line reason, after the pattern.
+        Some(regex::Regex::new(&pattern).map_err(|e| e.to_string().lines().last().unwrap_or_default().trim().to_string()))
+    }
+
+    /// Show a bad pattern's error under it as it's typed.
+    pub fn check_replace_pattern(&mut self) {
+        let error = self.replace_regex().and_then(|re| re.err());
+        self.replace_find.set_note(error);
+    }
+
+    fn replace_scope(&self) -> Option<replace::Scope> {
+        self.replace.as_ref().filter(|r| r.in_selection).and_then(|r| r.scope)
+    }
+
+    /// What the replace would make of the rows on screen that it changes.
+    pub fn replace_preview(&self) -> Vec<(usize, Replaced)> {
+        let Some(Ok(re)) = self.replace_regex() else { return Vec::new() };
+        let with = self.replace_with.text();
+        let editor = &self.buffer.editor;
+        self.visible_rows()
+            .filter_map(|row| Some((row, replace::line(&re, &editor.get_line(row)?, &with, row, self.replace_scope())?)))
+            .collect()
+    }
+
+    pub fn toggle_replace_field(&mut self) {
+        if let Some(state) = &mut self.replace {
+            state.editing_with = !state.editing_with;
+        }
+    }
+
+    pub fn toggle_replace_in_selection(&mut self) {
+        let Some(state) = &mut self.replace else { return };
+        if state.scope.is_none() {
+            self.set_status("No selection to replace in");
+            return;
+        }
+        state.in_selection = !state.in_selection;
+        self.update_replace_title();
+    }
+
+    /// Enter: from the pattern on to the replacement, then replace.
+    pub fn replace_enter(&mut self) {
+        let Some(state) = &self.replace else { return };
+        if state.editing_with {
+            self.apply_replace();
+        } else if let Some(Ok(_)) = self.replace_regex() {
+            self.toggle_replace_field();
+        }
+    }
+
+    pub fn cancel_replace(&mut self) {
+        self.replace = None;
+        self.remove_mode(AppMode::Replace);
+    }
+
+    /// Replace every match (in the scope) as one undoable edit.
+    fn apply_replace(&mut self) {
+        let Some(Ok(re)) = self.replace_regex() else { return };
+        let with = self.replace_with.text();
+        let scope = self.replace_scope();
+        let editor = &self.buffer.editor;
+        let changed: Vec<(usize, Replaced)> = (0..editor.len_lines())
+            .filter_map(|row| Some((row, replace::line(&re, &editor.line(row), &with, row, scope)?)))
+            .collect();
+        self.cancel_replace();
+        if changed.is_empty() {
+            self.set_status("No matches");
+            return;
+        }
+        let (row, col) = self.buffer.editor.cursor();
+        self.buffer.begin_group();
+        for (row, replaced) in &changed {
+            self.replace_rows(*row..row + 1, std::slice::from_ref(&replaced.text));
+        }
+        self.buffer.end_group();
+        let col = col.min(self.buffer.editor.line_len(row));
+        self.buffer.editor.jump((row, col));
+        self.mark_dirty();
+        let count: usize = changed.iter().map(|(_, r)| r.old.len()).sum();
+        self.set_status(&format!("Replaced {} matches on {} lines", count, changed.len()));
+    }
+
+    /// Alt+S in the search bar: the whole buffer or just the selection.
+    pub fn toggle_search_in_selection(&mut self) {
+        if self.search_scope.is_none() {
+            self.set_status("No selection to search in");
+            return;
+        }
+        self.search_in_selection = !self.search_in_selection;
+        self.update_search_title();
+    }
+
+    fn update_search_title(&mut self) {
+        self.search_input.set_title(if self.search_in_selection { " Search in selection " } else { " Search " });
+    }
+
+    /// List every match of `query`, starting at the first one from the
+    /// cursor on, and go to it.
+    pub fn search_all(&mut self, query: String) {
+        let scope = self.search_scope.filter(|_| self.search_in_selection);
+        let mut matches = find_all(&self.buffer.editor, &query);
+        in_scope(&mut matches, query.chars().count(), scope);
+        let within = if scope.is_some() { " in the selection" } else { "" };
+        if matches.is_empty() {
+            self.search_results = None;
+            self.set_status(&format!("Not found{}: {}", within, query));
+            return;
+        }
+        let cursor = self.buffer.editor.cursor();
+        let selected = matches.iter().position(|m| *m >= cursor).unwrap_or(0);
+        let (row, col) = matches[selected];
+        self.set_status(&format!("{} matches{}", matches.len(), within));
+        self.search_results = Some(SearchResults { query, matches, selected, scope });
+        // Moving with the selection on would stretch it.
+        self.buffer.editor.cancel_selection();
+        self.jump_to(row, col);
+        self.push_mode(AppMode::SearchResults);
+    }
+
+    /// Find the matches again after an edit or a buffer switch.
+    fn refresh_search_results(&mut self) {
+        let Some(results) = &mut self.search_results else { return };
+        results.matches = find_all(&self.buffer.editor, &results.query);
+        in_scope(&mut results.matches, results.query.chars().count(), results.scope);
+        results.selected = results.selected.min(results.matches.len().saturating_sub(1));
+    }
+
+    pub fn move_search_selection(&mut self, delta: isize) {
+        let Some(results) = &mut self.search_results else { return };
+        let last = results.matches.len().saturating_sub(1);
+        results.selected = results.selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Go to the highlighted match, back in the editor with the list still
+    /// open.
+    pub fn jump_to_search_result(&mut self) {
+        self.remove_mode(AppMode::SearchResults);
+        let Some(&(row, col)) = self.search_results.as_ref().and_then(|r| r.matches.get(r.selected)) else { return };
+        self.jump_to(row, col);
+    }
+
+    pub fn close_search_results(&mut self) {
+        self.search_results = None;
+        self.remove_mode(AppMode::SearchResults);
+    }
+
+    /// A new search from the list of matches, in the same selection.
+    pub fn new_search(&mut self) {
+        let scope = self.search_results.as_ref().and_then(|r| r.scope);
+        self.close_search_results();
+        self.open_search_bar(scope);
+    }
+
+    pub fn exit_search_mode(&mut self) {
+        self.pop_mode();
+        // Clear search text on exit? Maybe keep it for next time.
+    }
+
+    pub fn save_file(&mut self) -> anyhow::Result<()> {
+        if self.filename == "[No Name]" || self.filename.is_empty() {
+            return Err(anyhow::anyhow!("No filename specified"));
+        }
+        if self.loading.is_some() {
+            return Err(anyhow::anyhow!("Still loading the file"));
+        }
+        if let Some(reason) = fileio::write_blocker(Path::new(&self.filename)) {
+            return Err(anyhow::anyhow!("Can't save: {}", reason));
+        }
+
+        if self.config.backup && !self.secure {
+            let backup_dir = self.config.backup_dir.as_deref();
+            fileio::write_backup(std::path::Path::new(&self.filename), backup_dir)
+                .map_err(|e| anyhow::anyhow!("Backup failed, file not saved: {}", e))?;
+        }
+
+        let mut bytes = fileio::encode(&self.file_contents(), self.encoding, self.bom)?;
+        let path = Path::new(&self.filename);
+        if let Some(cipher) = Cipher::for_path(path) {
+            let config = &self.config;
+            self.repaint = true;
+            bytes = ui::suspended(self.keyboard_enhanced, || crypto::encrypt(cipher, &bytes, path, config))
+                .map_err(|e| anyhow::anyhow!("Encryption failed, file not saved: {}", e))?;
+        }
+        fileio::write_atomic(std::path::Path::new(&self.filename), &bytes)?;
+        self.disk_stamp = Some(DiskStamp::for_contents(&bytes));
+
+        // Take in a formatter's rewrite before it counts as clean.
+        self.sync_buffer();
+        self.buffer.modified = false;
+        if let Some(swap) = self.swap_file.take() {
+            swap::remove(&swap);
+        }
+        // Saved under a new name, it may be another file to git.
+        self.load_git_head();
+        self.lsp_saved();
+        self.record(Op::Save { file: self.filename.clone() });
+        // Rows saved with the text they point into.
+        self.save_bookmarks();
+        self.remember_file();
+        if self.write_protected.is_some() {
+            self.check_write_access();
+        }
+        self.set_status("File Saved!");
+        Ok(())
+    }
+
+    /// Save, first piping the buffer through the profile's formatter in
+    /// the background when there is one; the file is written when it's
+    /// done, or unformatted if it fails or takes too long.
+    pub fn format_and_save(&mut self) -> anyhow::Result<()> {
+        if let Some(formatting) = &self.formatting {
+            return Err(anyhow::anyhow!("Still formatting with {}", formatting.formatter));
+        }
+        let Some(formatter) = self.profile.formatter.clone() else { return self.save_file() };
+        if self.loading.is_some() || self.filename == "[No Name]" {
+            return self.save_file();
+        }
+        let text = self.buffer.text().clone();
+        let tx = self.format_tx.clone();
+        let (command, input) = (formatter.clone(), text.to_string());
+        tokio::spawn(async move {
+            let _ = tx.send(format_text(&command, input).await).await;
+        });
+        self.set_status(&format!("Formatting with {}...", formatter));
+        self.formatting = Some(Formatting { formatter, file: self.filename.clone(), text });
+        Ok(())
+    }
+
+    /// Write the file the formatter was run for, once it's done.
+    pub fn poll_format(&mut self) {
+        let Some(rx) = &mut self.format_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        let Some(formatting) = self.formatting.take() else { return };
+        // Closed while it ran.
+        let Some(index) = self.buffer_named(&formatting.file) else { return };
+        self.with_buffer(index, |app| {
+            let formatted = match result {
+                // The output is for text that's since been edited.
+                Ok(_) if *app.buffer.text() != formatting.text => Err("the buffer changed while formatting".to_string()),
+                Ok(output) => {
+                    app.apply_formatted(&output);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            match (app.save_file(), formatted) {
+                (Err(e), _) => app.set_status(&format!("Error: {}", e)),
+                (Ok(()), Err(e)) => app.set_status(&format!("Saved unformatted: {}", e)),
+                (Ok(()), Ok(())) => app.set_status("File Formatted & Saved!"),
+            }
+        });
+    }
+
+    /// `--apply`: run the formatter in place, as a save would.
+    pub async fn format(&mut self) -> Result<bool, String> {
+        let Some(formatter) = self.profile.formatter.clone() else { return Ok(false) };
+        let output = format_text(&formatter, self.buffer.text().to_string()).await?;
+        self.apply_formatted(&output);
+        Ok(true)
+    }
+
+    /// Read and decode `path`, decrypting `.gpg`/`.age` files in memory (the
+    /// tools may ask for a passphrase on the terminal).
+    fn read_file(path: &Path, config: &Config, keyboard_enhanced: bool) -> anyhow::Result<fileio::Decoded> {
+        match Cipher::for_path(path) {
+            Some(cipher) if path.exists() => {
+                let bytes = ui::suspended(keyboard_enhanced, || crypto::decrypt(cipher, path, config))?;
+                Ok(fileio::decode(&bytes))
+            }
+            _ => fileio::read_text(path),
+        }
+    }
+
+    /// The start of `path` and the loader for the rest, when it's over
+    /// `large_file_mb`. None reads it whole.
+    fn open_large(path: &Path, config: &Config) -> Option<(fileio::Decoded, Loading)> {
+        let limit = config.large_file_mb.saturating_mul(1 << 20);
+        if limit == 0 || Cipher::for_path(path).is_some() {
+            return None;
+        }
+        let size = fs::metadata(path).ok()?.len();
+        if size < limit {
+            return None;
+        }
+        large::open(path, size).ok()
+    }
+
+    /// Show what's loaded of a large file and keep reading the rest in
+    /// the background. It stays read-only until it's all in.
+    fn start_loading(&mut self, loading: Loading) {
+        self.large_file = true;
+        self.read_only = true;
+        // Undo would keep a copy of every chunk.
+        self.buffer.editor.set_max_histories(0);
+        self.set_status(&format!("Large file: loading the rest of {} in the background", self.filename));
+        if loading.malformed {
+            self.lock_malformed();
+        }
+        self.loading = Some(loading);
+    }
+
+    /// Saving would write the replacement characters back.
+    fn lock_malformed(&mut self) {
+        self.locked = true;
+        self.set_status(&format!("Not all of the file is {}; it can't be saved", self.encoding.name()));
+    }
+
+    /// Append the next piece of a large file, if one has been read.
+    pub fn poll_loading(&mut self) {
+        let Some(mut loading) = self.loading.take() else { return };
+        match loading.rx.try_recv() {
+            Ok(Ok(Loaded::Text { text, bytes, malformed })) => {
+                loading.loaded += bytes;
+                // The editor takes the loader's rope as it is; the cursor,
+                // selection and undo history stay as they were.
+                self.buffer.editor.append(text);
+                if malformed && !loading.malformed {
+                    loading.malformed = true;
+                    self.lock_malformed();
+                }
+                // Reading the file isn't an edit: nothing for the journal,
+                // but what's shown of the text is out of date.
+                self.git_stale = Some(Instant::now());
+                self.refresh_search_results();
+                self.loading = Some(loading);
+            }
+            Ok(Ok(Loaded::Done { final_newline })) => {
+                self.final_newline = final_newline;
+                self.buffer.editor.set_max_histories(50);
+                let lines = self.buffer.editor.len_lines();
+                if !loading.malformed {
+                    self.set_status(&format!("Loaded {} lines; M-K to edit", lines));
+                }
+            }
+            Ok(Err(e)) => {
+                // Saving now would cut the file short.
+                self.locked = true;
+                self.buffer.editor.set_max_histories(50);
+                self.set_status(&format!("Stopped loading {}: {}", self.filename, e));
+            }
+            Err(mpsc::error::TryRecvError::Empty) => self.loading = Some(loading),
+            Err(mpsc::error::TryRecvError::Disconnected) => {
+                self.locked = true;
+                self.buffer.editor.set_max_histories(50);
+                self.set_status(&format!("Stopped loading {}", self.filename));
+            }
+        }
+    }
+
+    /// The buffer as it goes to disk, with the file's line endings and
+    /// final newline.
+    pub fn file_contents(&mut self) -> String {
+        let ending = self.line_ending.as_str();
+        let text = self.buffer.text();
+        let mut content = String::with_capacity(text.len_bytes() + text.len_lines() * ending.len());
+        for chunk in text.chunks() {
+            for (i, part) in chunk.split('\n').enumerate() {
+                if i > 0 {
+                    content.push_str(ending);
+                }
+                content.push_str(part);
+            }
+        }
+        if self.final_newline && text.len_bytes() > 0 {
+            content.push_str(ending);
+        }
+        content
+    }
+
+    pub fn toggle_line_ending(&mut self) {
+        self.line_ending = match self.line_ending {
+            LineEnding::Lf => LineEnding::CrLf,
+            LineEnding::CrLf => LineEnding::Lf,
+        };
+        self.mark_dirty();
+        self.set_status(&format!("Line endings: {}", self.line_ending.label()));
+    }
+
+    pub fn set_status(&mut self, msg: &str) {
+        self.status_message = Some(msg.to_string());
+    }
+
+    /// Show the welcome screen if the config wants it.
+    pub fn open_welcome(&mut self) {
+        if !self.config.welcome_screen {
+            return;
+        }
+        self.recent_files = if self.secure { Vec::new() } else { recent::load() };
+        self.recent_files.truncate(self.config.welcome_recent);
+        self.welcome_selected = 0;
+        self.push_mode(AppMode::Welcome);
+    }
+
+    pub fn move_welcome_selection(&mut self, delta: isize) {
+        let last = self.recent_files.len() + WELCOME_ACTIONS.len() - 1;
+        self.welcome_selected = self.welcome_selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Open the recent file or run the action at `index` of the welcome
+    /// screen.
+    pub fn choose_welcome(&mut self, index: usize) {
+        if let Some(file) = self.recent_files.get(index).cloned() {
+            self.remove_mode(AppMode::Welcome);
+            self.open_file(&file);
+            return;
+        }
+        match WELCOME_ACTIONS.get(index - self.recent_files.len()).map(|(key, _)| *key) {
+            Some('o') => self.prompt_open_file(),
+            Some('n') => self.remove_mode(AppMode::Welcome),
+            Some('s') => {
+                self.remove_mode(AppMode::Welcome);
+                // Written out first so there is something to edit.
+                if !Config::path().exists() {
+                    if let Err(e) = self.config.save() {
+                        self.set_status(&format!("Couldn't write the config: {}", e));
+                        return;
+                    }
+                }
+                self.open_file(&Config::path().to_string_lossy());
+                self.set_status("Changes to the config apply from the next start");
+            }
+            _ => {}
+        }
+    }
+
+    /// The tip of the day for the welcome screen.
+    pub fn welcome_tip(&self) -> Option<String> {
+        let day = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86400) as usize;
+        if self.config.welcome_tips.is_empty() {
+            Some(TIPS[day % TIPS.len()].to_string())
+        } else {
+            self.config.welcome_tips.get(day % self.config.welcome_tips.len()).cloned()
+        }
+    }
+
+    /// Load `name` into the (untouched) start buffer: the welcome screen's
+    /// open. A file that doesn't exist yet starts out empty.
+    pub fn open_file(&mut self, name: &str) {
+        let path = Path::new(name);
+        let mut loading = None;
+        let (text, encoding, bom) = if path.exists() {
+            let read = match Self::open_large(path, &self.config) {
+                Some((decoded, rest)) => {
+                    loading = Some(rest);
+                    Ok(decoded)
+                }
+                None => Self::read_file(path, &self.config, self.keyboard_enhanced),
+            };
+            match read {
+                Ok(decoded) => (decoded.text, decoded.encoding, decoded.bom),
+                Err(e) => {
+                    self.set_status(&format!("Can't open {}: {}", name, e));
+                    return;
+                }
+            }
+        } else {
+            (String::new(), UTF_8, false)
+        };
+        self.filename = name.to_string();
+        self.encoding = encoding;
+        self.bom = bom;
+        self.load_content(&text);
+        self.line_ending = LineEnding::detect(&text);
+        self.final_newline = text.is_empty() || text.ends_with('\n');
+        self.repaint = Cipher::for_path(path).is_some();
+        self.disk_stamp = DiskStamp::read(path);
+        let rows = self.buffer.editor.len_lines();
+        self.bookmarks = if self.disk_stamp.is_some() {
+            bookmarks::load(path).into_iter().filter(|b| b.row < rows).collect()
+        } else {
+            Vec::new()
+        };
+        self.apply_profile();
+        self.buffer.soft_wrap = self.soft_wrap_pref();
+        self.remember_file();
+        let verb = if self.disk_stamp.is_some() { "Opened" } else { "New file" };
+        self.set_status(&format!("{} {}", verb, name));
+        if let Some(loading) = loading {
+            self.start_loading(loading);
+        }
+        self.load_git_head();
+        self.check_write_access();
+        self.check_swap();
+        self.lsp_open();
+    }
+
+    /// Open a file that can't be saved read-only, saying so up front
+    /// rather than at the first save.
+    pub fn check_write_access(&mut self) {
+        let was_protected = self.write_protected.take().is_some();
+        let blocker = Some(&self.filename).filter(|f| *f != "[No Name]").and_then(|f| fileio::write_blocker(Path::new(f)));
+        match blocker {
+            Some(reason) => {
+                self.read_only = true;
+                self.set_status(&format!("Read-only: {}", reason));
+                self.write_protected = Some(reason);
+            }
+            // Saved somewhere it can be.
+            None if was_protected && !self.locked => self.read_only = false,
+            None => {}
+        }
+    }
+
+    /// F3: ask for a file to open in a new buffer.
+    pub fn prompt_open_file(&mut self) {
+        self.filename_input.set_title(" Open ");
+        self.filename_input.reset();
+        self.push_mode(AppMode::OpenFile);
+    }
+
+    /// Back to the hits of the last find in files, or ask for a new one.
+    pub fn find_in_files(&mut self) {
+        if self.grep.is_some() {
+            self.push_mode(AppMode::GrepResults);
+        } else {
+            self.prompt_grep();
+        }
+    }
+
+    pub fn prompt_grep(&mut self) {
+        self.grep_input.reset();
+        if let Some(grep) = &self.grep {
+            self.grep_input.set_text(&grep.query);
+        }
+        self.remove_mode(AppMode::GrepResults);
+        self.push_mode(AppMode::Grep);
+    }
+
+    /// Search the files under the working directory for `query`, listing
+    /// the hits as they come in.
+    pub fn start_grep(&mut self, query: String) {
+        let root = std::env::current_dir().unwrap_or_else(|_| ".".into());
+        let rx = grep::search(root, query.clone());
+        self.grep = Some(Grep { query, hits: Vec::new(), selected: 0, files: None, rx: Some(rx) });
+        self.remove_mode(AppMode::Grep);
+        self.push_mode(AppMode::GrepResults);
+    }
+
+    /// Take in what the search has found since the last frame.
+    pub fn poll_grep(&mut self) {
+        let Some(grep) = &mut self.grep else { return };
+        let Some(rx) = &mut grep.rx else { return };
+        loop {
+            match rx.try_recv() {
+                Ok(Found::Hits(hits)) => grep.hits.extend(hits),
+                Ok(Found::Done { files }) => {
+                    grep.files = Some(files);
+                    grep.rx = None;
+                    let more = if grep.hits.len() == grep::MAX_HITS { "+" } else { "" };
+                    let message = format!("{}{} matches for \"{}\" in {} files", grep.hits.len(), more, grep.query, files);
+                    self.set_status(&message);
+                    return;
+                }
+                Err(mpsc::error::TryRecvError::Empty) => return,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    grep.rx = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn move_grep_selection(&mut self, delta: isize) {
+        let Some(grep) = &mut self.grep else { return };
+        let last = grep.hits.len().saturating_sub(1);
+        grep.selected = grep.selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Open the file of the highlighted hit at its line. The hits stay
+    /// around for the next one.
+    pub fn open_grep_hit(&mut self) {
+        let Some(hit) = self.grep.as_ref().and_then(|g| g.hits.get(g.selected)) else { return };
+        let (path, row, col) = (hit.path.clone(), hit.row, hit.col);
+        self.remove_mode(AppMode::GrepResults);
+        self.open_buffer(&path);
+        let canonical = |file: &str| fs::canonicalize(file).ok();
+        if canonical(&self.filename).is_some() && canonical(&self.filename) == canonical(&path) {
+            self.jump_to(row, col);
+        }
+    }
+
+    pub fn close_grep(&mut self) {
+        self.grep = None;
+        self.remove_mode(AppMode::GrepResults);
+    }
+
+    /// Name and modified flag of every buffer, in list order.
+    pub fn buffer_entries(&self) -> Vec<(&str, bool)> {
+        (0..self.buffers.len())
+            .map(|i| match i == self.active_buffer {
+                true => (self.filename.as_str(), self.buffer.modified),
+                false => (self.buffers[i].filename.as_str(), self.buffers[i].buffer.modified),
+            })
+            .collect()
+    }
+
+    /// Show `name`: the buffer it's already open in, else a new one (or
+    /// the empty start buffer, if that's all there is).
+    pub fn open_buffer(&mut self, name: &str) {
+        if self.share.is_some() || self.tail.is_some() || self.merge.is_some() {
+            self.set_status("Only one file while sharing, tailing or merging");
+            return;
+        }
+        let canonical = |file: &str| fs::canonicalize(file).unwrap_or_else(|_| file.into());
+        let open = self.buffer_entries().iter().position(|(file, _)| canonical(file) == canonical(name));
+        if let Some(i) = open {
+            self.switch_buffer(i);
+            return;
+        }
+        let blank = self.filename == "[No Name]" && !self.buffer.modified && self.buffer.editor.is_empty();
+        if !blank {
+            self.buffers.push(BufferState::new(self.config.soft_wrap));
+            self.switch_buffer(self.buffers.len() - 1);
+        }
+        self.open_file(name);
+        // Couldn't be read; the status says why.
+        if !blank && self.filename == "[No Name]" {
+            self.close_buffer(self.active_buffer);
+        }
+    }
+
+    /// Swap the shown buffer's state with `state`.
+    fn exchange_buffer(&mut self, state: &mut BufferState<'a>) {
+        std::mem::swap(&mut self.buffer, &mut state.buffer);
+        std::mem::swap(&mut self.filename, &mut state.filename);
+        std::mem::swap(&mut self.encoding, &mut state.encoding);
+        std::mem::swap(&mut self.bom, &mut state.bom);
+        std::mem::swap(&mut self.line_ending, &mut state.line_ending);
+        std::mem::swap(&mut self.final_newline, &mut state.final_newline);
+        std::mem::swap(&mut self.read_only, &mut state.read_only);
+        std::mem::swap(&mut self.write_protected, &mut state.write_protected);
+        std::mem::swap(&mut self.large_file, &mut state.large_file);
+        std::mem::swap(&mut self.loading, &mut state.loading);
+        std::mem::swap(&mut self.table_view, &mut state.table_view);
+        std::mem::swap(&mut self.disk_stamp, &mut state.disk_stamp);
+        std::mem::swap(&mut self.swap_dirty, &mut state.swap_dirty);
+        std::mem::swap(&mut self.swap_file, &mut state.swap_file);
+        std::mem::swap(&mut self.bookmarks, &mut state.bookmarks);
+        std::mem::swap(&mut self.folds, &mut state.folds);
+        std::mem::swap(&mut self.spell_check, &mut state.spell_check);
+        std::mem::swap(&mut self.diagnostics, &mut state.diagnostics);
+        std::mem::swap(&mut self.ai_snapshots, &mut state.ai_snapshots);
+        std::mem::swap(&mut self.git_head, &mut state.git_head);
+        std::mem::swap(&mut self.git_marks, &mut state.git_marks);
+        std::mem::swap(&mut self.editor_scroll, &mut state.editor_scroll);
+        std::mem::swap(&mut self.wrap_top, &mut state.wrap_top);
+        self.apply_profile();
+    }
+
+    /// Show buffer `index` of the list.
+    pub fn switch_buffer(&mut self, index: usize) {
+        if index == self.active_buffer || index >= self.buffers.len() {
+            return;
+        }
+        // Edits not yet sent are about the buffer being left.
+        self.sync_lsp(true);
+        let mut state = std::mem::replace(&mut self.buffers[index], BufferState::new(false));
+        self.exchange_buffer(&mut state);
+        self.buffers[self.active_buffer] = state;
+        self.active_buffer = index;
+        // Positions in the old buffer mean nothing in this one.
+        self.cursors.clear();
+        self.block = None;
+        self.smart_edit = None;
+        self.ghost = None;
+        self.completion = None;
+        self.completion_request = None;
+        self.disk_diff = None;
+        self.diagnostic_selected = None;
+        self.ai_snapshot_selected = 0;
+        self.screen_rows.clear();
+        self.refresh_search_results();
+        self.git_stale = Some(Instant::now());
+        self.update_git_marks(true);
+        self.set_status(&format!("Switched to {}", self.filename));
+    }
+
+    /// Run `f` with buffer `index` shown for the time being.
+    fn with_buffer<T>(&mut self, index: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        if index == self.active_buffer {
+            return f(self);
+        }
+        let mut state = std::mem::replace(&mut self.buffers[index], BufferState::new(false));
+        self.exchange_buffer(&mut state);
+        let result = f(self);
+        self.exchange_buffer(&mut state);
+        self.buffers[index] = state;
+        result
+    }
+
+    /// Drop buffer `index`, unsaved changes and all. The last one is
+    /// replaced by an empty buffer.
+    fn close_buffer(&mut self, index: usize) {
+        if self.buffers.len() == 1 {
+            let mut state = BufferState::new(self.config.soft_wrap);
+            self.exchange_buffer(&mut state);
+            self.lsp_close(&state.filename);
+            if let Some(swap) = state.swap_file.take() {
+                swap::remove(&swap);
+            }
+            self.cursors.clear();
+            self.block = None;
+            if self.secure {
+                state.wipe();
+            }
+            return;
+        }
+        if index == self.active_buffer {
+            self.switch_buffer(if index + 1 < self.buffers.len() { index + 1 } else { index - 1 });
+        }
+        let mut state = self.buffers.remove(index);
+        self.lsp_close(&state.filename);
+        if let Some(swap) = state.swap_file.take() {
+            swap::remove(&swap);
+        }
+        if self.secure {
+            state.wipe();
+        }
+        if self.active_buffer > index {
+            self.active_buffer -= 1;
+        }
+    }
+
+    /// F4: the list of open buffers, starting at the shown one.
+    pub fn open_buffer_list(&mut self) {
+        self.buffer_selected = self.active_buffer;
+        self.buffer_close_pending = None;
+        self.push_mode(AppMode::Buffers);
+    }
+
+    pub fn move_buffer_selection(&mut self, delta: isize) {
+        self.buffer_close_pending = None;
+        let last = self.buffers.len() - 1;
+        self.buffer_selected = self.buffer_selected.saturating_add_signed(delta).min(last);
+    }
+
+    pub fn switch_to_selected_buffer(&mut self) {
+        self.switch_buffer(self.buffer_selected);
+        self.pop_mode();
+    }
+
+    /// Close the highlighted buffer; one with unsaved changes only on the
+    /// second press.
+    pub fn close_selected_buffer(&mut self) {
+        let index = self.buffer_selected;
+        let (name, modified) = self.buffer_entries()[index];
+        if modified && self.buffer_close_pending != Some(index) {
+            let message = format!("{} has unsaved changes: D again to discard them, S to save", name);
+            self.buffer_close_pending = Some(index);
+            self.set_status(&message);
+            return;
+        }
+        self.buffer_close_pending = None;
+        self.close_buffer(index);
+        self.buffer_selected = self.buffer_selected.min(self.buffers.len() - 1);
+    }
+
+    /// Save buffer `index`; the error says why not.
+    fn save_buffer(&mut self, index: usize) -> anyhow::Result<()> {
+        self.with_buffer(index, |app| {
+            if app.filename == "[No Name]" {
+                return Err(anyhow::anyhow!("no file name yet"));
+            }
+            if app.changed_on_disk() {
+                return Err(anyhow::anyhow!("changed on disk"));
+            }
+            app.save_file()
+        })
+    }
+
+    pub fn save_selected_buffer(&mut self) {
+        let index = self.buffer_selected;
+        self.buffer_close_pending = None;
+        let name = self.buffer_entries()[index].0.to_string();
+        match self.save_buffer(index) {
+            Ok(()) => self.set_status(&format!("Saved {}", name)),
+            Err(e) => self.set_status(&format!("{}: {}", name, e)),
+        }
+    }
+
+    /// Save every modified buffer; the ones that failed are listed in a
+    /// report.
+    pub fn save_all_buffers(&mut self) {
+        self.buffer_close_pending = None;
+        let modified: Vec<usize> = (0..self.buffers.len()).filter(|&i| self.buffer_entries()[i].1).collect();
+        let mut failed = Vec::new();
+        for &i in &modified {
+            if let Err(e) = self.save_buffer(i) {
+                failed.push(format!("{}: {}", self.buffer_entries()[i].0, e));
+            }
+        }
+        if failed.is_empty() {
+            self.set_status(&format!("Saved {} buffers", modified.len()));
+        } else {
+            let title = format!(" Saved {} of {} ", modified.len() - failed.len(), modified.len());
+            self.open_report(title, failed);
+        }
+    }
+
+    /// Close the shown buffer; with unsaved changes only on the second
+    /// press.
+    pub fn close_current_buffer(&mut self) {
+        let index = self.active_buffer;
+        if self.buffer.modified && self.buffer_close_pending != Some(index) {
+            let key = self.keymap.label(Action::CloseBuffer);
+            self.buffer_close_pending = Some(index);
+            self.set_status(&format!("{} has unsaved changes: {} again to discard them", self.filename, key));
+            return;
+        }
+        self.buffer_close_pending = None;
+        self.close_buffer(index);
+    }
+
+    /// Close every buffer but the shown one. Ones with unsaved changes stay
+    /// open and are listed in a report.
+    pub fn close_other_buffers(&mut self) {
+        self.buffer_close_pending = None;
+        let mut kept = Vec::new();
+        let mut closed = 0;
+        for i in (0..self.buffers.len()).rev() {
+            if i == self.active_buffer {
+                continue;
+            }
+            let (name, modified) = self.buffer_entries()[i];
+            if modified {
+                kept.push(format!("{}: unsaved changes", name));
+            } else {
+                self.close_buffer(i);
+                closed += 1;
+            }
+        }
+        self.buffer_selected = self.active_buffer;
+        if kept.is_empty() {
+            self.set_status(&format!("Closed {} buffers", closed));
+        } else {
+            kept.reverse();
+            self.open_report(format!(" Closed {}, kept {} ", closed, kept.len()), kept);
+        }
+    }
+
+    fn open_report(&mut self, title: String, lines: Vec<String>) {
+        self.report = Some((title, lines));
+        self.push_mode(AppMode::Report);
+    }
+
+    /// Quit, first asking about each buffer with unsaved changes in turn,
+    /// the shown one first. Called again after each answer.
+    pub fn request_quit(&mut self) {
+        let dirty: Vec<usize> = self
+            .buffer_entries()
+            .iter()
+            .enumerate()
+            .filter(|(i, (_, modified))| *modified && !self.quit_discarded.contains(i))
+            .map(|(i, _)| i)
+            .collect();
+        let Some(&next) = dirty.iter().find(|&&i| i == self.active_buffer).or(dirty.first()) else {
+            self.quit();
+            return;
+        };
+        self.switch_buffer(next);
+        if self.mode() != AppMode::ConfirmQuit {
+            self.push_mode(AppMode::ConfirmQuit);
+        }
+        if dirty.len() > 1 {
+            self.set_status(&format!("{} buffers have unsaved changes", dirty.len()));
+        }
+    }
+
+    /// "No" in the quit confirmation: leave this buffer unsaved and go on.
+    pub fn discard_for_quit(&mut self) {
+        self.quit_discarded.push(self.active_buffer);
+        self.request_quit();
+    }
+
+    pub fn cancel_quit(&mut self) {
+        self.quit_discarded.clear();
+        self.pop_mode();
+    }
+
+    /// Record the open files for `--continue`: not in secure mode, and
+    /// leaving out unsaved and encrypted ones. With none left, the last
+    /// session is kept.
+    pub fn save_session(&self) {
+        if self.secure || self.share.is_some() || self.tail.is_some() || self.merge.is_some() {
+            return;
+        }
+        let mut files = Vec::new();
+        let mut active = 0;
+        for i in 0..self.buffers.len() {
+            let (name, stamp, cursor) = match i == self.active_buffer {
+                true => (&self.filename, &self.disk_stamp, self.buffer.editor.cursor()),
+                false => {
+                    let state = &self.buffers[i];
+                    (&state.filename, &state.disk_stamp, state.buffer.editor.cursor())
+                }
+            };
+            if name == "[No Name]" || stamp.is_none() || Cipher::for_path(Path::new(name)).is_some() {
+                continue;
+            }
+            if i == self.active_buffer {
+                active = files.len();
+            }
+            let path = fs::canonicalize(name).map_or_else(|_| name.clone(), |p| p.to_string_lossy().into_owned());
+            files.push(SessionFile { path, row: cursor.0, col: cursor.1 });
+        }
+        if files.is_empty() {
+            return;
+        }
+        let search = self.search_results.as_ref().filter(|r| r.scope.is_none()).map(|r| r.query.clone());
+        if let Err(e) = session::save(&session::Session { files, active, search }) {
+            log::error!("Failed to save the session: {}", e);
+        }
+    }
+
+    /// `--continue`: reopen the files of the last session with the cursors
+    /// where they were, and list the search that was open.
+    pub fn restore_session(&mut self) {
+        let saved = match session::load() {
+            Ok(saved) => saved,
+            Err(e) => {
+                self.set_status(&format!("Can't continue: {}", e));
+                return;
+            }
+        };
+        let mut opened = 0;
+        let mut shown = None;
+        for (i, file) in saved.files.iter().enumerate() {
+            if !Path::new(&file.path).is_file() {
+                continue;
+            }
+            self.open_buffer(&file.path);
+            if self.filename != file.path {
+                continue;
+            }
+            // Not start_at(): this editor hasn't been drawn, so scrolling
+            // would pull the cursor back to the top. The view follows it.
+            self.buffer.editor.jump((file.row, file.col));
+            opened += 1;
+            if i == saved.active {
+                shown = Some(self.active_buffer);
+            }
+        }
+        if let Some(i) = shown {
+            self.switch_buffer(i);
+        }
+        if let Some(query) = saved.search {
+            self.search_results = Some(SearchResults { query, matches: Vec::new(), selected: 0, scope: None });
+            self.refresh_search_results();
+            if let Some(results) = &mut self.search_results {
+                let cursor = self.buffer.editor.cursor();
+                results.selected = results.matches.iter().position(|m| *m >= cursor).unwrap_or(0);
+                if results.matches.is_empty() {
+                    self.search_results = None;
+                }
+            }
+        }
+        let missing = saved.files.len() - opened;
+        let note = if missing > 0 { format!(" ({} no longer there)", missing) } else { String::new() };
+        self.set_status(&format!("Continuing with {} files{}", opened, note));
+    }
+
+    /// Put the file first in the welcome screen's recent files, except in
+    /// secure mode.
+    pub fn remember_file(&mut self) {
+        if self.secure || self.filename == "[No Name]" || self.disk_stamp.is_none() {
+            return;
+        }
+        if let Err(e) = recent::add(Path::new(&self.filename)) {
+            self.set_status(&format!("Couldn't save recent files: {}", e));
+        }
+    }
+
+    pub fn prompt_save_as(&mut self) {
+        self.filename_input.set_title(" Save As ");
+        self.push_mode(AppMode::SaveAs);
+        // Pre-fill with current filename if it's not [No Name]
+        if self.filename != "[No Name]" {
+            self.filename_input.set_text(&self.filename);
+        } else {
+            self.filename_input.reset();
+        }
+    }
+
+    pub fn open_apply_patch(&mut self) {
+        self.patch_input.set_title(" Apply patch ");
+        self.patch_input.reset();
+        self.push_mode(AppMode::ApplyPatch);
+    }
+
+    pub fn open_export_patch(&mut self) {
+        if self.filename == "[No Name]" {
+            self.set_status("Save the file first; a patch needs its name");
+            return;
+        }
+        self.patch_input.set_title(" Export unsaved changes as patch ");
+        self.patch_input.set_text(&format!("{}.patch", self.filename));
+        self.push_mode(AppMode::ExportPatch);
+    }
+
+    /// Apply a unified diff: hunks for this file go into the buffer (as an
+    /// undoable edit, not saved), other files are patched on disk. Hunks
+    /// that don't apply are written to `<file>.rej`.
+    pub fn apply_patch(&mut self, path: &str) -> anyhow::Result<()> {
+        let files = patch::parse(&fs::read_to_string(path)?)?;
+        let current = fs::canonicalize(&self.filename).ok();
+        let (mut applied, mut rejected, mut skipped) = (0, 0, 0);
+        for file in &files {
+            let Some(target) = file.target() else { continue };
+            if file.deletes() {
+                skipped += 1;
+                continue;
+            }
+            let rejects = if current.is_some() && fs::canonicalize(&target).ok() == current {
+                if self.read_only {
+                    return Err(anyhow::anyhow!("Buffer is read-only"));
+                }
+                let current = self.buffer.editor.lines();
+                let (lines, rejects) = file.apply(&current);
+                if lines != current {
+                    let rows = self.buffer.editor.len_lines();
+                    self.replace_rows(0..rows, &lines);
+                    self.mark_dirty();
+                }
+                rejects
+            } else {
+                let decoded = match file.old_path {
+                    Some(_) => fileio::read_text(&target)?,
+                    None => fileio::decode(b""),
+                };
+                let ending = LineEnding::detect(&decoded.text);
+                let lines: Vec<String> = decoded.text.lines().map(|l| l.to_string()).collect();
+                let (lines, rejects) = file.apply(&lines);
+                let mut text = lines.join(ending.as_str());
+                if !lines.is_empty() && (decoded.text.is_empty() || decoded.text.ends_with('\n')) {
+                    text.push_str(ending.as_str());
+                }
+                fileio::write_atomic(&target, &fileio::encode(&text, decoded.encoding, decoded.bom)?)?;
+                rejects
+            };
+            applied += file.hunks.len() - rejects.len();
+            if !rejects.is_empty() {
+                rejected += rejects.len();
+                let mut rej = target.clone().into_os_string();
+                rej.push(".rej");
+                fs::write(&rej, patch::rejects_text(&target, &rejects))?;
+            }
+        }
+        let mut status = format!("Applied {} hunk{}", applied, if applied == 1 { "" } else { "s" });
+        if rejected > 0 {
+            status.push_str(&format!(", {} rejected (see .rej files)", rejected));
+        }
+        if skipped > 0 {
+            status.push_str(&format!(", skipped {} file deletion{}", skipped, if skipped == 1 { "" } else { "s" }));
+        }
+        self.set_status(&status);
+        Ok(())
+    }
+
+    /// Write the buffer's changes against the file on disk as a patch.
+    pub fn export_patch(&mut self, path: &str) -> anyhow::Result<()> {
+        if Cipher::for_path(Path::new(&self.filename)).is_some() || self.secure {
+            return Err(anyhow::anyhow!("Not writing the plaintext of this buffer to a patch"));
+        }
+        let disk = fileio::read_text(Path::new(&self.filename)).map(|d| d.text).unwrap_or_default();
+        let buffer = self.file_contents();
+        if disk == buffer {
+            self.set_status("No unsaved changes");
+            return Ok(());
+        }
+        let patch = TextDiff::from_lines(&disk, &buffer)
+            .unified_diff()
+            .header(&format!("a/{}", self.filename), &format!("b/{}", self.filename))
+            .to_string();
+        fs::write(path, patch)?;
+        self.set_status(&format!("Changes written to {}", path));
+        Ok(())
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.buffer.modified = true;
+        self.status_message = None; // Clear status on edit
+    }
+
+    pub fn detect_language(&self) -> Option<String> {
+        self.buffer.language.clone()
+    }
+
+    /// Pass the buffer's changes on to what tracks positions in it.
+    pub fn sync_buffer(&mut self) {
+        let changes = self.buffer.take_changes();
+        self.track_changes(&changes);
+        for change in &changes {
+            self.record(Op::Edit(change.clone()));
+        }
+        if let Some(session) = &mut self.share {
+            session.send_changes(changes);
+        }
+    }
+
+    /// Keep positions into the buffer (diagnostics, ghost text) in line
+    /// with `changes`.
+    fn track_changes(&mut self, changes: &[Change]) {
+        self.swap_dirty |= !changes.is_empty();
+        if !changes.is_empty() {
+            self.git_stale = Some(Instant::now());
+            self.lsp_changed = Some(Instant::now());
+            if let Some(edited) = &mut self.autosave_edited {
+                *edited = true;
+            }
+        }
+        for change in changes {
+            self.ghost = None;
+            // A close waiting for confirmation was about the old text.
+            self.buffer_close_pending = None;
+            let old_end = change.start + change.old.len();
+            for d in &mut self.diagnostics {
+                for row in [&mut d.row, &mut d.end.0] {
+                    if *row >= old_end {
+                        *row = row.saturating_add_signed(change.delta());
+                    } else if *row >= change.start {
+                        *row = (*row).min(change.start + change.new.len().saturating_sub(1));
+                    }
+                }
+            }
+            for b in &mut self.bookmarks {
+                if b.row >= old_end {
+                    b.row = b.row.saturating_add_signed(change.delta());
+                } else if b.row >= change.start {
+                    b.row = b.row.min(change.start + change.new.len().saturating_sub(1));
+                }
+            }
+            // Folds move with the text; an edit in one (other than
+            // retyping its first line) opens it.
+            self.folds.retain_mut(|f| {
+                if f.start >= old_end {
+                    f.start = f.start.saturating_add_signed(change.delta());
+                    f.end = f.end.saturating_add_signed(change.delta());
+                    true
+                } else {
+                    change.start > f.end || (change.start == f.start && change.old.len() == 1 && change.new.len() == 1)
+                }
+            });
+        }
+        // A search scope grows and shrinks with edits inside it.
+        if let Some((start, end)) = self.search_results.as_mut().and_then(|r| r.scope.as_mut()) {
+            for change in changes {
+                let old_end = change.start + change.old.len();
+                if old_end <= start.0 {
+                    start.0 = start.0.saturating_add_signed(change.delta());
+                    end.0 = end.0.saturating_add_signed(change.delta());
+                } else if change.start <= end.0 {
+                    end.0 = end.0.saturating_add_signed(change.delta()).max(start.0);
+                    if end.0 < self.buffer.editor.len_lines() {
+                        end.1 = end.1.min(self.buffer.editor.line_len(end.0));
+                    }
+                }
+            }
+        }
+        if !changes.is_empty() {
+            self.refresh_search_results();
+        }
+    }
+
+    /// Filetype used for per-format settings. Special formats (git commit
+    /// messages, changelogs, man pages) are recognised by name; everything
+    /// else falls back to the detected language.
+    pub fn filetype(&self) -> Option<String> {
+        let path = std::path::Path::new(&self.filename);
+        let name = path.file_name()?.to_str()?;
+        let upper = name.to_uppercase();
+
+        if matches!(name, "COMMIT_EDITMSG" | "MERGE_MSG" | "TAG_EDITMSG" | "SQUASH_MSG") {
+            return Some("git-commit".to_string());
+        }
+        if upper.starts_with("CHANGELOG") || upper.starts_with("CHANGES") || upper.starts_with("NEWS") {
+            return Some("changelog".to_string());
+        }
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            if ext == "org" {
+                return Some("org".to_string());
+            }
+            if ext == "man" || (ext.len() == 1 && ext.chars().all(|c| ('1'..='9').contains(&c))) {
+                return Some("man".to_string());
+            }
+        }
+
+        self.detect_language()
+    }
+
+    pub fn is_markdown(&self) -> bool {
+        self.detect_language().as_deref() == Some("Markdown")
+    }
+
+    /// Open the heading outline with the section under the cursor selected.
+    pub fn open_outline(&mut self) {
+        if !self.is_markdown() {
+            self.set_status("The outline is only available for Markdown files");
+            return;
+        }
+        self.outline = markdown::headings(&self.buffer.editor.lines());
+        if self.outline.is_empty() {
+            self.set_status("No headings");
+            return;
+        }
+        let row = self.buffer.editor.cursor().0;
+        self.outline_selected = self.outline.iter().rposition(|h| h.row <= row).unwrap_or(0);
+        self.push_mode(AppMode::Outline);
+    }
+
+    pub fn move_outline(&mut self, delta: isize) {
+        let last = self.outline.len().saturating_sub(1);
+        self.outline_selected = self.outline_selected.saturating_add_signed(delta).min(last);
+    }
+
+    pub fn jump_to_outline(&mut self) {
+        if let Some(heading) = self.outline.get(self.outline_selected) {
+            self.buffer.editor.cancel_selection();
+            self.jump_to(heading.row, 0);
+        }
+        self.pop_mode();
+    }
+
+    /// Ctrl+B: bookmark the cursor line with the lowest free number, or
+    /// remove the bookmark it has.
+    pub fn toggle_bookmark(&mut self) {
+        let (row, col) = self.buffer.editor.cursor();
+        if let Some(i) = self.bookmarks.iter().position(|b| b.row == row) {
+            let removed = self.bookmarks.remove(i);
+            self.set_status(&format!("Bookmark {} removed", removed.number));
+        } else {
+            let number = bookmarks::next_number(&self.bookmarks);
+            self.bookmarks.push(Bookmark { number, row, col });
+            self.bookmarks.sort_by_key(|b| b.number);
+            self.set_status(&format!("Bookmark {} set ({} lists them)", number, self.keymap.label(Action::Bookmarks)));
+        }
+        self.save_bookmarks();
+    }
+
+    /// Persist the bookmarks, except for files that aren't on disk yet and
+    /// in secure mode.
+    fn save_bookmarks(&mut self) {
+        if self.secure || self.filename == "[No Name]" || self.disk_stamp.is_none() {
+            return;
+        }
+        if let Err(e) = bookmarks::save(Path::new(&self.filename), &self.bookmarks) {
+            self.set_status(&format!("Couldn't save bookmarks: {}", e));
+        }
+    }
+
+    /// F2: the bookmark picker, starting at the bookmark nearest above the
+    /// cursor.
+    pub fn open_bookmarks(&mut self) {
+        if self.bookmarks.is_empty() {
+            self.set_status(&format!("No bookmarks ({} sets one)", self.keymap.label(Action::ToggleBookmark)));
+            return;
+        }
+        let row = self.buffer.editor.cursor().0;
+        self.bookmark_selected = self
+            .bookmarks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.row <= row)
+            .max_by_key(|(_, b)| b.row)
+            .map_or(0, |(i, _)| i);
+        self.push_mode(AppMode::Bookmarks);
+    }
+
+    pub fn move_bookmark_selection(&mut self, delta: isize) {
+        let last = self.bookmarks.len().saturating_sub(1);
+        self.bookmark_selected = self.bookmark_selected.saturating_add_signed(delta).min(last);
+    }
+
+    /// Jump to the bookmark at `index` of the list and close the picker.
+    pub fn jump_to_bookmark(&mut self, index: usize) {
+        if let Some(&Bookmark { row, col, .. }) = self.bookmarks.get(index) {
+            self.buffer.editor.cancel_selection();
+            let col = col.min(self.buffer.editor.line_len(row));
+            self.jump_to(row, col);
+            self.pop_mode();
+        }
+    }
+
+    pub fn jump_to_bookmark_number(&mut self, number: usize) {
+        match self.bookmarks.iter().position(|b| b.number == number) {
+            Some(i) => self.jump_to_bookmark(i),
+            None => self.set_status(&format!("No bookmark {}", number)),
+        }
+    }
+
+    pub fn delete_selected_bookmark(&mut self) {
+        if self.bookmark_selected < self.bookmarks.len() {
+            self.bookmarks.remove(self.bookmark_selected);
+            self.move_bookmark_selection(0);
+            self.save_bookmarks();
+        }
+        if self.bookmarks.is_empty() {
+            self.pop_mode();
+        }
+    }
+
+    fn spell_check_on(&self) -> bool {
+        self.spell_check.or(self.profile.spell_check).unwrap_or(self.config.spell_check)
+    }
+
+    /// What gets spell checked in this buffer, if it's on: all of prose
+    /// files (Markdown, plain text, commit messages) and the line comments
+    /// of code.
+    pub fn spell_scope(&self) -> Option<Scope> {
+        if !self.spell_check_on() || self.table_view || self.large_file {
+            return None;
+        }
+        match self.filetype().as_deref() {
+            Some("Markdown") => Some(Scope::Prose { markdown: true }),
+            Some("Plain Text" | "git-commit" | "changelog" | "org") => Some(Scope::Prose { markdown: false }),
+            // Unknown extensions are more likely data than prose.
+            None => Path::new(&self.filename).extension().is_none().then_some(Scope::Prose { markdown: false }),
+            Some(filetype) => self.config.comment_leaders.get(filetype).map(|leader| Scope::Comments(leader.clone())),
+        }
+    }
+
+    /// Load the dictionary the first time a buffer needs it. When there
+    /// is none, that's said once.
+    fn load_dictionary(&mut self) -> bool {
+        if self.dictionary.is_none() && self.dictionary_error.is_none() {
+            match Dictionary::load(&self.config.spell_language) {
+                Ok(dictionary) => self.dictionary = Some(dictionary),
+                Err(e) => {
+                    self.set_status(&format!("Spell checking off: {}", e));
+                    self.dictionary_error = Some(e.to_string());
+                }
+            }
+        }
+        self.dictionary.is_some()
+    }
+
+    /// Misspelled words of buffer rows `rows`, as (row, start, end) char
+    /// ranges.
+    pub fn misspelled(&self, rows: std::ops::Range<usize>) -> Vec<(usize, usize, usize)> {
+        match (&self.dictionary, self.spell_scope()) {
+            (Some(dictionary), Some(scope)) => spell::misspelled(dictionary, &self.buffer.editor.rows(0..rows.end), rows, &scope),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Shift+F7: spell checking on or off for this buffer.
+    pub fn toggle_spell_check(&mut self) {
+        self.spell_check = Some(!self.spell_check_on());
+        if !self.spell_check_on() {
+            self.set_status("Spell checking off");
+        } else if self.spell_scope().is_none() {
+            self.set_status("Nothing to spell check in this filetype (see comment_leaders)");
+        } else if self.load_dictionary() {
+            self.set_status("Spell checking on");
+        }
+    }
+
+    /// F7: suggestions for the misspelled word at the cursor, or else the
+    /// next one after it (wrapping around).
+    pub fn open_spelling(&mut self) {
+        if self.spell_scope().is_none() {
+            self.set_status(&format!("Spell checking is off here ({} turns it on)", self.keymap.label(Action::ToggleSpellCheck)));
+            return;
+        }
+        if !self.load_dictionary() {
+            let error = self.dictionary_error.clone().unwrap_or_default();
+            self.set_status(&format!("Spell checking off: {}", error));
+            return;
+        }
+        let cursor = self.buffer.editor.cursor();
+        let found = self.misspelled(0..self.buffer.editor.len_lines());
+        let Some(&(row, start, end)) = found.iter().find(|(row, _, end)| (*row, *end) >= cursor).or(found.first()) else {
+            self.set_status("No misspelled words");
+            return;
+        };
+        let word: String = self.buffer.editor.line(row).chars().skip(start).take(end - start).collect();
+        let suggestions = self.dictionary.as_ref().map(|d| d.suggest(&word, 9)).unwrap_or_default();
+        self.buffer.editor.cancel_selection();
+        self.jump_to(row, start);
+        self.spelling = Some(Spelling { row, start, end, word, suggestions, selected: 0 });
+        self.push_mode(AppMode::Spelling);
+    }
+
+    pub fn move_spelling_selection(&mut self, delta: isize) {
+        if let Some(spelling) = &mut self.spelling {
+            let last = spelling.suggestions.len().saturating_sub(1);
+            spelling.selected = spelling.selected.saturating_add_signed(delta).min(last);
+        }
+    }
+
+    /// Replace the misspelled word with suggestion `index` and close the
+    /// popup.
+    pub fn apply_spelling(&mut self, index: usize) {
+        let Some(spelling) = &self.spelling else { return };
+        let Some(suggestion) = spelling.suggestions.get(index).cloned() else { return };
+        let (row, start, end) = (spelling.row, spelling.start, spelling.end);
+        self.spelling = None;
+        self.pop_mode();
+        if self.read_only {
+            self.set_status("Buffer is read-only");
+            return;
+        }
+        let line: Vec<char> = self.buffer.editor.line(row).chars().collect();
+        let replaced = format!("{}{}{}", line[..start].iter().collect::<String>(), suggestion, line[end..].iter().collect::<String>());
+        self.replace_rows(row..row + 1, &[replaced]);
+        self.buffer.editor.jump((row, start + suggestion.chars().count()));
+        self.mark_dirty();
+    }
+
+    /// Accept the word from now on: for good in the personal word list,
+    /// or just for this session. In secure mode nothing is written.
+    pub fn accept_spelling(&mut self, for_good: bool) {
+        let Some(spelling) = self.spelling.take() else { return };
+        self.pop_mode();
+        if let Some(dictionary) = &mut self.dictionary {
+            dictionary.add(&spelling.word);
+        }
+        if !for_good || self.secure {
+            self.set_status(&format!("Ignoring \"{}\" for this session", spelling.word));
+        } else if let Err(e) = spell::add_personal(&spelling.word) {
+            self.set_status(&format!("Couldn't add \"{}\": {}", spelling.word, e));
+        } else {
+            self.set_status(&format!("Added \"{}\" to the dictionary", spelling.word));
+        }
+    }
+
+    /// Check the links of a Markdown buffer: relative files and `#anchors`
+    /// right away, http(s) targets in the background if enabled. Broken
+    /// ones are listed in the diagnostics panel.
+    pub fn check_links(&mut self) {
+        if !self.is_markdown() {
+            self.set_status("Link checking is only available for Markdown files");
+            return;
+        }
+        let lines = &self.buffer.editor.lines();
+        let anchors: Vec<String> = markdown::headings(lines).iter().map(|h| links::slug(&h.title)).collect();
+        let base = Path::new(&self.filename)
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty() && self.filename != "[No Name]")
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+
+        let found = links::find_links(lines);
+        // A new run replaces the remote checks of the last one.
+        for task in self.link_tasks.drain(..) {
+            task.abort();
+        }
+        self.link_run += 1;
+        self.links_pending = 0;
+        // What a language server reported stays.
+        self.diagnostics.retain(|d| d.source.is_some());
+        let broken: Vec<Diagnostic> = found
+            .iter()
+            .filter_map(|link| {
+                links::check_local(&base, &link.target, &anchors)
+                    .map(|message| Diagnostic::at(link.row, link.col, message))
+            })
+            .collect();
+        let broken_count = broken.len();
+        self.diagnostics.extend(broken);
+        self.diagnostics.sort_by_key(|d| (d.row, d.col));
+        self.diagnostic_selected = None;
+        self.show_diagnostics = true;
+
+        let total = found.len();
+        let remote: Vec<_> = found.into_iter().filter(|l| links::is_remote(&l.target)).collect();
+        if !self.config.check_remote_links || remote.is_empty() {
+            self.set_status(&format!("{} of {} links broken", broken_count, total));
+            return;
+        }
+        self.set_status(&format!("Checking {} web links...", remote.len()));
+
+        self.links_pending = remote.len();
+        let client = reqwest::Client::new();
+        let run = self.link_run;
+        for link in remote {
+            let client = client.clone();
+            let tx = self.link_result_tx.clone();
+            let task = tokio::spawn(async move {
+                let result = links::check_remote(&client, &link.target)
+                    .await
+                    .map(|message| Diagnostic::at(link.row, link.col, message));
+                let _ = tx.send((run, result)).await;
+            });
+            self.link_tasks.push(task.abort_handle());
+        }
+    }
+
+    /// Collect finished remote link checks, driven from the event loop.
+    pub fn poll_link_checks(&mut self) {
+        let Some(rx) = &mut self.link_result_rx else { return };
+        let mut finished = false;
+        while let Ok((run, result)) = rx.try_recv() {
+            if run != self.link_run {
+                continue;
+            }
+            if let Some(diagnostic) = result {
+                self.diagnostics.push(diagnostic);
+            }
+            self.links_pending = self.links_pending.saturating_sub(1);
+            finished = self.links_pending == 0;
+        }
+        if finished {
+            self.link_tasks.clear();
+            self.diagnostics.sort_by_key(|d| (d.row, d.col));
+            let count = self.diagnostics.iter().filter(|d| d.source.is_none()).count();
+            self.set_status(&format!("Link check done: {} broken", count));
+        }
+    }
+
+    /// Jump to the next diagnostic after the cursor, wrapping around.
+    pub fn next_diagnostic(&mut self) {
+        if self.diagnostics.is_empty() {
+            self.set_status("No diagnostics");
+            return;
+        }
+        let cursor = self.buffer.editor.cursor();
+        let index = self
+            .diagnostics
+            .iter()
+            .position(|d| (d.row, d.col) > cursor)
+            .unwrap_or(0);
+        let diagnostic = &self.diagnostics[index];
+        self.buffer.editor.cancel_selection();
+        self.jump_to(diagnostic.row, diagnostic.col);
+        self.diagnostic_selected = Some(index);
+        self.show_diagnostics = true;
+    }
+
+    /// Show the diagnostics of the cursor line in full.
+    pub fn open_diagnostic_details(&mut self) {
+        let row = self.buffer.editor.cursor().0;
+        if !self.diagnostics.iter().any(|d| d.row == row) {
+            self.set_status("No diagnostics on this line");
+            return;
+        }
+        self.push_mode(AppMode::DiagnosticDetails);
+    }
+
+    /// Hand the buffer to the language server of its profile, starting
+    /// the server if needed. Not for unnamed, encrypted or huge files, nor
+    /// in secure mode.
+    pub fn lsp_open(&mut self) {
+        let Some(command) = self.profile.language_server.clone() else { return };
+        let path = Path::new(&self.filename);
+        if !self.config.lsp || self.secure || self.large_file || self.filename == "[No Name]" || Cipher::for_path(path).is_some() {
+            return;
+        }
+        if self.lsp_failed.contains(&command) {
+            return;
+        }
+        let path = lsp::absolute(path);
+        if !self.lsp.contains_key(&command) {
+            match lsp::Client::start(&command, &path, self.lsp_tx.clone()) {
+                Ok(client) => {
+                    self.lsp.insert(command.clone(), client);
+                }
+                Err(e) => {
+                    self.set_status(&format!("{:#}", e));
+                    self.lsp_failed.push(command);
+                    return;
+                }
+            }
+        }
+        let language = lsp::language_id(&self.filetype().unwrap_or_default());
+        let text = self.file_contents();
+        if let Some(client) = self.lsp.get_mut(&command) {
+            match client.is_open(&path) {
+                true => client.change(&path, text),
+                false => client.open(&path, &language, text),
+            }
+        }
+        self.lsp_changed = None;
+    }
+
+    /// Tell the server about edits once typing pauses (or right away with
+    /// `now`); driven from the event loop.
+    pub fn sync_lsp(&mut self, now: bool) {
+        let Some(changed) = self.lsp_changed else { return };
+        if !now && changed.elapsed() < Duration::from_millis(300) {
+            return;
+        }
+        self.lsp_changed = None;
+        let Some(command) = self.profile.language_server.clone() else { return };
+        let path = lsp::absolute(Path::new(&self.filename));
+        let text = self.file_contents();
+        if let Some(client) = self.lsp.get_mut(&command) {
+            client.change(&path, text);
+        }
+    }
+
+    /// After a save; a file saved under a new name is opened anew.
+    fn lsp_saved(&mut self) {
+        self.sync_lsp(true);
+        let path = lsp::absolute(Path::new(&self.filename));
+        match self.profile.language_server.as_ref().and_then(|c| self.lsp.get(c)) {
+            Some(client) if client.is_open(&path) => client.save(&path),
+            _ => self.lsp_open(),
+        }
+    }
+
+    fn lsp_close(&mut self, filename: &str) {
+        let path = lsp::absolute(Path::new(filename));
+        for client in self.lsp.values_mut() {
+            client.close(&path);
+        }
+    }
+
+    /// Take in what the language servers sent; driven from the event loop.
+    pub fn poll_lsp(&mut self) {
+        let Some(rx) = &mut self.lsp_rx else { return };
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        for event in events {
+            match event {
+                lsp::Event::Diagnostics { server, path, diagnostics } => {
+                    let index = (0..self.buffers.len()).find(|&i| {
+                        let name = if i == self.active_buffer { &self.filename } else { &self.buffers[i].filename };
+                        name != "[No Name]" && lsp::absolute(Path::new(name)) == path
+                    });
+                    let Some(index) = index else { continue };
+                    let encoding = self.lsp.get(&server).map_or(lsp::Encoding::Utf16, |c| c.encoding());
+                    self.with_buffer(index, |app| app.set_server_diagnostics(&server, encoding, diagnostics));
+                }
+                lsp::Event::Stopped { server, error } => {
+                    if self.lsp.remove(&server).is_some() {
+                        self.set_status(&format!("Language server {} stopped: {}", server, error));
+                        self.lsp_failed.push(server);
+                    }
+                }
+            }
+        }
+        self.poll_lsp_completion();
+        self.filter_completions();
+    }
+
+    /// Ask the language server for completions at the cursor. False if
+    /// the buffer has no server that completes.
+    fn request_lsp_completion(&mut self, manual: bool) -> bool {
+        let Some(command) = self.profile.language_server.clone() else { return false };
+        if !self.lsp.get(&command).is_some_and(|client| client.completes()) {
+            return false;
+        }
+        // The server has to see what was just typed.
+        self.sync_buffer();
+        self.sync_lsp(true);
+        let (row, col) = self.buffer.editor.cursor();
+        let line = self.buffer.editor.line(row).into_owned();
+        let before: Vec<char> = line.chars().take(col).collect();
+        let start = col - before.iter().rev().take_while(|c| cursors::is_word_char(**c)).count();
+        let path = lsp::absolute(Path::new(&self.filename));
+        let Some(client) = self.lsp.get_mut(&command).filter(|client| client.is_open(&path)) else { return false };
+        let encoding = client.encoding();
+        let response = client.completion(&path, (row, lsp::server_col(&line, col, encoding)));
+        self.completion_request = Some(CompletionRequest { row, start, col, line, encoding, manual, response });
+        true
+    }
+
+    /// After typing `c`: ask for completions at the start of a word or
+    /// after a trigger character like `.`, and again as the word grows if
+    /// the server's list was incomplete.
+    fn complete_while_typing(&mut self, c: char) {
+        if !self.config.lsp_complete_while_typing || !self.cursors.is_empty() || self.completion_request.is_some() {
+            return;
+        }
+        let trigger = self.profile.language_server.as_ref().and_then(|command| self.lsp.get(command)).is_some_and(|client| client.triggers_completion(c));
+        let (row, col) = self.buffer.editor.cursor();
+        let word_start = cursors::is_word_char(c)
+            && !self.buffer.editor.line(row).chars().nth(col.wrapping_sub(2)).is_some_and(cursors::is_word_char);
+        let incomplete = self.completion.as_ref().is_some_and(|menu| menu.incomplete);
+        if trigger || (word_start && self.completion.is_none()) || (cursors::is_word_char(c) && incomplete) {
+            self.request_lsp_completion(false);
+        }
+    }
+
+    fn poll_lsp_completion(&mut self) {
+        let Some(request) = &mut self.completion_request else { return };
+        let result = match request.response.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => {
+                self.completion_request = None;
+                return;
+            }
+        };
+        let Some(request) = self.completion_request.take() else { return };
+        let completions = lsp::parse_completions(&result);
+        // Still in the word it was asked for?
+        let (row, col) = self.buffer.editor.cursor();
+        let line = &self.buffer.editor.line(row);
+        if row != request.row || col < request.start || !line.chars().take(request.start).eq(request.line.chars().take(request.start)) {
+            return;
+        }
+        if completions.items.is_empty() {
+            if request.manual {
+                self.set_status("No completions");
+            }
+            return;
+        }
+        self.completion = Some(CompletionMenu {
+            row,
+            start: request.start,
+            col: request.col,
+            line: request.line,
+            encoding: request.encoding,
+            incomplete: completions.incomplete,
+            items: completions.items,
+            shown: Vec::new(),
+            selected: 0,
+            typed: None,
+        });
+        self.filter_completions();
+    }
+
+    /// Narrow the menu to the items matching the word typed so far (its
+    /// letters in order, those starting with it first); close it once the
+    /// cursor leaves the word.
+    fn filter_completions(&mut self) {
+        let Some(menu) = &mut self.completion else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        let typed: Option<String> =
+            (row == menu.row && col >= menu.start).then(|| self.buffer.editor.line(row).chars().skip(menu.start).take(col - menu.start).collect());
+        let Some(typed) = typed.filter(|t| t.chars().all(cursors::is_word_char)) else {
+            self.completion = None;
+            return;
+        };
+        if menu.typed.as_ref() == Some(&typed) {
+            return;
+        }
+        let typed_lower = typed.to_lowercase();
+        let matches = |text: &str| {
+            let mut letters = text.chars().flat_map(char::to_lowercase);
+            typed_lower.chars().all(|t| letters.any(|c| c == t))
+        };
+        let mut shown: Vec<usize> = (0..menu.items.len()).filter(|&i| matches(&menu.items[i].filter_text)).collect();
+        shown.sort_by_cached_key(|&i| {
+            let item = &menu.items[i];
+            (!item.filter_text.to_lowercase().starts_with(&typed_lower), item.sort_text.clone())
+        });
+        menu.shown = shown;
+        menu.selected = 0;
+        menu.typed = Some(typed);
+    }
+
+    /// Up, Down, PgUp and PgDn pick in the completion menu, Enter or Tab
+    /// puts the pick in and Esc closes it. Other keys are handled as
+    /// usual, typing narrowing the menu. Returns whether the key was used
+    /// up.
+    pub fn completion_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::KeyCode;
+        if key.code == KeyCode::Esc {
+            // Not wanted after all, if still on its way.
+            self.completion_request = None;
+        }
+        let Some(menu) = self.completion.as_mut().filter(|menu| !menu.shown.is_empty()) else { return false };
+        if !key.modifiers.is_empty() {
+            return false;
+        }
+        let last = menu.shown.len() - 1;
+        match key.code {
+            KeyCode::Up => menu.selected = menu.selected.checked_sub(1).unwrap_or(last),
+            KeyCode::Down => menu.selected = if menu.selected == last { 0 } else { menu.selected + 1 },
+            KeyCode::PageUp => menu.selected = menu.selected.saturating_sub(COMPLETION_ROWS),
+            KeyCode::PageDown => menu.selected = (menu.selected + COMPLETION_ROWS).min(last),
+            KeyCode::Enter | KeyCode::Tab => self.accept_completion(),
+            KeyCode::Esc => self.completion = None,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Put in the picked completion: its edit (stretched over what was
+    /// typed since asking) or its text over the word, and any edits it
+    /// brings elsewhere, as one undoable change.
+    fn accept_completion(&mut self) {
+        let Some(menu) = self.completion.take() else { return };
+        let Some(item) = menu.shown.get(menu.selected).map(|&i| &menu.items[i]) else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        let editor = &self.buffer.editor;
+        let (start, end, text) = match &item.edit {
+            Some(edit) => {
+                let start = lsp::char_col(&menu.line, edit.start.1, menu.encoding);
+                let mut end = lsp::char_col(&menu.line, edit.end.1, menu.encoding);
+                if end >= menu.col {
+                    end = (end + col).saturating_sub(menu.col);
+                }
+                let len = editor.line_len(row);
+                (start.min(col), end.clamp(col, len), edit.text.clone())
+            }
+            None => (menu.start, col, item.insert_text.clone()),
+        };
+        let mut edits = vec![((row, start), (row, end), text)];
+        for edit in &item.additional_edits {
+            let position = |(r, c): (usize, usize)| {
+                let r = r.min(editor.len_lines() - 1);
+                (r, lsp::char_col(&editor.line(r), c, menu.encoding))
+            };
+            edits.push((position(edit.start), position(edit.end), edit.text.clone()));
+        }
+
+        // Apply them back to front over the rows they span.
+        let first = edits.iter().map(|(start, _, _)| start.0).min().unwrap_or(row);
+        let last = edits.iter().map(|(_, end, _)| end.0).max().unwrap_or(row);
+        let offset = |at: (usize, usize)| editor.char_index(at) - editor.char_index((first, 0));
+        let mut text: Vec<char> = editor.range((first, 0), (last, usize::MAX)).chars().collect();
+        let main = offset((row, start));
+        let mut cursor = main + edits[0].2.chars().count();
+        let mut ranges: Vec<(usize, usize, &str)> = edits.iter().map(|(s, e, t)| (offset(*s), offset(*e).max(offset(*s)), t.as_str())).collect();
+        ranges.sort_by_key(|(start, _, _)| std::cmp::Reverse(*start));
+        for &(start, end, new) in &ranges {
+            if start < main {
+                cursor = (cursor + new.chars().count()).saturating_sub(end - start);
+            }
+            text.splice(start..end, new.chars());
+        }
+        let text: String = text.into_iter().collect();
+        let before: String = text.chars().take(cursor).collect();
+        let cursor_row = first + before.matches('\n').count();
+        let cursor_col = before.rsplit('\n').next().map_or(0, |l| l.chars().count());
+        let new_lines: Vec<String> = text.split('\n').map(String::from).collect();
+        self.replace_rows(first..last + 1, &new_lines);
+        self.buffer.editor.jump((cursor_row, cursor_col));
+        self.mark_dirty();
+    }
+
+    /// Replace the server's diagnostics of the shown buffer with `found`.
+    fn set_server_diagnostics(&mut self, server: &str, encoding: lsp::Encoding, found: Vec<lsp::ServerDiagnostic>) {
+        let name = server.split_whitespace().next().unwrap_or(server);
+        let editor = &self.buffer.editor;
+        let last = editor.len_lines().saturating_sub(1);
+        let position = |(row, col): (usize, usize)| {
+            let row = row.min(last);
+            (row, lsp::char_col(&editor.line(row), col, encoding))
+        };
+        let converted: Vec<Diagnostic> = found
+            .into_iter()
+            .map(|d| {
+                let (row, col) = position(d.start);
+                let severity = match d.severity {
+                    Some(2) => Severity::Warning,
+                    Some(3) => Severity::Info,
+                    Some(4) => Severity::Hint,
+                    _ => Severity::Error,
+                };
+                let source = d.source.unwrap_or_else(|| name.to_string());
+                let message = match d.code {
+                    Some(code) => format!("[{}] {}", code, d.message),
+                    None => d.message,
+                };
+                Diagnostic { row, col, end: position(d.end), severity, message, source: Some(source) }
+            })
+            .collect();
+        self.diagnostics.retain(|d| d.source.is_none());
+        self.diagnostics.extend(converted);
+        self.diagnostics.sort_by_key(|d| (d.row, d.col));
+        self.diagnostic_selected = None;
+    }
+
+    pub fn front_matter(&self) -> Option<FrontMatter> {
+        if !self.is_markdown() {
+            return None;
+        }
+        frontmatter::detect(self.buffer.editor.iter())
+    }
+
+    /// Open the title/date/tags popup, prefilled from the front matter.
+    pub fn open_front_matter_form(&mut self) {
+        if !self.is_markdown() {
+            self.set_status("Front matter is only available for Markdown files");
+            return;
+        }
+        let fields = match self.front_matter() {
+            Some(fm) => {
+                let lines = self.buffer.editor.rows(0..fm.end + 1);
+                if let Some((_, err)) = frontmatter::validate(&lines, &fm) {
+                    self.set_status(&format!("Front matter doesn't parse: {}", err));
+                    return;
+                }
+                frontmatter::read_fields(&lines, &fm)
+            }
+            None => frontmatter::Fields::default(),
+        };
+        let inputs = [fields.title, fields.date, fields.tags]
+            .into_iter()
+            .zip(frontmatter::FIELD_NAMES)
+            .map(|(value, name)| {
+                let mut input = PromptInput::new(&format!(" {} ", name), "");
+                input.set_text(&value);
+                input
+            })
+            .collect();
+        self.front_matter_form = Some(FrontMatterForm { inputs, focus: 0 });
+        self.push_mode(AppMode::FrontMatter);
+    }
+
+    /// Write the popup's fields back, creating a YAML block if the file has
+    /// no front matter yet.
+    pub fn apply_front_matter_form(&mut self) {
+        self.pop_mode();
+        let Some(form) = self.front_matter_form.take() else { return };
+        let values: Vec<String> = form.inputs.iter().map(|i| i.text()).collect();
+
+        let (format, rows, mut body) = match self.front_matter() {
+            Some(fm) => (fm.format, fm.start..fm.end + 1, self.buffer.editor.rows(fm.body())),
+            None => (frontmatter::Format::Yaml, 0..0, Vec::new()),
+        };
+        for (name, value) in frontmatter::FIELD_NAMES.iter().zip(&values) {
+            body = frontmatter::set_field(&body, format, name, value);
+        }
+        let fence = match format {
+            frontmatter::Format::Yaml => "---",
+            frontmatter::Format::Toml => "+++",
+        };
+        let mut lines = vec![fence.to_string()];
+        lines.extend(body);
+        lines.push(fence.to_string());
+
+        let cursor = self.buffer.editor.cursor();
+        let shift = lines.len() as isize - rows.len() as isize;
+        self.replace_rows(rows, &lines);
+        let row = if cursor.0 == 0 { 0 } else { cursor.0.saturating_add_signed(shift) };
+        self.buffer.editor.jump((row, cursor.1));
+        self.mark_dirty();
+    }
+
+    /// Undo the last edit, or all of an AI answer, formatting or macro run.
+    /// Right after an auto-correction it brings back what was typed.
+    pub fn undo(&mut self) {
+        if let Some(edit) = self.smart_edit.take().filter(|e| e.at == self.buffer.editor.cursor()) {
+            // One edit, so a further undo takes the typed text back too.
+            let editor = &mut self.buffer.editor;
+            let from = editor.position(editor.char_index(edit.at).saturating_sub(edit.replacement_len));
+            editor.replace_range(from, edit.at, &edit.literal);
+            self.mark_dirty();
+            return;
+        }
+        if self.buffer.undo() {
+            self.mark_dirty();
+        } else {
+            self.set_status("Nothing to undo");
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if self.buffer.redo() {
+            self.mark_dirty();
+        } else {
+            self.set_status("Nothing to redo");
+        }
+    }
+
+    /// Replace buffer rows `rows` with `lines` as one edit, so it can be
+    /// undone.
+    pub fn replace_rows(&mut self, rows: std::ops::Range<usize>, lines: &[String]) {
+        let editor = &mut self.buffer.editor;
+        let total = editor.len_lines();
+        if rows.end <= total && editor.rows(rows.clone()) == lines {
+            return;
+        }
+        let mut text = lines.join("\n");
+        let (from, to) = if rows.end < total {
+            if !lines.is_empty() {
+                text.push('\n');
+            }
+            ((rows.start, 0), (rows.end, 0))
+        } else if rows.start > 0 && (lines.is_empty() || rows.start >= total) {
+            // At the end of the text the line break goes before the rows.
+            if !lines.is_empty() {
+                text.insert(0, '\n');
+            }
+            ((rows.start - 1, usize::MAX), (total - 1, usize::MAX))
+        } else {
+            ((rows.start, 0), (total - 1, usize::MAX))
+        };
+        editor.replace_range(from, to, &text);
+    }
+
+    /// Replace the cursor line with `edit(lines, row)`, keeping the cursor
+    /// on the same text. `lines` are the rows up to the cursor's.
+    fn edit_cursor_line(&mut self, edit: impl FnOnce(&[String], usize) -> Option<String>) {
+        let (row, col) = self.buffer.editor.cursor();
+        let lines = self.buffer.editor.rows(0..row + 1);
+        let old = &lines[row];
+        let Some(new) = edit(&lines, row) else { return };
+        // Only the part that changed moves the cursor, and only if it's
+        // before it.
+        let (old_chars, new_chars): (Vec<char>, Vec<char>) = (old.chars().collect(), new.chars().collect());
+        let prefix = old_chars.iter().zip(&new_chars).take_while(|(a, b)| a == b).count();
+        let suffix = old_chars[prefix..]
+            .iter()
+            .rev()
+            .zip(new_chars[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let col = if col <= prefix {
+            col
+        } else if col >= old_chars.len() - suffix {
+            col + new_chars.len() - old_chars.len()
+        } else {
+            col.min(new_chars.len() - suffix)
+        };
+        self.replace_rows(row..row + 1, &[new]);
+        self.buffer.editor.jump((row, col));
+        self.mark_dirty();
+    }
+
+    pub fn toggle_checkbox(&mut self) {
+        let org = self.filetype().as_deref() == Some("org");
+        self.edit_cursor_line(|lines, row| lists::toggle_checkbox(&lines[row], org));
+    }
+
+    pub fn shift_level(&mut self, deeper: bool) {
+        let org = self.filetype().as_deref() == Some("org");
+        self.edit_cursor_line(|lines, row| lists::shift_level(lines, row, deeper, org));
+    }
+
+    /// Comment or uncomment the selected lines (or the cursor line) with
+    /// the filetype's leader from `comment_leaders`.
+    pub fn toggle_comment(&mut self) {
+        let Some(leader) = self.filetype().and_then(|ft| self.config.comment_leaders.get(&ft).cloned()) else {
+            self.set_status("No line comments for this filetype (see comment_leaders)");
+            return;
+        };
+        let (row, col) = self.buffer.editor.cursor();
+        let rows = match self.buffer.editor.selection_range() {
+            // A selection ending at the start of a line doesn't include it.
+            Some((start, end)) if start != end => start.0..if end.1 == 0 && end.0 > start.0 { end.0 } else { end.0 + 1 },
+            _ => row..row + 1,
+        };
+        let lines = comment::toggle(&self.buffer.editor.rows(rows.clone()), &leader);
+        let shift = rows.contains(&row).then(|| {
+            lines[row - rows.start].chars().count() as isize - self.buffer.editor.line_len(row) as isize
+        });
+        self.replace_rows(rows, &lines);
+        let col = if col == 0 { 0 } else { col.saturating_add_signed(shift.unwrap_or(0)) };
+        self.buffer.editor.jump((row, col));
+        self.mark_dirty();
+    }
+
+    pub fn renumber_list(&mut self) {
+        let (row, col) = self.buffer.editor.cursor();
+        let Some((start, block)) = lists::renumber(&self.buffer.editor.lines(), row) else {
+            self.set_status("Not in an ordered list");
+            return;
+        };
+        self.replace_rows(start..start + block.len(), &block);
+        self.buffer.editor.jump((row, col));
+        self.mark_dirty();
+    }
+
+    pub fn line_length_rule(&self) -> Option<LineLengthRule> {
+        if let Some(rule) = self.profile.line_length {
+            return Some(rule);
+        }
+        let filetype = self.filetype()?;
+        self.config.line_length.get(&filetype).copied()
+    }
+
+    /// Resolve the feature profile for the current filename. Called on open
+    /// and whenever the buffer gets a new name.
+    pub fn apply_profile(&mut self) {
+        self.buffer.language = self.syntax_set.find_syntax_for_file(&self.filename).ok().flatten().map(|s| s.name.clone());
+        let filetype = self.filetype();
+        self.profile = profile::resolve(&self.config.profiles, &self.filename, filetype.as_deref());
+        let mut keybindings = self.config.keybindings.clone();
+        keybindings.extend(self.profile.keybindings.clone());
+        self.keymap = KeyMap::new(&keybindings, self.keyboard_enhanced);
+        if let Some(table_view) = self.profile.table_view {
+            self.table_view = table_view && self.csv_delimiter().is_some();
+        }
+        let configured = Indent {
+            hard_tabs: !self.profile.insert_spaces.unwrap_or(self.config.insert_spaces),
+            width: self.profile.tab_width.unwrap_or(self.config.tab_width).max(1),
+        };
+        let detected = self.config.detect_indent.then(|| indent::detect(self.buffer.editor.iter(), configured.width)).flatten();
+        self.indent = detected.unwrap_or(configured);
+        self.apply_indent();
+        if self.spell_scope().is_some() {
+            self.load_dictionary();
+        }
+    }
+
+    /// Set up the editor (which inserts Tab's indentation) for `indent`.
+    fn apply_indent(&mut self) {
+        self.buffer.editor.set_tab_length(self.indent.width);
+        self.buffer.editor.set_hard_tab_indent(self.indent.hard_tabs);
+    }
+
+    /// Replace the buffer with the formatter's output.
+    fn apply_formatted(&mut self, output: &str) {
+        // Replace only the rows the formatter changed, as one undoable edit.
+        let formatted: Vec<String> = output.lines().map(String::from).collect();
+        let lines = self.buffer.editor.lines();
+        let head = lines.iter().zip(&formatted).take_while(|(a, b)| a == b).count();
+        let tail = lines[head..]
+            .iter()
+            .rev()
+            .zip(formatted[head..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let rows = head..lines.len() - tail;
+        let cursor = self.buffer.editor.cursor();
+        self.replace_rows(rows, &formatted[head..formatted.len() - tail]);
+        self.buffer.editor.jump((cursor.0, cursor.1));
+    }
+
+    /// Scroll the editor view, keeping the viewport mirror in sync.
+    pub fn scroll_editor(&mut self, rows: i16) {
+        self.buffer.editor.scroll((rows, 0));
+        self.wrap_top = 0;
+        if !self.folds.is_empty() {
+            // Folded rows don't count.
+            let last = self.buffer.editor.len_lines() - 1;
+            let mut top = (self.editor_scroll.0 as usize).min(last);
+            for _ in 0..rows.unsigned_abs() {
+                top = if rows > 0 { fold::next_visible(&self.folds, top).min(last) } else { fold::prev_visible(&self.folds, top) };
+            }
+            self.editor_scroll.0 = top as u16;
+            return;
+        }
+        self.editor_scroll.0 = if rows >= 0 {
+            self.editor_scroll.0.saturating_add(rows as u16)
+        } else {
+            self.editor_scroll.0.saturating_sub(rows.unsigned_abs())
+        };
+    }
+
+    /// Buffer rows on screen as of the last frame.
+    pub fn visible_rows(&self) -> std::ops::Range<usize> {
+        if let (true, Some(first), Some(last)) = (self.screen_row_view(), self.screen_rows.first(), self.screen_rows.last()) {
+            return first.row..last.row + 1;
+        }
+        let top = self.editor_scroll.0 as usize;
+        let bottom = (top + self.editor_area.height as usize).min(self.buffer.editor.len_lines());
+        top..bottom.max(top)
+    }
+
+    /// Scroll so `row` is the top line. The cursor is pulled into view if
+    /// it would leave it.
+    pub fn scroll_to_row(&mut self, row: usize) {
+        let row = row.min(self.buffer.editor.len_lines().saturating_sub(1));
+        let delta = row as i64 - self.editor_scroll.0 as i64;
+        self.scroll_editor(delta.clamp(i16::MIN as i64, i16::MAX as i64) as i16);
+    }
+
+    /// Scroll so the cursor line is in the middle of the view.
+    pub fn center_cursor(&mut self) {
+        let row = self.buffer.editor.cursor().0;
+        self.scroll_to_row(row.saturating_sub(self.editor_area.height as usize / 2));
+    }
+
+    /// Move the cursor to (row, col), centering the view on it when it
+    /// lands off screen.
+    pub fn jump_to(&mut self, row: usize, col: usize) {
+        self.buffer.editor.jump((row, col));
+        if !self.visible_rows().contains(&self.buffer.editor.cursor().0) {
+            self.center_cursor();
+        }
+    }
+
+    /// Start at 1-based `line` and `col` from the command line, clamped to
+    /// the buffer, with the line centered.
+    pub fn start_at(&mut self, line: usize, col: usize) {
+        let row = line.clamp(1, self.buffer.editor.len_lines()) - 1;
+        let col = col.clamp(1, self.buffer.editor.line_len(row) + 1) - 1;
+        self.buffer.editor.jump((row, col));
+        self.center_cursor();
+    }
+
+    /// Type a key into the editor, running the as-you-type helpers
+    /// (abbreviation expansion, hard wrap).
+    pub fn type_key(&mut self, key: crossterm::event::KeyEvent) {
+        use crossterm::event::{KeyCode, KeyModifiers};
+
+        if self.block_key(key) {
+            return;
+        }
+
+        if !self.cursors.is_empty() && self.type_at_cursors(key) {
+            return;
+        }
+
+        if self.move_by_screen_row(key) {
+            return;
+        }
+
+        // Only undo right after an auto-correction restores the literal input.
+        self.smart_edit = None;
+
+        if let KeyCode::Char(c) = key.code {
+            let plain = !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+            if plain && self.type_prose_char(c) {
+                self.mark_dirty();
+                return;
+            }
+        }
+
+        let ends_word = match key.code {
+            KeyCode::Char(c) => !(c.is_alphanumeric() || c == '_'),
+            KeyCode::Enter | KeyCode::Tab => true,
+            _ => false,
+        };
+        if ends_word && self.expand_abbreviation() {
+            self.mark_dirty();
+        }
+
+        if key.code == KeyCode::Enter && key.modifiers.is_empty() && self.auto_indent_newline() {
+            self.mark_dirty();
+            return;
+        }
+
+        if self.buffer.editor.input(key) {
+            self.mark_dirty();
+            if let KeyCode::Char(c) = key.code {
+                self.hard_wrap_current_line();
+                self.complete_while_typing(c);
+            }
+        }
+    }
+
+    /// Alt+arrows start or extend the block selection; while there is one,
+    /// typed chars, Tab, Backspace and Delete edit every row of it and Esc
+    /// drops it. Other keys drop it and return false.
+    fn block_key(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::{KeyCode, KeyModifiers};
+
+        if key.modifiers == KeyModifiers::ALT && matches!(key.code, KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right) {
+            let editor = &self.buffer.editor;
+            let cursor = self.buffer.editor.cursor();
+            let block = self.block.get_or_insert(Block { anchor: cursor, head: cursor });
+            // Not past the longest line of the block.
+            let widest = block.rows().map(|r| editor.line_len(r)).max().unwrap_or(0);
+            let (row, col) = &mut block.head;
+            match key.code {
+                KeyCode::Up => *row = row.saturating_sub(1),
+                KeyCode::Down => *row = (*row + 1).min(editor.len_lines() - 1),
+                KeyCode::Left => *col = col.saturating_sub(1),
+                _ => *col = (*col + 1).min(widest),
+            }
+            let (rows, cols) = (block.rows(), block.cols());
+            self.move_to_block_head();
+            self.set_status(&format!("Block {}×{} (type to edit every row, Esc to drop)", rows.count(), cols.len()));
+            return true;
+        }
+        let Some(current) = self.block else { return false };
+        let plain = !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        let edit = match key.code {
+            KeyCode::Char(c) if plain => block::Edit::Insert(c),
+            KeyCode::Tab => block::Edit::Insert('\t'),
+            KeyCode::Backspace => block::Edit::Backspace,
+            KeyCode::Delete => block::Edit::Delete,
+            code => {
+                self.block = None;
+                return code == KeyCode::Esc;
+            }
+        };
+        let rows = current.rows();
+        let (lines, col) = block::apply(&self.buffer.editor.rows(*rows.start()..*rows.end() + 1), current.cols(), &edit);
+        self.replace_rows(*rows.start()..*rows.end() + 1, &lines);
+        self.block = Some(Block { anchor: (current.anchor.0, col), head: (current.head.0, col) });
+        self.move_to_block_head();
+        self.mark_dirty();
+        true
+    }
+
+    fn move_to_block_head(&mut self) {
+        let Some(Block { head: (row, col), .. }) = self.block else { return };
+        let col = col.min(self.buffer.editor.line_len(row));
+        self.buffer.editor.cancel_selection();
+        self.buffer.editor.jump((row, col));
+    }
+
+    /// Apply `key` at every cursor, front to back. Esc drops the extra
+    /// cursors; keys that only make sense in one place drop them too and
+    /// return false so they're handled as usual.
+    fn type_at_cursors(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::{KeyCode, KeyModifiers};
+
+        let plain = !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        let everywhere = plain
+            && matches!(
+                key.code,
+                KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Delete | KeyCode::Enter | KeyCode::Tab
+                    | KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down | KeyCode::Home | KeyCode::End
+            );
+        if !everywhere {
+            self.cursors.clear();
+            return key.code == KeyCode::Esc;
+        }
+
+        // One key at every cursor undoes as one.
+        self.buffer.begin_group();
+        let editor = &mut self.buffer.editor;
+        editor.cancel_selection();
+        let primary = editor.cursor();
+        let mut all: Vec<((usize, usize), bool)> = self.cursors.iter().map(|&c| (c, false)).collect();
+        all.push((primary, true));
+        all.sort();
+        let mut offsets: Vec<usize> = all.iter().map(|&(c, _)| editor.char_index(c)).collect();
+        let mut changed = false;
+        for i in 0..offsets.len() {
+            let before = editor.text().len_chars();
+            editor.jump(editor.position(offsets[i]));
+            changed |= editor.input(key);
+            let delta = editor.text().len_chars() as isize - before as isize;
+            offsets[i] = editor.char_index(editor.cursor());
+            for later in &mut offsets[i + 1..] {
+                *later = later.saturating_add_signed(delta);
+            }
+        }
+
+        let primary = all.iter().zip(&offsets).find(|((_, main), _)| *main).map_or(0, |(_, &o)| o);
+        let mut extra: Vec<usize> = offsets.iter().copied().filter(|&o| o != primary).collect();
+        extra.dedup();
+        self.cursors = extra.into_iter().map(|o| editor.position(o)).collect();
+        editor.jump(editor.position(primary));
+        self.buffer.end_group();
+        if changed {
+            self.mark_dirty();
+        }
+        true
+    }
+
+    /// Ctrl+D: add a cursor at the next whole-word occurrence of the word
+    /// under the cursor, at the same place within the word.
+    pub fn add_cursor_at_next_match(&mut self) {
+        let lines = &self.buffer.editor.lines();
+        let (row, col) = self.buffer.editor.cursor();
+        let Some(word) = cursors::word_at(&lines[row], col) else {
+            self.set_status("No word under the cursor");
+            return;
+        };
+        let within = col.clamp(word.start, word.end) - word.start;
+        let text: String = lines[row].chars().skip(word.start).take(word.len()).collect();
+        let last = self.cursors.iter().copied().chain([(row, col)]).max().unwrap_or((row, col));
+        let mut from = (last.0, last.1.saturating_sub(within));
+        // Skip occurrences that already have a cursor.
+        for _ in 0..=self.cursors.len() {
+            let Some((r, c)) = cursors::next_occurrence(lines, &text, from) else { break };
+            let at = (r, c + within);
+            if at != (row, col) && !self.cursors.contains(&at) {
+                self.cursors.push(at);
+                self.set_status(&format!("{} cursors (Esc to drop the extra ones)", self.cursors.len() + 1));
+                return;
+            }
+            from = (r, c);
+        }
+        self.set_status(&format!("No more occurrences of \"{}\"", text));
+    }
+
+    /// Alt+Click: add a cursor there, or remove the one that's there.
+    pub fn toggle_cursor(&mut self, row: usize, col: usize) {
+        if let Some(i) = self.cursors.iter().position(|&c| c == (row, col)) {
+            self.cursors.remove(i);
+        } else if self.buffer.editor.cursor() != (row, col) {
+            self.cursors.push((row, col));
+        }
+    }
+
+    /// Break the line keeping its indentation, one level deeper after an
+    /// opener from `indent_after`. Between a bracket pair the closer goes on
+    /// its own line below. Returns false when auto-indent is off.
+    fn auto_indent_newline(&mut self) -> bool {
+        if !self.profile.auto_indent.unwrap_or(self.config.auto_indent) || self.buffer.editor.is_selecting() {
+            return false;
+        }
+        let (row, col) = self.buffer.editor.cursor();
+        let line = &self.buffer.editor.line(row);
+        let indent: String = line.chars().take_while(|c| c.is_whitespace()).take(col).collect();
+        let before: String = line.chars().take(col).collect();
+        let after: String = line.chars().skip(col).collect();
+        let openers = self.profile.indent_after.as_ref().unwrap_or(&self.config.indent_after);
+        let opener = openers.iter().find(|o| !o.is_empty() && before.trim_end().ends_with(o.as_str()));
+        let closer = match opener.map(String::as_str) {
+            Some("{") => Some('}'),
+            Some("(") => Some(')'),
+            Some("[") => Some(']'),
+            _ => None,
+        };
+        let split_pair = closer.is_some_and(|c| after.trim_start().starts_with(c));
+        let level = if indent.contains('\t') { "\t" } else { self.buffer.editor.indent() };
+        let deeper = if opener.is_some() { format!("{}{}", indent, level) } else { indent.clone() };
+
+        let editor = &mut self.buffer.editor;
+        // Blanks the cursor was in front of would only push the text right.
+        for _ in after.chars().take_while(|c| c.is_whitespace()) {
+            editor.delete_next_char();
+        }
+        editor.insert_newline();
+        editor.insert_str(&deeper);
+        if split_pair {
+            editor.insert_newline();
+            editor.insert_str(&indent);
+            editor.move_cursor(CursorMove::Up);
+            editor.move_cursor(CursorMove::End);
+        }
+        true
+    }
+
+    /// Prose helpers from the profile. Returns true if `c` was inserted in a
+    /// corrected form.
+    fn type_prose_char(&mut self, c: char) -> bool {
+        let smart = self.profile.smart_punctuation.unwrap_or(false);
+        let capitalize = self.profile.auto_capitalize.unwrap_or(false);
+        if !smart && !capitalize {
+            return false;
+        }
+
+        let (row, col) = self.buffer.editor.cursor();
+        let editor = &self.buffer.editor;
+        let before: String = editor.line(row).chars().take(col).collect();
+        let previous_line = row.checked_sub(1).map(|r| editor.line(r).into_owned());
+
+        let fix = smart.then(|| prose::smart_punctuation(&before, c)).flatten();
+        let upper = capitalize
+            .then(|| prose::capitalize(&before, previous_line.as_deref(), c))
+            .flatten();
+        let (drop, replacement) = if let Some(fix) = fix {
+            fix
+        } else if let Some(upper) = upper {
+            (0, upper.to_string())
+        } else {
+            return false;
+        };
+
+        let mut literal: String = before.chars().skip(before.chars().count() - drop).collect();
+        literal.push(c);
+        self.buffer.editor.replace_range((row, col - drop), (row, col), &replacement);
+        self.smart_edit = Some(SmartEdit {
+            at: self.buffer.editor.cursor(),
+            replacement_len: replacement.chars().count(),
+            literal,
+        });
+        true
+    }
+
+    /// Replace the word right before the cursor with its expansion, if it is
+    /// an abbreviation. Profile abbreviations win over global ones.
+    fn expand_abbreviation(&mut self) -> bool {
+        if !self.config.expand_abbreviations {
+            return false;
+        }
+        let (row, col) = self.buffer.editor.cursor();
+        let before: Vec<char> = self.buffer.editor.line(row).chars().take(col).collect();
+        let start = before
+            .iter()
+            .rposition(|c| !(c.is_alphanumeric() || *c == '_'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let word: String = before[start..].iter().collect();
+        if word.is_empty() {
+            return false;
+        }
+
+        let expansion = self
+            .profile
+            .abbreviations
+            .get(&word)
+            .or_else(|| self.config.abbreviations.get(&word))
+            .cloned();
+        let Some(expansion) = expansion else { return false };
+
+        for _ in 0..word.chars().count() {
+            self.buffer.editor.delete_char();
+        }
+        self.buffer.editor.insert_str(expansion);
+        true
+    }
+
+    /// Merge `ours` and `theirs` into the buffer and open the merge view
+    /// on the first conflict. The buffer always holds the result so far.
+    pub fn start_merge(&mut self, base: &str, ours: &str, theirs: &str) {
+        let merge = diff::Merge::new(base, ours, theirs);
+        let selected = merge.hunks().position(|h| h.take.is_none()).unwrap_or(0);
+        let conflicts = merge.conflicts();
+        self.merge = Some(MergeView { merge, selected });
+        self.write_merge_result();
+        self.push_mode(AppMode::Merge);
+        self.set_status(&format!("{} conflict{} to resolve", conflicts, if conflicts == 1 { "" } else { "s" }));
+    }
+
+    fn write_merge_result(&mut self) {
+        let Some(view) = &self.merge else { return };
+        let (lines, starts) = view.merge.result();
+        let row = starts.get(view.selected).copied().unwrap_or(0);
+        if lines != self.buffer.editor.lines() {
+            let rows = self.buffer.editor.len_lines();
+            self.replace_rows(0..rows, &lines);
+            self.mark_dirty();
+        }
+        self.jump_to(row, 0);
+    }
+
+    /// Settle the selected hunk (None brings the conflict back) and move on
+    /// to the next conflict.
+    pub fn merge_take(&mut self, take: Option<diff::Take>) {
+        let Some(view) = &mut self.merge else { return };
+        if let Some(hunk) = view.merge.hunk_mut(view.selected) {
+            hunk.take = take;
+        }
+        if take.is_some() {
+            let hunks: Vec<bool> = view.merge.hunks().map(|h| h.take.is_none()).collect();
+            let after = (view.selected + 1..hunks.len()).chain(0..view.selected).find(|&i| hunks[i]);
+            view.selected = after.unwrap_or(view.selected);
+        }
+        self.write_merge_result();
+    }
+
+    pub fn move_merge_hunk(&mut self, delta: isize) {
+        let Some(view) = &mut self.merge else { return };
+        let last = view.merge.hunks().count().saturating_sub(1);
+        view.selected = view.selected.saturating_add_signed(delta).min(last);
+        self.write_merge_result();
+    }
+
+    /// Leave the merge view for the editor, at the selected hunk; whatever
+    /// is unresolved stays as conflict markers to fix by hand.
+    pub fn edit_merge(&mut self) {
+        self.remove_mode(AppMode::Merge);
+        self.merge = None;
+        self.set_status("Editing the merge result by hand");
+    }
+
+    /// Save the result and quit.
+    pub fn finish_merge(&mut self) {
+        match self.save_file() {
+            Ok(()) => self.quit(),
+            Err(e) => self.set_status(&format!("Error: {}", e)),
+        }
+    }
+
+    /// Alt+F: fold the block the cursor is in (or starts), or open the
+    /// fold on the cursor line.
+    pub fn toggle_fold(&mut self) {
+        let (row, col) = self.buffer.editor.cursor();
+        if let Some(i) = self.folds.iter().position(|f| f.start == row) {
+            self.folds.remove(i);
+            return;
+        }
+        let tab_len = self.buffer.editor.tab_length() as usize;
+        let Some(new) = fold::enclosing(&self.buffer.editor.lines(), row, tab_len) else {
+            self.set_status("No block to fold here");
+            return;
+        };
+        self.folds.retain(|f| f.start < new.start || f.start > new.end);
+        self.folds.push(new);
+        self.folds.sort_by_key(|f| f.start);
+        if new.start != row {
+            let col = col.min(self.buffer.editor.line_len(new.start));
+            self.buffer.editor.jump((new.start, col));
+        }
+    }
+
+    /// Alt+U: open every fold, or fold all outermost blocks when nothing
+    /// is folded.
+    pub fn toggle_all_folds(&mut self) {
+        if !self.folds.is_empty() {
+            self.folds.clear();
+            self.set_status("All folds opened");
+            return;
+        }
+        let tab_len = self.buffer.editor.tab_length() as usize;
+        self.folds = fold::outermost(&self.buffer.editor.lines(), tab_len);
+        if self.folds.is_empty() {
+            self.set_status("No blocks to fold");
+            return;
+        }
+        let row = self.buffer.editor.cursor().0;
+        let shown = fold::shown(&self.folds, row);
+        if shown != row {
+            self.buffer.editor.jump((shown, 0));
+        }
+        self.set_status(&format!("{} blocks folded", self.folds.len()));
+    }
+
+    /// Open the folds hiding the cursor, e.g. after a search or jump
+    /// lands inside one.
+    pub fn unfold_at_cursor(&mut self) {
+        let row = self.buffer.editor.cursor().0;
+        self.folds.retain(|f| !f.hides(row));
+    }
+
+    /// The editor is drawn by screen row (`screen_rows`) rather than by
+    /// the editor widget: with soft wrap or folds.
+    pub fn screen_row_view(&self) -> bool {
+        (self.buffer.soft_wrap || !self.folds.is_empty()) && !self.table_view
+    }
+
+    /// Screen rows of buffer `row`: none when folded away, several when it
+    /// wraps.
+    pub fn view_rows(&self, row: usize) -> Vec<wrap::ScreenRow> {
+        if fold::is_hidden(&self.folds, row) {
+            return Vec::new();
+        }
+        let line = &self.buffer.editor.line(row);
+        if self.buffer.soft_wrap {
+            wrap::screen_rows(row, line, self.wrap_width(), self.buffer.editor.tab_length() as usize)
+        } else {
+            vec![wrap::ScreenRow { row, start: 0, end: line.chars().count() }]
+        }
+    }
+
+    pub fn toggle_soft_wrap(&mut self) {
+        self.buffer.soft_wrap = !self.buffer.soft_wrap;
+        self.wrap_top = 0;
+        self.screen_rows.clear();
+        self.set_status(if self.buffer.soft_wrap { "Soft wrap on" } else { "Soft wrap off" });
+        self.prefs.soft_wrap.insert(self.prefs_filetype(), self.buffer.soft_wrap);
+        self.save_prefs();
+    }
+
+    fn prefs_filetype(&self) -> String {
+        self.filetype().unwrap_or_else(|| "text".to_string())
+    }
+
+    /// Soft wrap as last toggled for this kind of file, else the config's.
+    fn soft_wrap_pref(&self) -> bool {
+        self.prefs.soft_wrap.get(&self.prefs_filetype()).copied().unwrap_or(self.config.soft_wrap)
+    }
+
+    /// Keep the display choices for the next start; not in secure mode,
+    /// which writes nothing but the file.
+    fn save_prefs(&mut self) {
+        if self.secure {
+            return;
+        }
+        if let Err(e) = prefs::save(&self.prefs) {
+            self.set_status(&format!("Couldn't save preferences: {}", e));
+        }
+    }
+
+    /// Columns a wrapped line gets: the editor width without the gutter.
+    pub fn wrap_width(&self) -> usize {
+        (self.editor_area.width as usize).saturating_sub(ui::gutter_width(self) as usize)
+    }
+
+    /// Up/Down by screen row rather than by line with soft wrap or folds.
+    /// Returns false for other keys, which the editor handles.
+    pub fn move_by_screen_row(&mut self, key: crossterm::event::KeyEvent) -> bool {
+        use crossterm::event::{KeyCode, KeyModifiers};
+        let down = match key.code {
+            KeyCode::Down => true,
+            KeyCode::Up => false,
+            _ => return false,
+        };
+        if !self.screen_row_view() || key.modifiers != KeyModifiers::NONE || self.wrap_width() == 0 {
+            return false;
+        }
+        let tab_len = self.buffer.editor.tab_length() as usize;
+        let editor = &self.buffer.editor;
+        let (row, col) = self.buffer.editor.cursor();
+        let rows = self.view_rows(row);
+        if rows.is_empty() {
+            return false;
+        }
+        let i = wrap::row_of(&rows, col);
+        let x_of = |line: &str, col: usize| {
+            let columns = ui::display_columns(line, tab_len);
+            columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, w)| start + w), |(start, _)| *start)
+        };
+        let x = x_of(&editor.line(row), col) - x_of(&editor.line(row), rows[i].start);
+
+        let (target, last) = if down {
+            if i + 1 < rows.len() {
+                (rows[i + 1], i + 2 == rows.len())
+            } else if fold::next_visible(&self.folds, row) < editor.len_lines() {
+                let next = self.view_rows(fold::next_visible(&self.folds, row));
+                (next[0], next.len() == 1)
+            } else {
+                return false;
+            }
+        } else if i > 0 {
+            (rows[i - 1], false)
+        } else if row > 0 {
+            let prev = self.view_rows(fold::prev_visible(&self.folds, row));
+            (prev[prev.len() - 1], true)
+        } else {
+            return false;
+        };
+        // Same screen column where the target row is long enough; a
+        // wrapped row's end is the next row's start, so stop before it.
+        let line = &editor.line(target.row);
+        let base = x_of(line, target.start);
+        let end = if last { target.end } else { target.end.saturating_sub(1).max(target.start) };
+        let col = (target.start..end).find(|&c| x_of(line, c + 1) - base > x).unwrap_or(end);
+        self.buffer.editor.jump((target.row, col));
+        true
+    }
+
+    /// The bracket at the cursor and its partner, if it has one.
+    pub fn matching_bracket(&self) -> Option<((usize, usize), (usize, usize))> {
+        // Only the rows the search can reach, not a copy of the whole text.
+        let (row, col) = self.buffer.editor.cursor();
+        let first = row.saturating_sub(brackets::MAX_ROWS);
+        let window = self.buffer.editor.rows(first..row + brackets::MAX_ROWS);
+        let (at, partner) = brackets::find(&window, (row - first, col))?;
+        Some(((at.0 + first, at.1), (partner.0 + first, partner.1)))
+    }
+
+    pub fn jump_to_matching_bracket(&mut self) {
+        match self.matching_bracket() {
+            Some((_, (row, col))) => self.jump_to(row, col),
+            None => self.set_status("No matching bracket"),
+        }
+    }
+
+    pub fn toggle_whitespace(&mut self) {
+        self.config.show_whitespace = !self.config.show_whitespace;
+        self.set_status(if self.config.show_whitespace { "Showing whitespace" } else { "Hiding whitespace" });
+        self.prefs.show_whitespace = Some(self.config.show_whitespace);
+        self.save_prefs();
+    }
+
+    pub fn toggle_abbreviations(&mut self) {
+        self.config.expand_abbreviations = !self.config.expand_abbreviations;
+        let state = if self.config.expand_abbreviations { "on" } else { "off" };
+        self.set_status(&format!("Abbreviation expansion {}", state));
+    }
+
+    /// Break the cursor line at the last space before the limit when the
+    /// filetype asks for hard wrapping (nano's "hard-wrap" behaviour).
+    pub fn hard_wrap_current_line(&mut self) {
+        let Some(rule) = self.line_length_rule() else { return };
+        if !rule.hard_wrap || rule.max == 0 {
+            return;
+        }
+
+        let (row, col) = self.buffer.editor.cursor();
+        let chars: Vec<char> = self.buffer.editor.line(row).chars().collect();
+        if chars.len() <= rule.max {
+            return;
+        }
+
+        // Break at the last space that keeps the first part within the limit.
+        let Some(break_at) = chars[..=rule.max].iter().rposition(|c| *c == ' ') else { return };
+        if break_at == 0 || col <= break_at {
+            return;
+        }
+
+        self.buffer.editor.jump((row, break_at));
+        self.buffer.editor.delete_next_char();
+        self.buffer.editor.insert_newline();
+        self.buffer.editor.jump((row + 1, col - break_at - 1));
+    }
+
+    /// Replace the whole editor content as one edit, so it can be undone.
+    pub fn replace_lines(&mut self, lines: Vec<String>) {
+        let rows = self.buffer.editor.len_lines();
+        self.replace_rows(0..rows, &lines);
+        self.buffer.editor.jump((0, 0));
+        self.folds.clear();
+        self.cursors.clear();
+        self.block = None;
+    }
+
+    /// Like `replace_lines`, but for text read from disk: the buffer
+    /// starts out unmodified.
+    pub fn load_content(&mut self, content: &str) {
+        self.buffer.load(Self::new_editor(content));
+        self.folds.clear();
+        self.cursors.clear();
+        self.block = None;
+        self.apply_indent();
+    }
+
+    fn new_editor(content: &str) -> Editor<'a> {
+        Self::editor_of(Editor::rope_of(content))
+    }
+
+    fn editor_of(text: Rope) -> Editor<'a> {
+        let mut editor = Editor::new(text);
+        editor.set_line_number_style(ratatui::style::Style::default().fg(ratatui::style::Color::DarkGray));
+        editor
+    }
+
+    /// Handle messages of the shared editing session and send our cursor.
+    pub fn poll_share(&mut self) {
+        let Some(session) = &mut self.share else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        session.send_cursor(row, col);
+        for update in session.poll() {
+            match update {
+                share::Update::Splice { from, to, text } => self.apply_shared_splice(from, to, &text),
+                share::Update::Load(text) => {
+                    let (row, col) = self.buffer.editor.cursor();
+                    self.buffer.load(Self::editor_of(Rope::from_str(&text)));
+                    self.apply_indent();
+                    self.buffer.editor.jump((row, col));
+                    self.absorb_remote_changes();
+                }
+                share::Update::Follow { row, col } => self.jump_to(row, col),
+                share::Update::Status(message) => self.set_status(&message),
+                share::Update::Closed(message) => {
+                    self.share = None;
+                    self.set_status(&message);
+                }
+            }
+        }
+    }
+
+    /// Apply a participant's edit of chars `from..to`, keeping our cursor
+    /// on the same text.
+    fn apply_shared_splice(&mut self, from: usize, to: usize, text: &str) {
+        let editor = &mut self.buffer.editor;
+        let total = editor.text().len_chars();
+        let (from, to) = (from.min(total), to.min(total));
+        let cursor = editor.char_index(editor.cursor());
+        let cursor = if cursor >= to {
+            cursor - (to - from) + text.chars().count()
+        } else {
+            cursor.min(from)
+        };
+        editor.replace_range(editor.position(from), editor.position(to), text);
+        editor.jump(editor.position(cursor));
+        self.absorb_remote_changes();
+    }
+
+    /// Take in the edits just applied from the session without sending
+    /// them back.
+    fn absorb_remote_changes(&mut self) {
+        let changes = self.buffer.take_changes();
+        self.track_changes(&changes);
+        for change in &changes {
+            self.record(Op::Edit(change.clone()));
+        }
+    }
+
+    pub fn enable_secure(&mut self) {
+        self.secure = true;
+        for input in [&mut self.prompt_input, &mut self.search_input, &mut self.grep_input, &mut self.command_input] {
+            if let Some(history) = input.history_mut() {
+                history.set_ephemeral();
+            }
+        }
+        self.set_status("Secure mode: no autosave, swap files, backups or history");
+        if self.config.idle_lock_minutes > 0 {
+            self.choosing_lock_passphrase = true;
+            self.lock_input.set_title(" Choose a passphrase for the idle lock (Esc: none) ");
+            self.push_mode(AppMode::Locked);
+        }
+    }
+
+    pub fn has_lock_passphrase(&self) -> bool {
+        self.lock_passphrase.is_some()
+    }
+
+    /// Lock the screen once `idle_lock_minutes` pass without input.
+    pub fn check_idle(&mut self) {
+        let minutes = self.config.idle_lock_minutes;
+        if minutes == 0 || self.modes.contains(&AppMode::Locked) || self.last_input.elapsed() < Duration::from_secs(minutes * 60) {
+            return;
+        }
+        self.lock_input.reset();
+        self.lock_input.set_note(None);
+        self.lock_input.set_title(" Passphrase ");
+        self.push_mode(AppMode::Locked);
+    }
+
+    /// A key on the lock screen: sets the passphrase when choosing one,
+    /// otherwise unlocks on the right passphrase, or on any key if there
+    /// is none.
+    pub fn lock_key(&mut self, key: crossterm::event::KeyEvent) {
+        use crossterm::event::KeyCode;
+
+        if self.lock_passphrase.is_none() && !self.choosing_lock_passphrase {
+            self.remove_mode(AppMode::Locked);
+            return;
+        }
+        match key.code {
+            KeyCode::Esc if self.choosing_lock_passphrase => {
+                self.choosing_lock_passphrase = false;
+                self.remove_mode(AppMode::Locked);
+                self.set_status("Idle lock without a passphrase: any key unlocks");
+            }
+            KeyCode::Enter => {
+                let mut typed = self.lock_input.text();
+                self.lock_input.reset();
+                if self.choosing_lock_passphrase {
+                    if typed.is_empty() {
+                        return;
+                    }
+                    self.lock_passphrase = Some(typed);
+                    self.choosing_lock_passphrase = false;
+                    self.remove_mode(AppMode::Locked);
+                    return;
+                }
+                if self.lock_passphrase.as_ref() == Some(&typed) {
+                    self.remove_mode(AppMode::Locked);
+                } else {
+                    self.lock_input.set_note(Some("Wrong passphrase".to_string()));
+                }
+                typed.zeroize();
+            }
+            _ => self.lock_input.handle_key(key),
+        }
+    }
+
+    /// Start the session journal if the config asks for one. Encrypted
+    /// files and `--secure` sessions are never journaled.
+    pub fn start_journal(&mut self) {
+        if !self.config.journal || self.secure || Cipher::for_path(Path::new(&self.filename)).is_some() {
+            return;
+        }
+        // Whatever happened before (a merge) is part of the opening state.
+        self.sync_buffer();
+        match Journal::create() {
+            Ok(journal) => {
+                self.journal = Some(journal);
+                self.record(Op::Open { file: self.filename.clone(), lines: self.buffer.editor.lines() });
+            }
+            Err(e) => self.set_status(&format!("Journal not started: {}", e)),
+        }
+    }
+
+    /// Append to the journal; a journal that can't be written is dropped.
+    fn record(&mut self, op: Op) {
+        let Some(journal) = &mut self.journal else { return };
+        if let Err(e) = journal.record(op) {
+            log::warn!("Journal {} stopped: {}", journal.path.display(), e);
+            self.journal = None;
+            self.set_status(&format!("Journal stopped: {}", e));
+        }
+    }
+
+    /// Overwrite the buffer and other copies of its text before exiting
+    /// (`--secure`).
+    pub fn wipe(&mut self) {
+        self.buffer.wipe();
+        self.kill_ring.wipe();
+        for mut snapshot in self.ai_snapshots.drain(..) {
+            snapshot.lines.zeroize();
+        }
+        for mut message in self.chat.drain(..) {
+            message.text.zeroize();
+        }
+        for line in self.disk_diff.iter_mut().chain(self.head_diff.iter_mut()).flatten() {
+            line.wipe();
+        }
+        if let Some(passphrase) = &mut self.lock_passphrase {
+            passphrase.zeroize();
+        }
+        for state in &mut self.buffers {
+            state.wipe();
+        }
+        self.ghost = None;
+    }
+
+    /// Switch to read-only tail mode: jump to the end of the file and follow
+    /// anything appended to it, highlighting `filter` matches if given.
+    pub fn start_tail(&mut self, filter: Option<&str>) {
+        let content = fs::read_to_string(&self.filename).unwrap_or_default();
+        self.load_content(&content);
+        self.buffer.editor.set_max_histories(0);
+        self.buffer.editor.move_cursor(CursorMove::Bottom);
+        self.read_only = true;
+        self.locked = true;
+        let changed = watch_file(Path::new(&self.filename));
+        if changed.is_none() {
+            log::warn!("Can't watch {}; checking it for changes instead", self.filename);
+        }
+        self.tail = Some(TailState {
+            offset: content.len() as u64,
+            ends_with_newline: content.ends_with('\n'),
+            changed,
+        });
+
+        if let Some(pattern) = filter {
+            self.buffer.editor.set_search_style(ratatui::style::Style::default().fg(self.theme.status_fg).bg(self.theme.status_bg));
+            if let Err(e) = self.buffer.editor.set_search_pattern(pattern) {
+                self.set_status(&format!("Invalid filter: {}", e));
+            }
+        }
+    }
+
+    /// Pick up data appended to the tailed file. Auto-scrolls only when the
+    /// end of the file is on screen, so scrolling up to read stays put.
+    pub fn poll_tail(&mut self) {
+        let Some(tail) = &mut self.tail else { return };
+        if let Some((_, changed)) = &mut tail.changed {
+            if changed.try_recv().is_err() {
+                return;
+            }
+        }
+        let (offset, ends_with_newline) = (tail.offset, tail.ends_with_newline);
+        let Ok(meta) = fs::metadata(&self.filename) else { return };
+        let len = meta.len();
+        if len == offset {
+            return;
+        }
+
+        let cursor = self.buffer.editor.cursor();
+        let following = self.visible_rows().end >= self.buffer.editor.len_lines();
+
+        if len < offset {
+            // Truncated or rotated: start over.
+            let filter = self.buffer.editor.search_pattern().map(|re| re.as_str().to_string());
+            self.start_tail(filter.as_deref());
+            return;
+        }
+
+        let mut chunk = Vec::new();
+        let read = fs::File::open(&self.filename).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_to_end(&mut chunk)
+        });
+        if read.is_err() {
+            return;
+        }
+
+        let text = String::from_utf8_lossy(&chunk);
+        let mut insert = String::new();
+        if ends_with_newline {
+            insert.push('\n');
+        }
+        insert.push_str(text.strip_suffix('\n').unwrap_or(&text));
+
+        self.buffer.editor.move_cursor(CursorMove::Bottom);
+        self.buffer.editor.move_cursor(CursorMove::End);
+        self.buffer.editor.insert_str(&insert);
+        if !following {
+            self.buffer.editor.jump((cursor.0, cursor.1));
+        }
+
+        if let Some(tail) = &mut self.tail {
+            tail.offset = offset + chunk.len() as u64;
+            tail.ends_with_newline = text.ends_with('\n');
+        }
+        // Following the file isn't an edit.
+        let modified = self.buffer.modified;
+        self.sync_buffer();
+        self.buffer.modified = modified;
+    }
+
+    pub fn csv_delimiter(&self) -> Option<char> {
+        table::delimiter_for(&self.filename)
+    }
+
+    pub fn toggle_table_view(&mut self) {
+        if self.csv_delimiter().is_none() {
+            self.set_status("Table view is only available for CSV/TSV files");
+            return;
+        }
+        self.table_view = !self.table_view;
+    }
+
+    /// Move the cursor to the start of the next/previous field, wrapping
+    /// across rows.
+    pub fn move_field(&mut self, forward: bool) {
+        let Some(delimiter) = self.csv_delimiter() else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        let editor = &self.buffer.editor;
+        let fields = table::field_ranges(&editor.line(row), delimiter);
+        let index = table::field_at(&fields, col);
+
+        let (row, col) = if forward {
+            if index + 1 < fields.len() {
+                (row, fields[index + 1].start)
+            } else if row + 1 < editor.len_lines() {
+                (row + 1, 0)
+            } else {
+                return;
+            }
+        } else if index > 0 {
+            (row, fields[index - 1].start)
+        } else if row > 0 {
+            let previous = table::field_ranges(&editor.line(row - 1), delimiter);
+            (row - 1, previous.last().map(|r| r.start).unwrap_or(0))
+        } else {
+            return;
+        };
+
+        self.buffer.editor.cancel_selection();
+        self.buffer.editor.jump((row, col));
+    }
+
+    /// Select the text of the field under the cursor.
+    pub fn select_field(&mut self) {
+        let Some(delimiter) = self.csv_delimiter() else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        let fields = table::field_ranges(&self.buffer.editor.line(row), delimiter);
+        let field = fields[table::field_at(&fields, col)].clone();
+
+        self.buffer.editor.cancel_selection();
+        self.buffer.editor.jump((row, field.start));
+        self.buffer.editor.start_selection();
+        self.buffer.editor.jump((row, field.end));
+    }
+
+    /// Switch to the next theme (presets, then user themes) for this session.
+    pub fn cycle_theme(&mut self) {
+        let names = Theme::names(&self.config.themes);
+        let current = names.iter().position(|n| *n == self.config.theme);
+        let next = current.map(|i| (i + 1) % names.len()).unwrap_or(0);
+        self.config.theme = names[next].clone();
+        self.theme = Theme::resolve(&self.config.theme, &self.config.themes).fit(self.colors);
+        self.set_status(&format!("Theme: {}", self.config.theme));
+        self.prefs.theme = Some(self.config.theme.clone());
+        self.save_prefs();
+    }
+
+    /// Execute the `# %%` cell under the cursor with the interpreter
+    /// configured for this file's extension; output lands in the results pane.
+    pub fn run_cell(&mut self) {
+        let ext = std::path::Path::new(&self.filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let interpreter = self.profile.interpreter.clone().or_else(|| self.config.cell_interpreters.get(&ext).cloned());
+        let Some(interpreter) = interpreter else {
+            self.set_status(&format!("No cell interpreter configured for .{}", ext));
+            return;
+        };
+        if self.cell_output.as_ref().is_some_and(|o| o.running) {
+            self.set_status("A cell is already running");
+            return;
+        }
+
+        let lines = &self.buffer.editor.lines();
+        let range = cells::cell_range(lines, self.buffer.editor.cursor().0);
+        let code = lines[range.clone()].join("\n");
+        let tx = self.cell_result_tx.clone();
+
+        self.cell_output = Some(CellOutput {
+            text: format!("Running lines {}-{} with {}...", range.start + 1, range.end, interpreter),
+            running: true,
+            success: true,
+        });
+        self.show_cell_output = true;
+
+        tokio::spawn(async move {
+            let result = match cells::run(&interpreter, code).await {
+                Ok(result) => result,
+                Err(e) => {
+                    log::error!("Cell execution failed: {}", e);
+                    (format!("Failed to run '{}': {}", interpreter, e), false)
+                }
+            };
+            let _ = tx.send(result).await;
+        });
+    }
+
+    /// Ask for a shell command to run, like nano's ^T; while one is
+    /// running, stop it instead.
+    pub fn prompt_run_command(&mut self) {
+        if let Some(running) = self.running_command.take() {
+            running.task.abort();
+            self.set_status(&format!("Stopped '{}'", running.command));
+            return;
+        }
+        self.command_input.reset();
+        self.update_command_title();
+        self.push_mode(AppMode::RunCommand);
+    }
+
+    pub fn toggle_command_target(&mut self) {
+        self.command_to_new_buffer = !self.command_to_new_buffer;
+        self.update_command_title();
+    }
+
+    fn update_command_title(&mut self) {
+        let target = if self.command_to_new_buffer { "into a new buffer" } else { "insert at the cursor" };
+        self.command_input.set_title(&format!(" Run command ({}) ", target));
+    }
+
+    /// Start `command` in the background; its output is put in place by
+    /// `poll_command`.
+    pub fn run_command(&mut self, command: String) {
+        self.pop_mode();
+        let target = if self.command_to_new_buffer { CommandTarget::NewBuffer } else { CommandTarget::Cursor };
+        self.spawn_command(command, None, target);
+    }
+
+    /// Rows a filter works on: the lines of the selection, or everything.
+    fn filter_rows(&self) -> std::ops::Range<usize> {
+        match self.buffer.editor.selection_range() {
+            // A selection ending at the start of a line doesn't include it.
+            Some((start, end)) if start != end => start.0..if end.1 == 0 && end.0 > start.0 { end.0 } else { end.0 + 1 },
+            _ => 0..self.buffer.editor.len_lines(),
+        }
+    }
+
+    /// Ask for a command to pipe the selected lines (or the whole buffer)
+    /// through.
+    pub fn prompt_filter(&mut self) {
+        if let Some(running) = &self.running_command {
+            self.set_status(&format!("'{}' is still running (^T stops it)", running.command));
+            return;
+        }
+        let rows = self.filter_rows();
+        self.command_input.reset();
+        self.command_input.set_title(&format!(" Filter {} lines through ", rows.len()));
+        self.push_mode(AppMode::Filter);
+    }
+
+    /// Pipe the rows through `command`; they're replaced with its output
+    /// once it succeeds.
+    pub fn filter_through(&mut self, command: String) {
+        self.pop_mode();
+        let rows = self.filter_rows();
+        let old = self.buffer.editor.rows(rows.clone());
+        let input = format!("{}\n", old.join("\n"));
+        self.spawn_command(command, Some(input), CommandTarget::Rows { rows, old });
+    }
+
+    fn spawn_command(&mut self, command: String, input: Option<String>, target: CommandTarget) {
+        let tx = self.command_tx.clone();
+        let shell_command = command.clone();
+        let task = tokio::spawn(async move {
+            let result = shell::run(&shell_command, input).await;
+            let _ = tx.send(result.map_err(|e| e.to_string())).await;
+        });
+        self.set_status(&format!("Running '{}'... (^T stops it)", command));
+        self.running_command = Some(RunningCommand {
+            command,
+            target,
+            buffer: (self.active_buffer, self.filename.clone()),
+            task: task.abort_handle(),
+        });
+    }
+
+    /// Insert the output of the finished command; what it said on stderr
+    /// goes to the status line.
+    pub fn poll_command(&mut self) {
+        let Some(rx) = &mut self.command_rx else { return };
+        let Ok(result) = rx.try_recv() else { return };
+        let Some(running) = self.running_command.take() else { return };
+        let output = match result {
+            Ok(output) => output,
+            Err(e) => {
+                self.set_status(&format!("Couldn't run '{}': {}", running.command, e));
+                return;
+            }
+        };
+        let moved = running.buffer != (self.active_buffer, self.filename.clone());
+        if let CommandTarget::Rows { rows, old } = running.target {
+            self.finish_filter(&running.command, rows, &old, &output, moved);
+            return;
+        }
+        let inserted = match output.stdout.is_empty() {
+            true => false,
+            false if matches!(running.target, CommandTarget::NewBuffer) || moved || self.read_only => {
+                self.output_to_new_buffer(&output.stdout)
+            }
+            false => {
+                self.cursors.clear();
+                self.block = None;
+                self.buffer.begin_group();
+                self.buffer.editor.insert_str(&output.stdout);
+                self.buffer.end_group();
+                self.mark_dirty();
+                true
+            }
+        };
+        let lines = output.stdout.lines().count();
+        let status = match (output.success, output.complaint()) {
+            (false, Some(complaint)) => format!("'{}' failed: {}", running.command, complaint),
+            (false, None) => format!("'{}' failed", running.command),
+            (true, Some(complaint)) => complaint.to_string(),
+            (true, None) if inserted => format!("Inserted {} lines from '{}'", lines, running.command),
+            (true, None) => format!("'{}' printed nothing", running.command),
+        };
+        self.set_status(&status);
+        self.alert("command", &format!("'{}' finished", running.command));
+    }
+
+    /// Replace the filtered rows with the output, unless the command failed
+    /// or the text changed while it ran.
+    fn finish_filter(&mut self, command: &str, rows: std::ops::Range<usize>, old: &[String], output: &shell::Output, moved: bool) {
+        if !output.success {
+            let complaint = output.complaint().map(|c| format!(": {}", c)).unwrap_or_default();
+            self.set_status(&format!("'{}' failed{}; nothing replaced", command, complaint));
+            return;
+        }
+        if moved || self.read_only || (rows.end > self.buffer.editor.len_lines() || self.buffer.editor.rows(rows.clone()) != old) {
+            self.set_status(&format!("The text changed while '{}' ran; nothing replaced", command));
+            return;
+        }
+        let lines: Vec<String> = output.stdout.lines().map(String::from).collect();
+        self.cursors.clear();
+        self.block = None;
+        self.replace_rows(rows.clone(), &lines);
+        self.buffer.editor.jump((rows.start, 0));
+        self.mark_dirty();
+        match output.complaint() {
+            Some(complaint) => self.set_status(complaint),
+            None => self.set_status(&format!("Filtered {} lines through '{}' ({} now)", old.len(), command, lines.len())),
+        }
+    }
+
+    /// Show `text` in a new unnamed buffer (or the empty start buffer).
+    fn output_to_new_buffer(&mut self, text: &str) -> bool {
+        if self.share.is_some() || self.tail.is_some() || self.merge.is_some() {
+            self.set_status("Only one file while sharing, tailing or merging");
+            return false;
+        }
+        let blank = self.filename == "[No Name]" && !self.buffer.modified && self.buffer.editor.is_empty();
+        if !blank {
+            self.buffers.push(BufferState::new(self.config.soft_wrap));
+            self.switch_buffer(self.buffers.len() - 1);
+        }
+        self.load_content(text);
+        self.mark_dirty();
+        true
+    }
+
+    pub fn toggle_cell_output(&mut self) {
+        if self.cell_output.is_none() {
+            self.set_status("No cell output yet");
+            return;
+        }
+        self.show_cell_output = !self.show_cell_output;
+    }
+
+    /// View mode: keys only move around, edits and saves are refused.
+    pub fn toggle_read_only(&mut self) {
+        if self.locked {
+            self.set_status("This buffer can't be made editable");
+            return;
+        }
+        if self.loading.is_some() {
+            self.set_status("Still loading the file");
+            return;
+        }
+        self.read_only = !self.read_only;
+        self.set_status(if self.read_only { "View mode: editing disabled" } else { "Editing enabled" });
+    }
+
+    pub fn toggle_vim(&mut self) {
+        if self.vim.take().is_some() {
+            self.buffer.editor.cancel_selection();
+            self.set_status("Vim mode off");
+        } else {
+            self.vim = Some(VimState::new());
+            self.set_status("Vim mode on");
+        }
+    }
+
+    /// Periodic autosave, driven from the event loop. Skipped while an AI
+    /// request is in flight, since its answer is about to replace the buffer.
+    pub fn tick_autosave(&mut self) {
+        let interval = self.config.autosave_secs;
+        if interval == 0 || self.secure || self.autosave_checked.elapsed() < Duration::from_secs(interval) {
+            return;
+        }
+        self.autosave_checked = Instant::now();
+
+        if !self.buffer.modified || self.read_only || self.filename == "[No Name]" || self.modes.contains(&AppMode::Processing) {
+            return;
+        }
+        if self.autosave_edited.is_some() {
+            return;
+        }
+        // Encrypting may ask for a passphrase; only on explicit saves.
+        if Cipher::for_path(Path::new(&self.filename)).is_some() {
+            return;
+        }
+        // Someone else wrote the file; leave it to the reload prompt.
+        if self.changed_on_disk() {
+            return;
+        }
+
+        let path = self.filename.clone();
+        let bytes = match fileio::encode(&self.file_contents(), self.encoding, self.bom) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.set_status(&format!("Autosave skipped: {}", e));
+                return;
+            }
+        };
+        let stamp = DiskStamp::for_contents(&bytes);
+        let tx = self.autosave_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = fileio::write_atomic(std::path::Path::new(&path), &bytes).map_err(|e| e.to_string());
+            let _ = tx.blocking_send((path, stamp, result));
+        });
+        self.autosave_edited = Some(false);
+    }
+
+    /// The buffer is clean once its autosave is written, unless edited
+    /// meanwhile; a failed one leaves it modified.
+    pub fn poll_autosave(&mut self) {
+        let Some(rx) = &mut self.autosave_rx else { return };
+        let Ok((path, stamp, result)) = rx.try_recv() else { return };
+        let edited = self.autosave_edited.take().unwrap_or(true);
+        if let Err(e) = result {
+            log::error!("Autosave of {} failed: {}", path, e);
+            self.set_status(&format!("Autosave of {} failed: {}", path, e));
+            return;
+        }
+        let Some(index) = self.buffer_named(&path) else { return };
+        self.with_buffer(index, |app| {
+            app.disk_stamp = Some(stamp);
+            app.buffer.modified &= edited;
+        });
+        self.autosaved_at = Some(Instant::now());
+    }
+
+    /// The buffer holding the file `name`.
+    fn buffer_named(&self, name: &str) -> Option<usize> {
+        (0..self.buffers.len()).find(|&i| {
+            let filename = if i == self.active_buffer { &self.filename } else { &self.buffers[i].filename };
+            filename == name
+        })
+    }
+
+    /// Read the committed version of the file in the background, to mark
+    /// changed lines in the gutter. Not for large or encrypted files.
+    fn load_git_head(&mut self) {
+        let path = Path::new(&self.filename);
+        let wanted = self.config.git_gutter && !self.large_file && self.filename != "[No Name]" && Cipher::for_path(path).is_none();
+        self.git_head = None;
+        self.git_marks.clear();
+        if !wanted {
+            return;
+        }
+        let file = self.filename.clone();
+        let tx = self.git_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let lines = git::head_lines(Path::new(&file)).map(Arc::new);
+            let _ = tx.blocking_send(GitUpdate::Head { file, lines });
+        });
+    }
+
+    /// Redo the git gutter marks once edits pause (or right away with
+    /// `now`), diffing a copy of the text in the background; driven from
+    /// the event loop.
+    pub fn update_git_marks(&mut self, now: bool) {
+        let Some(stale) = self.git_stale else { return };
+        if !now && stale.elapsed() < Duration::from_millis(300) || self.git_marks_busy {
+            return;
+        }
+        self.git_stale = None;
+        let Some(head) = self.git_head.clone() else {
+            self.git_marks.clear();
+            return;
+        };
+        // Cloning the rope only shares its nodes.
+        let text = self.buffer.editor.text().clone();
+        let file = self.filename.clone();
+        let tx = self.git_tx.clone();
+        self.git_marks_busy = true;
+        tokio::task::spawn_blocking(move || {
+            let marks = diff::line_marks(&head, &Editor::lines_of(&text));
+            let _ = tx.blocking_send(GitUpdate::Marks { file, marks });
+        });
+    }
+
+    /// Take in HEAD's copy of a file or its new gutter marks, into the
+    /// buffer of that file.
+    pub fn poll_git(&mut self) {
+        let Some(rx) = &mut self.git_rx else { return };
+        let Ok(update) = rx.try_recv() else { return };
+        let file = match &update {
+            GitUpdate::Head { file, .. } | GitUpdate::Marks { file, .. } => file.clone(),
+        };
+        if let GitUpdate::Marks { .. } = update {
+            self.git_marks_busy = false;
+        }
+        let Some(index) = self.buffer_named(&file) else { return };
+        self.with_buffer(index, |app| match update {
+            GitUpdate::Head { lines, .. } => {
+                app.git_head = lines;
+                app.git_stale = Some(Instant::now());
+                app.update_git_marks(true);
+            }
+            GitUpdate::Marks { marks, .. } => app.git_marks = marks,
+        });
+    }
+
+    /// Show how the buffer differs from the file as committed in HEAD, or
+    /// close the view again.
+    pub fn toggle_head_diff(&mut self) {
+        if self.head_diff.take().is_some() {
+            self.remove_mode(AppMode::GitDiff);
+            return;
+        }
+        let path = Path::new(&self.filename);
+        if self.filename == "[No Name]" || Cipher::for_path(path).is_some() {
+            self.set_status("No diff against HEAD for this buffer");
+            return;
+        }
+        let Some(head) = git::head_lines(path) else {
+            self.set_status("The file isn't committed in a git repository");
+            return;
+        };
+        let lines = diff::unified(&head.join("\n"), &self.buffer.editor.text().to_string(), "HEAD", "buffer");
+        if lines.is_empty() {
+            self.set_status("No changes since HEAD");
+            return;
+        }
+        self.head_diff = Some(lines);
+        self.head_diff_scroll = 0;
+        self.push_mode(AppMode::GitDiff);
+    }
+
+    pub fn toggle_head_diff_layout(&mut self) {
+        self.head_diff_side_by_side = !self.head_diff_side_by_side;
+        self.head_diff_scroll = self.hunk_rows().into_iter().next().unwrap_or(0);
+    }
+
+    /// Rows of the diff view where hunks start, in the current layout.
+    fn hunk_rows(&self) -> Vec<usize> {
+        let Some(lines) = &self.head_diff else { return Vec::new() };
+        match self.head_diff_side_by_side {
+            true => diff::side_by_side(lines)
+                .iter()
+                .enumerate()
+                .filter(|(_, (left, _))| left.is_some_and(|l| l.kind == diff::Kind::Hunk))
+                .map(|(i, _)| i)
+                .collect(),
+            false => lines.iter().enumerate().filter(|(_, l)| l.kind == diff::Kind::Hunk).map(|(i, _)| i).collect(),
+        }
+    }
+
+    /// Scroll the diff view to the next (or previous) hunk.
+    pub fn jump_hunk(&mut self, forward: bool) {
+        let rows = self.hunk_rows();
+        let scroll = self.head_diff_scroll;
+        let target = match forward {
+            true => rows.into_iter().find(|&row| row > scroll),
+            false => rows.into_iter().rev().find(|&row| row < scroll),
+        };
+        if let Some(row) = target {
+            self.head_diff_scroll = row;
+        }
+    }
+
+    /// Have the AI draft a commit message from the file's diff and open it
+    /// for editing; the file is staged and committed once it's confirmed.
+    pub fn start_git_commit(&mut self) {
+        let path = Path::new(&self.filename).to_path_buf();
+        if self.secure {
+            self.set_status("No git commits in secure mode");
+            return;
+        }
+        if self.filename == "[No Name]" {
+            self.set_status("Save the file before committing it");
+            return;
+        }
+        if Cipher::for_path(&path).is_some() {
+            self.set_status("Encrypted files aren't committed from the editor");
+            return;
+        }
+        if self.buffer.modified {
+            if self.changed_on_disk() {
+                self.set_status("The file changed on disk; save it first");
+                return;
+            }
+            if let Err(e) = self.save_file() {
+                self.set_status(&format!("Error: {}", e));
+                return;
+            }
+        }
+        let gemini = self.gemini(false);
+        let filename = self.filename.clone();
+        let tx = self.commit_tx.clone();
+        let task = tokio::spawn(async move {
+            let diff = match tokio::task::spawn_blocking(move || git::commit_diff(&path)).await {
+                Ok(Ok(diff)) if diff.trim().is_empty() => Err("Nothing to commit: the file is as in HEAD".to_string()),
+                Ok(Ok(diff)) => Ok(diff),
+                Ok(Err(e)) => Err(format!("Git: {}", e)),
+                Err(e) => Err(format!("Git: {}", e)),
+            };
+            let step = match diff {
+                Ok(diff) => CommitStep::Draft(ai::request_commit_message(gemini, diff, filename).await.map_err(|e| e.to_string())),
+                Err(e) => CommitStep::NoDiff(e),
+            };
+            let _ = tx.send(step).await;
+        });
+        self.commit_task = Some(task.abort_handle());
+        self.commit_message = None;
+        self.push_mode(AppMode::GitCommit);
+    }
+
+    pub fn poll_commit_message(&mut self) {
+        let Some(rx) = &mut self.commit_rx else { return };
+        let Ok(step) = rx.try_recv() else { return };
+        let result = match step {
+            CommitStep::Draft(result) => result,
+            CommitStep::NoDiff(e) => {
+                self.commit_task = None;
+                self.remove_mode(AppMode::GitCommit);
+                self.set_status(&e);
+                return;
+            }
+            CommitStep::Committed(result) => {
+                self.committing = false;
+                match result {
+                    Ok(summary) => {
+                        self.commit_message = None;
+                        self.remove_mode(AppMode::GitCommit);
+                        self.load_git_head();
+                        self.set_status(&format!("Committed {}", summary));
+                    }
+                    Err(e) => self.set_status(&format!("Git: {}", e)),
+                }
+                return;
+            }
+        };
+        self.commit_task = None;
+        let draft = match result {
+            Ok(answer) => {
+                self.record_usage(&answer);
+                answer.text
+            }
+            Err(e) => {
+                self.set_status(&format!("Couldn't draft a message: {}", e));
+                String::new()
+            }
+        };
+        let mut message = TextArea::from(draft.lines().map(String::from));
+        message.move_cursor(CursorMove::Top);
+        message.move_cursor(CursorMove::End);
+        self.commit_message = Some(message);
+    }
+
+    /// Stage and commit the file with the message in the popup, in the
+    /// background; `poll_commit_message` closes the popup when it's done.
+    pub fn confirm_git_commit(&mut self) {
+        let Some(message) = &self.commit_message else { return };
+        if self.committing {
+            return;
+        }
+        let text = message.lines().join("\n").trim().to_string();
+        if text.is_empty() {
+            self.set_status("Write a commit message first");
+            return;
+        }
+        let path = Path::new(&self.filename).to_path_buf();
+        let tx = self.commit_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = git::commit(&path, &format!("{}\n", text)).map_err(|e| e.to_string());
+            let _ = tx.blocking_send(CommitStep::Committed(result));
+        });
+        self.committing = true;
+        self.set_status("Committing...");
+    }
+
+    /// Close the popup without committing; nothing was staged.
+    pub fn cancel_git_commit(&mut self) {
+        if self.committing {
+            self.set_status("Committing; wait for git to finish");
+            return;
+        }
+        if let Some(task) = self.commit_task.take() {
+            task.abort();
+        }
+        if let Some(rx) = &mut self.commit_rx {
+            while rx.try_recv().is_ok() {}
+        }
+        self.commit_message = None;
+        self.remove_mode(AppMode::GitCommit);
+        self.set_status("Commit cancelled");
+    }
+
+    /// Write the unsaved changes of every buffer to its swap file now and
+    /// then, driven from the event loop. Never in secure mode.
+    pub fn tick_swap(&mut self) {
+        let interval = self.config.swap_secs;
+        if interval == 0 || self.secure || self.share.is_some() || self.swap_checked.elapsed() < Duration::from_secs(interval) {
+            return;
+        }
+        self.swap_checked = Instant::now();
+        for i in 0..self.buffers.len() {
+            if i == self.active_buffer {
+                write_swap(&self.filename, &self.buffer, self.large_file, &mut self.swap_dirty, &mut self.swap_file);
+            } else {
+                let state = &mut self.buffers[i];
+                write_swap(&state.filename, &state.buffer, state.large_file, &mut state.swap_dirty, &mut state.swap_file);
+            }
+        }
+    }
+
+    /// Delete the swap files written for the buffers: on a clean exit,
+    /// unsaved changes included, since quitting confirmed dropping them.
+    pub fn remove_swaps(&mut self) {
+        for path in self.swap_file.take().into_iter().chain(self.buffers.iter_mut().filter_map(|s| s.swap_file.take())) {
+            swap::remove(&path);
+        }
+    }
+
+    /// Offer back the changes a session that didn't exit cleanly left in
+    /// the file's swap file.
+    pub fn check_swap(&mut self) {
+        if self.secure || self.large_file || self.filename == "[No Name]" || Cipher::for_path(Path::new(&self.filename)).is_some() {
+            return;
+        }
+        // One offer at a time (a session may reopen several files).
+        if self.recovery.is_some() {
+            return;
+        }
+        let Some(found) = swap::find(Path::new(&self.filename)) else { return };
+        if found.in_use() {
+            self.set_status(&format!("{} is also open in another neuronano (pid {})", self.filename, found.pid));
+            return;
+        }
+        // Nothing was lost.
+        if *self.buffer.editor.text() == found.text {
+            if let Err(e) = fs::remove_file(&found.path) {
+                log::error!("Failed to remove {}: {}", found.path.display(), e);
+            }
+            return;
+        }
+        self.recovery = Some(found);
+        self.push_mode(AppMode::Recover);
+    }
+
+    /// Take the swap file's text as an edit of the file, which undo takes
+    /// back to what was saved.
+    pub fn recover_swap(&mut self) {
+        self.remove_mode(AppMode::Recover);
+        let Some(found) = self.recovery.take() else { return };
+        let (row, col) = self.buffer.editor.cursor();
+        self.replace_lines(found.text.split('\n').map(String::from).collect());
+        self.mark_dirty();
+        self.buffer.editor.jump((row, col));
+        // It stays until the next swap write replaces it, in case we crash too.
+        self.swap_dirty = true;
+        self.set_status("Recovered the unsaved changes; undo shows the file as saved");
+    }
+
+    pub fn discard_swap(&mut self) {
+        self.remove_mode(AppMode::Recover);
+        let Some(found) = self.recovery.take() else { return };
+        match fs::remove_file(&found.path) {
+            Ok(()) => self.set_status("Deleted the unsaved changes"),
+            Err(e) => self.set_status(&format!("Can't delete {}: {}", found.path.display(), e)),
+        }
+    }
+
+    /// Open the file as saved, leaving the swap file for later (until the
+    /// buffer is edited and its own swap replaces it).
+    pub fn skip_recovery(&mut self) {
+        self.remove_mode(AppMode::Recover);
+        self.recovery = None;
+    }
+
+    /// Has the file been changed on disk by someone else since we last
+    /// loaded or saved it?
+    pub fn changed_on_disk(&mut self) -> bool {
+        if self.filename == "[No Name]" || self.tail.is_some() {
+            return false;
+        }
+        let path = Path::new(&self.filename);
+        match &mut self.disk_stamp {
+            Some(stamp) => stamp.check(path),
+            // We started from a new file that has appeared since.
+            None => path.exists(),
+        }
+    }
+
+    /// Periodic external-change check, driven from the event loop.
+    pub fn check_disk(&mut self) {
+        // Only over dialogs where a prompt on top makes sense.
+        let can_overlay = matches!(self.mode(), AppMode::Normal | AppMode::Prompting | AppMode::Search | AppMode::SaveAs | AppMode::Chat);
+        if !can_overlay || self.disk_checked.elapsed() < Duration::from_secs(2) {
+            return;
+        }
+        self.disk_checked = Instant::now();
+        // An autosave may still be writing in the background.
+        if self.autosave_edited.is_some() {
+            return;
+        }
+        if self.changed_on_disk() {
+            self.disk_diff = None;
+            self.push_mode(AppMode::FileChanged);
+        }
+    }
+
+    /// Throw away the buffer and load what is on disk now, keeping the
+    /// cursor roughly where it was.
+    pub fn reload_from_disk(&mut self) {
+        self.pop_mode();
+        self.repaint = true;
+        let content = match Self::read_file(Path::new(&self.filename), &self.config, self.keyboard_enhanced) {
+            Ok(decoded) => {
+                self.encoding = decoded.encoding;
+                self.bom = decoded.bom;
+                decoded.text
+            }
+            Err(e) => {
+                self.set_status(&format!("Reload failed: {}", e));
+                return;
+            }
+        };
+        let (row, col) = self.buffer.editor.cursor();
+        self.load_content(&content);
+        self.line_ending = LineEnding::detect(&content);
+        self.final_newline = content.is_empty() || content.ends_with('\n');
+        self.buffer.editor.jump((row, col));
+        self.disk_stamp = DiskStamp::read(Path::new(&self.filename));
+        // Likely a checkout, which may have moved HEAD too.
+        self.load_git_head();
+        self.lsp_changed = Some(Instant::now());
+        self.set_status("Reloaded from disk");
+    }
+
+    /// Write the buffer over the changed file.
+    pub fn overwrite_disk(&mut self) {
+        if self.read_only {
+            return self.ignore_disk_change();
+        }
+        self.pop_mode();
+        if let Err(e) = self.format_and_save() {
+            self.set_status(&format!("Error: {}", e));
+        }
+    }
+
+    /// Keep editing the buffer; the next save overwrites the disk version
+    /// without asking again.
+    pub fn ignore_disk_change(&mut self) {
+        self.pop_mode();
+        self.disk_stamp = DiskStamp::read(Path::new(&self.filename));
+        self.set_status("File changed on disk; saving will overwrite it");
+    }
+
+    pub fn toggle_disk_diff(&mut self) {
+        if self.disk_diff.take().is_some() {
+            return;
+        }
+        self.repaint = true;
+        let disk = Self::read_file(Path::new(&self.filename), &self.config, self.keyboard_enhanced)
+            .map(|d| d.text)
+            .unwrap_or_default();
+        let buffer = self.file_contents();
+        self.disk_diff = Some(diff::unified(&disk, &buffer, "on disk", "buffer"));
+    }
+
+    /// Look up the action bound to `key`, ending kill/yank sequences when
+    /// anything else happens.
+    pub fn next_action(&mut self, key: &crossterm::event::KeyEvent) -> Option<Action> {
+        let action = self.keymap.action_for(key);
+        if action != Some(Action::Cut) {
+            self.kill_ring.appending = false;
+        }
+        if !matches!(action, Some(Action::Paste | Action::YankPop)) {
+            self.kill_ring.yanked = None;
+        }
+        if action.is_some_and(|a| a != Action::RunMacro) {
+            self.repeat_count = None;
+        }
+        action
+    }
+
+    /// Alt+digits: how many times the next macro run repeats, like Emacs'
+    /// M-5 prefix.
+    pub fn digit_argument(&mut self, key: &crossterm::event::KeyEvent) -> bool {
+        let crossterm::event::KeyCode::Char(c @ '0'..='9') = key.code else { return false };
+        if key.modifiers != crossterm::event::KeyModifiers::ALT {
+            return false;
+        }
+        let count = (self.repeat_count.unwrap_or(0) * 10 + c.to_digit(10).unwrap_or(0) as usize).min(MAX_MACRO_REPEAT);
+        self.repeat_count = Some(count);
+        self.set_status(&format!("Repeat {}× ({} runs the macro)", count, self.keymap.label(Action::RunMacro)));
+        true
+    }
+
+    /// Keys typed while recording a macro go into it.
+    pub fn record_key(&mut self, key: crossterm::event::KeyEvent) {
+        if let Some(keys) = &mut self.recording {
+            keys.push(key);
+        }
+    }
+
+    pub fn toggle_macro_recording(&mut self) {
+        match self.recording.take() {
+            Some(mut keys) => {
+                // The key that stopped the recording.
+                keys.pop();
+                let count = keys.len();
+                self.macro_keys = keys;
+                self.set_status(&format!("Macro recorded ({} keys); {} runs it", count, self.keymap.label(Action::RunMacro)));
+            }
+            None => {
+                self.recording = Some(Vec::new());
+                self.set_status(&format!("Recording a macro; {} stops", self.keymap.label(Action::RecordMacro)));
+            }
+        }
+    }
+
+    pub fn run_macro(&mut self) {
+        let times = self.repeat_count.take().unwrap_or(1);
+        if let Some(keys) = &mut self.recording {
+            keys.pop();
+            self.set_status("Can't run a macro while recording it");
+        } else if self.macro_keys.is_empty() {
+            self.set_status(&format!("No macro recorded ({} starts recording)", self.keymap.label(Action::RecordMacro)));
+        } else {
+            self.macro_run = Some(times);
+        }
+    }
+
+    /// The macro's keys and how many times to replay them, if a run was
+    /// asked for.
+    /// The run undoes as one step in every buffer it edits; `end_macro_run`
+    /// closes the groups.
+    pub fn take_macro_run(&mut self) -> Option<(Vec<crossterm::event::KeyEvent>, usize)> {
+        let times = self.macro_run.take()?;
+        for index in 0..self.buffers.len() {
+            self.with_buffer(index, |app| app.buffer.begin_group());
+        }
+        Some((self.macro_keys.clone(), times))
+    }
+
+    /// Close the undo groups of a macro run. Buffers it opened have none,
+    /// and a group with no edits leaves no entry.
+    pub fn end_macro_run(&mut self) {
+        for index in 0..self.buffers.len() {
+            self.with_buffer(index, |app| app.buffer.end_group());
+        }
+    }
+
+    /// Run an editor action, whichever key, menu or macro asked for it.
+    /// Returns false when it doesn't apply here (e.g. Tab outside the table
+    /// view), so the caller can treat the key as text instead.
+    pub fn dispatch(&mut self, action: Action) -> bool {
+        let edits = matches!(
+            action,
+            Action::AiPrompt | Action::Complete | Action::RevertAi | Action::AiSnapshots | Action::Cut | Action::Paste | Action::YankPop | Action::Save | Action::ToggleLineEnding
+                | Action::EditFrontMatter | Action::ToggleCheckbox | Action::RenumberList | Action::Promote | Action::Demote
+                | Action::ToggleComment | Action::Undo | Action::Redo | Action::Replace | Action::Filter
+        );
+        // A write-protected file can still be saved under another name.
+        let save_as = action == Action::Save && self.write_protected.is_some();
+        if edits && self.read_only && !save_as {
+            self.set_status("Buffer is read-only");
+            return true;
+        }
+        // Extra cursors and blocks don't follow what these do to the text.
+        if edits && action != Action::Save {
+            self.cursors.clear();
+            self.block = None;
+        }
+
+        match action {
+            Action::Quit => {
+                self.quit_discarded.clear();
+                self.request_quit();
+            }
+            Action::Save => {
+                if let Some(reason) = &self.write_protected {
+                    self.set_status(&format!("Can't save: {}", reason));
+                    self.prompt_save_as();
+                } else if self.filename == "[No Name]" {
+                    self.prompt_save_as();
+                } else if self.changed_on_disk() {
+                    self.disk_diff = None;
+                    self.push_mode(AppMode::FileChanged);
+                } else if let Err(e) = self.format_and_save() {
+                    self.set_status(&format!("Error: {}", e));
+                }
+            }
+            Action::Cut => self.kill(),
+            Action::Paste => self.yank(),
+            Action::YankPop => self.yank_pop(),
+            Action::Search => self.enter_search_mode(),
+            Action::AiPrompt => self.enter_prompt_mode(),
+            Action::ToggleTableView => self.toggle_table_view(),
+            Action::SelectField if self.csv_delimiter().is_some() => self.select_field(),
+            Action::NextField if self.table_view => self.move_field(true),
+            Action::PrevField if self.table_view => self.move_field(false),
+            Action::SelectField | Action::NextField | Action::PrevField => return false,
+            Action::CycleTheme => self.cycle_theme(),
+            Action::RunCell => self.run_cell(),
+            Action::ToggleOutput => self.toggle_cell_output(),
+            Action::ToggleVim => self.toggle_vim(),
+            Action::ToggleAbbreviations => self.toggle_abbreviations(),
+            Action::Outline => self.open_outline(),
+            Action::CheckLinks => self.check_links(),
+            Action::NextDiagnostic => self.next_diagnostic(),
+            Action::DiagnosticDetails => self.open_diagnostic_details(),
+            Action::ToggleDiagnostics => self.show_diagnostics = !self.show_diagnostics,
+            Action::ToggleLineEnding => self.toggle_line_ending(),
+            Action::EditFrontMatter => self.open_front_matter_form(),
+            Action::Chat => self.open_chat(),
+            Action::Complete => {
+                if !self.request_lsp_completion(true) {
+                    self.request_completion();
+                }
+            }
+            Action::Explain => self.explain_selection(),
+            Action::RevertAi => self.revert_ai_change(),
+            Action::AiSnapshots => self.open_ai_snapshots(),
+            Action::Preview => self.open_preview(),
+            Action::ToggleReadOnly => self.toggle_read_only(),
+            Action::ToggleSoftWrap => self.toggle_soft_wrap(),
+            Action::WidenChat => self.resize_chat(5),
+            Action::NarrowChat => self.resize_chat(-5),
+            Action::ToggleWhitespace => self.toggle_whitespace(),
+            Action::MatchBracket => self.jump_to_matching_bracket(),
+            Action::ToggleComment => self.toggle_comment(),
+            Action::AddCursor => self.add_cursor_at_next_match(),
+            Action::ToggleBookmark => self.toggle_bookmark(),
+            Action::Bookmarks => self.open_bookmarks(),
+            Action::OpenFile => self.prompt_open_file(),
+            Action::Buffers => self.open_buffer_list(),
+            Action::SaveAll => self.save_all_buffers(),
+            Action::CloseBuffer => self.close_current_buffer(),
+            Action::CloseOtherBuffers => self.close_other_buffers(),
+            Action::Spelling => self.open_spelling(),
+            Action::ToggleSpellCheck => self.toggle_spell_check(),
+            Action::Replace => self.open_replace(),
+            Action::GitCommit => self.start_git_commit(),
+            Action::GitDiff => self.toggle_head_diff(),
+            Action::RunCommand => self.prompt_run_command(),
+            Action::Filter => self.prompt_filter(),
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::FindInFiles => self.find_in_files(),
+            Action::ToggleFold => self.toggle_fold(),
+            Action::ToggleAllFolds => self.toggle_all_folds(),
+            Action::RecordMacro => self.toggle_macro_recording(),
+            Action::RunMacro => self.run_macro(),
+            Action::ApplyPatch => self.open_apply_patch(),
+            Action::ExportPatch => self.open_export_patch(),
+            Action::ToggleCheckbox => self.toggle_checkbox(),
+            Action::RenumberList => self.renumber_list(),
+            Action::Promote => self.shift_level(false),
+            Action::Demote => self.shift_level(true),
+        }
+        true
+    }
+
+    /// Cut the selection, or the whole cursor line like nano's ^K, into the
+    /// kill ring.
+    pub fn kill(&mut self) {
+        if !self.buffer.editor.is_selecting() {
+            let row = self.buffer.editor.cursor().0;
+            self.buffer.editor.move_cursor(CursorMove::Head);
+            self.buffer.editor.start_selection();
+            self.buffer.editor.move_cursor(CursorMove::Down);
+            if self.buffer.editor.cursor().0 == row {
+                self.buffer.editor.move_cursor(CursorMove::End);
+            } else {
+                self.buffer.editor.move_cursor(CursorMove::Head);
+            }
+        }
+        if self.buffer.editor.cut() {
+            self.kill_ring.kill(self.buffer.editor.yank_text());
+            // Mirror the accumulated entry into the editor's register (used by vim `p`).
+            if let Some(text) = self.kill_ring.newest() {
+                let text = text.to_string();
+                if self.uses_clipboard() {
+                    self.kill_ring.clipboard.set(&text);
+                }
+                self.buffer.editor.set_yank_text(text);
+            }
+            self.mark_dirty();
+        } else {
+            self.buffer.editor.cancel_selection();
+        }
+    }
+
+    /// Insert the newest kill (^U).
+    pub fn yank(&mut self) {
+        self.kill_ring.remember(&self.buffer.editor.yank_text());
+        if self.uses_clipboard() {
+            if let Some(text) = self.kill_ring.clipboard.take_new() {
+                self.kill_ring.remember(&text);
+            }
+        }
+        let Some(text) = self.kill_ring.newest().map(|s| s.to_string()) else { return };
+        self.insert_yank(&text);
+    }
+
+    /// Replace the text just yanked with the next older kill (M-Y).
+    pub fn yank_pop(&mut self) {
+        let Some((start, end)) = self.kill_ring.yanked else {
+            self.set_status("Previous command was not a yank");
+            return;
+        };
+        let Some(text) = self.kill_ring.rotate().map(|s| s.to_string()) else { return };
+
+        self.buffer.editor.jump((start.0, start.1));
+        self.buffer.editor.start_selection();
+        self.buffer.editor.jump((end.0, end.1));
+        // Keep the register untouched; the ring owns the killed text.
+        let register = self.buffer.editor.yank_text();
+        self.buffer.editor.cut();
+        self.buffer.editor.set_yank_text(register);
+        self.insert_yank(&text);
+    }
+
+    /// Clipboard managers keep what's copied, so nothing from `--secure`
+    /// or encrypted files goes there.
+    fn uses_clipboard(&self) -> bool {
+        !self.secure && Cipher::for_path(Path::new(&self.filename)).is_none()
+    }
+
+    fn insert_yank(&mut self, text: &str) {
+        let start = self.buffer.editor.cursor();
+        self.buffer.editor.insert_str(text);
+        self.kill_ring.yanked = Some((start, self.buffer.editor.cursor()));
+        self.mark_dirty();
+    }
+}
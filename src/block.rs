@@ -0,0 +1,56 @@
+//! Block (column) selection: a rectangle of rows × char columns, extended
+//! with Alt+arrows, where typing edits every row at once.
+use std::ops::{Range, RangeInclusive};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    /// Where the block was started and the corner that moves, as (row,
+    /// col). The column may be past the end of short lines.
+    pub anchor: (usize, usize),
+    pub head: (usize, usize),
+}
+
+impl Block {
+    pub fn rows(&self) -> RangeInclusive<usize> {
+        self.anchor.0.min(self.head.0)..=self.anchor.0.max(self.head.0)
+    }
+
+    pub fn cols(&self) -> Range<usize> {
+        self.anchor.1.min(self.head.1)..self.anchor.1.max(self.head.1)
+    }
+}
+
+pub enum Edit {
+    Insert(char),
+    Backspace,
+    Delete,
+}
+
+/// Apply `edit` to each of `lines` at columns `cols`. A non-empty block's
+/// text is removed first; on an empty one Backspace and Delete take the char
+/// before or after it. Lines ending before the block are left alone.
+/// Returns the new lines and the column of the (now empty) block.
+pub fn apply(lines: &[String], cols: Range<usize>, edit: &Edit) -> (Vec<String>, usize) {
+    let remove = match edit {
+        _ if !cols.is_empty() => cols.clone(),
+        Edit::Backspace if cols.start > 0 => cols.start - 1..cols.start,
+        Edit::Delete => cols.start..cols.start + 1,
+        _ => cols.start..cols.start,
+    };
+    let lines = lines
+        .iter()
+        .map(|line| {
+            let mut chars: Vec<char> = line.chars().collect();
+            if chars.len() < remove.start {
+                return line.clone();
+            }
+            chars.drain(remove.start..remove.end.min(chars.len()));
+            if let Edit::Insert(c) = edit {
+                chars.insert(remove.start, *c);
+            }
+            chars.into_iter().collect()
+        })
+        .collect();
+    let col = remove.start + usize::from(matches!(edit, Edit::Insert(_)));
+    (lines, col)
+}
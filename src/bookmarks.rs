@@ -0,0 +1,53 @@
+//! Bookmarks: numbered spots in a file (Ctrl+B), picked from a list to
+//! jump back to (F2). Kept per file in `~/.config/neuronano/bookmarks.json`.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bookmark {
+    pub number: usize,
+    pub row: usize,
+    pub col: usize,
+}
+
+/// Bookmarks of every file, by absolute path.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Store(HashMap<String, Vec<Bookmark>>);
+
+fn store_path() -> PathBuf {
+    Config::dir().join("bookmarks.json")
+}
+
+fn key(file: &Path) -> String {
+    fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().into_owned()
+}
+
+fn load_store() -> Store {
+    fs::read_to_string(store_path()).ok().and_then(|content| serde_json::from_str(&content).ok()).unwrap_or_default()
+}
+
+pub fn load(file: &Path) -> Vec<Bookmark> {
+    load_store().0.remove(&key(file)).unwrap_or_default()
+}
+
+/// Replace the bookmarks of `file`; none removes its entry.
+pub fn save(file: &Path, bookmarks: &[Bookmark]) -> Result<()> {
+    let mut store = load_store();
+    if bookmarks.is_empty() {
+        store.0.remove(&key(file));
+    } else {
+        store.0.insert(key(file), bookmarks.to_vec());
+    }
+    fs::create_dir_all(Config::dir())?;
+    fs::write(store_path(), serde_json::to_string_pretty(&store)?)?;
+    Ok(())
+}
+
+/// The lowest number not taken yet.
+pub fn next_number(bookmarks: &[Bookmark]) -> usize {
+    (1..).find(|n| bookmarks.iter().all(|b| b.number != *n)).unwrap_or(1)
+}
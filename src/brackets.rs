@@ -0,0 +1,62 @@
+//! Bracket matching: the partner of the bracket at the cursor, for the
+//! highlight and the jump key.
+
+const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+/// Rows searched past the cursor before giving up, so huge files stay fast.
+pub const MAX_ROWS: usize = 5000;
+
+/// The bracket under the cursor (or right before it) and its partner, as
+/// (row, col) positions.
+pub fn find(lines: &[String], (row, col): (usize, usize)) -> Option<((usize, usize), (usize, usize))> {
+    let line: Vec<char> = lines.get(row)?.chars().collect();
+    let at = [Some(col), col.checked_sub(1)]
+        .into_iter()
+        .flatten()
+        .find(|&c| line.get(c).is_some_and(|ch| PAIRS.iter().any(|(o, e)| ch == o || ch == e)))?;
+    let bracket = line[at];
+    let &(open, close) = PAIRS.iter().find(|(open, close)| bracket == *open || bracket == *close)?;
+    let partner = if bracket == open {
+        forward(lines, (row, at), open, close)
+    } else {
+        backward(lines, (row, at), open, close)
+    }?;
+    Some(((row, at), partner))
+}
+
+fn forward(lines: &[String], (row, col): (usize, usize), open: char, close: char) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    for (r, line) in lines.iter().enumerate().skip(row).take(MAX_ROWS) {
+        let skip = if r == row { col + 1 } else { 0 };
+        for (c, ch) in line.chars().enumerate().skip(skip) {
+            if ch == open {
+                depth += 1;
+            } else if ch == close {
+                if depth == 0 {
+                    return Some((r, c));
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
+
+fn backward(lines: &[String], (row, col): (usize, usize), open: char, close: char) -> Option<(usize, usize)> {
+    let mut depth = 0;
+    for r in (row.saturating_sub(MAX_ROWS)..=row).rev() {
+        let chars: Vec<char> = lines[r].chars().collect();
+        let end = if r == row { col } else { chars.len() };
+        for c in (0..end).rev() {
+            if chars[c] == close {
+                depth += 1;
+            } else if chars[c] == open {
+                if depth == 0 {
+                    return Some((r, c));
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,113 @@
+//! The document being edited: its editor (text, cursor and undo history),
+//! dirty state and language, plus a feed of what changed since the last
+//! look so other parts of the editor can keep up without rescanning.
+//!
+//! The text lives in the editor as a rope, so saving, AI requests and
+//! other whole-buffer reads take ranges of it instead of joining every
+//! line, and each edit is recorded as it's made rather than found by
+//! comparing the lines afterwards.
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::editor::Editor;
+
+/// `old` rows starting at `start` were replaced by `new`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub start: usize,
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+}
+
+impl Change {
+    /// Rows gained (or lost, if negative) by this change.
+    pub fn delta(&self) -> isize {
+        self.new.len() as isize - self.old.len() as isize
+    }
+}
+
+pub struct Buffer<'a> {
+    pub editor: Editor<'a>,
+    pub modified: bool,
+    /// Syntax name, detected from the file name.
+    pub language: Option<String>,
+    /// Long lines wrap at the window edge instead of scrolling sideways.
+    pub soft_wrap: bool,
+    /// Changes not taken yet, oldest first.
+    pending: Vec<Change>,
+}
+
+impl<'a> Buffer<'a> {
+    pub fn new(editor: Editor<'a>) -> Self {
+        Self { editor, modified: false, language: None, soft_wrap: false, pending: Vec::new() }
+    }
+
+    /// Swap in new content (a file load or reload). Reported as a change of
+    /// every row, but the buffer counts as unmodified.
+    pub fn load(&mut self, editor: Editor<'a>) {
+        self.collect_edits();
+        let old = std::mem::replace(&mut self.editor, editor);
+        self.pending.push(Change { start: 0, old: old.lines(), new: self.editor.lines() });
+        self.modified = false;
+    }
+
+    /// Start an operation that should undo as one step. Groups nest; the
+    /// outermost one counts.
+    pub fn begin_group(&mut self) {
+        self.editor.begin_group();
+    }
+
+    pub fn end_group(&mut self) {
+        self.editor.end_group();
+    }
+
+    /// Undo the last edit, or the whole group it ended. Returns whether
+    /// anything changed.
+    pub fn undo(&mut self) -> bool {
+        self.editor.undo()
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.editor.redo()
+    }
+
+    /// The whole text, lines joined with `\n`, with the buffer marked
+    /// modified if it was edited since the last look.
+    pub fn text(&mut self) -> &Rope {
+        self.collect_edits();
+        self.editor.text()
+    }
+
+    /// Text from one (row, col) position to another, in chars.
+    pub fn range(&mut self, from: (usize, usize), to: (usize, usize)) -> String {
+        self.collect_edits();
+        self.editor.range(from, to)
+    }
+
+    /// Move the editor's changes to `pending`; edits mark the buffer
+    /// modified.
+    fn collect_edits(&mut self) {
+        let edits = self.editor.take_changes();
+        if !edits.is_empty() {
+            self.modified = true;
+            self.pending.extend(edits);
+        }
+    }
+
+    /// Overwrite the text in memory before it's freed (`--secure`). The
+    /// rope's nodes can't be written to and are only dropped.
+    pub fn wipe(&mut self) {
+        self.editor.wipe();
+        for mut change in self.pending.drain(..) {
+            change.old.zeroize();
+            change.new.zeroize();
+        }
+    }
+
+    /// Changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.collect_edits();
+        std::mem::take(&mut self.pending)
+    }
+}
@@ -0,0 +1,53 @@
+//! Jupyter-style code cells: regions of a script separated by `# %%`
+//! (or `// %%`, `-- %%`) marker lines, runnable one at a time.
+use std::ops::Range;
+use std::process::Stdio;
+use anyhow::{anyhow, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+pub fn is_marker(line: &str) -> bool {
+    let line = line.trim_start();
+    ["#", "//", "--"]
+        .iter()
+        .any(|leader| line.strip_prefix(leader).is_some_and(|rest| rest.trim_start().starts_with("%%")))
+}
+
+/// Lines of the cell containing `row`, markers excluded.
+pub fn cell_range(lines: &[String], row: usize) -> Range<usize> {
+    let start = lines[..=row]
+        .iter()
+        .rposition(|l| is_marker(l))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let end = lines[start..]
+        .iter()
+        .position(|l| is_marker(l))
+        .map(|i| start + i)
+        .unwrap_or(lines.len());
+    start..end.max(start)
+}
+
+/// Run `code` through `interpreter` (a command line, code on stdin) and
+/// return combined stdout/stderr plus whether it exited successfully.
+pub async fn run(interpreter: &str, code: String) -> Result<(String, bool)> {
+    let mut parts = interpreter.split_whitespace();
+    let program = parts.next().ok_or_else(|| anyhow!("Empty interpreter command"))?;
+
+    let mut child = Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(code.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok((text, output.status.success()))
+}
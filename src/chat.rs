@@ -0,0 +1,43 @@
+//! Conversation with the AI about the buffer, shown in a side panel instead
+//! of rewriting the file.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Model,
+    /// A failed request; shown in the panel but not sent back to the model.
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub text: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, text: &str) -> Self {
+        Self { role, text: text.to_string() }
+    }
+}
+
+/// Contents of the fenced code blocks in `text`, in order. An unclosed
+/// fence runs to the end.
+pub fn code_blocks(text: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            match current.take() {
+                Some(block) => blocks.push(block.join("\n")),
+                None => current = Some(Vec::new()),
+            }
+        } else if let Some(block) = &mut current {
+            block.push(line);
+        }
+    }
+    if let Some(block) = current {
+        blocks.push(block.join("\n"));
+    }
+    blocks
+}
@@ -0,0 +1,28 @@
+//! Line comments: toggling a language's comment leader on a block of lines.
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Comment out `lines` with `leader`, or uncomment them if every non-blank
+/// one is commented already. New leaders go at the block's shallowest
+/// indentation so nested code keeps its shape; blank lines are left alone.
+pub fn toggle(lines: &[String], leader: &str) -> Vec<String> {
+    let code: Vec<&String> = lines.iter().filter(|l| !l.trim().is_empty()).collect();
+    let Some(column) = code.iter().map(|l| indentation(l)).min() else { return lines.to_vec() };
+    let commented = code.iter().all(|l| l[indentation(l)..].starts_with(leader));
+    lines
+        .iter()
+        .map(|line| {
+            if line.trim().is_empty() {
+                line.clone()
+            } else if commented {
+                let at = indentation(line);
+                let rest = &line[at + leader.len()..];
+                format!("{}{}", &line[..at], rest.strip_prefix(' ').unwrap_or(rest))
+            } else {
+                format!("{}{} {}", &line[..column], leader, &line[column..])
+            }
+        })
+        .collect()
+}
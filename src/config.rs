@@ -1,23 +1,378 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
+use std::path::PathBuf;
 use anyhow::Result;
+use crate::keychain;
+use crate::keymap::{Action, KeySpec};
+use crate::notify::Alert;
+use crate::profile::Profile;
+use crate::theme::{ColorSupport, Theme};
+
+/// Soft limit on line length for a filetype. Lines over `max` columns are
+/// marked in the editor; with `hard_wrap` the line is broken while typing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineLengthRule {
+    pub max: usize,
+    #[serde(default)]
+    pub hard_wrap: bool,
+}
+
+/// Where the effective API key came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeySource {
+    #[default]
+    File,
+    Keychain,
+    Env,
+}
+
+/// Environment variables checked for an API key, in priority order.
+const API_KEY_ENV_VARS: [&str; 2] = ["NEURONANO_API_KEY", "GEMINI_API_KEY"];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Config {
     pub api_key: String,
+    /// Keep the API key in the OS keychain instead of this file.
+    pub use_keychain: bool,
+    #[serde(skip)]
+    pub api_key_source: KeySource,
+    /// Keyed by filetype ("git-commit", "changelog", "man") or language name ("Markdown").
+    pub line_length: HashMap<String, LineLengthRule>,
+    /// Line comment leader by filetype or language, for Ctrl+/.
+    pub comment_leaders: HashMap<String, String>,
+    /// "bell", "desktop" (a notification through the terminal), "both" or
+    /// "none" when something finishes in the background, by event: "ai"
+    /// (rewrites), "chat", "explain", "cell" (code cell runs), "command"
+    /// (Ctrl+T shell commands) and "error" (failed AI requests). When the terminal reports focus, only while
+    /// it's unfocused.
+    pub notify: HashMap<String, Alert>,
+    /// Per-action key overrides, e.g. `"save": "ctrl+s"`.
+    pub keybindings: HashMap<Action, KeySpec>,
+    /// Active theme: a preset ("dark", "light", "solarized") or a key of `themes`.
+    pub theme: String,
+    /// User-defined themes; missing colors fall back to the dark preset.
+    pub themes: HashMap<String, Theme>,
+    /// Colors the terminal supports ("truecolor", "256" or "16"); detected
+    /// from COLORTERM/TERM when unset. Theme colors are mapped to the
+    /// nearest one available.
+    pub colors: Option<ColorSupport>,
+    /// Interpreter command per file extension for running `# %%` cells.
+    pub cell_interpreters: HashMap<String, String>,
+    /// Feature profiles keyed by filetype or filename glob.
+    pub profiles: HashMap<String, Profile>,
+    /// Start with the vim-style modal layer enabled.
+    pub vim_mode: bool,
+    /// Wrap long lines at the window edge (toggled per buffer with Alt+B).
+    pub soft_wrap: bool,
+    /// Show tabs as `→` and trailing spaces highlighted (toggled with
+    /// Alt+J), plus `¬` at line ends with `show_eol`.
+    pub show_whitespace: bool,
+    pub show_eol: bool,
+    /// Underline misspelled words in prose and in code comments (toggled
+    /// per buffer with Shift+F7), using the hunspell dictionary for
+    /// `spell_language`.
+    pub spell_check: bool,
+    pub spell_language: String,
+    /// Columns per indentation level and per tab.
+    pub tab_width: u8,
+    /// Indent with spaces rather than tabs (per language in `profiles`).
+    pub insert_spaces: bool,
+    /// Follow the indentation an opened file already uses instead.
+    pub detect_indent: bool,
+    /// Start new lines with the previous line's indentation.
+    pub auto_indent: bool,
+    /// Line endings that indent the next line one level further.
+    pub indent_after: Vec<String>,
+    /// Log every edit, save and AI answer of a session to
+    /// `~/.config/neuronano/journal/` (see `--replay`). Never for encrypted
+    /// files or `--secure`.
+    pub journal: bool,
+    /// Write modified, named buffers every N seconds (0 disables autosave).
+    pub autosave_secs: u64,
+    /// Mark lines added, changed or removed since the last git commit in
+    /// the line number gutter.
+    pub git_gutter: bool,
+    /// Run the `language_server` of the file's profile (e.g.
+    /// "rust-analyzer") for its diagnostics and completions. Never for encrypted
+    /// files or `--secure`.
+    pub lsp: bool,
+    /// Open the language server's completion menu while typing, not just
+    /// with Ctrl+Space.
+    pub lsp_complete_while_typing: bool,
+    /// Copy unsaved changes to `.name.swp` next to the file every N
+    /// seconds, to get them back after a crash (0 disables). Never for
+    /// encrypted files or `--secure`.
+    pub swap_secs: u64,
+    /// Blank the screen after N minutes without input until a key is
+    /// pressed, or in `--secure` mode a passphrase chosen at startup is
+    /// typed (0 disables the lock).
+    pub idle_lock_minutes: u64,
+    /// Files from this many MB on open at once and load the rest in the
+    /// background, read-only and without highlighting (0 disables).
+    pub large_file_mb: u64,
+    /// Start on a welcome screen (recent files, a tip, open/new/settings)
+    /// when no file is given.
+    pub welcome_screen: bool,
+    /// Recent files listed on the welcome screen.
+    pub welcome_recent: usize,
+    /// Tips for the welcome screen, one a day; empty uses the built-in ones.
+    pub welcome_tips: Vec<String>,
+    /// Abbreviations expanded when a word is ended (e.g. "teh" -> "the").
+    /// Per-filetype ones go in `profiles`.
+    pub abbreviations: HashMap<String, String>,
+    pub expand_abbreviations: bool,
+    /// Keep the previous version of a file as `name~` when saving.
+    pub backup: bool,
+    /// Put backups in this directory instead of next to the file.
+    pub backup_dir: Option<String>,
+    /// Also check http(s) links when checking Markdown links.
+    pub check_remote_links: bool,
+    /// AI prices in USD per million prompt/output tokens, for the cost
+    /// estimates.
+    pub ai_input_price: f64,
+    pub ai_output_price: f64,
+    /// Tries per AI request when Gemini is overloaded or rate limited.
+    pub ai_max_attempts: u32,
+    /// Sampling settings sent with every AI rewrite, chat and explanation;
+    /// unset ones are left to Gemini. A temperature of 0 gives repeatable
+    /// rewrites.
+    pub ai_temperature: Option<f32>,
+    pub ai_top_p: Option<f32>,
+    pub ai_max_output_tokens: Option<u32>,
+    /// Gemini models tried in order: when one keeps failing with a quota,
+    /// outage or timeout error, the next one answers instead.
+    pub ai_models: Vec<String>,
+    /// Gemini model that embeds project files and questions for `--ask`.
+    pub ai_embedding_model: String,
+    /// Gemini stops generating at any of these.
+    pub ai_stop_sequences: Vec<String>,
+    /// Size limits in bytes: bigger requests aren't sent, and answers are
+    /// cut off past the response limit.
+    pub ai_max_request_bytes: usize,
+    pub ai_max_response_bytes: usize,
+    /// Instructions sent with AI rewrites instead of the built-in ones.
+    /// `{filename}`, `{instruction}` and `{language}` are filled in. Wins
+    /// over the template file `--edit-prompt` opens.
+    pub ai_system_prompt: Option<String>,
+    /// Language the AI writes comments and explanations in (e.g.
+    /// "Spanish"), whatever the prompt or file is written in.
+    pub ai_response_language: Option<String>,
+    /// Saved AI prompts by name, picked from the AI popup. `{input}` is
+    /// replaced with what was typed, `{filename}` and `{language}` as in
+    /// `ai_system_prompt`.
+    pub prompts: HashMap<String, String>,
+    /// Who `.gpg`/`.asc` files are encrypted to on save; empty means a
+    /// passphrase (`gpg --symmetric`).
+    pub gpg_recipients: Vec<String>,
+    /// Same for `.age` files (`age --passphrase` when empty), and the
+    /// identity file used to decrypt them.
+    pub age_recipients: Vec<String>,
+    pub age_identity: Option<String>,
+    /// Use the kitty keyboard protocol where the terminal supports it, so
+    /// chords like ctrl+shift+p or ctrl+enter can be bound.
+    pub kitty_keyboard: bool,
 }
 
-impl Config {
-    pub fn default() -> Self {
+impl Default for Config {
+    fn default() -> Self {
+        let mut line_length = HashMap::new();
+        line_length.insert("git-commit".to_string(), LineLengthRule { max: 72, hard_wrap: true });
+        line_length.insert("changelog".to_string(), LineLengthRule { max: 80, hard_wrap: true });
+        line_length.insert("man".to_string(), LineLengthRule { max: 80, hard_wrap: false });
+
+        // List and heading editing keys for outline-style documents.
+        let outline_keys: HashMap<Action, KeySpec> = [
+            (Action::ToggleCheckbox, "alt+x"),
+            (Action::RenumberList, "alt+n"),
+            (Action::Promote, "alt+left"),
+            (Action::Demote, "alt+right"),
+        ]
+        .into_iter()
+        .map(|(action, key)| (action, KeySpec::One(key.to_string())))
+        .collect();
+        let mut profiles: HashMap<String, Profile> = ["Markdown", "org"]
+            .into_iter()
+            .map(|ft| (ft.to_string(), Profile { keybindings: outline_keys.clone(), ..Profile::default() }))
+            .collect();
+        profiles.insert("Python".to_string(), Profile { indent_after: Some(vec![":".to_string()]), ..Profile::default() });
+        for language in ["Go", "Makefile"] {
+            profiles.insert(language.to_string(), Profile { insert_spaces: Some(false), ..Profile::default() });
+        }
+        for (language, server) in [("Rust", "rust-analyzer"), ("Python", "pyright-langserver --stdio"), ("Go", "gopls")] {
+            profiles.entry(language.to_string()).or_default().language_server = Some(server.to_string());
+        }
+
+        let notify = [("ai", Alert::Bell), ("chat", Alert::Bell), ("error", Alert::Bell)]
+            .into_iter()
+            .map(|(event, alert)| (event.to_string(), alert))
+            .collect();
+
+        let comment_leaders = [
+            ("Rust", "//"),
+            ("C", "//"),
+            ("C++", "//"),
+            ("C#", "//"),
+            ("Objective-C", "//"),
+            ("Java", "//"),
+            ("JavaScript", "//"),
+            ("Go", "//"),
+            ("Scala", "//"),
+            ("PHP", "//"),
+            ("Python", "#"),
+            ("Ruby", "#"),
+            ("Perl", "#"),
+            ("R", "#"),
+            ("Bourne Again Shell (bash)", "#"),
+            ("Makefile", "#"),
+            ("YAML", "#"),
+            ("git-commit", "#"),
+            ("Lua", "--"),
+            ("SQL", "--"),
+            ("Haskell", "--"),
+            ("Erlang", "%"),
+            ("LaTeX", "%"),
+            ("TeX", "%"),
+            ("Lisp", ";"),
+            ("Clojure", ";"),
+        ]
+        .into_iter()
+        .map(|(language, leader)| (language.to_string(), leader.to_string()))
+        .collect();
+
+        let cell_interpreters = [
+            ("py", "python3"),
+            ("sh", "sh"),
+            ("bash", "bash"),
+            ("rb", "ruby"),
+            ("js", "node"),
+            ("r", "Rscript"),
+            ("jl", "julia"),
+            ("lua", "lua"),
+        ]
+        .into_iter()
+        .map(|(ext, cmd)| (ext.to_string(), cmd.to_string()))
+        .collect();
+
         Self {
             api_key: String::new(),
+            use_keychain: false,
+            api_key_source: KeySource::File,
+            line_length,
+            comment_leaders,
+            notify,
+            keybindings: HashMap::new(),
+            theme: "dark".to_string(),
+            themes: HashMap::new(),
+            colors: None,
+            cell_interpreters,
+            profiles,
+            vim_mode: false,
+            soft_wrap: false,
+            show_whitespace: false,
+            show_eol: false,
+            spell_check: true,
+            spell_language: "en_US".to_string(),
+            tab_width: 4,
+            insert_spaces: true,
+            detect_indent: true,
+            auto_indent: true,
+            journal: false,
+            indent_after: ["{", "(", "["].into_iter().map(String::from).collect(),
+            autosave_secs: 0,
+            swap_secs: 15,
+            git_gutter: true,
+            lsp: true,
+            lsp_complete_while_typing: true,
+            idle_lock_minutes: 0,
+            large_file_mb: 50,
+            welcome_screen: true,
+            welcome_recent: 9,
+            welcome_tips: Vec::new(),
+            abbreviations: HashMap::new(),
+            expand_abbreviations: true,
+            backup: false,
+            backup_dir: None,
+            check_remote_links: false,
+            ai_input_price: 0.30,
+            ai_output_price: 2.50,
+            ai_max_attempts: 3,
+            ai_temperature: None,
+            ai_top_p: None,
+            ai_max_output_tokens: None,
+            ai_models: vec!["gemini-flash-latest".to_string()],
+            ai_embedding_model: "gemini-embedding-001".to_string(),
+            ai_stop_sequences: Vec::new(),
+            ai_max_request_bytes: 4 << 20,
+            ai_max_response_bytes: 16 << 20,
+            ai_system_prompt: None,
+            ai_response_language: None,
+            gpg_recipients: Vec::new(),
+            age_recipients: Vec::new(),
+            age_identity: None,
+            prompts: HashMap::from([
+                ("add docstrings".to_string(), "Add doc comments to every public item, in the usual style for {language}".to_string()),
+                ("convert to async".to_string(), "Convert this code to async/await".to_string()),
+                ("add error handling".to_string(), "Add error handling where it's missing. {input}".to_string()),
+            ]),
+            kitty_keyboard: true,
         }
     }
+}
+
+/// Pre-XDG location: a `config.json` in the working directory.
+const LEGACY_CONFIG_FILE: &str = "config.json";
+
+impl Config {
+    /// `~/.config/neuronano` (or the platform equivalent).
+    pub fn dir() -> PathBuf {
+        dirs::config_dir()
+            .map(|dir| dir.join("neuronano"))
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    pub fn path() -> PathBuf {
+        Self::dir().join("config.json")
+    }
 
+    /// Load the config file, then resolve the API key with the precedence
+    /// env > keychain > file.
     pub fn load() -> Result<Self> {
-        if let Ok(content) = fs::read_to_string("config.json") {
-            let config: Config = serde_json::from_str(&content)?;
+        let mut config = Self::load_file()?;
+
+        if config.use_keychain {
+            if let Some(key) = keychain::load() {
+                config.api_key = key;
+                config.api_key_source = KeySource::Keychain;
+            }
+        }
+
+        let from_env = API_KEY_ENV_VARS
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|key| !key.trim().is_empty());
+        if let Some(key) = from_env {
+            config.api_key = key.trim().to_string();
+            config.api_key_source = KeySource::Env;
+        }
+
+        Ok(config)
+    }
+
+    fn load_file() -> Result<Self> {
+        Self::migrate_legacy();
+
+        if let Ok(content) = fs::read_to_string(Self::path()) {
+            let mut config: Config = serde_json::from_str(&content)?;
+            // Profiles the user names are laid over the built-in ones
+            // rather than replacing the whole map.
+            let mut profiles = Self::default().profiles;
+            for (key, profile) in config.profiles {
+                profiles.entry(key).or_default().merge(&profile);
+            }
+            config.profiles = profiles;
             Ok(config)
         } else {
             Ok(Self::default())
@@ -25,9 +380,55 @@ impl Config {
     }
 
     pub fn save(&self) -> Result<()> {
-        let content = serde_json::to_string_pretty(self)?;
-        let mut file = fs::File::create("config.json")?;
+        let mut stored = self.clone();
+        if self.api_key_source == KeySource::Env {
+            // Never persist a key that was only handed to us via the environment.
+            stored.api_key = Self::load_file().map(|c| c.api_key).unwrap_or_default();
+        } else if self.use_keychain {
+            keychain::store(&self.api_key)?;
+            stored.api_key.clear();
+        }
+
+        fs::create_dir_all(Self::dir())?;
+        let content = serde_json::to_string_pretty(&stored)?;
+        let mut file = fs::File::create(Self::path())?;
         file.write_all(content.as_bytes())?;
         Ok(())
     }
+
+    /// Delete the stored configuration (any unmigrated legacy file and the
+    /// keychain entry included). Returns false if there was none.
+    pub fn reset() -> bool {
+        let legacy = Self::read_legacy().is_some() && fs::remove_file(LEGACY_CONFIG_FILE).is_ok();
+        let keychain = keychain::delete();
+        fs::remove_file(Self::path()).is_ok() || legacy || keychain
+    }
+
+    /// Contents of `./config.json`, but only if it is actually ours; other
+    /// projects have config.json files too.
+    fn read_legacy() -> Option<String> {
+        let content = fs::read_to_string(LEGACY_CONFIG_FILE).ok()?;
+        let value: serde_json::Value = serde_json::from_str(&content).ok()?;
+        value.get("api_key")?;
+        Some(content)
+    }
+
+    /// Move a `config.json` left in the working directory by older versions
+    /// into the config directory, so API keys stop leaking into projects.
+    fn migrate_legacy() {
+        let target = Self::path();
+        if target.exists() {
+            return;
+        }
+        let Some(content) = Self::read_legacy() else { return };
+
+        let migrated = fs::create_dir_all(Self::dir()).and_then(|_| fs::write(&target, &content));
+        match migrated {
+            Ok(()) => {
+                let _ = fs::remove_file(LEGACY_CONFIG_FILE);
+                log::info!("Migrated ./config.json to {}", target.display());
+            }
+            Err(e) => log::warn!("Failed to migrate ./config.json: {}", e),
+        }
+    }
 }
@@ -0,0 +1,98 @@
+//! Encrypted files (`.gpg`/`.asc`, `.age`): decrypted into memory and
+//! encrypted again on save by the gpg/age tools, which ask for passphrases
+//! on the terminal themselves. Plaintext never goes to disk.
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Result};
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Gpg,
+    Age,
+}
+
+impl Cipher {
+    pub fn for_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gpg" | "asc" => Some(Self::Gpg),
+            "age" => Some(Self::Age),
+            _ => None,
+        }
+    }
+}
+
+pub fn decrypt(cipher: Cipher, path: &Path, config: &Config) -> Result<Vec<u8>> {
+    let mut command = match cipher {
+        Cipher::Gpg => {
+            let mut command = Command::new("gpg");
+            command.args(["--quiet", "--decrypt"]);
+            command
+        }
+        Cipher::Age => {
+            let mut command = Command::new("age");
+            command.arg("--decrypt");
+            if let Some(identity) = &config.age_identity {
+                command.arg("--identity").arg(identity);
+            }
+            command
+        }
+    };
+    command.arg(path);
+    run(command, None)
+}
+
+/// Encrypt to the configured recipients, or with a passphrase if there
+/// are none. `.asc` files get ASCII armor.
+pub fn encrypt(cipher: Cipher, plaintext: &[u8], path: &Path, config: &Config) -> Result<Vec<u8>> {
+    let command = match cipher {
+        Cipher::Gpg => {
+            let mut command = Command::new("gpg");
+            command.args(["--quiet", "--yes"]);
+            if path.extension().is_some_and(|e| e == "asc") {
+                command.arg("--armor");
+            }
+            if config.gpg_recipients.is_empty() {
+                command.arg("--symmetric");
+            } else {
+                command.arg("--encrypt");
+                for recipient in &config.gpg_recipients {
+                    command.arg("--recipient").arg(recipient);
+                }
+            }
+            command
+        }
+        Cipher::Age => {
+            let mut command = Command::new("age");
+            if config.age_recipients.is_empty() {
+                command.arg("--passphrase");
+            }
+            for recipient in &config.age_recipients {
+                command.arg("--recipient").arg(recipient);
+            }
+            command
+        }
+    };
+    run(command, Some(plaintext))
+}
+
+/// Run with the terminal left to the tool (stdin too, unless `input` is
+/// piped in) and return its stdout.
+fn run(mut command: Command, input: Option<&[u8]>) -> Result<Vec<u8>> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::inherit() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| anyhow!("{}: {}", program, e))?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{} failed ({})", program, output.status));
+    }
+    Ok(output.stdout)
+}
@@ -0,0 +1,44 @@
+//! Extra cursors: positions besides the editor's own cursor where typed
+//! keys are applied too. Positions are tracked as char offsets into the
+//! whole text while the keys are replayed, so an edit at one cursor moves
+//! the ones after it along.
+use std::ops::Range;
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Char range of the word at (or right before) `col` in `line`.
+pub fn word_at(line: &str, col: usize) -> Option<Range<usize>> {
+    let chars: Vec<char> = line.chars().collect();
+    let at = [Some(col), col.checked_sub(1)].into_iter().flatten().find(|&c| chars.get(c).is_some_and(|&ch| is_word_char(ch)))?;
+    let start = chars[..at].iter().rposition(|&c| !is_word_char(c)).map_or(0, |i| i + 1);
+    let end = chars[at..].iter().position(|&c| !is_word_char(c)).map_or(chars.len(), |i| at + i);
+    Some(start..end)
+}
+
+/// Start of the next whole-word occurrence of `word` after `from`,
+/// wrapping around the end; `from`'s own occurrence comes last.
+pub fn next_occurrence(lines: &[String], word: &str, from: (usize, usize)) -> Option<(usize, usize)> {
+    let word: Vec<char> = word.chars().collect();
+    let rows = (from.0..lines.len()).chain(0..=from.0);
+    for (i, row) in rows.enumerate() {
+        let chars: Vec<char> = lines[row].chars().collect();
+        let matches = (0..chars.len().saturating_sub(word.len() - 1)).filter(|&col| {
+            chars[col..].starts_with(&word)
+                && (col == 0 || !is_word_char(chars[col - 1]))
+                && chars.get(col + word.len()).is_none_or(|&c| !is_word_char(c))
+        });
+        // The first pass over `from`'s row only looks after it, the last
+        // (after wrapping) only up to it.
+        let found = match i {
+            0 => matches.into_iter().find(|&col| col > from.1),
+            _ if row == from.0 => matches.into_iter().find(|&col| col <= from.1),
+            _ => matches.into_iter().next(),
+        };
+        if let Some(col) = found {
+            return Some((row, col));
+        }
+    }
+    None
+}
@@ -0,0 +1,319 @@
+//! Line diffs for the diff views, with the changed words of each changed
+//! line marked so small edits in long lines stand out.
+use similar::udiff::UnifiedHunkHeader;
+use similar::{ChangeTag, TextDiff};
+use zeroize::Zeroize;
+
+/// Lines of context around each hunk.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Header,
+    Hunk,
+    Context,
+    Removed,
+    Added,
+}
+
+/// One row of a diff view: its pieces of text, with `true` on the words
+/// that changed within the line.
+#[derive(Debug, Clone)]
+pub struct DiffLine {
+    pub kind: Kind,
+    pub segments: Vec<(bool, String)>,
+}
+
+impl DiffLine {
+    fn plain(kind: Kind, text: String) -> Self {
+        Self { kind, segments: vec![(false, text)] }
+    }
+
+    /// Overwrite the text in memory (`--secure`).
+    pub fn wipe(&mut self) {
+        for (_, text) in &mut self.segments {
+            text.zeroize();
+        }
+    }
+}
+
+/// Unified diff of `old` to `new` with word-level marks; empty when they're
+/// the same.
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> Vec<DiffLine> {
+    let diff = TextDiff::from_lines(old, new);
+    let groups = diff.grouped_ops(CONTEXT);
+    if groups.is_empty() {
+        return Vec::new();
+    }
+    let mut lines = vec![
+        DiffLine::plain(Kind::Header, format!("--- {}", old_name)),
+        DiffLine::plain(Kind::Header, format!("+++ {}", new_name)),
+    ];
+    for group in &groups {
+        lines.push(DiffLine::plain(Kind::Hunk, UnifiedHunkHeader::new(group).to_string()));
+        for op in group {
+            for change in diff.iter_inline_changes(op) {
+                let (kind, sign) = match change.tag() {
+                    ChangeTag::Equal => (Kind::Context, " "),
+                    ChangeTag::Delete => (Kind::Removed, "-"),
+                    ChangeTag::Insert => (Kind::Added, "+"),
+                };
+                let mut segments = vec![(false, sign.to_string())];
+                for (emphasized, text) in change.iter_strings_lossy() {
+                    let text = text.trim_end_matches(['\n', '\r']);
+                    if text.is_empty() {
+                        continue;
+                    }
+                    match segments.last_mut() {
+                        Some((last, joined)) if *last == emphasized => joined.push_str(text),
+                        _ => segments.push((emphasized, text.to_string())),
+                    }
+                }
+                lines.push(DiffLine { kind, segments });
+            }
+        }
+    }
+    lines
+}
+
+/// The rows of a unified diff laid out side by side: old on the left, new
+/// on the right. Removed lines are paired with the added lines that
+/// follow them; headers and hunk lines span both sides.
+pub fn side_by_side(lines: &[DiffLine]) -> Vec<(Option<&DiffLine>, Option<&DiffLine>)> {
+    let mut rows = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        match lines[i].kind {
+            Kind::Removed | Kind::Added => {
+                let removed = lines[i..].iter().take_while(|l| l.kind == Kind::Removed).count();
+                let added = lines[i + removed..].iter().take_while(|l| l.kind == Kind::Added).count();
+                for k in 0..removed.max(added) {
+                    let left = (k < removed).then(|| &lines[i + k]);
+                    let right = (k < added).then(|| &lines[i + removed + k]);
+                    rows.push((left, right));
+                }
+                i += removed + added;
+            }
+            // "--- old" and "+++ new" go on one row.
+            Kind::Header if lines.get(i + 1).is_some_and(|l| l.kind == Kind::Header) => {
+                rows.push((Some(&lines[i]), Some(&lines[i + 1])));
+                i += 2;
+            }
+            Kind::Header | Kind::Hunk | Kind::Context => {
+                rows.push((Some(&lines[i]), Some(&lines[i])));
+                i += 1;
+            }
+        }
+    }
+    rows
+}
+
+/// How a buffer line differs from the committed file, for the gutter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Added,
+    Modified,
+    /// Lines were removed just above this one (or below the last line).
+    Deleted,
+}
+
+/// A mark for each row of `new` compared with `old`, None where it's the
+/// same. Gives up after a moment on huge, very different files.
+pub fn line_marks(old: &[String], new: &[String]) -> Vec<Option<Mark>> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_millis(50);
+    let mut marks = vec![None; new.len()];
+    for op in similar::capture_diff_slices_deadline(similar::Algorithm::Myers, old, new, Some(deadline)) {
+        let (tag, _, rows) = op.as_tag_tuple();
+        match tag {
+            similar::DiffTag::Equal => {}
+            similar::DiffTag::Insert => marks[rows].fill(Some(Mark::Added)),
+            similar::DiffTag::Replace => marks[rows].fill(Some(Mark::Modified)),
+            similar::DiffTag::Delete => {
+                let row = rows.start.min(new.len().saturating_sub(1));
+                if let Some(mark @ None) = marks.get_mut(row) {
+                    *mark = Some(Mark::Deleted);
+                }
+            }
+        }
+    }
+    marks
+}
+
+/// How a merge hunk is settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Take {
+    Ours,
+    Theirs,
+    /// Ours, then theirs.
+    Both,
+}
+
+/// A stretch of base lines that one or both sides changed.
+#[derive(Debug, Clone)]
+pub struct MergeHunk {
+    pub base: Vec<String>,
+    pub ours: Vec<String>,
+    pub theirs: Vec<String>,
+    /// None while it's a conflict.
+    pub take: Option<Take>,
+}
+
+impl MergeHunk {
+    /// The lines this hunk puts in the result, with conflict markers while
+    /// it's unresolved.
+    pub fn result(&self) -> Vec<String> {
+        match self.take {
+            Some(Take::Ours) => self.ours.clone(),
+            Some(Take::Theirs) => self.theirs.clone(),
+            Some(Take::Both) => self.ours.iter().chain(&self.theirs).cloned().collect(),
+            None => {
+                let mut lines = vec!["<<<<<<< ours".to_string()];
+                lines.extend(self.ours.iter().cloned());
+                lines.push("||||||| base".to_string());
+                lines.extend(self.base.iter().cloned());
+                lines.push("=======".to_string());
+                lines.extend(self.theirs.iter().cloned());
+                lines.push(">>>>>>> theirs".to_string());
+                lines
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Chunk {
+    Same(Vec<String>),
+    Changed(MergeHunk),
+}
+
+/// Three-way merge of two versions of a file against their common base.
+#[derive(Debug, Clone)]
+pub struct Merge {
+    pub chunks: Vec<Chunk>,
+}
+
+/// A change of base lines `base` into `new` lines on one side.
+struct Edit {
+    base: std::ops::Range<usize>,
+    new: std::ops::Range<usize>,
+}
+
+fn edits(base: &[&str], side: &[&str]) -> Vec<Edit> {
+    TextDiff::from_slices(base, side)
+        .ops()
+        .iter()
+        .filter(|op| op.tag() != similar::DiffTag::Equal)
+        .map(|op| Edit { base: op.old_range(), new: op.new_range() })
+        .collect()
+}
+
+impl Merge {
+    /// Changes made on only one side (or the same on both) are taken as
+    /// they are; overlapping ones become conflicts.
+    pub fn new(base: &str, ours: &str, theirs: &str) -> Self {
+        let base: Vec<&str> = base.lines().collect();
+        let ours: Vec<&str> = ours.lines().collect();
+        let theirs: Vec<&str> = theirs.lines().collect();
+        let sides = [edits(&base, &ours), edits(&base, &theirs)];
+        let owned = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+
+        let mut chunks = Vec::new();
+        // Next unmerged edit of each side, and where each side is at.
+        let mut next = [0, 0];
+        let mut pos = [0, 0];
+        let mut base_pos = 0;
+        loop {
+            let start = (0..2).filter_map(|s| sides[s].get(next[s]).map(|e| e.base.start)).min();
+            let Some(start) = start else { break };
+            // Grow the hunk while an edit of either side touches it.
+            let mut end = start;
+            let mut taken = [next[0], next[1]];
+            loop {
+                let mut grew = false;
+                for s in 0..2 {
+                    while let Some(edit) = sides[s].get(taken[s]).filter(|e| e.base.start <= end) {
+                        end = end.max(edit.base.end);
+                        taken[s] += 1;
+                        grew = true;
+                    }
+                }
+                if !grew {
+                    break;
+                }
+            }
+            if start > base_pos {
+                chunks.push(Chunk::Same(owned(&base[base_pos..start])));
+            }
+            // Each side's lines for base `start..end`: unchanged lines shift
+            // by the edits before, changed ones come from the side.
+            let mut lines = [Vec::new(), Vec::new()];
+            for (s, side) in [&ours, &theirs].iter().enumerate() {
+                let side_start = pos[s] + (start - base_pos);
+                let shift: isize = sides[s][next[s]..taken[s]]
+                    .iter()
+                    .map(|e| e.new.len() as isize - e.base.len() as isize)
+                    .sum();
+                let side_end = (side_start as isize + (end - start) as isize + shift) as usize;
+                lines[s] = owned(&side[side_start..side_end]);
+                pos[s] = side_end;
+            }
+            let [ours_lines, theirs_lines] = lines;
+            let take = if taken[1] == next[1] || ours_lines == theirs_lines {
+                Some(Take::Ours)
+            } else if taken[0] == next[0] {
+                Some(Take::Theirs)
+            } else {
+                None
+            };
+            chunks.push(Chunk::Changed(MergeHunk { base: owned(&base[start..end]), ours: ours_lines, theirs: theirs_lines, take }));
+            next = taken;
+            base_pos = end;
+        }
+        if base_pos < base.len() {
+            chunks.push(Chunk::Same(owned(&base[base_pos..])));
+        }
+        Self { chunks }
+    }
+
+    pub fn hunks(&self) -> impl Iterator<Item = &MergeHunk> {
+        self.chunks.iter().filter_map(|c| match c {
+            Chunk::Changed(hunk) => Some(hunk),
+            Chunk::Same(_) => None,
+        })
+    }
+
+    pub fn hunk_mut(&mut self, index: usize) -> Option<&mut MergeHunk> {
+        self.chunks
+            .iter_mut()
+            .filter_map(|c| match c {
+                Chunk::Changed(hunk) => Some(hunk),
+                Chunk::Same(_) => None,
+            })
+            .nth(index)
+    }
+
+    pub fn conflicts(&self) -> usize {
+        self.hunks().filter(|h| h.take.is_none()).count()
+    }
+
+    /// The merged lines, and the row where each hunk starts in them.
+    pub fn result(&self) -> (Vec<String>, Vec<usize>) {
+        let mut lines = Vec::new();
+        let mut starts = Vec::new();
+        for chunk in &self.chunks {
+            match chunk {
+                Chunk::Same(same) => lines.extend(same.iter().cloned()),
+                Chunk::Changed(hunk) => {
+                    starts.push(lines.len());
+                    lines.extend(hunk.result());
+                }
+            }
+        }
+        (lines, starts)
+    }
+}
+
+/// Whether `lines` still hold conflict markers from a merge.
+pub fn has_conflict_markers(lines: &[String]) -> bool {
+    lines.iter().any(|l| l.starts_with("<<<<<<< ") || l.starts_with(">>>>>>> "))
+}
@@ -0,0 +1,180 @@
+//! `--doctor`: check the setup (config, API key and connection, terminal,
+//! outside tools) and print what works and what doesn't.
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use crossterm::terminal::supports_keyboard_enhancement;
+use crate::ai;
+use crate::config::{Config, KeySource};
+use crate::graphics::Protocol;
+use crate::keymap;
+use crate::spell::Dictionary;
+use crate::theme::{ColorSupport, Theme};
+
+enum Status {
+    Ok,
+    /// Works, but something is missing or off.
+    Warn,
+    Fail,
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn line(&mut self, status: Status, what: &str, detail: impl AsRef<str>) {
+        let label = match status {
+            Status::Ok => "ok  ",
+            Status::Warn => "warn",
+            Status::Fail => {
+                self.failed = true;
+                "FAIL"
+            }
+        };
+        println!("  {}  {:<14} {}", label, what, detail.as_ref());
+    }
+}
+
+/// Where `command` would run from, looked up in PATH.
+fn find_in_path(command: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command)).filter(|p| p.is_file());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?).map(|dir| dir.join(command)).find(|p| p.is_file())
+}
+
+/// The program a shell command line starts.
+fn program(command_line: &str) -> &str {
+    command_line.split_whitespace().find(|word| !word.contains('=')).unwrap_or("")
+}
+
+/// Print the report; false if anything failed.
+pub async fn run() -> bool {
+    let mut report = Report { failed: false };
+
+    println!("Configuration");
+    let config = match Config::load() {
+        Ok(config) => {
+            let path = Config::path();
+            match path.exists() {
+                true => report.line(Status::Ok, "config", path.display().to_string()),
+                false => report.line(Status::Ok, "config", format!("{} (not written yet, using defaults)", path.display())),
+            }
+            config
+        }
+        Err(e) => {
+            report.line(Status::Fail, "config", format!("{}: {}", Config::path().display(), e));
+            Config::default()
+        }
+    };
+    if !Theme::names(&config.themes).contains(&config.theme) {
+        report.line(Status::Warn, "theme", format!("no theme named \"{}\"", config.theme));
+    }
+    let overrides = std::iter::once(&config.keybindings).chain(config.profiles.values().map(|p| &p.keybindings));
+    let unknown: Vec<String> = overrides
+        .flat_map(keymap::unknown_keys)
+        .map(|(action, key)| format!("'{}' ({:?})", key, action))
+        .collect();
+    match unknown.is_empty() {
+        true => report.line(Status::Ok, "keybindings", format!("{} overridden", config.keybindings.len())),
+        false => report.line(Status::Warn, "keybindings", format!("unknown keys ignored: {}", unknown.join(", "))),
+    }
+    let writable = std::fs::create_dir_all(Config::dir()).and_then(|()| {
+        let probe = Config::dir().join(".doctor");
+        std::fs::write(&probe, b"")?;
+        std::fs::remove_file(probe)
+    });
+    match writable {
+        Ok(()) => report.line(Status::Ok, "config dir", "writable"),
+        Err(e) => report.line(Status::Fail, "config dir", format!("{}: {}", Config::dir().display(), e)),
+    }
+    if config.spell_check {
+        match Dictionary::load(&config.spell_language) {
+            Ok(_) => report.line(Status::Ok, "spelling", format!("{} dictionary", config.spell_language)),
+            Err(e) => report.line(Status::Warn, "spelling", e.to_string()),
+        }
+    }
+
+    println!("AI");
+    if config.api_key.is_empty() {
+        report.line(Status::Fail, "API key", "none (paste one at startup, or set GEMINI_API_KEY)");
+    } else {
+        let source = match config.api_key_source {
+            KeySource::Env => "from the environment",
+            KeySource::Keychain => "from the keychain",
+            KeySource::File => "from the config file",
+        };
+        report.line(Status::Ok, "API key", source);
+        for model in &config.ai_models {
+            match ai::check_model(&config.api_key, model).await {
+                Ok(()) => report.line(Status::Ok, "model", model),
+                Err(e) => report.line(Status::Fail, "model", format!("{}: {:#}", model, e)),
+            }
+        }
+    }
+
+    println!("Terminal");
+    let tty = std::io::stdout().is_terminal();
+    let var = |name: &str| std::env::var(name).unwrap_or_default();
+    report.line(Status::Ok, "TERM", format!("{} (COLORTERM={})", var("TERM"), var("COLORTERM")));
+    let colors = match config.colors {
+        Some(colors) => format!("{:?}, set in the config", colors),
+        None => format!("{:?}, detected", ColorSupport::detect()),
+    };
+    report.line(Status::Ok, "colors", colors);
+    if !tty {
+        report.line(Status::Warn, "keyboard", "stdout isn't a terminal; run it in one to check the kitty protocol");
+    } else if !config.kitty_keyboard {
+        report.line(Status::Ok, "keyboard", "kitty protocol turned off in the config");
+    } else {
+        match supports_keyboard_enhancement() {
+            Ok(true) => report.line(Status::Ok, "keyboard", "kitty protocol (Shift and Ctrl combinations are told apart)"),
+            Ok(false) => report.line(Status::Warn, "keyboard", "no kitty protocol; some Ctrl+Shift keys fall back to their Ctrl ones"),
+            Err(e) => report.line(Status::Warn, "keyboard", format!("couldn't ask the terminal: {}", e)),
+        }
+    }
+    match Protocol::detect() {
+        Some(protocol) => report.line(Status::Ok, "images", format!("{:?} graphics", protocol)),
+        None => report.line(Status::Warn, "images", "no graphics protocol; previews show text"),
+    }
+    match arboard::Clipboard::new() {
+        Ok(_) => report.line(Status::Ok, "clipboard", "system clipboard (kills are copied to it)"),
+        Err(e) => report.line(Status::Warn, "clipboard", format!("no system clipboard ({}); kills stay in the editor", e)),
+    }
+
+    println!("Tools");
+    let mut tools: Vec<(String, String)> = vec![
+        ("git".into(), "project files, find in files".into()),
+        ("gpg".into(), ".gpg files".into()),
+        ("age".into(), ".age files".into()),
+        ("mmdc".into(), "Mermaid previews".into()),
+    ];
+    let mut profiles: Vec<_> = config.profiles.iter().collect();
+    profiles.sort_by_key(|(name, _)| name.as_str());
+    for (name, profile) in profiles {
+        if let Some(formatter) = &profile.formatter {
+            tools.push((program(formatter).to_string(), format!("formatter for {}", name)));
+        }
+        if let Some(interpreter) = &profile.interpreter {
+            tools.push((program(interpreter).to_string(), format!("cells in {}", name)));
+        }
+        if let Some(server) = profile.language_server.as_ref().filter(|_| config.lsp) {
+            tools.push((program(server).to_string(), format!("language server for {}", name)));
+        }
+    }
+    let mut interpreters: Vec<_> = config.cell_interpreters.iter().collect();
+    interpreters.sort();
+    for (ext, interpreter) in interpreters {
+        tools.push((program(interpreter).to_string(), format!("cells in .{} files", ext)));
+    }
+    for (tool, used_for) in tools {
+        match find_in_path(&tool) {
+            Some(path) => report.line(Status::Ok, &tool, format!("{} ({})", path.display(), used_for)),
+            None => report.line(Status::Warn, &tool, format!("not found ({})", used_for)),
+        }
+    }
+
+    println!();
+    println!("{}", if report.failed { "Some checks failed." } else { "All set." });
+    !report.failed
+}
@@ -0,0 +1,1083 @@
+//! The editing widget behind a buffer: its text is a rope, so inserts,
+//! deletes and taking ranges stay cheap however big the file is. Keys,
+//! motions and drawing follow tui-textarea (which the prompts still use),
+//! so the editor behaves the same.
+//!
+//! Every edit is recorded twice as it's made: as rows replaced, for the
+//! rest of the editor to keep up with (`take_changes`), and as an undo
+//! entry. Edits made between `begin_group` and `end_group` form one entry.
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use crossterm::event::KeyEvent;
+use ratatui::buffer::Buffer as Cells;
+use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{Block, Paragraph, Widget};
+use regex::Regex;
+use ropey::{Rope, RopeBuilder};
+use tui_textarea::{CursorMove, Input, Key};
+use unicode_width::UnicodeWidthChar;
+use zeroize::Zeroize;
+
+use crate::buffer::Change;
+
+/// Undo entries kept by default, like tui-textarea.
+const MAX_HISTORIES: usize = 50;
+
+/// Chars `at..at + removed.len()` were replaced by `inserted`.
+struct Edit {
+    at: usize,
+    removed: String,
+    inserted: String,
+}
+
+/// One undo step: its edits in the order they were made, and where the
+/// cursor was before and after.
+struct Entry {
+    edits: Vec<Edit>,
+    before: (usize, usize),
+    after: (usize, usize),
+}
+
+#[derive(Clone, Copy)]
+enum Scrolling {
+    Delta(i16, i16),
+    PageDown,
+    PageUp,
+}
+
+pub struct Editor<'a> {
+    /// The lines joined with `\n`, with no break after the last one.
+    text: Rope,
+    cursor: (usize, usize),
+    selection_start: Option<(usize, usize)>,
+    yank: String,
+    tab_len: u8,
+    hard_tab_indent: bool,
+    /// Undo entries, oldest first; the first `done` of them are applied.
+    history: VecDeque<Entry>,
+    done: usize,
+    max_histories: usize,
+    /// The entry being built between `begin_group` and `end_group`.
+    group: Option<Entry>,
+    group_depth: usize,
+    changes: Vec<Change>,
+    search: Option<Regex>,
+    search_style: Style,
+    style: Style,
+    cursor_style: Style,
+    cursor_line_style: Style,
+    line_number_style: Option<Style>,
+    select_style: Style,
+    block: Option<Block<'a>>,
+    /// Top row and column shown and the size of the text area, as of the
+    /// last draw; PageUp/PageDown move by it.
+    viewport: Cell<(usize, u16, u16, u16)>,
+}
+
+impl Default for Editor<'_> {
+    fn default() -> Self {
+        Self::new(Rope::new())
+    }
+}
+
+impl<'a> Editor<'a> {
+    pub fn new(text: Rope) -> Self {
+        Self {
+            text,
+            cursor: (0, 0),
+            selection_start: None,
+            yank: String::new(),
+            tab_len: 4,
+            hard_tab_indent: false,
+            history: VecDeque::new(),
+            done: 0,
+            max_histories: MAX_HISTORIES,
+            group: None,
+            group_depth: 0,
+            changes: Vec::new(),
+            search: None,
+            search_style: Style::default().bg(Color::Blue),
+            style: Style::default(),
+            cursor_style: Style::default().add_modifier(Modifier::REVERSED),
+            cursor_line_style: Style::default().add_modifier(Modifier::UNDERLINED),
+            line_number_style: None,
+            select_style: Style::default().bg(Color::LightBlue),
+            block: None,
+            viewport: Cell::new((0, 0, 0, 0)),
+        }
+    }
+
+    /// The lines of `content` (split like `str::lines`), joined with `\n`.
+    pub fn rope_of(content: &str) -> Rope {
+        let mut builder = RopeBuilder::new();
+        for (i, line) in content.lines().enumerate() {
+            if i > 0 {
+                builder.append("\n");
+            }
+            builder.append(line);
+        }
+        builder.finish()
+    }
+
+    pub fn text(&self) -> &Rope {
+        &self.text
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.text.len_lines()
+    }
+
+    /// Row `row`, without its line break.
+    pub fn line(&self, row: usize) -> Cow<'_, str> {
+        let line = self.text.line(row);
+        let len = line.len_chars();
+        let line = if len > 0 && line.char(len - 1) == '\n' { line.slice(..len - 1) } else { line };
+        match line.as_str() {
+            Some(s) => Cow::Borrowed(s),
+            None => Cow::Owned(line.to_string()),
+        }
+    }
+
+    /// Chars in row `row`.
+    pub fn line_len(&self, row: usize) -> usize {
+        let end = if row + 1 < self.text.len_lines() { self.text.line_to_char(row + 1) - 1 } else { self.text.len_chars() };
+        end - self.text.line_to_char(row)
+    }
+
+    /// Row `row`, if there is one.
+    pub fn get_line(&self, row: usize) -> Option<Cow<'_, str>> {
+        (row < self.len_lines()).then(|| self.line(row))
+    }
+
+    /// Rows `rows`, clamped to the text.
+    pub fn rows(&self, rows: Range<usize>) -> Vec<String> {
+        let end = rows.end.min(self.len_lines());
+        (rows.start.min(end)..end).map(|row| self.line(row).into_owned()).collect()
+    }
+
+    /// The rows in order, borrowed from the rope where they can be.
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.text.lines().map(|line| {
+            let len = line.len_chars();
+            let line = if len > 0 && line.char(len - 1) == '\n' { line.slice(..len - 1) } else { line };
+            match line.as_str() {
+                Some(s) => Cow::Borrowed(s),
+                None => Cow::Owned(line.to_string()),
+            }
+        })
+    }
+
+    /// Every row; a copy of the text, for the few places that want it so.
+    pub fn lines(&self) -> Vec<String> {
+        self.rows(0..self.len_lines())
+    }
+
+    /// The lines of `text`, as `lines` has them; for a copy of the text
+    /// taken off to another thread.
+    pub fn lines_of(text: &Rope) -> Vec<String> {
+        text.lines()
+            .map(|line| {
+                let mut line = line.to_string();
+                if line.ends_with('\n') {
+                    line.pop();
+                }
+                line
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.len_chars() == 0
+    }
+
+    /// Char offset of `(row, col)` into the text, clamped to it.
+    pub fn char_index(&self, (row, col): (usize, usize)) -> usize {
+        let row = row.min(self.len_lines() - 1);
+        self.text.line_to_char(row) + col.min(self.line_len(row))
+    }
+
+    /// `(row, col)` of char offset `index`, clamped to the end of the text.
+    pub fn position(&self, index: usize) -> (usize, usize) {
+        let index = index.min(self.text.len_chars());
+        let row = self.text.char_to_line(index);
+        (row, index - self.text.line_to_char(row))
+    }
+
+    /// Text from one (row, col) position to another.
+    pub fn range(&self, from: (usize, usize), to: (usize, usize)) -> String {
+        let from = self.char_index(from);
+        self.text.slice(from..self.char_index(to).max(from)).to_string()
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        self.cursor
+    }
+
+    /// Put the cursor at `(row, col)`, clamped to the text, like
+    /// `CursorMove::Jump` without its 16-bit limit.
+    pub fn jump(&mut self, (row, col): (usize, usize)) {
+        let row = row.min(self.len_lines() - 1);
+        self.set_cursor((row, col.min(self.line_len(row))), self.selection_start.is_some());
+    }
+
+    /// Changes since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Add `text` at the very end without an undo entry or a change (more
+    /// of a file being read in), leaving the cursor where it is.
+    pub fn append(&mut self, text: Rope) {
+        self.text.append(text);
+    }
+
+    /// Replace chars `from..to` with `inserted`: the one place the text
+    /// changes. Records the rows touched and returns the edit.
+    fn splice(&mut self, from: usize, to: usize, inserted: &str) -> Edit {
+        let first = self.text.char_to_line(from);
+        let old = self.rows(first..self.text.char_to_line(to) + 1);
+        let removed = self.text.slice(from..to).to_string();
+        self.text.remove(from..to);
+        self.text.insert(from, inserted);
+        let new = self.rows(first..first + inserted.matches('\n').count() + 1);
+        self.changes.push(Change { start: first, old, new });
+        Edit { at: from, removed, inserted: inserted.to_string() }
+    }
+
+    /// Replace chars `from..to` with `text` as one edit, leaving the
+    /// cursor at the end of the new text.
+    fn replace(&mut self, from: usize, to: usize, text: &str) {
+        // As in tui-textarea, undoing a deletion puts the cursor after the
+        // text it brings back.
+        let before = if text.is_empty() { self.position(to) } else { self.cursor };
+        let edit = self.splice(from, to, text);
+        self.cursor = self.position(from + text.chars().count());
+        if self.max_histories == 0 {
+            return;
+        }
+        match &mut self.group {
+            Some(entry) => entry.edits.push(edit),
+            None => self.push_entry(Entry { edits: vec![edit], before, after: self.cursor }),
+        }
+    }
+
+    fn push_entry(&mut self, entry: Entry) {
+        self.history.truncate(self.done);
+        if self.history.len() == self.max_histories {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+        self.done = self.history.len();
+    }
+
+    /// Replace the text from one (row, col) position to another with
+    /// `text`, as one edit; the cursor ends up after it.
+    pub fn replace_range(&mut self, from: (usize, usize), to: (usize, usize), text: &str) {
+        self.cancel_selection();
+        let from = self.char_index(from);
+        let to = self.char_index(to).max(from);
+        if self.text.slice(from..to) == text {
+            self.cursor = self.position(to);
+            return;
+        }
+        self.replace(from, to, text);
+    }
+
+    /// Start an operation that should undo as one step. Groups nest; the
+    /// outermost one counts.
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.group = Some(Entry { edits: Vec::new(), before: self.cursor, after: self.cursor });
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.group_depth == 0 {
+            return;
+        }
+        self.group_depth -= 1;
+        if self.group_depth > 0 {
+            return;
+        }
+        if let Some(mut entry) = self.group.take() {
+            if !entry.edits.is_empty() {
+                entry.after = self.cursor;
+                self.push_entry(entry);
+            }
+        }
+    }
+
+    /// Undo the last entry. Returns whether anything changed.
+    pub fn undo(&mut self) -> bool {
+        self.end_open_group();
+        if self.done == 0 {
+            return false;
+        }
+        self.done -= 1;
+        let entry = std::mem::replace(&mut self.history[self.done], Entry { edits: Vec::new(), before: (0, 0), after: (0, 0) });
+        for edit in entry.edits.iter().rev() {
+            self.splice(edit.at, edit.at + edit.inserted.chars().count(), &edit.removed);
+        }
+        self.cancel_selection();
+        self.cursor = self.clamp(entry.before);
+        self.history[self.done] = entry;
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.end_open_group();
+        if self.done == self.history.len() {
+            return false;
+        }
+        let entry = std::mem::replace(&mut self.history[self.done], Entry { edits: Vec::new(), before: (0, 0), after: (0, 0) });
+        for edit in &entry.edits {
+            self.splice(edit.at, edit.at + edit.removed.chars().count(), &edit.inserted);
+        }
+        self.cancel_selection();
+        self.cursor = self.clamp(entry.after);
+        self.history[self.done] = entry;
+        self.done += 1;
+        true
+    }
+
+    /// Undo and redo work on whole entries; a group still open is closed.
+    fn end_open_group(&mut self) {
+        if self.group_depth > 0 {
+            self.group_depth = 1;
+            self.end_group();
+        }
+    }
+
+    fn clamp(&self, (row, col): (usize, usize)) -> (usize, usize) {
+        let row = row.min(self.len_lines() - 1);
+        (row, col.min(self.line_len(row)))
+    }
+
+    /// How many undo entries are kept; 0 turns undo off and forgets it.
+    pub fn set_max_histories(&mut self, max: usize) {
+        self.max_histories = max;
+        while self.history.len() > max {
+            self.history.pop_front();
+            self.done = self.done.saturating_sub(1);
+        }
+    }
+
+    /// Handle a key the way tui-textarea does. Returns whether the text
+    /// changed.
+    pub fn input(&mut self, key: KeyEvent) -> bool {
+        let input = Input::from(key);
+        let Input { key, ctrl, alt, shift } = input;
+        match (key, ctrl, alt) {
+            (Key::Char('m'), true, false) | (Key::Char('\n' | '\r'), false, false) | (Key::Enter, _, _) => {
+                self.insert_newline();
+                true
+            }
+            (Key::Char(c), false, false) => {
+                self.insert_char(c);
+                true
+            }
+            (Key::Tab, false, false) => self.insert_tab(),
+            (Key::Char('h'), true, false) | (Key::Backspace, false, false) => self.delete_char(),
+            (Key::Char('d'), true, false) | (Key::Delete, false, false) => self.delete_next_char(),
+            (Key::Char('k'), true, false) => self.delete_line_by_end(),
+            (Key::Char('j'), true, false) => self.delete_line_by_head(),
+            (Key::Char('w'), true, false) | (Key::Char('h'), false, true) | (Key::Backspace, false, true) => self.delete_word(),
+            (Key::Delete, false, true) | (Key::Char('d'), false, true) => self.delete_next_word(),
+            (Key::Char('n'), true, false) | (Key::Down, false, false) => self.shifted(CursorMove::Down, shift),
+            (Key::Char('p'), true, false) | (Key::Up, false, false) => self.shifted(CursorMove::Up, shift),
+            (Key::Char('f'), true, false) | (Key::Right, false, false) => self.shifted(CursorMove::Forward, shift),
+            (Key::Char('b'), true, false) | (Key::Left, false, false) => self.shifted(CursorMove::Back, shift),
+            (Key::Char('a'), true, false) | (Key::Home, _, _) | (Key::Left | Key::Char('b'), true, true) => {
+                self.shifted(CursorMove::Head, shift)
+            }
+            (Key::Char('e'), true, false) | (Key::End, _, _) | (Key::Right | Key::Char('f'), true, true) => {
+                self.shifted(CursorMove::End, shift)
+            }
+            (Key::Char('<'), false, true) | (Key::Up | Key::Char('p'), true, true) => self.shifted(CursorMove::Top, shift),
+            (Key::Char('>'), false, true) | (Key::Down | Key::Char('n'), true, true) => self.shifted(CursorMove::Bottom, shift),
+            (Key::Char('f'), false, true) | (Key::Right, true, false) => self.shifted(CursorMove::WordForward, shift),
+            (Key::Char('b'), false, true) | (Key::Left, true, false) => self.shifted(CursorMove::WordBack, shift),
+            (Key::Char(']' | 'n'), false, true) | (Key::Down, true, false) => self.shifted(CursorMove::ParagraphForward, shift),
+            (Key::Char('[' | 'p'), false, true) | (Key::Up, true, false) => self.shifted(CursorMove::ParagraphBack, shift),
+            (Key::Char('u'), true, false) => self.undo(),
+            (Key::Char('r'), true, false) => self.redo(),
+            (Key::Char('y'), true, false) | (Key::Paste, _, _) => self.paste(),
+            (Key::Char('x'), true, false) | (Key::Cut, _, _) => self.cut(),
+            (Key::Char('c'), true, false) | (Key::Copy, _, _) => {
+                self.copy();
+                false
+            }
+            (Key::Char('v'), true, false) | (Key::PageDown, _, _) => self.scroll_shifted(Scrolling::PageDown, shift),
+            (Key::Char('v'), false, true) | (Key::PageUp, _, _) => self.scroll_shifted(Scrolling::PageUp, shift),
+            (Key::MouseScrollDown, _, _) => {
+                self.scroll((1, 0));
+                false
+            }
+            (Key::MouseScrollUp, _, _) => {
+                self.scroll((-1, 0));
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn shifted(&mut self, m: CursorMove, shift: bool) -> bool {
+        self.move_cursor_with_shift(m, shift);
+        false
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        if c == '\n' || c == '\r' {
+            self.insert_newline();
+            return;
+        }
+        self.delete_selection(false);
+        let at = self.char_index(self.cursor);
+        self.replace(at, at, c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Insert `s` at the cursor (replacing the selection); a `\r` before
+    /// each line break is dropped.
+    pub fn insert_str<S: AsRef<str>>(&mut self, s: S) -> bool {
+        let modified = self.delete_selection(false);
+        let text: Vec<&str> = s.as_ref().split('\n').map(|s| s.strip_suffix('\r').unwrap_or(s)).collect();
+        let text = text.join("\n");
+        if text.is_empty() {
+            return modified;
+        }
+        let at = self.char_index(self.cursor);
+        self.replace(at, at, &text);
+        true
+    }
+
+    pub fn insert_newline(&mut self) {
+        self.delete_selection(false);
+        let at = self.char_index(self.cursor);
+        self.replace(at, at, "\n");
+    }
+
+    pub fn insert_tab(&mut self) -> bool {
+        let modified = self.delete_selection(false);
+        if self.tab_len == 0 {
+            return modified;
+        }
+        if self.hard_tab_indent {
+            self.insert_char('\t');
+            return true;
+        }
+        let (row, col) = self.cursor;
+        let width: usize = self.line(row).chars().take(col).map(|c| c.width().unwrap_or(0)).sum();
+        let len = self.tab_len as usize - width % self.tab_len as usize;
+        self.insert_str(" ".repeat(len))
+    }
+
+    /// Delete `chars` chars from `col` of the cursor row, keeping them as
+    /// the text to paste.
+    fn delete_piece(&mut self, col: usize, chars: usize) -> bool {
+        let row = self.cursor.0;
+        let len = self.line_len(row);
+        if chars == 0 || col >= len {
+            return false;
+        }
+        let from = self.char_index((row, col));
+        let to = from + chars.min(len - col);
+        self.yank = self.text.slice(from..to).to_string();
+        self.replace(from, to, "");
+        true
+    }
+
+    /// Join the cursor row to the one above.
+    pub fn delete_newline(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        if self.cursor.0 == 0 {
+            return false;
+        }
+        let at = self.char_index((self.cursor.0, 0));
+        self.replace(at - 1, at, "");
+        true
+    }
+
+    pub fn delete_char(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        if self.cursor.1 == 0 {
+            return self.delete_newline();
+        }
+        let at = self.char_index(self.cursor);
+        self.replace(at - 1, at, "");
+        true
+    }
+
+    pub fn delete_next_char(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        let at = self.char_index(self.cursor);
+        if at == self.text.len_chars() {
+            return false;
+        }
+        self.replace(at, at + 1, "");
+        true
+    }
+
+    pub fn delete_line_by_end(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        self.delete_piece(self.cursor.1, usize::MAX) || self.delete_next_char()
+    }
+
+    pub fn delete_line_by_head(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        let col = self.cursor.1;
+        if col > 0 {
+            self.delete_piece(0, col)
+        } else {
+            self.delete_newline()
+        }
+    }
+
+    pub fn delete_word(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        let (row, col) = self.cursor;
+        if let Some(start) = word_start_backward(&self.line(row), col) {
+            self.delete_piece(start, col - start)
+        } else if col > 0 {
+            self.delete_piece(0, col)
+        } else {
+            self.delete_newline()
+        }
+    }
+
+    pub fn delete_next_word(&mut self) -> bool {
+        if self.delete_selection(false) {
+            return true;
+        }
+        let (row, col) = self.cursor;
+        let len = self.line_len(row);
+        if let Some(end) = word_exclusive_end_forward(&self.line(row), col) {
+            self.delete_piece(col, end - col)
+        } else if col < len {
+            self.delete_piece(col, len - col)
+        } else if row + 1 < self.len_lines() {
+            self.cursor = (row + 1, 0);
+            self.delete_newline()
+        } else {
+            false
+        }
+    }
+
+    pub fn paste(&mut self) -> bool {
+        self.delete_selection(false);
+        if self.yank.is_empty() {
+            return false;
+        }
+        let text = self.yank.clone();
+        let at = self.char_index(self.cursor);
+        self.replace(at, at, &text);
+        true
+    }
+
+    pub fn yank_text(&self) -> String {
+        self.yank.clone()
+    }
+
+    pub fn set_yank_text(&mut self, text: impl Into<String>) {
+        let text = text.into();
+        let lines: Vec<&str> = text.split('\n').map(|s| s.strip_suffix('\r').unwrap_or(s)).collect();
+        self.yank = lines.join("\n");
+    }
+
+    pub fn start_selection(&mut self) {
+        self.selection_start = Some(self.cursor);
+    }
+
+    pub fn cancel_selection(&mut self) {
+        self.selection_start = None;
+    }
+
+    pub fn is_selecting(&self) -> bool {
+        self.selection_start.is_some()
+    }
+
+    /// The selection, start first.
+    pub fn selection_range(&self) -> Option<((usize, usize), (usize, usize))> {
+        self.selection_start.map(|pos| if pos > self.cursor { (self.cursor, pos) } else { (pos, self.cursor) })
+    }
+
+    /// Char offsets of a non-empty selection.
+    fn selection_chars(&self) -> Option<(usize, usize)> {
+        let start = self.char_index(self.selection_start?);
+        let end = self.char_index(self.cursor);
+        match start.cmp(&end) {
+            Ordering::Less => Some((start, end)),
+            Ordering::Equal => None,
+            Ordering::Greater => Some((end, start)),
+        }
+    }
+
+    pub fn copy(&mut self) {
+        if let Some((start, end)) = self.selection_chars() {
+            self.yank = self.text.slice(start..end).to_string();
+        }
+        self.cancel_selection();
+    }
+
+    pub fn cut(&mut self) -> bool {
+        self.delete_selection(true)
+    }
+
+    fn delete_selection(&mut self, yank: bool) -> bool {
+        let range = self.selection_chars();
+        self.cancel_selection();
+        let Some((start, end)) = range else { return false };
+        if yank {
+            self.yank = self.text.slice(start..end).to_string();
+        }
+        self.replace(start, end, "");
+        true
+    }
+
+    pub fn move_cursor(&mut self, m: CursorMove) {
+        self.move_cursor_with_shift(m, self.selection_start.is_some());
+    }
+
+    fn move_cursor_with_shift(&mut self, m: CursorMove, shift: bool) {
+        if let Some(cursor) = self.next_cursor(m) {
+            self.set_cursor(cursor, shift);
+        }
+    }
+
+    fn set_cursor(&mut self, cursor: (usize, usize), shift: bool) {
+        if !shift {
+            self.cancel_selection();
+        } else if self.selection_start.is_none() {
+            self.start_selection();
+        }
+        self.cursor = cursor;
+    }
+
+    /// Where `m` takes the cursor, with tui-textarea's rules.
+    fn next_cursor(&self, m: CursorMove) -> Option<(usize, usize)> {
+        let (row, col) = self.cursor;
+        let last = self.len_lines() - 1;
+        let fit = |row: usize, col: usize| (row, col.min(self.line_len(row)));
+        Some(match m {
+            CursorMove::Forward if col >= self.line_len(row) => (row < last).then_some((row + 1, 0))?,
+            CursorMove::Forward => (row, col + 1),
+            CursorMove::Back if col == 0 => {
+                let row = row.checked_sub(1)?;
+                (row, self.line_len(row))
+            }
+            CursorMove::Back => (row, col - 1),
+            CursorMove::Up => fit(row.checked_sub(1)?, col),
+            CursorMove::Down => (row < last).then(|| fit(row + 1, col))?,
+            CursorMove::Head => (row, 0),
+            CursorMove::End => (row, self.line_len(row)),
+            CursorMove::Top => fit(0, col),
+            CursorMove::Bottom => fit(last, col),
+            CursorMove::WordEnd => match word_inclusive_end_forward(&self.line(row), col + 1) {
+                Some(col) => (row, col),
+                None => {
+                    let mut row = row;
+                    loop {
+                        if row == last {
+                            break (row, self.line_len(row));
+                        }
+                        row += 1;
+                        if let Some(col) = word_inclusive_end_forward(&self.line(row), 0) {
+                            break (row, col);
+                        }
+                    }
+                }
+            },
+            CursorMove::WordForward => match word_start_forward(&self.line(row), col) {
+                Some(col) => (row, col),
+                None if row < last => (row + 1, 0),
+                None => (row, self.line_len(row)),
+            },
+            CursorMove::WordBack => match word_start_backward(&self.line(row), col) {
+                Some(col) => (row, col),
+                None if row > 0 => (row - 1, self.line_len(row - 1)),
+                None => (row, 0),
+            },
+            CursorMove::ParagraphForward => {
+                let mut prev_empty = self.line_len(row) == 0;
+                for row in row + 1..=last {
+                    let empty = self.line_len(row) == 0;
+                    if !empty && prev_empty {
+                        return Some(fit(row, col));
+                    }
+                    prev_empty = empty;
+                }
+                fit(last, col)
+            }
+            CursorMove::ParagraphBack => {
+                let row = row.checked_sub(1)?;
+                let mut prev_empty = self.line_len(row) == 0;
+                for row in (0..row).rev() {
+                    let empty = self.line_len(row) == 0;
+                    if empty && !prev_empty {
+                        return Some(fit(row + 1, col));
+                    }
+                    prev_empty = empty;
+                }
+                fit(0, col)
+            }
+            CursorMove::Jump(row, col) => fit((row as usize).min(last), col as usize),
+            CursorMove::InViewport => {
+                let (top, left, width, height) = self.viewport.get();
+                let bottom = top + (height as usize).max(1) - 1;
+                let right = left as usize + (width as usize).max(1) - 1;
+                fit(row.clamp(top, bottom).min(last), col.clamp(left as usize, right))
+            }
+            _ => return None,
+        })
+    }
+
+    /// Scroll the view by `(rows, cols)`, taking the cursor along if it
+    /// would leave it.
+    pub fn scroll(&mut self, (rows, cols): (i16, i16)) {
+        self.scroll_shifted(Scrolling::Delta(rows, cols), self.selection_start.is_some());
+    }
+
+    fn scroll_shifted(&mut self, scrolling: Scrolling, shift: bool) -> bool {
+        if shift && self.selection_start.is_none() {
+            self.selection_start = Some(self.cursor);
+        }
+        let (top, left, width, height) = self.viewport.get();
+        let (rows, cols) = match scrolling {
+            Scrolling::Delta(rows, cols) => (rows as isize, cols as isize),
+            Scrolling::PageDown => (height as isize, 0),
+            Scrolling::PageUp => (-(height as isize), 0),
+        };
+        let left = (left as isize + cols).clamp(0, u16::MAX as isize) as u16;
+        self.viewport.set((top.saturating_add_signed(rows), left, width, height));
+        self.move_cursor_with_shift(CursorMove::InViewport, shift);
+        false
+    }
+
+    pub fn tab_length(&self) -> u8 {
+        self.tab_len
+    }
+
+    pub fn set_tab_length(&mut self, len: u8) {
+        self.tab_len = len;
+    }
+
+    pub fn set_hard_tab_indent(&mut self, enabled: bool) {
+        self.hard_tab_indent = enabled;
+    }
+
+    /// What Tab inserts at the start of a line.
+    pub fn indent(&self) -> &'static str {
+        const SPACES: &str = "                                                                ";
+        if self.hard_tab_indent {
+            "\t"
+        } else {
+            &SPACES[..(self.tab_len as usize).min(SPACES.len())]
+        }
+    }
+
+    pub fn set_search_pattern(&mut self, query: &str) -> Result<(), regex::Error> {
+        self.search = if query.is_empty() { None } else { Some(Regex::new(query)?) };
+        Ok(())
+    }
+
+    pub fn search_pattern(&self) -> Option<&Regex> {
+        self.search.as_ref()
+    }
+
+    pub fn search_style(&self) -> Style {
+        self.search_style
+    }
+
+    pub fn set_search_style(&mut self, style: Style) {
+        self.search_style = style;
+    }
+
+    pub fn style(&self) -> Style {
+        self.style
+    }
+
+    pub fn set_cursor_style(&mut self, style: Style) {
+        self.cursor_style = style;
+    }
+
+    pub fn cursor_line_style(&self) -> Style {
+        self.cursor_line_style
+    }
+
+    pub fn set_line_number_style(&mut self, style: Style) {
+        self.line_number_style = Some(style);
+    }
+
+    pub fn remove_line_number(&mut self) {
+        self.line_number_style = None;
+    }
+
+    pub fn line_number_style(&self) -> Option<Style> {
+        self.line_number_style
+    }
+
+    pub fn selection_style(&self) -> Style {
+        self.select_style
+    }
+
+    pub fn set_block(&mut self, block: Block<'a>) {
+        self.block = Some(block);
+    }
+
+    pub fn block(&self) -> Option<&Block<'a>> {
+        self.block.as_ref()
+    }
+
+    /// Overwrite what can be reached of the text before it's freed
+    /// (`--secure`): the undo history, the text to paste and the changes
+    /// not taken yet. The rope's chunks can't be written to and are only
+    /// dropped.
+    pub fn wipe(&mut self) {
+        self.text = Rope::new();
+        self.cursor = (0, 0);
+        self.selection_start = None;
+        self.yank.zeroize();
+        for mut entry in self.history.drain(..).chain(self.group.take()) {
+            for edit in &mut entry.edits {
+                edit.removed.zeroize();
+                edit.inserted.zeroize();
+            }
+        }
+        self.done = 0;
+        for mut change in self.changes.drain(..) {
+            change.old.zeroize();
+            change.new.zeroize();
+        }
+    }
+
+    /// Row `row` drawn as tui-textarea draws it.
+    fn line_spans(&self, line: &str, row: usize, digits: usize) -> Line<'_> {
+        let mut spans = Vec::new();
+        if let Some(style) = self.line_number_style {
+            spans.push(Span::styled(format!(" {:>w$} ", row + 1, w = digits), style));
+        }
+        // Style boundaries by byte offset: a style starts, or the last one
+        // ends.
+        let mut marks: Vec<(usize, u8, Option<Style>)> = Vec::new();
+        let mut cursor_at_end = false;
+        let mut select_at_end = false;
+        let mut base = Style::default();
+        if row == self.cursor.0 {
+            match line.char_indices().nth(self.cursor.1) {
+                Some((start, c)) => {
+                    marks.push((start, 3, Some(self.cursor_style)));
+                    marks.push((start + c.len_utf8(), 0, None));
+                }
+                None => cursor_at_end = true,
+            }
+            base = self.cursor_line_style;
+        }
+        if let Some(pattern) = &self.search {
+            for m in pattern.find_iter(line).filter(|m| m.start() != m.end()) {
+                marks.push((m.start(), 2, Some(self.search_style)));
+                marks.push((m.end(), 0, None));
+            }
+        }
+        if let Some(((start_row, start_col), (end_row, end_col))) =
+            self.selection_range().filter(|(start, end)| start != end)
+        {
+            let offset = |col: usize| line.char_indices().nth(col).map_or(line.len(), |(i, _)| i);
+            let range = if row == start_row && row == end_row {
+                Some((offset(start_col), offset(end_col)))
+            } else if row == start_row {
+                select_at_end = true;
+                Some((offset(start_col), line.len()))
+            } else if row == end_row {
+                Some((0, offset(end_col)))
+            } else if start_row < row && row < end_row {
+                select_at_end = true;
+                Some((0, line.len()))
+            } else {
+                None
+            };
+            if let Some((start, end)) = range.filter(|(start, end)| start != end) {
+                marks.push((start, 1, Some(self.select_style)));
+                marks.push((end, 0, None));
+            }
+        }
+        marks.sort_by_key(|&(at, rank, _)| (at, rank));
+
+        let mut columns = 0;
+        let mut style = base;
+        let mut stack = Vec::new();
+        let mut start = 0;
+        for (at, _, next) in marks {
+            if start < at {
+                spans.push(Span::styled(self.expand_tabs(&line[start..at], &mut columns), style));
+            }
+            style = match next {
+                Some(next) => {
+                    stack.push(style);
+                    next
+                }
+                None => stack.pop().unwrap_or(base),
+            };
+            start = at;
+        }
+        if start < line.len() {
+            spans.push(Span::styled(self.expand_tabs(&line[start..], &mut columns), style));
+        }
+        if cursor_at_end {
+            spans.push(Span::styled(" ", self.cursor_style));
+        } else if select_at_end {
+            spans.push(Span::styled(" ", self.select_style));
+        }
+        Line::from(spans)
+    }
+
+    /// `s` with tabs turned into spaces up to the next tab stop, `columns`
+    /// being the display width before it.
+    fn expand_tabs(&self, s: &str, columns: &mut usize) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            if c == '\t' {
+                if self.tab_len > 0 {
+                    let len = self.tab_len as usize - *columns % self.tab_len as usize;
+                    out.extend(std::iter::repeat_n(' ', len));
+                    *columns += len;
+                }
+            } else {
+                out.push(c);
+                *columns += c.width().unwrap_or(0);
+            }
+        }
+        out
+    }
+}
+
+/// The view only moves once the cursor leaves it.
+fn next_scroll_top(prev_top: usize, cursor: usize, len: usize) -> usize {
+    if cursor < prev_top {
+        cursor
+    } else if prev_top + len <= cursor {
+        cursor + 1 - len
+    } else {
+        prev_top
+    }
+}
+
+impl Widget for &Editor<'_> {
+    fn render(self, area: Rect, cells: &mut Cells) {
+        let inner = self.block.as_ref().map_or(area, |b| b.inner(area));
+        let (top, left, _, _) = self.viewport.get();
+        let top = next_scroll_top(top, self.cursor.0, inner.height as usize);
+        let digits = self.len_lines().to_string().len();
+        let mut cursor_col = self.cursor.1;
+        if self.line_number_style.is_some() {
+            let gutter = digits + 2;
+            // Slide the line numbers back in smoothly when scrolling left.
+            cursor_col = if cursor_col <= gutter { cursor_col * 2 } else { cursor_col + gutter };
+        }
+        let left = next_scroll_top(left as usize, cursor_col, inner.width as usize) as u16;
+        self.viewport.set((top, left, inner.width, inner.height));
+
+        let bottom = (top + inner.height as usize).min(self.len_lines());
+        let lines: Vec<String> = (top..bottom).map(|row| self.line(row).into_owned()).collect();
+        let text: Vec<Line> = lines.iter().enumerate().map(|(i, line)| self.line_spans(line, top + i, digits)).collect();
+        if let Some(block) = &self.block {
+            block.clone().render(area, cells);
+        }
+        let mut paragraph = Paragraph::new(Text::from(text)).style(self.style);
+        if left != 0 {
+            paragraph = paragraph.scroll((0, left));
+        }
+        paragraph.render(inner, cells);
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CharKind {
+    Space,
+    Punct,
+    Other,
+}
+
+impl CharKind {
+    fn of(c: char) -> Self {
+        if c.is_whitespace() {
+            Self::Space
+        } else if c.is_ascii_punctuation() {
+            Self::Punct
+        } else {
+            Self::Other
+        }
+    }
+}
+
+fn word_start_forward(line: &str, start_col: usize) -> Option<usize> {
+    let mut it = line.chars().enumerate().skip(start_col);
+    let mut prev = CharKind::of(it.next()?.1);
+    for (col, c) in it {
+        let cur = CharKind::of(c);
+        if cur != CharKind::Space && prev != cur {
+            return Some(col);
+        }
+        prev = cur;
+    }
+    None
+}
+
+fn word_exclusive_end_forward(line: &str, start_col: usize) -> Option<usize> {
+    let mut it = line.chars().enumerate().skip(start_col);
+    let mut prev = CharKind::of(it.next()?.1);
+    for (col, c) in it {
+        let cur = CharKind::of(c);
+        if prev != CharKind::Space && prev != cur {
+            return Some(col);
+        }
+        prev = cur;
+    }
+    None
+}
+
+fn word_inclusive_end_forward(line: &str, start_col: usize) -> Option<usize> {
+    let mut it = line.chars().enumerate().skip(start_col);
+    let (mut last_col, c) = it.next()?;
+    let mut prev = CharKind::of(c);
+    for (col, c) in it {
+        let cur = CharKind::of(c);
+        if prev != CharKind::Space && cur != prev {
+            return Some(col.saturating_sub(1));
+        }
+        prev = cur;
+        last_col = col;
+    }
+    (prev != CharKind::Space).then_some(last_col)
+}
+
+fn word_start_backward(line: &str, start_col: usize) -> Option<usize> {
+    let idx = line.char_indices().nth(start_col).map_or(line.len(), |(i, _)| i);
+    let mut it = line[..idx].chars().rev().enumerate();
+    let mut cur = CharKind::of(it.next()?.1);
+    for (i, c) in it {
+        let next = CharKind::of(c);
+        if cur != CharKind::Space && next != cur {
+            return Some(start_col - i);
+        }
+        cur = next;
+    }
+    (cur != CharKind::Space).then_some(0)
+}
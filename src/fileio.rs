@@ -0,0 +1,287 @@
+//! Writing files to disk safely.
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::Result;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+/// Where the backup of `path` goes: `path~` next to it (nano style), or
+/// inside `backup_dir` with the full path flattened into the name so files
+/// from different directories don't collide.
+pub fn backup_path(path: &Path, backup_dir: Option<&str>) -> PathBuf {
+    match backup_dir {
+        Some(dir) => {
+            let full = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+            let flat = full.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "!");
+            PathBuf::from(dir).join(format!("{}~", flat))
+        }
+        None => {
+            let mut name = path.as_os_str().to_owned();
+            name.push("~");
+            PathBuf::from(name)
+        }
+    }
+}
+
+/// Copy the current on-disk contents of `path` to its backup location.
+/// Nothing to do if the file doesn't exist yet.
+pub fn write_backup(path: &Path, backup_dir: Option<&str>) -> Result<()> {
+    if !path.is_file() {
+        return Ok(());
+    }
+    if let Some(dir) = backup_dir {
+        fs::create_dir_all(dir)?;
+    }
+    fs::copy(path, backup_path(path, backup_dir))?;
+    Ok(())
+}
+
+/// Replace `path` with `contents` without ever leaving a truncated file:
+/// write a temp file in the same directory, fsync it, then rename it over
+/// the original. Permissions of an existing file are kept, and symlinks are
+/// followed so the link itself survives.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let name = target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp = dir.join(format!(".{}.neuronano-{}.tmp", name, std::process::id()));
+
+    let result = (|| -> Result<()> {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        if let Ok(meta) = fs::metadata(&target) {
+            fs::set_permissions(&tmp, meta.permissions())?;
+        }
+        fs::rename(&tmp, &target)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+
+    // Make the rename itself durable.
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Why `path` can't be saved, if it can't: no permission to write the file,
+/// or to create the temp file `write_atomic` renames over it.
+pub fn write_blocker(path: &Path) -> Option<String> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if target.is_file() {
+        if let Err(e) = fs::OpenOptions::new().write(true).open(&target) {
+            return Some(match e.kind() {
+                std::io::ErrorKind::PermissionDenied => "no permission to write this file".to_string(),
+                _ => e.to_string(),
+            });
+        }
+    }
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    match fs::metadata(&dir) {
+        Ok(meta) if meta.permissions().readonly() => Some(format!("{} is write-protected", dir.display())),
+        Ok(_) => None,
+        Err(_) => Some(format!("{} doesn't exist", dir.display())),
+    }
+}
+
+/// Text of a file plus what is needed to write it back the same way.
+pub struct Decoded {
+    pub text: String,
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+/// Decode file contents: see `detect`.
+pub fn decode(bytes: &[u8]) -> Decoded {
+    let (encoding, bom) = detect(bytes, true);
+    // With a BOM, decode() strips it; otherwise don't let a stray one win.
+    let (text, _) = if bom {
+        encoding.decode_with_bom_removal(bytes)
+    } else {
+        encoding.decode_without_bom_handling(bytes)
+    };
+    Decoded { text: text.into_owned(), encoding, bom }
+}
+
+/// The encoding of text that starts with `bytes` (all of it when `whole`),
+/// and whether it has a BOM: a BOM wins, then valid UTF-8, then BOM-less
+/// UTF-16 (lots of NULs on one side), then chardetng's best guess for
+/// legacy 8-bit encodings.
+pub fn detect(bytes: &[u8], whole: bool) -> (&'static Encoding, bool) {
+    let utf8 = match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        // Only the last character cut short by the end of the start.
+        Err(e) => !whole && e.error_len().is_none(),
+    };
+    match Encoding::for_bom(bytes) {
+        Some((encoding, _)) => (encoding, true),
+        None if utf8 => (UTF_8, false),
+        None => (guess_utf16(bytes).unwrap_or_else(|| {
+            let mut detector = chardetng::EncodingDetector::new();
+            detector.feed(bytes, whole);
+            detector.guess(None, true)
+        }), false),
+    }
+}
+
+fn guess_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let zeros = |start: usize| bytes.iter().skip(start).step_by(2).filter(|&&b| b == 0).count();
+    let half = bytes.len() / 2;
+    // ASCII-heavy text has its high bytes zeroed.
+    if zeros(1) > half / 2 && zeros(0) == 0 {
+        Some(UTF_16LE)
+    } else if zeros(0) > half / 2 && zeros(1) == 0 {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// Read and decode `path`.
+pub fn read_text(path: &Path) -> Result<Decoded> {
+    Ok(decode(&fs::read(path)?))
+}
+
+/// Split `file:line` or `file:line:col` (as in compiler and grep output,
+/// maybe with a trailing colon) into the file and a 1-based position. A
+/// file whose name really looks like that is left alone.
+pub fn split_position(spec: &str) -> (String, Option<(usize, usize)>) {
+    if Path::new(spec).exists() {
+        return (spec.to_string(), None);
+    }
+    let trimmed = spec.strip_suffix(':').unwrap_or(spec);
+    let numbers: Vec<&str> = trimmed.rsplitn(3, ':').take_while(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit())).collect();
+    let position = match numbers.as_slice() {
+        [line] => (line.parse().unwrap_or(1), 1),
+        [col, line] => (line.parse().unwrap_or(1), col.parse().unwrap_or(1)),
+        _ => return (spec.to_string(), None),
+    };
+    // A bare `12:3` has no file part.
+    match trimmed.len().checked_sub(numbers.iter().map(|n| n.len() + 1).sum()) {
+        Some(end) if end > 0 => (trimmed[..end].to_string(), Some(position)),
+        _ => (spec.to_string(), None),
+    }
+}
+
+/// Encode `text` back into `encoding`. Fails instead of silently writing
+/// replacement characters when something isn't representable.
+pub fn encode(text: &str, encoding: &'static Encoding, bom: bool) -> Result<Vec<u8>> {
+    // encoding_rs only encodes to UTF-16 as UTF-8 (per the WHATWG spec), so
+    // do that one by hand.
+    let utf16 = |to_bytes: fn(u16) -> [u8; 2]| -> Vec<u8> {
+        let mut out: Vec<u8> = if bom { to_bytes(0xFEFF).to_vec() } else { Vec::new() };
+        out.extend(text.encode_utf16().flat_map(to_bytes));
+        out
+    };
+    if encoding == UTF_16LE {
+        return Ok(utf16(u16::to_le_bytes));
+    }
+    if encoding == UTF_16BE {
+        return Ok(utf16(u16::to_be_bytes));
+    }
+
+    let mut out = if bom && encoding == UTF_8 { vec![0xEF, 0xBB, 0xBF] } else { Vec::new() };
+    let (bytes, _, had_errors) = encoding.encode(text);
+    if had_errors {
+        anyhow::bail!("Text contains characters that can't be saved as {}", encoding.name());
+    }
+    out.extend_from_slice(&bytes);
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// The dominant line ending of `content`; LF when there are no breaks.
+    pub fn detect(content: &str) -> Self {
+        let crlf = content.matches("\r\n").count();
+        let lf = content.matches('\n').count() - crlf;
+        if crlf > lf { LineEnding::CrLf } else { LineEnding::Lf }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+/// What the file looked like on disk the last time we read or wrote it, so
+/// changes made behind our back (git checkout, another editor) can be noticed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl DiskStamp {
+    /// Stamp for contents we just wrote ourselves. The mtime is filled in by
+    /// the next `check`.
+    pub fn for_contents(contents: &[u8]) -> Self {
+        Self { modified: None, len: contents.len() as u64, hash: hash(contents) }
+    }
+
+    /// Stamp of `path` as it is now, or None if it can't be read.
+    pub fn read(path: &Path) -> Option<Self> {
+        let contents = fs::read(path).ok()?;
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some(Self { modified, ..Self::for_contents(&contents) })
+    }
+
+    /// Has `path` changed since this stamp was taken? mtime and size are
+    /// checked first; the contents are only hashed when those moved, so a
+    /// plain `touch` doesn't count. A deleted file isn't reported either:
+    /// saving simply recreates it.
+    pub fn check(&mut self, path: &Path) -> bool {
+        let Ok(meta) = fs::metadata(path) else { return false };
+        let modified = meta.modified().ok();
+        if modified.is_some() && modified == self.modified && meta.len() == self.len {
+            return false;
+        }
+        match Self::read(path) {
+            Some(now) if now.hash == self.hash => {
+                *self = now;
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+fn hash(contents: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    hasher.finish()
+}
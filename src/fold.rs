@@ -0,0 +1,94 @@
+//! Code folding: a fold hides the body of a block under its first line,
+//! which shows how many lines are hidden. Blocks are the lines indented
+//! deeper than the first, or, when the body isn't indented, up to the
+//! partner of a bracket left open at the end of the first line.
+use crate::brackets;
+use crate::ui::display_columns;
+
+/// Row `start` stays visible; `start + 1..=end` are hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fold {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Fold {
+    pub fn hides(&self, row: usize) -> bool {
+        row > self.start && row <= self.end
+    }
+
+    pub fn hidden(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+fn indent_of(line: &str, tab_len: usize) -> usize {
+    let columns = display_columns(line, tab_len);
+    line.chars().position(|c| !c.is_whitespace()).map_or(0, |i| columns[i].0)
+}
+
+/// Last row of the block that starts at `row`, if a block starts there.
+pub fn block_end(lines: &[String], row: usize, tab_len: usize) -> Option<usize> {
+    let line = lines.get(row).filter(|l| !l.trim().is_empty())?;
+    let indent = indent_of(line, tab_len);
+    let mut end = None;
+    for (r, l) in lines.iter().enumerate().skip(row + 1) {
+        if l.trim().is_empty() {
+            continue;
+        }
+        if indent_of(l, tab_len) <= indent {
+            break;
+        }
+        end = Some(r);
+    }
+    end.or_else(|| {
+        let trimmed = line.trim_end();
+        if !trimmed.ends_with(['(', '[', '{']) {
+            return None;
+        }
+        let (_, (close, _)) = brackets::find(lines, (row, trimmed.chars().count() - 1))?;
+        (close > row + 1).then(|| close - 1)
+    })
+}
+
+/// The block holding `row`: the one starting there, or else the nearest
+/// one above that reaches it.
+pub fn enclosing(lines: &[String], row: usize, tab_len: usize) -> Option<Fold> {
+    (0..=row).rev().find_map(|start| block_end(lines, start, tab_len).filter(|&end| end >= row).map(|end| Fold { start, end }))
+}
+
+/// The outermost blocks of the buffer.
+pub fn outermost(lines: &[String], tab_len: usize) -> Vec<Fold> {
+    let mut folds = Vec::new();
+    let mut row = 0;
+    while row < lines.len() {
+        match block_end(lines, row, tab_len) {
+            Some(end) => {
+                folds.push(Fold { start: row, end });
+                row = end + 1;
+            }
+            None => row += 1,
+        }
+    }
+    folds
+}
+
+pub fn is_hidden(folds: &[Fold], row: usize) -> bool {
+    folds.iter().any(|f| f.hides(row))
+}
+
+/// The first row shown after `row`.
+pub fn next_visible(folds: &[Fold], row: usize) -> usize {
+    folds.iter().filter(|f| f.start == row || f.hides(row)).map(|f| f.end).max().unwrap_or(row) + 1
+}
+
+/// The row shown before `row`, or `row` itself for the first one.
+pub fn prev_visible(folds: &[Fold], row: usize) -> usize {
+    let above = row.saturating_sub(1);
+    folds.iter().filter(|f| f.hides(above)).map(|f| f.start).min().unwrap_or(above)
+}
+
+/// `row`, or the first line of the fold hiding it.
+pub fn shown(folds: &[Fold], row: usize) -> usize {
+    folds.iter().filter(|f| f.hides(row)).map(|f| f.start).min().unwrap_or(row)
+}
@@ -0,0 +1,177 @@
+//! YAML (`---`) and TOML (`+++`) front matter at the top of Markdown files,
+//! as used by static site generators.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+}
+
+/// Rows of the opening and closing fences.
+#[derive(Debug, Clone, Copy)]
+pub struct FrontMatter {
+    pub format: Format,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl FrontMatter {
+    /// Rows between the fences.
+    pub fn body(&self) -> std::ops::Range<usize> {
+        self.start + 1..self.end
+    }
+}
+
+/// The commonly edited fields, as plain strings (tags comma-separated).
+#[derive(Debug, Default)]
+pub struct Fields {
+    pub title: String,
+    pub date: String,
+    pub tags: String,
+}
+
+pub const FIELD_NAMES: [&str; 3] = ["title", "date", "tags"];
+
+pub fn detect<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Option<FrontMatter> {
+    let mut lines = lines.into_iter();
+    let format = match lines.next()?.as_ref().trim_end() {
+        "---" => Format::Yaml,
+        "+++" => Format::Toml,
+        _ => return None,
+    };
+    let end = lines.position(|l| match format {
+        Format::Yaml => matches!(l.as_ref().trim_end(), "---" | "..."),
+        Format::Toml => l.as_ref().trim_end() == "+++",
+    })? + 1;
+    Some(FrontMatter { format, start: 0, end })
+}
+
+/// Parse error, with the buffer row it points at when the parser says.
+pub fn validate(lines: &[String], fm: &FrontMatter) -> Option<(Option<usize>, String)> {
+    let text = lines[fm.body()].join("\n");
+    match fm.format {
+        Format::Yaml => {
+            let err = serde_yaml::from_str::<serde_yaml::Value>(&text).err()?;
+            let row = err.location().map(|l| fm.start + l.line());
+            Some((row, err.to_string()))
+        }
+        Format::Toml => {
+            let err = text.parse::<toml::Table>().err()?;
+            let row = err.span().map(|span| fm.start + 1 + text[..span.start].matches('\n').count());
+            let message = match err.message() {
+                "" => "invalid TOML".to_string(),
+                message => message.to_string(),
+            };
+            Some((row, message))
+        }
+    }
+}
+
+pub fn read_fields(lines: &[String], fm: &FrontMatter) -> Fields {
+    let text = lines[fm.body()].join("\n");
+    let mut fields = Fields::default();
+    match fm.format {
+        Format::Yaml => {
+            let Ok(serde_yaml::Value::Mapping(map)) = serde_yaml::from_str(&text) else { return fields };
+            let get = |key: &str| map.get(serde_yaml::Value::from(key));
+            let plain = |value: &serde_yaml::Value| match value {
+                serde_yaml::Value::String(s) => s.clone(),
+                serde_yaml::Value::Number(n) => n.to_string(),
+                serde_yaml::Value::Bool(b) => b.to_string(),
+                _ => String::new(),
+            };
+            fields.title = get("title").map(plain).unwrap_or_default();
+            fields.date = get("date").map(plain).unwrap_or_default();
+            fields.tags = match get("tags") {
+                Some(serde_yaml::Value::Sequence(tags)) => tags.iter().map(plain).collect::<Vec<_>>().join(", "),
+                Some(value) => plain(value),
+                None => String::new(),
+            };
+        }
+        Format::Toml => {
+            let Ok(table) = text.parse::<toml::Table>() else { return fields };
+            let plain = |value: &toml::Value| match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Datetime(d) => d.to_string(),
+                toml::Value::Array(_) | toml::Value::Table(_) => String::new(),
+                other => other.to_string(),
+            };
+            fields.title = table.get("title").map(plain).unwrap_or_default();
+            fields.date = table.get("date").map(plain).unwrap_or_default();
+            fields.tags = match table.get("tags") {
+                Some(toml::Value::Array(tags)) => tags.iter().map(plain).collect::<Vec<_>>().join(", "),
+                Some(value) => plain(value),
+                None => String::new(),
+            };
+        }
+    }
+    fields
+}
+
+/// New body lines with `key` set to `value` (or removed when empty). Other
+/// lines, comments and ordering are left alone; only the key's own line
+/// (plus a YAML block list under it) is rewritten.
+pub fn set_field(body: &[String], format: Format, key: &str, value: &str) -> Vec<String> {
+    let is_key_line = |line: &str| {
+        let rest = match line.strip_prefix(key) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        match format {
+            Format::Yaml => rest.starts_with(':'),
+            Format::Toml => rest.trim_start().starts_with('='),
+        }
+    };
+
+    let mut out: Vec<String> = Vec::with_capacity(body.len() + 1);
+    let new_line = (!value.trim().is_empty()).then(|| format_field(format, key, value));
+    let mut placed = false;
+    let mut lines = body.iter().peekable();
+    while let Some(line) = lines.next() {
+        if !is_key_line(line) {
+            out.push(line.clone());
+            continue;
+        }
+        // Swallow the block-style list items of the old value.
+        if format == Format::Yaml {
+            while lines.peek().is_some_and(|l| l.starts_with(' ') || l.starts_with('-')) {
+                lines.next();
+            }
+        }
+        if let Some(new_line) = new_line.clone() {
+            out.push(new_line);
+        }
+        placed = true;
+    }
+    if !placed {
+        if let Some(new_line) = new_line {
+            out.push(new_line);
+        }
+    }
+    out
+}
+
+fn format_field(format: Format, key: &str, value: &str) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let value = value.trim();
+    let rendered = match key {
+        "tags" => {
+            let tags: Vec<String> = value.split(',').map(str::trim).filter(|t| !t.is_empty()).map(quote).collect();
+            format!("[{}]", tags.join(", "))
+        }
+        // Bare dates are real dates in both YAML and TOML.
+        "date" if looks_like_date(value) => value.to_string(),
+        _ => quote(value),
+    };
+    match format {
+        Format::Yaml => format!("{}: {}", key, rendered),
+        Format::Toml => format!("{} = {}", key, rendered),
+    }
+}
+
+fn looks_like_date(value: &str) -> bool {
+    let b = value.as_bytes();
+    b.len() >= 10
+        && b[..10].iter().enumerate().all(|(i, c)| if i == 4 || i == 7 { *c == b'-' } else { c.is_ascii_digit() })
+        && value.parse::<toml::value::Datetime>().is_ok()
+}
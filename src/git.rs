@@ -0,0 +1,96 @@
+//! What git knows about the file being edited, asked through its command
+//! line like the rest of neuronano does; HEAD's copy of the file, read for
+//! the gutter marks, comes from libgit2 without starting a process.
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Context, Result};
+use crate::fileio;
+
+/// `git` run in the directory of `path`.
+fn git_at(path: &Path) -> Command {
+    let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir);
+    // Fail instead of asking for credentials on the terminal we draw on.
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    command
+}
+
+/// The file name of `path`, which git is given relative to its directory.
+fn name_of(path: &Path) -> Result<&str> {
+    path.file_name().and_then(|n| n.to_str()).ok_or_else(|| anyhow!("Not a file name: {}", path.display()))
+}
+
+/// Run `command` (with `input` on stdin) and return its stdout, or what
+/// it said on stderr when it fails.
+fn run(command: Command, input: Option<&str>) -> Result<String> {
+    run_ok(command, input, &[0])
+}
+
+/// `run`, counting the exit codes in `ok` as success.
+fn run_ok(mut command: Command, input: Option<&str>, ok: &[i32]) -> Result<String> {
+    let mut child = command
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Couldn't run git")?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let out = child.wait_with_output()?;
+    if !out.status.code().is_some_and(|code| ok.contains(&code)) {
+        let err = String::from_utf8_lossy(&out.stderr);
+        let err = err.lines().find(|l| !l.trim().is_empty()).unwrap_or("git failed");
+        return Err(anyhow!("{}", err.trim_start_matches("fatal: ").trim_start_matches("error: ")));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// What committing the file would change, without staging it: its diff
+/// against HEAD, or all of it when HEAD doesn't have it.
+pub fn commit_diff(path: &Path) -> Result<String> {
+    let name = name_of(path)?;
+    let mut command = git_at(path);
+    command.args(["rev-parse", "--show-toplevel"]);
+    run(command, None)?;
+    let mut command = git_at(path);
+    command.args(["ls-tree", "--name-only", "HEAD", "--", name]);
+    // Fails before the first commit, which has nothing either.
+    let in_head = run(command, None).is_ok_and(|out| !out.trim().is_empty());
+    let mut command = git_at(path);
+    if in_head {
+        command.args(["diff", "HEAD", "--", name]);
+        run(command, None)
+    } else {
+        // Exits with 1 when there are differences.
+        command.args(["diff", "--no-index", "--", "/dev/null", name]);
+        run_ok(command, None, &[0, 1])
+    }
+}
+
+/// `git add` the file and commit it alone (other staged files stay
+/// staged) with `message`. Returns git's summary line, e.g. "[main
+/// 1a2b3c4] Fix the parser".
+pub fn commit(path: &Path, message: &str) -> Result<String> {
+    let mut command = git_at(path);
+    command.args(["add", "--", name_of(path)?]);
+    run(command, None)?;
+    let mut command = git_at(path);
+    command.args(["commit", "--file=-", "--", name_of(path)?]);
+    let out = run(command, Some(message))?;
+    Ok(out.lines().next().unwrap_or_default().to_string())
+}
+
+/// The lines of `path` as committed in HEAD; None outside a repository
+/// or for a file HEAD doesn't have.
+pub fn head_lines(path: &Path) -> Option<Vec<String>> {
+    let path = path.canonicalize().ok()?;
+    let repo = git2::Repository::discover(path.parent()?).ok()?;
+    let workdir = repo.workdir()?.canonicalize().ok()?;
+    let tree = repo.head().ok()?.peel_to_tree().ok()?;
+    let entry = tree.get_path(path.strip_prefix(workdir).ok()?).ok()?;
+    let blob = entry.to_object(&repo).ok()?.peel_to_blob().ok()?;
+    Some(fileio::decode(blob.content()).text.lines().map(String::from).collect())
+}
@@ -0,0 +1,209 @@
+//! Inline images through terminal graphics protocols (kitty, iTerm2,
+//! sixel), for previewing image files and Mermaid/Graphviz diagrams.
+//! Diagrams and format conversions go through external tools (`dot`,
+//! `mmdc`, `img2sixel`, ImageMagick's `convert`).
+use std::path::Path;
+use std::process::Stdio;
+use anyhow::{anyhow, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Kitty,
+    Iterm,
+    Sixel,
+}
+
+impl Protocol {
+    /// Guess from the environment; None means text placeholders only.
+    pub fn detect() -> Option<Self> {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let program = var("TERM_PROGRAM");
+        if term == "xterm-kitty" || std::env::var_os("KITTY_WINDOW_ID").is_some() || program == "ghostty" {
+            Some(Self::Kitty)
+        } else if program == "iTerm.app" || program == "WezTerm" {
+            Some(Self::Iterm)
+        } else if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") {
+            Some(Self::Sixel)
+        } else {
+            None
+        }
+    }
+}
+
+const IMAGE_EXTENSIONS: [&str; 7] = ["png", "jpg", "jpeg", "gif", "bmp", "webp", "svg"];
+
+pub fn is_image(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+}
+
+/// Language and source of the ```mermaid or ```dot/```graphviz block
+/// around `row`.
+pub fn diagram_at(lines: &[String], row: usize) -> Option<(String, String)> {
+    let mut open: Option<(usize, String)> = None;
+    for (i, line) in lines.iter().enumerate() {
+        let Some(info) = line.trim_start().strip_prefix("```") else { continue };
+        match open.take() {
+            Some((start, lang)) => {
+                if (start..=i).contains(&row) {
+                    let known = matches!(lang.as_str(), "mermaid" | "dot" | "graphviz");
+                    return known.then(|| (lang, lines[start + 1..i].join("\n")));
+                }
+            }
+            None if i > row => return None,
+            None => open = Some((i, info.trim().to_lowercase())),
+        }
+    }
+    None
+}
+
+/// PNG of a diagram, rendered with `dot` or `mmdc`.
+pub async fn render_diagram(lang: &str, source: String) -> Result<Vec<u8>> {
+    if lang != "mermaid" {
+        return pipe("dot", &["-Tpng"], source.as_bytes()).await;
+    }
+    // mmdc only works with files.
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("neuronano-{}.mmd", std::process::id()));
+    let output = input.with_extension("png");
+    tokio::fs::write(&input, source).await?;
+    let status = Command::new("mmdc")
+        .arg("-i")
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .status()
+        .await
+        .map_err(|e| anyhow!("mmdc: {}", e));
+    let png = match status {
+        Ok(status) if status.success() => tokio::fs::read(&output).await.map_err(Into::into),
+        Ok(_) => Err(anyhow!("mmdc could not render the diagram")),
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&input).await;
+    let _ = tokio::fs::remove_file(&output).await;
+    png
+}
+
+/// Turn image file data into what `protocol` displays: PNG for kitty
+/// (converted if needed), the file as-is for iTerm2, sixel data for sixel
+/// terminals, fitted to `max_px` (width, height) pixels.
+pub async fn prepare(protocol: Protocol, data: Vec<u8>, max_px: (u32, u32)) -> Result<Vec<u8>> {
+    match protocol {
+        Protocol::Iterm => Ok(data),
+        Protocol::Kitty if png_size(&data).is_some() => Ok(data),
+        Protocol::Kitty => pipe("convert", &["-", "png:-"], &data).await,
+        Protocol::Sixel => {
+            // Only one side is given so the aspect ratio is kept.
+            let wide = png_size(&data).is_none_or(|(w, h)| w * max_px.1 >= h * max_px.0);
+            let size = if wide { format!("-w{}", max_px.0) } else { format!("-h{}", max_px.1) };
+            pipe("img2sixel", &[size.as_str()], &data).await
+        }
+    }
+}
+
+/// Run `program` with `input` on stdin and return its stdout.
+async fn pipe(program: &str, args: &[&str], input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow!("{}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("{} failed: {}", program, message.lines().next().unwrap_or("").trim()));
+    }
+    Ok(output.stdout)
+}
+
+/// Width and height from a PNG header.
+pub fn png_size(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") || data.len() < 24 {
+        return None;
+    }
+    let read = |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    Some((read(16), read(20)))
+}
+
+/// Escape sequence drawing `image` (from `prepare`) in a box of `cols` x
+/// `rows` cells at the cursor.
+pub fn escape(protocol: Protocol, image: &[u8], cols: u16, rows: u16) -> Vec<u8> {
+    match protocol {
+        Protocol::Kitty => {
+            // Cells are about twice as tall as wide; keep the aspect ratio.
+            let (cols, rows) = match png_size(image) {
+                Some((w, h)) if w > 0 && h > 0 => {
+                    let fit_cols = ((w as f64 * 2.0 * rows as f64) / h as f64).round() as u16;
+                    if fit_cols <= cols {
+                        (fit_cols.max(1), rows)
+                    } else {
+                        (cols, ((h as f64 * cols as f64) / (w as f64 * 2.0)).round().max(1.0) as u16)
+                    }
+                }
+                _ => (cols, rows),
+            };
+            let data = base64(image);
+            let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+            let mut out = Vec::new();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = (i + 1 < chunks.len()) as u8;
+                // q=2: no replies, they would arrive as key presses.
+                let header = if i == 0 { format!("f=100,a=T,q=2,C=1,c={},r={},m={}", cols, rows, more) } else { format!("m={}", more) };
+                out.extend_from_slice(format!("\x1b_G{};", header).as_bytes());
+                out.extend_from_slice(chunk);
+                out.extend_from_slice(b"\x1b\\");
+            }
+            out
+        }
+        Protocol::Iterm => format!(
+            "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07",
+            image.len(),
+            cols,
+            rows,
+            base64(image)
+        )
+        .into_bytes(),
+        Protocol::Sixel => image.to_vec(),
+    }
+}
+
+/// Sequence removing every image a protocol left on screen, where a
+/// repaint isn't enough.
+pub fn clear_images(protocol: Protocol) -> &'static [u8] {
+    match protocol {
+        Protocol::Kitty => b"\x1b_Ga=d,q=2\x1b\\",
+        Protocol::Iterm | Protocol::Sixel => b"",
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,65 @@
+//! Find in files: the project's text files (see `semantic::project_files`)
+//! are searched on a background thread, the hits of each file sent back as
+//! soon as it's done.
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use crate::fileio;
+use crate::semantic;
+
+/// Bigger files are skipped.
+const MAX_FILE_BYTES: u64 = 16 << 20;
+/// A query that matches more than this needs narrowing down.
+pub const MAX_HITS: usize = 2000;
+/// Lines are cut to this many characters for the list.
+const MAX_LINE_CHARS: usize = 300;
+
+pub struct Hit {
+    /// Relative to the directory searched.
+    pub path: String,
+    pub row: usize,
+    /// Char column where the match starts.
+    pub col: usize,
+    pub line: String,
+}
+
+pub enum Found {
+    Hits(Vec<Hit>),
+    /// Searched this many files (or stopped at MAX_HITS).
+    Done { files: usize },
+}
+
+/// Search the files under `root` for lines containing `query`, literally
+/// and case-sensitively like the in-buffer search. Dropping the receiver
+/// stops the search.
+pub fn search(root: PathBuf, query: String) -> mpsc::UnboundedReceiver<Found> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut files = semantic::project_files(&root, MAX_FILE_BYTES);
+        files.sort();
+        let mut count = 0;
+        for (path, _) in &files {
+            let Ok(decoded) = fileio::read_text(&root.join(path)) else { continue };
+            let hits: Vec<Hit> = decoded
+                .text
+                .lines()
+                .enumerate()
+                .filter_map(|(row, line)| {
+                    let at = line.find(&query)?;
+                    let col = line[..at].chars().count();
+                    let line = line.chars().take(MAX_LINE_CHARS).collect();
+                    Some(Hit { path: path.clone(), row, col, line })
+                })
+                .take(MAX_HITS - count)
+                .collect();
+            count += hits.len();
+            if !hits.is_empty() && tx.send(Found::Hits(hits)).is_err() {
+                return;
+            }
+            if count == MAX_HITS {
+                break;
+            }
+        }
+        let _ = tx.send(Found::Done { files: files.len() });
+    });
+    rx
+}
@@ -0,0 +1,99 @@
+//! Persistent input history (AI prompts, searches), kept next to the
+//! config file.
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use crate::config::Config;
+
+/// Oldest entries are dropped past this many.
+const MAX_ENTRIES: usize = 200;
+
+#[derive(Default)]
+pub struct PromptHistory {
+    /// File name inside the config directory.
+    file: &'static str,
+    /// Oldest first.
+    entries: Vec<String>,
+    /// Entry being shown while browsing; None when editing fresh input.
+    browsing: Option<usize>,
+    /// What was typed before browsing started, restored when stepping past
+    /// the newest entry.
+    draft: String,
+    /// Kept in memory only (`--secure`); `save` does nothing.
+    ephemeral: bool,
+}
+
+impl PromptHistory {
+    pub fn path(&self) -> PathBuf {
+        Config::dir().join(self.file)
+    }
+
+    pub fn load(file: &'static str) -> Self {
+        let mut history = Self { file, ..Self::default() };
+        history.entries = fs::read_to_string(history.path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        history
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if self.ephemeral {
+            return Ok(());
+        }
+        fs::create_dir_all(Config::dir())?;
+        fs::write(self.path(), serde_json::to_string_pretty(&self.entries)?)?;
+        Ok(())
+    }
+
+    pub fn set_ephemeral(&mut self) {
+        self.ephemeral = true;
+    }
+
+    pub fn last(&self) -> Option<&str> {
+        self.entries.last().map(|s| s.as_str())
+    }
+
+    /// Record a submitted entry, moving a repeated one to the end.
+    pub fn push(&mut self, prompt: &str) {
+        self.browsing = None;
+        if prompt.trim().is_empty() {
+            return;
+        }
+        self.entries.retain(|e| e != prompt);
+        self.entries.push(prompt.to_string());
+        if self.entries.len() > MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Step to an older entry. `current` is the text in the input, saved
+    /// as the draft when browsing starts.
+    pub fn older(&mut self, current: &str) -> Option<&str> {
+        let index = match self.browsing {
+            None => {
+                self.draft = current.to_string();
+                self.entries.len().checked_sub(1)?
+            }
+            Some(i) => i.checked_sub(1)?,
+        };
+        self.browsing = Some(index);
+        Some(&self.entries[index])
+    }
+
+    /// Step to a newer entry, ending at the draft.
+    pub fn newer(&mut self) -> Option<&str> {
+        let index = self.browsing?;
+        if index + 1 < self.entries.len() {
+            self.browsing = Some(index + 1);
+            Some(&self.entries[index + 1])
+        } else {
+            self.browsing = None;
+            Some(&self.draft)
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.browsing = None;
+    }
+}
@@ -0,0 +1,76 @@
+//! Indentation style: tabs or spaces and how wide, from the config and
+//! profiles or detected from the file being opened.
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Indent {
+    pub hard_tabs: bool,
+    /// Columns per level (and per tab).
+    pub width: u8,
+}
+
+/// The style most indented lines of `lines` use, or None if nothing is
+/// indented. Tab-indented files keep `width`; for spaces the most common
+/// step between a line and the more indented one below it wins.
+pub fn detect<S: AsRef<str>>(lines: impl IntoIterator<Item = S>, width: u8) -> Option<Indent> {
+    let (mut tabs, mut spaces) = (0, 0);
+    let mut steps: HashMap<usize, usize> = HashMap::new();
+    // Spaces before the previous line, unless it was tab-indented.
+    let mut previous = Some(0);
+    for line in lines {
+        let line = line.as_ref();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('\t') {
+            tabs += 1;
+            previous = None;
+            continue;
+        }
+        let n = line.len() - line.trim_start_matches(' ').len();
+        if n > 0 {
+            spaces += 1;
+        }
+        // Steps of one are block comment stars and alignment, not levels.
+        if let Some(step) = previous.filter(|&p| n > p + 1).map(|p| n - p) {
+            *steps.entry(step).or_default() += 1;
+        }
+        previous = Some(n);
+    }
+    if tabs == 0 && spaces == 0 {
+        return None;
+    }
+    if tabs > spaces {
+        return Some(Indent { hard_tabs: true, width });
+    }
+    let step = steps
+        .into_iter()
+        .filter(|&(step, _)| step <= 8)
+        .max_by_key(|&(step, count)| (count, std::cmp::Reverse(step)))
+        .map_or(width, |(step, _)| step as u8);
+    Some(Indent { hard_tabs: false, width: step })
+}
+
+/// Re-indent `text` (an AI answer) in `indent`'s style: leading tabs become
+/// spaces or leading spaces tabs, keeping the column.
+pub fn normalize(text: &str, indent: Indent) -> String {
+    let width = indent.width.max(1) as usize;
+    text.split('\n')
+        .map(|line| {
+            let body = line.trim_start_matches([' ', '\t']);
+            let leading = &line[..line.len() - body.len()];
+            let wrong = if indent.hard_tabs { ' ' } else { '\t' };
+            if !leading.contains(wrong) {
+                return line.to_string();
+            }
+            let columns = leading.chars().fold(0, |col, c| if c == '\t' { (col / width + 1) * width } else { col + 1 });
+            let leading = if indent.hard_tabs {
+                format!("{}{}", "\t".repeat(columns / width), " ".repeat(columns % width))
+            } else {
+                " ".repeat(columns)
+            };
+            format!("{}{}", leading, body)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
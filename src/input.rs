@@ -0,0 +1,303 @@
+//! Single-line input used by every modal prompt (AI prompt, search, Save
+//! As, API key, front matter fields): optional history, Tab completion,
+//! validation and masking on top of a TextArea. The AI prompt also takes
+//! several lines.
+use std::fs;
+use std::path::Path;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ratatui::{
+    layout::Rect,
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders},
+    Frame,
+};
+use tui_textarea::{CursorMove, TextArea};
+use unicode_width::UnicodeWidthChar;
+use crate::history::PromptHistory;
+use crate::ui;
+
+/// Candidates for completing the current text.
+pub type Completer = fn(&str) -> Vec<String>;
+/// Error message for text that can't be submitted.
+pub type Validator = fn(&str) -> Result<(), String>;
+
+pub struct PromptInput<'a> {
+    textarea: TextArea<'a>,
+    title: String,
+    placeholder: String,
+    mask: Option<char>,
+    history: Option<PromptHistory>,
+    completer: Option<Completer>,
+    /// Candidates being cycled with Tab, and the next one to show.
+    completions: Vec<String>,
+    completion_index: usize,
+    validator: Option<Validator>,
+    /// Ctrl+J starts a new line, and set text keeps all of its lines.
+    multiline: bool,
+    error: Option<String>,
+    /// Extra information shown under the input (e.g. a size estimate).
+    note: Option<String>,
+    /// Mirror of the TextArea's scroll, to place the terminal cursor.
+    scroll_col: u16,
+    scroll_row: u16,
+}
+
+impl<'a> PromptInput<'a> {
+    pub fn new(title: &str, placeholder: &str) -> Self {
+        let mut input = Self {
+            textarea: TextArea::default(),
+            title: title.to_string(),
+            placeholder: placeholder.to_string(),
+            mask: None,
+            history: None,
+            completer: None,
+            completions: Vec::new(),
+            completion_index: 0,
+            validator: None,
+            multiline: false,
+            error: None,
+            note: None,
+            scroll_col: 0,
+            scroll_row: 0,
+        };
+        input.set_text("");
+        input
+    }
+
+    pub fn with_history(mut self, history: PromptHistory) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    pub fn with_completion(mut self, completer: Completer) -> Self {
+        self.completer = Some(completer);
+        self
+    }
+
+    pub fn with_validation(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    pub fn multiline(mut self) -> Self {
+        self.multiline = true;
+        self
+    }
+
+    /// Hide the text, e.g. for secrets.
+    pub fn masked(mut self) -> Self {
+        self.mask = Some('•');
+        self.textarea.set_mask_char('•');
+        self
+    }
+
+    pub fn text(&self) -> String {
+        self.textarea.lines().join("\n")
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.textarea.lines().len()
+    }
+
+    /// Replace the text, with the cursor at the end.
+    pub fn set_text(&mut self, text: &str) {
+        let lines: Vec<String> = match self.multiline {
+            true => text.split('\n').map(String::from).collect(),
+            false => vec![text.lines().next().unwrap_or("").to_string()],
+        };
+        self.textarea = TextArea::from(lines);
+        self.textarea.set_placeholder_text(self.placeholder.clone());
+        self.textarea.set_cursor_line_style(Style::default());
+        if let Some(mask) = self.mask {
+            self.textarea.set_mask_char(mask);
+        }
+        self.textarea.move_cursor(CursorMove::Bottom);
+        self.textarea.move_cursor(CursorMove::End);
+        self.error = None;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    pub fn set_note(&mut self, note: Option<String>) {
+        self.note = note;
+    }
+
+    pub fn history(&self) -> Option<&PromptHistory> {
+        self.history.as_ref()
+    }
+
+    pub fn history_mut(&mut self) -> Option<&mut PromptHistory> {
+        self.history.as_mut()
+    }
+
+    /// Clear the text and any error, and stop browsing the history.
+    pub fn reset(&mut self) {
+        self.set_text("");
+        if let Some(history) = &mut self.history {
+            history.reset();
+        }
+    }
+
+    /// Editing keys, Up/Down for history (from the first or last line) and
+    /// Tab for completion. Enter and Esc are left to the caller.
+    pub fn handle_key(&mut self, key: KeyEvent) {
+        if key.code != KeyCode::Tab {
+            self.completions.clear();
+        }
+        let row = self.textarea.cursor().0;
+        let at_edge = match key.code {
+            KeyCode::Up => row == 0,
+            _ => row + 1 == self.textarea.lines().len(),
+        };
+        match key.code {
+            KeyCode::Enter => {}
+            KeyCode::Char('j') if key.modifiers.contains(KeyModifiers::CONTROL) && self.multiline => {
+                self.textarea.insert_newline();
+                self.error = None;
+            }
+            KeyCode::Char('m' | 'j') if key.modifiers.contains(KeyModifiers::CONTROL) => {}
+            KeyCode::Up | KeyCode::Down if self.history.is_some() && at_edge => {
+                let current = self.text();
+                let Some(history) = &mut self.history else { return };
+                let recalled = if key.code == KeyCode::Up { history.older(&current) } else { history.newer() };
+                if let Some(text) = recalled.map(|t| t.to_string()) {
+                    self.set_text(&text);
+                }
+            }
+            KeyCode::Tab if self.completer.is_some() => self.complete(),
+            _ => {
+                if self.textarea.input(key) {
+                    self.error = None;
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self) {
+        let Some(completer) = self.completer else { return };
+        if self.completions.is_empty() {
+            self.completions = completer(&self.text());
+            self.completion_index = 0;
+        }
+        if self.completions.is_empty() {
+            return;
+        }
+        let candidate = self.completions[self.completion_index].clone();
+        self.completion_index = (self.completion_index + 1) % self.completions.len();
+        // set_text() would drop the cycle.
+        let completions = std::mem::take(&mut self.completions);
+        self.set_text(&candidate);
+        self.completions = completions;
+    }
+
+    /// The text if it passes validation, recorded in the history; otherwise
+    /// the error is shown under the input and None returned.
+    pub fn submit(&mut self) -> Option<String> {
+        let text = self.text();
+        if let Some(Err(e)) = self.validator.map(|validate| validate(&text)) {
+            self.error = Some(e);
+            return None;
+        }
+        if let Some(history) = &mut self.history {
+            history.push(&text);
+            if let Err(e) = history.save() {
+                log::error!("Failed to save {}: {}", history.path().display(), e);
+            }
+        }
+        Some(text)
+    }
+
+    pub fn render(&mut self, f: &mut Frame, area: Rect, style: Style, focused: bool) {
+        let mut block = Block::default()
+            .borders(Borders::ALL)
+            .title(self.title.clone())
+            .style(style);
+        if let Some(error) = &self.error {
+            block = block.title_bottom(Line::styled(format!(" {} ", error), Style::default().add_modifier(Modifier::BOLD)));
+        } else if self.completions.len() > 1 {
+            block = block.title_bottom(format!(" {} matches, Tab for next ", self.completions.len()));
+        } else if let Some(note) = &self.note {
+            block = block.title_bottom(Line::from(format!(" {} ", note)).right_aligned());
+        }
+        let inner = block.inner(area);
+        self.textarea.set_block(block);
+        self.textarea.set_style(style);
+        // The terminal cursor marks the focused input; see `ui::CursorShape`.
+        self.textarea.set_cursor_style(Style::default());
+        f.render_widget(&self.textarea, area);
+
+        if focused {
+            let (row, col) = self.textarea.cursor();
+            self.scroll_row = ui::next_scroll_top(self.scroll_row, row as u16, inner.height);
+            self.scroll_col = ui::next_scroll_top(self.scroll_col, col as u16, inner.width);
+            let width: usize = match self.mask {
+                Some(mask) => col * mask.width().unwrap_or(1),
+                None => self.textarea.lines()[row].chars().take(col).map(|c| c.width().unwrap_or(0)).sum(),
+            };
+            let x = (width as u16).saturating_sub(self.scroll_col).min(inner.width.saturating_sub(1));
+            let y = (row as u16).saturating_sub(self.scroll_row).min(inner.height.saturating_sub(1));
+            f.set_cursor_position((inner.x + x, inner.y + y));
+        }
+    }
+}
+
+/// Complete the last path component against the file system; directories
+/// get a trailing slash.
+pub fn complete_path(text: &str) -> Vec<String> {
+    let (dir, prefix) = match text.rfind('/') {
+        Some(slash) => (&text[..slash + 1], &text[slash + 1..]),
+        None => ("", text),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) else {
+        return Vec::new();
+    };
+    let mut matches: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let slash = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir, name, slash))
+        })
+        .collect();
+    matches.sort();
+    matches
+}
+
+pub fn validate_filename(text: &str) -> Result<(), String> {
+    let name = text.trim();
+    if name.is_empty() {
+        return Err("Enter a file name".to_string());
+    }
+    if name.ends_with('/') {
+        return Err("That's a directory".to_string());
+    }
+    match Path::new(name).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(format!("No such directory: {}", dir.display())),
+        _ => Ok(()),
+    }
+}
+
+pub fn validate_api_key(text: &str) -> Result<(), String> {
+    let key = text.trim();
+    if key.is_empty() {
+        Err("Paste your API key".to_string())
+    } else if key.contains(char::is_whitespace) {
+        Err("API keys don't contain spaces".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn validate_not_empty(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        Err("Type something first".to_string())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Session journal (`journal` in the config): every edit, save and applied
+//! AI answer with its prompt, appended as gzipped JSON lines under
+//! `~/.config/neuronano/journal/`. `--replay` reads one back to show how a
+//! file got to its current state.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use crate::buffer::Change;
+use crate::config::Config;
+use crate::diff::{self, Kind};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// The buffer's contents when journaling started.
+    Open { file: String, lines: Vec<String> },
+    Edit(Change),
+    Save { file: String },
+    /// An AI answer about to go in; the edit after it is the answer.
+    Ai { prompt: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// Unix time in seconds.
+    pub at: u64,
+    #[serde(flatten)]
+    pub op: Op,
+}
+
+pub struct Journal {
+    pub path: PathBuf,
+    writer: GzEncoder<File>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Journal {
+    pub fn dir() -> PathBuf {
+        Config::dir().join("journal")
+    }
+
+    /// Start a new journal file for this session.
+    pub fn create() -> Result<Self> {
+        fs::create_dir_all(Self::dir())?;
+        let path = Self::dir().join(format!("{}-{}.jsonl.gz", now(), std::process::id()));
+        let file = File::create(&path)?;
+        Ok(Self { path, writer: GzEncoder::new(file, Compression::default()) })
+    }
+
+    /// Append `op`. Each entry is flushed so a crash loses at most the one
+    /// being written.
+    pub fn record(&mut self, op: Op) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, &Entry { at: now(), op })?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = self.writer.try_finish();
+    }
+}
+
+/// Read a journal back. A journal cut short (the editor was killed) keeps
+/// the entries up to its last flush.
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let mut bytes = Vec::new();
+    match MultiGzDecoder::new(File::open(path)?).read_to_end(&mut bytes) {
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => return Err(e.into()),
+        _ => {}
+    }
+    let text = String::from_utf8_lossy(&bytes);
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        match serde_json::from_str(line) {
+            Ok(entry) => entries.push(entry),
+            // Only the last line can be partly written.
+            Err(_) if entries.len() + 1 == text.lines().count() => break,
+            Err(e) => return Err(anyhow!("Bad journal entry {}: {}", entries.len() + 1, e)),
+        }
+    }
+    Ok(entries)
+}
+
+/// The buffer as it was after each entry is applied in turn.
+struct Replay {
+    lines: Vec<String>,
+}
+
+impl Replay {
+    fn apply(&mut self, op: &Op) -> Result<()> {
+        match op {
+            Op::Open { lines, .. } => self.lines = lines.clone(),
+            Op::Edit(change) => {
+                let end = change.start + change.old.len();
+                if self.lines.get(change.start..end) != Some(change.old.as_slice()) {
+                    return Err(anyhow!("Edit of row {} doesn't match the text", change.start + 1));
+                }
+                self.lines.splice(change.start..end, change.new.iter().cloned());
+            }
+            Op::Save { .. } | Op::Ai { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// `--replay`: with `at`, print the file as of entry `at`; otherwise list
+/// the entries, with runs of typing folded into one line and the diff of
+/// each AI answer.
+pub fn replay(path: &Path, at: Option<usize>) -> Result<()> {
+    let entries = read(path)?;
+    let start = entries.first().map_or(0, |e| e.at);
+    let elapsed = |entry: &Entry| {
+        let secs = entry.at.saturating_sub(start);
+        format!("+{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    };
+    let mut out = io::stdout().lock();
+    let mut state = Replay { lines: vec![String::new()] };
+    let mut i = 0;
+    while i < entries.len() {
+        if at.is_some_and(|at| i >= at) {
+            break;
+        }
+        let entry = &entries[i];
+        let before = state.lines.clone();
+        state.apply(&entry.op).map_err(|e| anyhow!("Entry {}: {}", i + 1, e))?;
+        if at.is_some() {
+            i += 1;
+            continue;
+        }
+        match &entry.op {
+            Op::Open { file, lines } => writeln!(out, "#{} {} open {} ({} lines)", i + 1, elapsed(entry), file, lines.len())?,
+            Op::Save { file } => writeln!(out, "#{} {} save {}", i + 1, elapsed(entry), file)?,
+            Op::Edit(_) => {
+                let first = i;
+                while matches!(entries.get(i + 1), Some(Entry { op: Op::Edit(_), .. })) {
+                    i += 1;
+                    state.apply(&entries[i].op).map_err(|e| anyhow!("Entry {}: {}", i + 1, e))?;
+                }
+                if i == first {
+                    writeln!(out, "#{} {} edit", i + 1, elapsed(entry))?;
+                } else {
+                    writeln!(out, "#{}-#{} {} {} edits", first + 1, i + 1, elapsed(entry), i - first + 1)?;
+                }
+            }
+            Op::Ai { prompt } => {
+                writeln!(out, "#{} {} AI: {}", i + 1, elapsed(entry), prompt)?;
+                if let Some(Entry { op: edit @ Op::Edit(_), .. }) = entries.get(i + 1) {
+                    i += 1;
+                    state.apply(edit).map_err(|e| anyhow!("Entry {}: {}", i + 1, e))?;
+                    let lines = diff::unified(&before.join("\n"), &state.lines.join("\n"), "before", "after");
+                    for line in lines.iter().filter(|l| l.kind != Kind::Header) {
+                        let text: String = line.segments.iter().map(|(_, s)| s.as_str()).collect();
+                        writeln!(out, "    {}", text)?;
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    if at.is_some() {
+        for line in &state.lines {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! OS keychain storage for the API key. We shell out to the platform's own
+//! tooling (`secret-tool` from libsecret, macOS `security`) instead of linking
+//! a D-Bus/Security framework binding.
+use std::io::Write;
+use std::process::{Command, Stdio};
+use anyhow::{anyhow, Result};
+
+const SERVICE: &str = "neuronano";
+const ACCOUNT: &str = "api_key";
+
+#[cfg(target_os = "macos")]
+const TOOL: &str = "security";
+#[cfg(not(target_os = "macos"))]
+const TOOL: &str = "secret-tool";
+
+pub fn is_available() -> bool {
+    if cfg!(windows) {
+        return false;
+    }
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(TOOL).is_file()))
+        .unwrap_or(false)
+}
+
+pub fn load() -> Option<String> {
+    if !is_available() {
+        return None;
+    }
+    let output = if cfg!(target_os = "macos") {
+        Command::new(TOOL)
+            .args(["find-generic-password", "-s", SERVICE, "-a", ACCOUNT, "-w"])
+            .stderr(Stdio::null())
+            .output()
+    } else {
+        Command::new(TOOL)
+            .args(["lookup", "service", SERVICE, "account", ACCOUNT])
+            .stderr(Stdio::null())
+            .output()
+    }
+    .ok()?;
+
+    let key = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (output.status.success() && !key.is_empty()).then_some(key)
+}
+
+pub fn store(secret: &str) -> Result<()> {
+    if !is_available() {
+        return Err(anyhow!("No keychain tool ({}) found", TOOL));
+    }
+    // The secret goes in on stdin, never in the arguments (which `ps`
+    // shows to everyone).
+    let (args, input): (&[&str], String) = if cfg!(target_os = "macos") {
+        // `-w` only takes the password as an argument, but `security -i`
+        // reads the command line from stdin.
+        let quoted = secret.replace('\\', "\\\\").replace('"', "\\\"");
+        let command = format!("add-generic-password -U -s {} -a {} -w \"{}\"\n", SERVICE, ACCOUNT, quoted);
+        (&["-i"], command)
+    } else {
+        (&["store", "--label=NeuroNano API key", "service", SERVICE, "account", ACCOUNT], secret.to_string())
+    };
+    let mut child = Command::new(TOOL).args(args).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    // `security -i` exits fine whether or not the command worked.
+    let stored = !cfg!(target_os = "macos") || load().as_deref() == Some(secret.trim());
+
+    if status.success() && stored {
+        Ok(())
+    } else {
+        Err(anyhow!("{} failed to store the API key", TOOL))
+    }
+}
+
+pub fn delete() -> bool {
+    if !is_available() {
+        return false;
+    }
+    let status = if cfg!(target_os = "macos") {
+        Command::new(TOOL)
+            .args(["delete-generic-password", "-s", SERVICE, "-a", ACCOUNT])
+            .stderr(Stdio::null())
+            .status()
+    } else {
+        Command::new(TOOL)
+            .args(["clear", "service", SERVICE, "account", ACCOUNT])
+            .stderr(Stdio::null())
+            .status()
+    };
+    status.map(|s| s.success()).unwrap_or(false)
+}
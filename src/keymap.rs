@@ -0,0 +1,313 @@
+//! Key bindings: maps key chords to editor actions. Defaults follow nano;
+//! users can rebind actions from the `keybindings` section of the config.
+use std::collections::HashMap;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Quit,
+    Save,
+    Cut,
+    Paste,
+    YankPop,
+    Search,
+    AiPrompt,
+    ToggleTableView,
+    SelectField,
+    NextField,
+    PrevField,
+    CycleTheme,
+    RunCell,
+    ToggleOutput,
+    ToggleVim,
+    ToggleAbbreviations,
+    Outline,
+    CheckLinks,
+    NextDiagnostic,
+    DiagnosticDetails,
+    ToggleDiagnostics,
+    ToggleLineEnding,
+    EditFrontMatter,
+    Chat,
+    Complete,
+    Explain,
+    RevertAi,
+    AiSnapshots,
+    Preview,
+    ToggleReadOnly,
+    ToggleSoftWrap,
+    ApplyPatch,
+    ExportPatch,
+    ToggleWhitespace,
+    MatchBracket,
+    ToggleComment,
+    AddCursor,
+    RecordMacro,
+    RunMacro,
+    ToggleBookmark,
+    Bookmarks,
+    ToggleFold,
+    ToggleAllFolds,
+    OpenFile,
+    Buffers,
+    SaveAll,
+    CloseBuffer,
+    CloseOtherBuffers,
+    Spelling,
+    ToggleSpellCheck,
+    FindInFiles,
+    Undo,
+    Redo,
+    Replace,
+    WidenChat,
+    NarrowChat,
+    GitCommit,
+    GitDiff,
+    RunCommand,
+    Filter,
+    // Bound by the default Markdown/Org profiles rather than globally.
+    ToggleCheckbox,
+    RenumberList,
+    Promote,
+    Demote,
+}
+
+/// One key or a list of keys, e.g. `"ctrl+s"` or `["ctrl+s", "f2"]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeySpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeySpec {
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            KeySpec::One(key) => vec![key.as_str()],
+            KeySpec::Many(keys) => keys.iter().map(|k| k.as_str()).collect(),
+        }
+    }
+}
+
+type Chord = (KeyCode, KeyModifiers);
+
+const DEFAULT_BINDINGS: &[(Action, &str)] = &[
+    (Action::Quit, "ctrl+x"),
+    (Action::Save, "ctrl+o"),
+    (Action::Cut, "ctrl+k"),
+    (Action::Paste, "ctrl+u"),
+    (Action::YankPop, "alt+y"),
+    (Action::Search, "ctrl+f"),
+    (Action::AiPrompt, "ctrl+p"),
+    (Action::ToggleTableView, "alt+t"),
+    (Action::SelectField, "alt+s"),
+    (Action::NextField, "tab"),
+    (Action::PrevField, "backtab"),
+    (Action::CycleTheme, "alt+c"),
+    (Action::RunCell, "alt+enter"),
+    (Action::RunCell, "f5"),
+    (Action::ToggleOutput, "alt+o"),
+    (Action::ToggleVim, "alt+v"),
+    (Action::ToggleAbbreviations, "alt+a"),
+    (Action::Outline, "alt+h"),
+    (Action::CheckLinks, "alt+l"),
+    (Action::NextDiagnostic, "f8"),
+    (Action::DiagnosticDetails, "shift+f8"),
+    (Action::ToggleDiagnostics, "alt+d"),
+    (Action::ToggleLineEnding, "alt+e"),
+    (Action::EditFrontMatter, "alt+m"),
+    (Action::Chat, "alt+i"),
+    (Action::Complete, "ctrl+space"),
+    (Action::Explain, "alt+w"),
+    (Action::RevertAi, "alt+r"),
+    (Action::AiSnapshots, "alt+z"),
+    (Action::Preview, "alt+g"),
+    (Action::ToggleReadOnly, "alt+k"),
+    (Action::ToggleSoftWrap, "alt+b"),
+    (Action::ApplyPatch, "alt+p"),
+    (Action::ExportPatch, "shift+f6"),
+    (Action::ToggleWhitespace, "alt+j"),
+    (Action::MatchBracket, "ctrl+]"),
+    (Action::ToggleComment, "ctrl+/"),
+    // What most terminals send for ctrl+/.
+    (Action::ToggleComment, "ctrl+_"),
+    (Action::AddCursor, "ctrl+d"),
+    (Action::RecordMacro, "alt+:"),
+    (Action::RunMacro, "alt+;"),
+    (Action::ToggleBookmark, "ctrl+b"),
+    (Action::Bookmarks, "f2"),
+    (Action::ToggleFold, "alt+f"),
+    (Action::ToggleAllFolds, "alt+u"),
+    (Action::OpenFile, "f3"),
+    (Action::Buffers, "f4"),
+    (Action::SaveAll, "f6"),
+    (Action::CloseBuffer, "ctrl+w"),
+    (Action::CloseOtherBuffers, "shift+f4"),
+    (Action::Spelling, "f7"),
+    (Action::ToggleSpellCheck, "shift+f7"),
+    (Action::FindInFiles, "ctrl+shift+f"),
+    // For terminals that can't tell ctrl+shift+f from ctrl+f.
+    (Action::FindInFiles, "alt+q"),
+    (Action::Undo, "ctrl+z"),
+    (Action::Redo, "ctrl+r"),
+    (Action::Replace, "ctrl+\\"),
+    (Action::WidenChat, "alt+."),
+    (Action::NarrowChat, "alt+,"),
+    (Action::GitCommit, "f9"),
+    (Action::GitDiff, "shift+f9"),
+    (Action::RunCommand, "ctrl+t"),
+    (Action::Filter, "alt+|"),
+];
+
+pub struct KeyMap {
+    bindings: HashMap<Chord, Action>,
+    /// The terminal reports Shift on letters (kitty keyboard protocol), so
+    /// e.g. ctrl+shift+p and ctrl+p are different keys.
+    shift_letters: bool,
+}
+
+impl KeyMap {
+    /// Build the default map, then apply per-action overrides. An override
+    /// replaces all default keys of that action.
+    pub fn new(overrides: &HashMap<Action, KeySpec>, shift_letters: bool) -> Self {
+        let mut bindings = HashMap::new();
+        for (action, key) in DEFAULT_BINDINGS {
+            if overrides.contains_key(action) {
+                continue;
+            }
+            // The first default for a chord wins: ctrl+shift+f is ctrl+f
+            // where Shift isn't reported.
+            if let Some(chord) = parse_key(key, shift_letters) {
+                bindings.entry(chord).or_insert(*action);
+            }
+        }
+        for (action, spec) in overrides {
+            for key in spec.keys() {
+                match parse_key(key, shift_letters) {
+                    Some(chord) => {
+                        bindings.insert(chord, *action);
+                    }
+                    None => log::warn!("Ignoring unknown key '{}' bound to {:?}", key, action),
+                }
+            }
+        }
+        Self { bindings, shift_letters }
+    }
+
+    pub fn action_for(&self, key: &KeyEvent) -> Option<Action> {
+        let mut modifiers = key.modifiers;
+        // Shift is implied by the character itself (e.g. 'A'), except for
+        // chords like shift+tab, unless the terminal reports it reliably.
+        if let KeyCode::Char(c) = key.code {
+            if self.shift_letters && c.is_ascii_uppercase() {
+                modifiers.insert(KeyModifiers::SHIFT);
+            } else if !self.shift_letters || !c.is_ascii_alphabetic() {
+                modifiers.remove(KeyModifiers::SHIFT);
+            }
+        }
+        let code = match key.code {
+            // Legacy terminals send ctrl+\ ] ^ _ as the control codes
+            // crossterm reads as ctrl+4..7.
+            KeyCode::Char(c @ '4'..='7') if !self.shift_letters && modifiers.contains(KeyModifiers::CONTROL) => {
+                KeyCode::Char(['\\', ']', '^', '_'][c as usize - '4' as usize])
+            }
+            KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+            code => code,
+        };
+        self.bindings.get(&(code, modifiers)).copied()
+    }
+
+    /// Footer label for the first key bound to `action` ("^X", "M-T", "F2").
+    pub fn label(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .filter(|(_, a)| **a == action)
+            .map(|(chord, _)| format_key(chord))
+            .min_by_key(|label| label.len())
+            .unwrap_or_default()
+    }
+}
+
+/// Keys in `overrides` that don't parse, with the action they were for.
+pub fn unknown_keys(overrides: &HashMap<Action, KeySpec>) -> Vec<(Action, String)> {
+    let mut unknown: Vec<(Action, String)> = overrides
+        .iter()
+        .flat_map(|(action, spec)| spec.keys().into_iter().map(move |key| (*action, key.to_string())))
+        .filter(|(_, key)| parse_key(key, true).is_none())
+        .collect();
+    unknown.sort_by(|a, b| a.1.cmp(&b.1));
+    unknown
+}
+
+fn parse_key(spec: &str, shift_letters: bool) -> Option<Chord> {
+    let spec = spec.trim().to_lowercase();
+    let mut parts: Vec<&str> = spec.split('+').collect();
+    let key = parts.pop()?;
+
+    let mut modifiers = KeyModifiers::NONE;
+    for part in parts {
+        match part {
+            "ctrl" => modifiers |= KeyModifiers::CONTROL,
+            "alt" | "meta" => modifiers |= KeyModifiers::ALT,
+            "shift" => modifiers |= KeyModifiers::SHIFT,
+            _ => return None,
+        }
+    }
+
+    let code = match key {
+        "esc" | "escape" => KeyCode::Esc,
+        "enter" | "return" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "backtab" => {
+            modifiers |= KeyModifiers::SHIFT;
+            KeyCode::BackTab
+        }
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "space" => KeyCode::Char(' '),
+        f if f.starts_with('f') && f.len() > 1 => KeyCode::F(f[1..].parse().ok()?),
+        c if c.chars().count() == 1 => KeyCode::Char(c.chars().next()?),
+        _ => return None,
+    };
+
+    match code {
+        // Shift+Tab arrives as BackTab.
+        KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => Some((KeyCode::BackTab, modifiers)),
+        // Legacy terminals can't report Shift on letters; match `action_for`.
+        KeyCode::Char(c) if !(shift_letters && c.is_ascii_alphabetic()) => Some((code, modifiers - KeyModifiers::SHIFT)),
+        _ => Some((code, modifiers)),
+    }
+}
+
+fn format_key((code, modifiers): &Chord) -> String {
+    let key = match code {
+        KeyCode::Char(' ') => "Space".to_string(),
+        KeyCode::Char(c) => c.to_ascii_uppercase().to_string(),
+        KeyCode::F(n) => format!("F{}", n),
+        KeyCode::BackTab => return "S-Tab".to_string(),
+        other => format!("{:?}", other),
+    };
+    let mut label = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        label.push('^');
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        label.push_str("M-");
+    }
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        label.push_str("S-");
+    }
+    label.push_str(&key);
+    label
+}
@@ -0,0 +1,104 @@
+//! Emacs-style kill ring: consecutive kills accumulate into one entry, and
+//! a yank can be cycled through older kills. Kills are copied to the
+//! system clipboard, and text copied in other programs is yanked first.
+use std::collections::VecDeque;
+use zeroize::Zeroize;
+
+const MAX_ENTRIES: usize = 16;
+
+#[derive(Default)]
+pub struct KillRing {
+    entries: VecDeque<String>,
+    /// Entry the last yank inserted (0 = newest).
+    index: usize,
+    /// The previous command was a kill, so the next kill appends.
+    pub appending: bool,
+    /// Buffer range of the last yank while it can still be cycled.
+    pub yanked: Option<((usize, usize), (usize, usize))>,
+    pub clipboard: Clipboard,
+}
+
+/// The system clipboard, connected to on first use. There's none without
+/// a display (e.g. over SSH); the kill ring then works on its own.
+#[derive(Default)]
+pub struct Clipboard {
+    system: Option<arboard::Clipboard>,
+    connected: bool,
+    /// What it held when we last set or read it.
+    seen: Option<String>,
+}
+
+impl Clipboard {
+    fn system(&mut self) -> Option<&mut arboard::Clipboard> {
+        if !self.connected {
+            self.connected = true;
+            self.system = arboard::Clipboard::new().map_err(|e| log::warn!("No system clipboard: {}", e)).ok();
+        }
+        self.system.as_mut()
+    }
+
+    pub fn set(&mut self, text: &str) {
+        let Some(system) = self.system() else { return };
+        match system.set_text(text) {
+            Ok(()) => self.seen = Some(text.to_string()),
+            Err(e) => log::warn!("Couldn't copy to the clipboard: {}", e),
+        }
+    }
+
+    /// Text copied in another program since we last looked.
+    pub fn take_new(&mut self) -> Option<String> {
+        let text = self.system()?.get_text().ok().filter(|t| !t.is_empty())?;
+        if self.seen.as_ref() == Some(&text) {
+            return None;
+        }
+        self.seen = Some(text.clone());
+        Some(text)
+    }
+}
+
+impl KillRing {
+    /// Record killed text, appending to the newest entry for consecutive kills.
+    pub fn kill(&mut self, text: String) {
+        match self.entries.front_mut() {
+            Some(front) if self.appending => front.push_str(&text),
+            _ => {
+                self.entries.push_front(text);
+                self.entries.truncate(MAX_ENTRIES);
+            }
+        }
+        self.appending = true;
+    }
+
+    /// Add text copied elsewhere (e.g. vim `y`) unless it's already the newest entry.
+    pub fn remember(&mut self, text: &str) {
+        if !text.is_empty() && self.entries.front().map(|s| s.as_str()) != Some(text) {
+            self.entries.push_front(text.to_string());
+            self.entries.truncate(MAX_ENTRIES);
+        }
+    }
+
+    /// Overwrite every entry in memory before it's freed.
+    pub fn wipe(&mut self) {
+        for mut entry in self.entries.drain(..) {
+            entry.zeroize();
+        }
+        self.yanked = None;
+        if let Some(mut seen) = self.clipboard.seen.take() {
+            seen.zeroize();
+        }
+    }
+
+    pub fn newest(&mut self) -> Option<&str> {
+        self.index = 0;
+        self.entries.front().map(|s| s.as_str())
+    }
+
+    /// Step to the next older entry, wrapping around.
+    pub fn rotate(&mut self) -> Option<&str> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.index = (self.index + 1) % self.entries.len();
+        self.entries.get(self.index).map(|s| s.as_str())
+    }
+}
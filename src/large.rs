@@ -0,0 +1,137 @@
+//! Opening files too big to read before drawing: the start is read right
+//! away and the rest streams in from a background thread, which decodes
+//! it and builds the rope pieces the editor appends as they come.
+use crate::fileio::{self, Decoded};
+use encoding_rs::Decoder;
+use ropey::Rope;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// Read before the first frame.
+const HEAD_BYTES: u64 = 1 << 20;
+/// Background chunks start at this size and double up to the maximum, so
+/// a huge file takes a handful of appends.
+const CHUNK_BYTES: u64 = 4 << 20;
+const MAX_CHUNK_BYTES: u64 = 16 << 20;
+
+/// What the loader sends.
+pub enum Loaded {
+    /// More text for the end of the buffer, read from `bytes` bytes of the
+    /// file. `malformed` when some of them weren't valid in its encoding.
+    Text { text: Rope, bytes: u64, malformed: bool },
+    /// All read; whether the file ended with a line break.
+    Done { final_newline: bool },
+}
+
+pub struct Loading {
+    pub size: u64,
+    pub loaded: u64,
+    /// The start wasn't valid in the file's encoding.
+    pub malformed: bool,
+    pub rx: mpsc::Receiver<Result<Loaded, String>>,
+}
+
+impl Loading {
+    pub fn percent(&self) -> u64 {
+        self.loaded * 100 / self.size.max(1)
+    }
+}
+
+/// Turns decoded text into the editor's: CRLF becomes LF, and the last
+/// line break is held back until more text follows it, as the editor has
+/// none after its last line. A CR is held back too, in case an LF follows.
+struct Lines {
+    decoder: Decoder,
+    held: &'static str,
+}
+
+impl Lines {
+    /// Decode `bytes` and return the text that can go in so far.
+    fn push(&mut self, bytes: &[u8], last: bool) -> (String, bool) {
+        let capacity = self.decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len());
+        let mut text = String::with_capacity(self.held.len() + capacity);
+        text.push_str(self.held);
+        let (_, _, malformed) = self.decoder.decode_to_string(bytes, &mut text, last);
+        self.held = if text.ends_with('\r') {
+            text.pop();
+            "\r"
+        } else if text.ends_with('\n') {
+            text.pop();
+            if text.ends_with('\r') {
+                text.pop();
+            }
+            "\n"
+        } else {
+            ""
+        };
+        if text.contains("\r\n") {
+            text = text.replace("\r\n", "\n");
+        }
+        (text, malformed)
+    }
+}
+
+/// The start of `path`, decoded, and the loader reading the rest. The
+/// start keeps its own line endings, for detecting them; its last line
+/// break is the one the editor drops.
+pub fn open(path: &Path, size: u64) -> std::io::Result<(Decoded, Loading)> {
+    let mut file = File::open(path)?;
+    let mut head = Vec::new();
+    (&mut file).take(HEAD_BYTES).read_to_end(&mut head)?;
+    let (encoding, bom) = fileio::detect(&head, false);
+    let decoder = if bom { encoding.new_decoder_with_bom_removal() } else { encoding.new_decoder_without_bom_handling() };
+    let mut lines = Lines { decoder, held: "" };
+    let capacity = lines.decoder.max_utf8_buffer_length(head.len()).unwrap_or(head.len());
+    let mut text = String::with_capacity(capacity);
+    let (_, _, malformed) = lines.decoder.decode_to_string(&head, &mut text, false);
+    if text.ends_with('\r') {
+        text.pop();
+        lines.held = "\r";
+        // Keep a line break before it from being the one dropped.
+        if text.ends_with('\n') {
+            text.push('\n');
+        }
+    } else if text.ends_with('\n') {
+        lines.held = "\n";
+    }
+
+    let (tx, rx) = mpsc::channel(2);
+    let offset = head.len() as u64;
+    let path = path.to_path_buf();
+    std::thread::spawn(move || {
+        let mut read = || -> std::io::Result<()> {
+            let mut file = File::open(&path)?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut chunk_bytes = CHUNK_BYTES;
+            let mut chunk = Vec::new();
+            loop {
+                chunk.clear();
+                let read = (&mut file).take(chunk_bytes).read_to_end(&mut chunk)?;
+                let (text, malformed) = lines.push(&chunk, read == 0);
+                if read == 0 && lines.held == "\r" {
+                    // A CR at the very end isn't a line break.
+                    let text = text + "\r";
+                    let _ = tx.blocking_send(Ok(Loaded::Text { text: Rope::from_str(&text), bytes: 0, malformed }));
+                } else if !text.is_empty() || malformed {
+                    let loaded = Loaded::Text { text: Rope::from_str(&text), bytes: read as u64, malformed };
+                    // The buffer was closed.
+                    if tx.blocking_send(Ok(loaded)).is_err() {
+                        return Ok(());
+                    }
+                }
+                if read == 0 {
+                    let _ = tx.blocking_send(Ok(Loaded::Done { final_newline: lines.held == "\n" }));
+                    return Ok(());
+                }
+                chunk_bytes = (chunk_bytes * 2).min(MAX_CHUNK_BYTES);
+            }
+        };
+        if let Err(e) = read() {
+            let _ = tx.blocking_send(Err(e.to_string()));
+        }
+    });
+    let loading = Loading { size, loaded: offset, malformed, rx };
+    Ok((Decoded { text, encoding, bom }, loading))
+}
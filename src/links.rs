@@ -0,0 +1,134 @@
+//! Finding and checking links in Markdown buffers.
+use std::path::Path;
+use std::time::Duration;
+use reqwest::Client;
+
+pub struct Link {
+    pub row: usize,
+    /// Char column where the target starts.
+    pub col: usize,
+    pub target: String,
+}
+
+/// Inline links and images (`[text](target)`), autolinks (`<https://...>`)
+/// and reference definitions (`[id]: target`). Fenced code is skipped.
+pub fn find_links(lines: &[String]) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut in_fence = false;
+
+    for (row, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+
+        let chars: Vec<char> = line.chars().collect();
+        let indent = chars.len() - trimmed.chars().count();
+        if let Some((offset, target)) = reference_definition(trimmed) {
+            let col = indent + trimmed[..offset].chars().count();
+            links.push(Link { row, col, target: target.to_string() });
+            continue;
+        }
+
+        let mut i = 0;
+        while i < chars.len() {
+            if chars[i] == ']' && chars.get(i + 1) == Some(&'(') {
+                let start = i + 2;
+                if let Some(len) = chars[start..].iter().position(|&c| c == ')') {
+                    let inside: String = chars[start..start + len].iter().collect();
+                    // Drop an optional title: [text](target "title")
+                    let target = inside.split_whitespace().next().unwrap_or("");
+                    let target = target.trim_start_matches('<').trim_end_matches('>');
+                    if !target.is_empty() {
+                        links.push(Link { row, col: start, target: target.to_string() });
+                    }
+                    i = start + len;
+                }
+            } else if chars[i] == '<' {
+                if let Some(len) = chars[i + 1..].iter().position(|&c| c == '>') {
+                    let inside: String = chars[i + 1..i + 1 + len].iter().collect();
+                    if is_remote(&inside) {
+                        links.push(Link { row, col: i + 1, target: inside });
+                    }
+                    i += len + 1;
+                }
+            }
+            i += 1;
+        }
+    }
+    links
+}
+
+/// Byte offset and target of a `[id]: target` line.
+fn reference_definition(line: &str) -> Option<(usize, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let close = rest.find("]:")?;
+    if close == 0 || rest[..close].contains(']') {
+        return None;
+    }
+    let after = &rest[close + 2..];
+    let target = after.split_whitespace().next()?;
+    let offset = line.len() - after.trim_start().len();
+    Some((offset, target.trim_start_matches('<').trim_end_matches('>')))
+}
+
+pub fn is_remote(target: &str) -> bool {
+    target.starts_with("http://") || target.starts_with("https://")
+}
+
+/// Schemes we can't check (mailto:, ftp:, ...).
+fn is_other_scheme(target: &str) -> bool {
+    match target.find(':') {
+        Some(colon) => !target[..colon].contains(['/', '#', '.']) && colon > 1,
+        None => false,
+    }
+}
+
+/// GitHub-style anchor for a heading title.
+pub fn slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Problem with a local target (a file relative to `base`, or a `#heading`
+/// anchor in this buffer), or None if it resolves.
+pub fn check_local(base: &Path, target: &str, anchors: &[String]) -> Option<String> {
+    if is_remote(target) || is_other_scheme(target) {
+        return None;
+    }
+    if let Some(anchor) = target.strip_prefix('#') {
+        return (!anchors.iter().any(|a| a == anchor)).then(|| format!("No heading for anchor #{}", anchor));
+    }
+    let path = target.split(['#', '?']).next().unwrap_or(target);
+    if path.is_empty() || base.join(path).exists() {
+        None
+    } else {
+        Some(format!("Not found: {}", path))
+    }
+}
+
+/// Problem reaching an HTTP(S) target, or None if it answers with a
+/// non-error status. Falls back to GET for servers that reject HEAD.
+pub async fn check_remote(client: &Client, url: &str) -> Option<String> {
+    let timeout = Duration::from_secs(10);
+    let status = match client.head(url).timeout(timeout).send().await {
+        Ok(response) if response.status().is_success() || response.status().is_redirection() => return None,
+        _ => client.get(url).timeout(timeout).send().await.map(|r| r.status()),
+    };
+    match status {
+        Ok(status) if status.is_success() || status.is_redirection() => None,
+        Ok(status) => Some(format!("{} returned {}", url, status)),
+        Err(e) => Some(format!("{} unreachable: {}", url, e)),
+    }
+}
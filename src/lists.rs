@@ -0,0 +1,142 @@
+//! Markdown/Org list and heading manipulation: checkboxes, ordered list
+//! numbering, promoting and demoting.
+
+/// Parsed start of a list item line like `  - [ ] text` or `3. text`.
+pub struct ListItem {
+    pub indent: usize,
+    /// Number of an ordered item, with its delimiter ('.' or ')').
+    pub number: Option<(usize, char)>,
+    /// Byte offset where the item text (or its checkbox) starts.
+    pub content: usize,
+}
+
+pub fn list_item(line: &str) -> Option<ListItem> {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+
+    let (number, marker_len) = if digits > 0 {
+        let delimiter = rest[digits..].chars().next()?;
+        if delimiter != '.' && delimiter != ')' {
+            return None;
+        }
+        (Some((rest[..digits].parse().ok()?, delimiter)), digits + 1)
+    } else if rest.starts_with(['-', '*', '+']) {
+        (None, 1)
+    } else {
+        return None;
+    };
+
+    let after = &rest[marker_len..];
+    if !(after.is_empty() || after.starts_with(' ')) {
+        return None;
+    }
+    let spaces = after.len() - after.trim_start_matches(' ').len();
+    Some(ListItem { indent, number, content: indent + marker_len + spaces.max(1).min(after.len()) })
+}
+
+/// Check or uncheck the item's `[ ]` box, adding one if it has none.
+pub fn toggle_checkbox(line: &str, org: bool) -> Option<String> {
+    let item = list_item(line)?;
+    let (head, body) = line.split_at(item.content);
+    let checked = if org { "[X]" } else { "[x]" };
+    let body = if let Some(rest) = body.strip_prefix("[ ]") {
+        format!("{}{}", checked, rest)
+    } else if let Some(rest) = body.strip_prefix("[x]").or_else(|| body.strip_prefix("[X]")) {
+        format!("[ ]{}", rest)
+    } else if head.ends_with(' ') {
+        format!("[ ] {}", body)
+    } else {
+        format!(" [ ] {}", body)
+    };
+    Some(format!("{}{}", head, body))
+}
+
+/// Renumber the ordered list around `row` from its first item's number.
+/// Returns the first row and the new lines of the block; nested items and
+/// continuation lines are kept as they are.
+pub fn renumber(lines: &[String], row: usize) -> Option<(usize, Vec<String>)> {
+    let (indent, _) = enclosing_item(lines, row)?;
+    let in_block = |line: &String| {
+        line.trim().is_empty()
+            || indentation(line) > indent
+            || list_item(line).is_some_and(|i| i.indent == indent && i.number.is_some())
+    };
+
+    let mut start = row;
+    while start > 0 && in_block(&lines[start - 1]) {
+        start -= 1;
+    }
+    let mut end = row + 1;
+    while end < lines.len() && in_block(&lines[end]) {
+        end += 1;
+    }
+    // Don't swallow blank lines around the block.
+    while start < row && lines[start].trim().is_empty() {
+        start += 1;
+    }
+
+    let mut next = None;
+    let block = lines[start..end]
+        .iter()
+        .map(|line| match list_item(line) {
+            Some(ListItem { indent: i, number: Some((n, delimiter)), .. }) if i == indent => {
+                let n = *next.get_or_insert(n);
+                next = Some(n + 1);
+                let digits = line[i..].chars().take_while(|c| c.is_ascii_digit()).count();
+                format!("{}{}{}{}", &line[..i], n, delimiter, &line[i + digits + 1..])
+            }
+            _ => line.clone(),
+        })
+        .collect();
+    Some((start, block))
+}
+
+/// The ordered item `row` belongs to: its own line, or the nearest item
+/// above it for continuation lines. Returns (indent, row).
+fn enclosing_item(lines: &[String], row: usize) -> Option<(usize, usize)> {
+    (0..=row).rev().find_map(|r| {
+        let item = list_item(&lines[r])?;
+        item.number.map(|_| (item.indent, r))
+    })
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Demote (`deeper`) or promote a heading or list item. Headings gain or
+/// lose a `#` (Org: `*`); list items are indented under the previous
+/// sibling or moved out to their parent's level.
+pub fn shift_level(lines: &[String], row: usize, deeper: bool, org: bool) -> Option<String> {
+    let line = &lines[row];
+    let heading_char = if org { '*' } else { '#' };
+    let level = line.chars().take_while(|&c| c == heading_char).count();
+    if level > 0 && line[level..].starts_with(' ') {
+        let level = if deeper {
+            if !org && level == 6 {
+                return None;
+            }
+            level + 1
+        } else {
+            level.checked_sub(1).filter(|l| *l > 0)?
+        };
+        return Some(format!("{}{}", heading_char.to_string().repeat(level), line.trim_start_matches(heading_char)));
+    }
+
+    let item = list_item(line)?;
+    let previous = lines[..row].iter().rev().filter_map(|l| list_item(l));
+    let indent = if deeper {
+        previous
+            .take_while(|p| p.indent >= item.indent)
+            .find(|p| p.indent == item.indent)
+            .map(|sibling| sibling.content)
+            .unwrap_or(item.indent + 2)
+    } else {
+        if item.indent == 0 {
+            return None;
+        }
+        previous.map(|p| p.indent).find(|&i| i < item.indent).unwrap_or(0)
+    };
+    Some(format!("{}{}", " ".repeat(indent), &line[item.indent..]))
+}
@@ -0,0 +1,525 @@
+//! A minimal Language Server Protocol client: one server process per
+//! `language_server` command line (from the file's profile), kept told of
+//! the full text of the open files, whose diagnostics come back as events
+//! and which can be asked for completions.
+//! JSON-RPC over the server's stdin/stdout, written by hand like the
+//! Gemini requests.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot};
+
+/// Files whose directory is taken as the project root.
+const ROOT_MARKERS: &[&str] = &["Cargo.toml", "pyproject.toml", "setup.py", "package.json", "go.mod", ".git"];
+
+/// What the servers send that the editor acts on.
+pub enum Event {
+    Diagnostics { server: String, path: PathBuf, diagnostics: Vec<ServerDiagnostic> },
+    /// The server couldn't start, or went away.
+    Stopped { server: String, error: String },
+}
+
+/// A diagnostic as the server reports it: rows, and columns in the
+/// server's position encoding.
+pub struct ServerDiagnostic {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    /// 1 error, 2 warning, 3 information, 4 hint.
+    pub severity: Option<u64>,
+    pub message: String,
+    pub source: Option<String>,
+    pub code: Option<String>,
+}
+
+/// An edit as the server sends it, in its position encoding.
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompletionItem {
+    pub label: String,
+    /// The LSP `CompletionItemKind`, 1 (text) to 25 (type parameter).
+    pub kind: Option<u64>,
+    pub detail: Option<String>,
+    /// What typed text is matched against; the label if not given.
+    pub filter_text: String,
+    pub sort_text: String,
+    /// Replaces its range if given, else `insert_text` replaces the word
+    /// before the cursor.
+    pub edit: Option<TextEdit>,
+    pub insert_text: String,
+    /// Elsewhere in the file, like an import to add.
+    pub additional_edits: Vec<TextEdit>,
+}
+
+/// A completion response: the items, and whether asking again as the
+/// word grows would give others.
+pub struct Completions {
+    pub items: Vec<CompletionItem>,
+    pub incomplete: bool,
+}
+
+/// How the server counts columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    Utf8,
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+type Pending = Arc<Mutex<HashMap<i64, oneshot::Sender<Value>>>>;
+
+/// What the server said it does, known once it answered `initialize`.
+#[derive(Default)]
+struct Capabilities {
+    encoding: Encoding,
+    completion: bool,
+    trigger_characters: Vec<String>,
+}
+
+pub struct Client {
+    outgoing: mpsc::UnboundedSender<Value>,
+    pending: Pending,
+    next_id: i64,
+    capabilities: Arc<Mutex<Capabilities>>,
+    /// Version of each open document, bumped on every change.
+    versions: HashMap<PathBuf, i64>,
+    /// Killed when the client is dropped.
+    _child: Child,
+}
+
+impl Client {
+    /// Start `command` for the project around `path` and begin the
+    /// handshake; messages sent meanwhile wait for it to finish.
+    pub fn start(command: &str, path: &Path, events: mpsc::UnboundedSender<Event>) -> Result<Self> {
+        let mut parts = command.split_whitespace();
+        let program = parts.next().ok_or_else(|| anyhow!("Empty language_server command"))?;
+        let root = root_for(path);
+        let mut child = Command::new(program)
+            .args(parts)
+            .current_dir(&root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Couldn't start {}", program))?;
+        let stdin = child.stdin.take().ok_or_else(|| anyhow!("No stdin"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("No stdout"))?;
+
+        let (outgoing, queued) = mpsc::unbounded_channel();
+        let pending: Pending = Arc::default();
+        let capabilities: Arc<Mutex<Capabilities>> = Arc::default();
+        let (initialized_tx, initialized_rx) = oneshot::channel();
+        pending.lock().unwrap().insert(0, initialized_tx);
+
+        let init = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": "initialize",
+            "params": {
+                "processId": std::process::id(),
+                "rootUri": uri(&root),
+                "workspaceFolders": [{ "uri": uri(&root), "name": root.file_name().map(|n| n.to_string_lossy()).unwrap_or_default() }],
+                "clientInfo": { "name": "neuronano", "version": env!("CARGO_PKG_VERSION") },
+                "capabilities": {
+                    "general": { "positionEncodings": ["utf-32", "utf-16"] },
+                    "textDocument": {
+                        "synchronization": { "didSave": true },
+                        "publishDiagnostics": { "relatedInformation": false },
+                        "completion": { "completionItem": { "snippetSupport": false } },
+                    },
+                },
+            },
+        });
+        tokio::spawn(write_loop(stdin, init, initialized_rx, queued, capabilities.clone()));
+        tokio::spawn(read_loop(stdout, command.to_string(), pending.clone(), outgoing.clone(), events));
+
+        Ok(Self { outgoing, pending, next_id: 1, capabilities, versions: HashMap::new(), _child: child })
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.capabilities.lock().unwrap().encoding
+    }
+
+    /// Whether the server completes; false until it has said so.
+    pub fn completes(&self) -> bool {
+        self.capabilities.lock().unwrap().completion
+    }
+
+    /// Whether typing `c` should ask for completions (like `.` or `::`).
+    pub fn triggers_completion(&self, c: char) -> bool {
+        self.capabilities.lock().unwrap().trigger_characters.iter().any(|t| t.ends_with(c))
+    }
+
+    pub fn is_open(&self, path: &Path) -> bool {
+        self.versions.contains_key(path)
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        let _ = self.outgoing.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Send a request; the response's `result` arrives on the receiver
+    /// (dropped if the server fails it).
+    fn request(&mut self, method: &str, params: Value) -> oneshot::Receiver<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        let _ = self.outgoing.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        rx
+    }
+
+    /// Ask for completions at server position `(line, col)` of `path`.
+    pub fn completion(&mut self, path: &Path, (line, col): (usize, usize)) -> oneshot::Receiver<Value> {
+        self.request(
+            "textDocument/completion",
+            json!({ "textDocument": { "uri": uri(path) }, "position": { "line": line, "character": col } }),
+        )
+    }
+
+    pub fn open(&mut self, path: &Path, language_id: &str, text: String) {
+        self.versions.insert(path.to_path_buf(), 1);
+        self.notify(
+            "textDocument/didOpen",
+            json!({ "textDocument": { "uri": uri(path), "languageId": language_id, "version": 1, "text": text } }),
+        );
+    }
+
+    /// Send the whole new text of `path`.
+    pub fn change(&mut self, path: &Path, text: String) {
+        let Some(version) = self.versions.get_mut(path) else { return };
+        *version += 1;
+        let version = *version;
+        self.notify(
+            "textDocument/didChange",
+            json!({ "textDocument": { "uri": uri(path), "version": version }, "contentChanges": [{ "text": text }] }),
+        );
+    }
+
+    pub fn save(&self, path: &Path) {
+        if self.is_open(path) {
+            self.notify("textDocument/didSave", json!({ "textDocument": { "uri": uri(path) } }));
+        }
+    }
+
+    pub fn close(&mut self, path: &Path) {
+        if self.versions.remove(path).is_some() {
+            self.notify("textDocument/didClose", json!({ "textDocument": { "uri": uri(path) } }));
+        }
+    }
+}
+
+/// Send `init`, hold everything else back until the server has answered
+/// it, then pass messages on as they come.
+async fn write_loop(
+    mut stdin: impl AsyncWrite + Unpin,
+    init: Value,
+    initialized: oneshot::Receiver<Value>,
+    mut queued: mpsc::UnboundedReceiver<Value>,
+    capabilities: Arc<Mutex<Capabilities>>,
+) {
+    if write_message(&mut stdin, &init).await.is_err() {
+        return;
+    }
+    let Ok(response) = initialized.await else { return };
+    let offered = &response["result"]["capabilities"];
+    *capabilities.lock().unwrap() = Capabilities {
+        encoding: match offered["positionEncoding"].as_str() {
+            Some("utf-32") => Encoding::Utf32,
+            Some("utf-8") => Encoding::Utf8,
+            _ => Encoding::Utf16,
+        },
+        completion: offered["completionProvider"].is_object(),
+        trigger_characters: offered["completionProvider"]["triggerCharacters"]
+            .as_array()
+            .map_or_else(Vec::new, |t| t.iter().filter_map(|c| c.as_str().map(String::from)).collect()),
+    };
+    let initialized = json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} });
+    if write_message(&mut stdin, &initialized).await.is_err() {
+        return;
+    }
+    while let Some(message) = queued.recv().await {
+        if write_message(&mut stdin, &message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_loop(
+    stdout: impl AsyncRead + Unpin,
+    server: String,
+    pending: Pending,
+    outgoing: mpsc::UnboundedSender<Value>,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let mut reader = BufReader::new(stdout);
+    let error = loop {
+        let message = match read_message(&mut reader).await {
+            Ok(message) => message,
+            Err(e) => break e.to_string(),
+        };
+        let method = message["method"].as_str();
+        match (method, message.get("id")) {
+            // A response to one of ours; an error one just drops the
+            // sender (initialize wants it whole).
+            (None, Some(id)) => {
+                let sender = id.as_i64().and_then(|id| pending.lock().unwrap().remove(&id).map(|sender| (id, sender)));
+                match sender {
+                    Some((0, sender)) => {
+                        let _ = sender.send(message);
+                    }
+                    Some((_, sender)) if message.get("error").is_none() => {
+                        let _ = sender.send(message["result"].clone());
+                    }
+                    _ => {}
+                }
+            }
+            // A request from the server: answer so it doesn't wait on us.
+            (Some(method), Some(id)) => {
+                let result = match method {
+                    "workspace/configuration" => {
+                        let items = message["params"]["items"].as_array().map_or(0, |items| items.len());
+                        Value::Array(vec![Value::Null; items])
+                    }
+                    _ => Value::Null,
+                };
+                let _ = outgoing.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+            }
+            (Some("textDocument/publishDiagnostics"), None) => {
+                let params = &message["params"];
+                let Some(path) = params["uri"].as_str().and_then(path_from_uri) else { continue };
+                let diagnostics = params["diagnostics"].as_array().map_or_else(Vec::new, |d| d.iter().map(parse_diagnostic).collect());
+                let _ = events.send(Event::Diagnostics { server: server.clone(), path, diagnostics });
+            }
+            _ => {}
+        }
+    };
+    let _ = events.send(Event::Stopped { server, error });
+}
+
+fn parse_diagnostic(value: &Value) -> ServerDiagnostic {
+    let position = |p: &Value| (p["line"].as_u64().unwrap_or(0) as usize, p["character"].as_u64().unwrap_or(0) as usize);
+    let code = match &value["code"] {
+        Value::String(code) => Some(code.clone()),
+        Value::Number(code) => Some(code.to_string()),
+        _ => None,
+    };
+    ServerDiagnostic {
+        start: position(&value["range"]["start"]),
+        end: position(&value["range"]["end"]),
+        severity: value["severity"].as_u64(),
+        message: value["message"].as_str().unwrap_or_default().to_string(),
+        source: value["source"].as_str().map(String::from),
+        code,
+    }
+}
+
+/// The items of a completion `result`: a list, or an object holding one.
+pub fn parse_completions(result: &Value) -> Completions {
+    let (items, incomplete) = match result {
+        Value::Array(items) => (items.as_slice(), false),
+        Value::Object(list) => (
+            list.get("items").and_then(Value::as_array).map_or(&[][..], |items| items.as_slice()),
+            list.get("isIncomplete").and_then(Value::as_bool).unwrap_or(false),
+        ),
+        _ => (&[][..], false),
+    };
+    let items = items
+        .iter()
+        .filter_map(|item| {
+            let label = item["label"].as_str()?.to_string();
+            // An InsertReplaceEdit inserts over `insert`, like VS Code does
+            // by default.
+            let edit = match &item["textEdit"] {
+                Value::Object(edit) => {
+                    let range = edit.get("range").or(edit.get("insert"))?;
+                    Some(parse_edit(range, edit.get("newText")?)?)
+                }
+                _ => None,
+            };
+            let additional_edits = item["additionalTextEdits"]
+                .as_array()
+                .map_or_else(Vec::new, |edits| edits.iter().filter_map(|e| parse_edit(&e["range"], &e["newText"])).collect());
+            Some(CompletionItem {
+                kind: item["kind"].as_u64(),
+                detail: item["detail"].as_str().map(String::from),
+                filter_text: item["filterText"].as_str().unwrap_or(&label).to_string(),
+                sort_text: item["sortText"].as_str().unwrap_or(&label).to_string(),
+                insert_text: item["insertText"].as_str().unwrap_or(&label).to_string(),
+                edit,
+                additional_edits,
+                label,
+            })
+        })
+        .collect();
+    Completions { items, incomplete }
+}
+
+fn parse_edit(range: &Value, text: &Value) -> Option<TextEdit> {
+    let position = |p: &Value| Some((p["line"].as_u64()? as usize, p["character"].as_u64()? as usize));
+    Some(TextEdit { start: position(&range["start"])?, end: position(&range["end"])?, text: text.as_str()?.to_string() })
+}
+
+/// A letter standing for a `CompletionItemKind` in the menu.
+pub fn kind_icon(kind: Option<u64>) -> &'static str {
+    match kind {
+        Some(2) => "m",
+        Some(3) => "f",
+        Some(4) => "c",
+        Some(5) | Some(10) => ".",
+        Some(6) => "v",
+        Some(7) => "C",
+        Some(8) => "I",
+        Some(9) => "M",
+        Some(11) => "u",
+        Some(12) => "=",
+        Some(13) => "E",
+        Some(14) => "k",
+        Some(15) => "s",
+        Some(16) => "#",
+        Some(17) => "F",
+        Some(18) => "&",
+        Some(19) => "/",
+        Some(20) => "e",
+        Some(21) => "K",
+        Some(22) => "S",
+        Some(23) => "!",
+        Some(24) => "+",
+        Some(25) => "T",
+        _ => "t",
+    }
+}
+
+async fn write_message(stdin: &mut (impl AsyncWrite + Unpin), message: &Value) -> Result<()> {
+    let body = message.to_string();
+    stdin.write_all(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+async fn read_message(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Result<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Err(anyhow!("the server exited"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| anyhow!("message without a Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// The nearest directory above `path` that looks like a project root, or
+/// its own directory.
+fn root_for(path: &Path) -> PathBuf {
+    let path = absolute(path);
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    dir.ancestors()
+        .find(|d| ROOT_MARKERS.iter().any(|marker| d.join(marker).exists()))
+        .unwrap_or(dir)
+        .to_path_buf()
+}
+
+pub fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// `file://` URI of `path`, with everything but unreserved characters and
+/// slashes percent-encoded.
+pub fn uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for &byte in absolute(path).to_string_lossy().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+fn path_from_uri(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (encoded[i], hex) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(bytes).ok()?))
+}
+
+/// The char index in `line` of server column `col`.
+pub fn char_col(line: &str, col: usize, encoding: Encoding) -> usize {
+    if encoding == Encoding::Utf32 {
+        return col.min(line.chars().count());
+    }
+    let mut units = 0;
+    for (i, c) in line.chars().enumerate() {
+        if units >= col {
+            return i;
+        }
+        units += match encoding {
+            Encoding::Utf8 => c.len_utf8(),
+            _ => c.len_utf16(),
+        };
+    }
+    line.chars().count()
+}
+
+/// The server column of char index `col` in `line`.
+pub fn server_col(line: &str, col: usize, encoding: Encoding) -> usize {
+    line.chars()
+        .take(col)
+        .map(|c| match encoding {
+            Encoding::Utf8 => c.len_utf8(),
+            Encoding::Utf16 => c.len_utf16(),
+            Encoding::Utf32 => 1,
+        })
+        .sum()
+}
+
+/// LSP `languageId` for a filetype (syntect's syntax names, mostly).
+pub fn language_id(filetype: &str) -> String {
+    match filetype {
+        "C++" => "cpp".to_string(),
+        "C#" => "csharp".to_string(),
+        "JavaScript (Babel)" => "javascript".to_string(),
+        "Bourne Again Shell (bash)" | "Shell-Unix-Generic" => "shellscript".to_string(),
+        "Objective-C" => "objective-c".to_string(),
+        _ => filetype.to_lowercase().replace(' ', ""),
+    }
+}
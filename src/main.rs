@@ -1,8 +1,12 @@
-use std::{io, time::Duration};
+use std::{fs, io::{self, IsTerminal, Read, Write}, process::ExitCode, time::Duration};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers, MouseEventKind, MouseButton},
+    cursor::{MoveTo, SetCursorStyle},
+    event::{
+        self, DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyModifiers, KeyboardEnhancementFlags,
+        MouseEventKind, MouseButton, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    terminal::{disable_raw_mode, enable_raw_mode, supports_keyboard_enhancement, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
@@ -15,252 +19,623 @@ use simplelog::{Config, WriteLogger};
 use std::fs::File;
 
 mod app;
+mod block;
+mod bookmarks;
+mod brackets;
+mod buffer;
+mod comment;
 mod config;
+mod crypto;
+mod cursors;
+mod diff;
+mod editor;
+mod doctor;
+mod fileio;
+mod git;
+mod fold;
+mod grep;
+mod history;
+mod indent;
+mod input;
+mod journal;
+mod frontmatter;
+mod graphics;
+mod keychain;
+mod keymap;
+mod links;
+mod lsp;
+mod lists;
+mod killring;
+mod large;
+mod markdown;
+mod notify;
+mod patch;
+mod profile;
+mod prefs;
+mod prose;
+mod recent;
+mod replace;
+mod semantic;
+mod session;
+mod shell;
+mod share;
+mod spell;
+mod stats;
+mod swap;
 mod ui;
 mod ai;
+mod cells;
+mod chat;
+mod table;
+mod theme;
+mod vim;
+mod wrap;
 
-use app::{App, AppMode};
+use app::{App, AppMode, WELCOME_ACTIONS};
+use keymap::Action;
 
-use tui_textarea::{TextArea, Input};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Optional file to open
+    /// Optional file to open; `-` reads the text from stdin. `FILE:LINE[:COL]`
+    /// and a leading `+LINE[:COL]` argument open it at that position.
     filename: Option<String>,
 
-    /// Reset configuration (delete config.json)
+    /// Write the buffer to stdout on exit, to use neuronano in a pipeline
+    /// (the editor itself is drawn on /dev/tty)
+    #[arg(long)]
+    stdout: bool,
+
+    /// Follow the file like `tail -f` (read-only, auto-reloads appended lines)
+    #[arg(long)]
+    tail: bool,
+
+    /// Regex to highlight in tail mode (e.g. "ERROR|WARN")
+    #[arg(long, requires = "tail")]
+    filter: Option<String>,
+
+    /// Rewrite FILE with this AI instruction and exit without opening the
+    /// editor. Exit status: 0 when done, 1 on errors; with --dry-run, 0 if
+    /// nothing would change and 3 if the printed diff isn't empty.
+    #[arg(long, value_name = "INSTRUCTION", requires = "filename", conflicts_with_all = ["tail", "share", "join", "follow", "stdout"])]
+    apply: Option<String>,
+
+    /// With --apply, print the change as a diff instead of saving it
+    #[arg(long, requires = "apply")]
+    dry_run: bool,
+
+    /// Three-way merge BASE, OURS and THEIRS into FILE, hunk by hunk. As a
+    /// git mergetool: `neuronano --merge "$BASE" "$LOCAL" "$REMOTE" "$MERGED"`.
+    /// Exits with 1 while conflict markers are left in FILE.
+    #[arg(long, num_args = 3, value_names = ["BASE", "OURS", "THEIRS"], requires = "filename",
+        conflicts_with_all = ["tail", "share", "join", "follow", "apply"])]
+    merge: Option<Vec<String>>,
+
+    /// Open the file read-only; keys only navigate (toggle with Alt+K)
+    #[arg(long)]
+    view: bool,
+
+    /// For secrets: no autosave, swap files, backups, input history or log
+    /// file, and the text is wiped from memory on exit
+    #[arg(long, conflicts_with_all = ["share", "join", "follow"])]
+    secure: bool,
+
+    /// Print the history of a session journal (see `journal` in the
+    /// config): edits, saves and AI answers with their diffs
+    #[arg(long, value_name = "JOURNAL", conflicts_with_all = ["filename", "apply", "merge"])]
+    replay: Option<String>,
+
+    /// With --replay, print the file as it was after the first N entries
+    #[arg(long, value_name = "N", requires = "replay")]
+    at: Option<usize>,
+
+    /// Edit the AI rewrite instructions (~/.config/neuronano/system_prompt.txt,
+    /// created from the built-in ones). `{filename}`, `{instruction}` and
+    /// `{language}` are filled in.
+    #[arg(long, conflicts_with_all = ["filename", "apply", "merge", "replay", "join", "follow"])]
+    edit_prompt: bool,
+
+    /// Search the project in the current directory by meaning: list the
+    /// places most related to QUESTION and have the AI say where it's
+    /// handled. Files are embedded (and re-embedded when changed) on the way.
+    #[arg(long, value_name = "QUESTION", conflicts_with_all = ["filename", "apply", "merge", "replay", "edit_prompt", "join", "follow", "share"])]
+    ask: Option<String>,
+
+    /// Put the built-in AI instructions back in the template file
+    #[arg(long)]
+    reset_prompt: bool,
+
+    /// Check the setup (config, API key and connection, terminal, outside
+    /// tools) and print a report. Exits with 1 if a check failed.
+    #[arg(long, conflicts_with_all = ["filename", "apply", "merge", "replay", "edit_prompt", "ask", "continue_session"])]
+    doctor: bool,
+
+    /// Reset configuration (delete ~/.config/neuronano/config.json)
     #[arg(long)]
     reset: bool,
+
+    /// Reopen the files of the last session, at the same cursor positions
+    #[arg(long = "continue", conflicts_with_all = ["filename", "apply", "merge", "replay", "edit_prompt", "ask", "tail", "share", "join", "follow", "secure"])]
+    continue_session: bool,
+
+    /// Experimental: share the buffer for pair editing, listening on ADDR: a
+    /// port (e.g. 7878) listens on this machine only, HOST:PORT on that
+    /// interface. Joiners need the session token it shows. Traffic isn't
+    /// encrypted; across machines, use an SSH tunnel.
+    #[arg(long, value_name = "ADDR", conflicts_with_all = ["tail", "join", "follow"])]
+    share: Option<String>,
+
+    /// Experimental: join a buffer shared with --share at ADDR
+    #[arg(long, value_name = "ADDR", requires = "token", conflicts_with_all = ["tail", "filename", "follow"])]
+    join: Option<String>,
+
+    /// Experimental: watch a buffer shared with --share at ADDR, read-only,
+    /// following the host's cursor
+    #[arg(long, value_name = "ADDR", requires = "token", conflicts_with_all = ["tail", "filename"])]
+    follow: Option<String>,
+
+    /// Session token for --join and --follow (the host shows it), or the one
+    /// --share uses instead of a random one
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    let _ = WriteLogger::init(
-        LevelFilter::Info,
-        Config::default(),
-        File::create("neuronano.log").unwrap_or_else(|_| File::create("/dev/null").unwrap()),
-    );
+async fn main() -> Result<ExitCode> {
+    let (args, mut position) = take_position_arg(std::env::args_os().collect());
+    let mut cli = Cli::parse_from(args);
 
-    let cli = Cli::parse();
+    // Initialize logging (the log can name files and quote errors, so not
+    // in secure mode)
+    if !cli.secure {
+        let _ = WriteLogger::init(
+            LevelFilter::Info,
+            Config::default(),
+            File::create("neuronano.log").unwrap_or_else(|_| File::create("/dev/null").unwrap()),
+        );
+    }
 
     if cli.reset {
-        if std::fs::remove_file("config.json").is_ok() {
-            log::info!("Configuration reset: config.json deleted.");
+        let path = config::Config::path();
+        if config::Config::reset() {
+            log::info!("Configuration reset: {} deleted.", path.display());
             println!("Configuration reset.");
-            return Ok(());
+            return Ok(ExitCode::SUCCESS);
         } else {
-            log::warn!("Failed to delete config.json (maybe it didn't exist).");
+            log::warn!("Failed to delete {} (maybe it didn't exist).", path.display());
         }
     }
 
-    // Setup terminal
+    if cli.reset_prompt {
+        return Ok(match ai::write_default_system_prompt() {
+            Ok(path) => {
+                println!("System prompt reset: {}", path.display());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("neuronano: {}", e);
+                ExitCode::FAILURE
+            }
+        });
+    }
+    if cli.edit_prompt {
+        let path = ai::system_prompt_path();
+        if !path.exists() {
+            ai::write_default_system_prompt()?;
+        }
+        cli.filename = Some(path.to_string_lossy().into_owned());
+    }
+
+    if let Some(path) = &cli.replay {
+        return Ok(match journal::replay(std::path::Path::new(path), cli.at) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("neuronano: {}: {}", path, e);
+                ExitCode::FAILURE
+            }
+        });
+    }
+
+    if let Some(instruction) = &cli.apply {
+        return Ok(apply(cli.filename.unwrap_or_default(), instruction, cli.dry_run).await);
+    }
+
+    if let Some(question) = &cli.ask {
+        return Ok(ask(question).await);
+    }
+
+    if cli.doctor {
+        return Ok(if doctor::run().await { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+
+    // `-` means piped text; keys then come from /dev/tty (crossterm does that itself).
+    let piped = if cli.filename.as_deref() == Some("-") {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Some(fileio::decode(&bytes))
+    } else {
+        None
+    };
+
+    // Setup terminal; with stdout in a pipe the UI goes to the terminal directly.
+    let interactive_stdout = io::stdout().is_terminal();
+    let mut out: Box<dyn Write> = if interactive_stdout {
+        Box::new(io::stdout())
+    } else {
+        Box::new(fs::OpenOptions::new().write(true).open("/dev/tty")?)
+    };
     enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
+    execute!(out, EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
+    let backend = CrosstermBackend::new(out);
     let mut terminal = Terminal::new(backend)?;
 
+    if let Some(spec) = cli.filename.take() {
+        let (file, at) = if piped.is_some() { (spec, None) } else { fileio::split_position(&spec) };
+        cli.filename = Some(file);
+        position = position.or(at);
+    }
+
+    let welcome = cli.filename.is_none()
+        && piped.is_none()
+        && cli.merge.is_none()
+        && cli.share.is_none()
+        && cli.join.is_none()
+        && cli.follow.is_none()
+        && !cli.tail
+        && !cli.continue_session;
     // Create app
-    let mut app = App::new(cli.filename);
+    let mut app = match piped {
+        Some(decoded) => App::with_contents(None, Some(decoded)),
+        None => App::new(cli.filename),
+    };
+    // The query is written to stdout, so skip it when that's a pipe.
+    if app.config.kitty_keyboard && interactive_stdout && supports_keyboard_enhancement().unwrap_or(false) {
+        execute!(
+            terminal.backend_mut(),
+            PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS
+            )
+        )?;
+        app.keyboard_enhanced = true;
+        app.apply_profile();
+    }
+    if let Some(paths) = &cli.merge {
+        let read = |path: &String| fileio::read_text(std::path::Path::new(path)).map(|d| d.text);
+        match (read(&paths[0]), read(&paths[1]), read(&paths[2])) {
+            (Ok(base), Ok(ours), Ok(theirs)) => app.start_merge(&base, &ours, &theirs),
+            (Err(e), ..) | (_, Err(e), _) | (.., Err(e)) => app.set_status(&format!("Can't merge: {}", e)),
+        }
+    }
+    if cli.edit_prompt && app.config.ai_system_prompt.is_some() {
+        app.set_status("ai_system_prompt in config.json overrides this template");
+    }
+    if cli.secure {
+        app.enable_secure();
+    }
+    app.start_journal();
+    app.check_swap();
+    app.lsp_open();
+    if welcome && !app.modes().contains(&AppMode::Setup) {
+        app.open_welcome();
+    } else {
+        app.remember_file();
+    }
+    if cli.view {
+        app.read_only = true;
+        app.set_status("View mode: editing disabled");
+    }
+    if cli.tail {
+        app.start_tail(cli.filter.as_deref());
+    }
+    let name = std::env::var("USER").unwrap_or_else(|_| "anonymous".to_string());
+    if let Some(address) = &cli.share {
+        // The session starts from the text with every edit so far passed on.
+        app.sync_buffer();
+        let text = app.buffer.editor.text().to_string();
+        let hosted = match cli.token.clone().map_or_else(share::new_token, Ok) {
+            Ok(token) => share::Session::host(address, &name, token, &text).await,
+            Err(e) => Err(e),
+        };
+        match hosted {
+            Ok(session) => {
+                let token = session.token().unwrap_or_default();
+                app.set_status(&format!("Sharing on {}, token {}", session.address, token));
+                app.share = Some(session);
+            }
+            Err(e) => app.set_status(&format!("Can't share on {}: {}", address, e)),
+        }
+    } else if let Some(address) = cli.join.as_ref().or(cli.follow.as_ref()) {
+        let observer = cli.follow.is_some();
+        let token = cli.token.as_deref().unwrap_or_default();
+        match share::Session::join(address, &name, token, observer).await {
+            Ok(session) => {
+                let verb = if observer { "Watching" } else { "Joined" };
+                app.set_status(&format!("{} {}", verb, session.address));
+                app.read_only = observer;
+                app.locked = observer;
+                app.share = Some(session);
+            }
+            Err(e) => app.set_status(&format!("Can't join {}: {}", address, e)),
+        }
+    }
+
+    if cli.continue_session {
+        app.restore_session();
+    }
+    if let Some((line, col)) = position {
+        // Draw once so the view knows its height and can center the line.
+        terminal.draw(|f| ui::ui(f, &mut app))?;
+        app.start_at(line, col);
+    }
 
     // Run app
     let res = run_app(&mut terminal, &mut app).await;
 
     // Restore terminal
+    if app.keyboard_enhanced {
+        execute!(terminal.backend_mut(), PopKeyboardEnhancementFlags)?;
+    }
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
-        DisableMouseCapture
+        DisableMouseCapture,
+        DisableFocusChange,
+        SetCursorStyle::DefaultUserShape
     )?;
     terminal.show_cursor()?;
 
-    if let Err(err) = res {
-        println!("{:?}", err);
+    match res {
+        Ok(()) => app.remove_swaps(),
+        Err(err) => eprintln!("{:?}", err),
+    }
+    app.save_session();
+
+    if cli.stdout {
+        let bytes = fileio::encode(&app.file_contents(), app.encoding, app.bom)?;
+        io::stdout().write_all(&bytes)?;
+        io::stdout().flush()?;
+    }
+    // git's mergetool takes a failure as "not resolved".
+    let unresolved = cli.merge.is_some()
+        && fs::read_to_string(&app.filename).map_or(true, |text| {
+            diff::has_conflict_markers(&text.lines().map(|l| l.to_string()).collect::<Vec<_>>())
+        });
+    if app.secure {
+        app.wipe();
+    }
+
+    Ok(if unresolved { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+/// `--apply`: one AI rewrite of `filename`, no UI. Errors go to stderr.
+async fn apply(filename: String, instruction: &str, dry_run: bool) -> ExitCode {
+    let piped = filename == "-";
+    if !piped && !std::path::Path::new(&filename).is_file() {
+        eprintln!("neuronano: {}: no such file", filename);
+        return ExitCode::FAILURE;
+    }
+    let mut app = if piped {
+        let mut bytes = Vec::new();
+        if let Err(e) = io::stdin().read_to_end(&mut bytes) {
+            eprintln!("neuronano: {}", e);
+            return ExitCode::FAILURE;
+        }
+        App::with_contents(None, Some(fileio::decode(&bytes)))
+    } else {
+        App::new(Some(filename.clone()))
+    };
+    if app.read_only && !piped {
+        eprintln!("neuronano: {}: {}", filename, app.status_message.as_deref().unwrap_or("can't be changed"));
+        return ExitCode::FAILURE;
+    }
+    let (diff, summary) = match app.apply_instruction(instruction).await {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("neuronano: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!("{}", summary);
+    if dry_run {
+        print!("{}", diff);
+        return if diff.is_empty() { ExitCode::SUCCESS } else { ExitCode::from(3) };
+    }
+    // A piped buffer has nowhere to be saved; it goes back to stdout.
+    let result = if piped {
+        fileio::encode(&app.file_contents(), app.encoding, app.bom).and_then(|bytes| Ok(io::stdout().write_all(&bytes)?))
+    } else if diff.is_empty() {
+        Ok(())
+    } else {
+        if let Err(e) = app.format().await {
+            eprintln!("neuronano: saving unformatted: {}", e);
+        }
+        app.save_file()
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("neuronano: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn ask(question: &str) -> ExitCode {
+    let root = match std::env::current_dir() {
+        Ok(root) => root,
+        Err(e) => {
+            eprintln!("neuronano: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut app = App::new(None);
+    eprintln!("Searching {}…", root.display());
+    let (hits, answer) = match app.ask_codebase(&root, question).await {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("neuronano: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut out = io::stdout().lock();
+    for hit in &hits {
+        let _ = writeln!(out, "{}:{}-{}  ({:.2})", hit.file, hit.start, hit.end, hit.score);
     }
+    let _ = writeln!(out, "\n{}", answer.text.trim_end());
+    eprintln!("{}", app.record_usage(&answer));
+    ExitCode::SUCCESS
+}
 
-    Ok(())
+/// Pull a vi-style `+LINE` or `+LINE:COL` argument out of the command line.
+fn take_position_arg(mut args: Vec<std::ffi::OsString>) -> (Vec<std::ffi::OsString>, Option<(usize, usize)>) {
+    let parse = |arg: &std::ffi::OsString| {
+        let (line, col) = match arg.to_str()?.strip_prefix('+')?.split_once(':') {
+            Some((line, col)) => (line.parse().ok()?, col.parse().ok()?),
+            None => (arg.to_str()?[1..].parse().ok()?, 1),
+        };
+        Some((line, col))
+    };
+    match args.iter().skip(1).position(|arg| parse(arg).is_some()) {
+        Some(i) => {
+            let position = parse(&args.remove(i + 1));
+            (args, position)
+        }
+        None => (args, None),
+    }
 }
 
-async fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mut App<'_>) -> Result<()> {
+async fn run_app(terminal: &mut Terminal<CrosstermBackend<Box<dyn Write>>>, app: &mut App<'_>) -> Result<()> {
     loop {
+        app.sync_buffer();
+        app.poll_share();
+        app.poll_tail();
+        app.poll_loading();
+        app.poll_grep();
+        app.tick_autosave();
+        app.poll_autosave();
+        app.tick_swap();
+        app.poll_git();
+        app.update_git_marks(false);
+        app.check_disk();
+        app.poll_link_checks();
+        app.poll_chat();
+        app.poll_completion();
+        app.poll_explanation();
+        app.poll_commit_message();
+        app.poll_command();
+        app.poll_format();
+        app.sync_lsp(false);
+        app.poll_lsp();
+        app.poll_preview();
+        app.poll_ai_progress();
+        app.check_idle();
+
         // Check for AI response
         if let Some(rx) = &mut app.ai_response_rx {
             if let Ok(response) = rx.try_recv() {
-                app.textarea = TextArea::from(response.lines().map(|s| s.to_string()));
-                app.set_processing(false);
+                app.apply_ai_response(response);
             }
         }
 
+        // Check for cell execution results
+        if let Some(rx) = &mut app.cell_result_rx {
+            if let Ok((text, success)) = rx.try_recv() {
+                app.cell_output = Some(app::CellOutput { text, running: false, success });
+                app.show_cell_output = true;
+                app.alert("cell", if success { "Cell finished" } else { "Cell failed" });
+            }
+        }
+
+        if std::mem::take(&mut app.repaint) {
+            if let Some(protocol) = app.graphics {
+                terminal.backend_mut().write_all(graphics::clear_images(protocol))?;
+            }
+            terminal.clear()?;
+        }
+        let shape = app.cursor_shape;
         terminal.draw(|f| ui::ui(f, app))?;
+        if app.cursor_shape != shape {
+            if let Some(style) = app.cursor_shape.style() {
+                execute!(terminal.backend_mut(), style)?;
+            }
+        }
+        // Images bypass ratatui: written over the popup after the frame.
+        if let Some(image) = app.take_preview_image() {
+            let area = app.preview_area;
+            let backend = terminal.backend_mut();
+            execute!(backend, MoveTo(area.x, area.y))?;
+            backend.write_all(&image)?;
+            backend.flush()?;
+        }
+        if let Some(alerts) = app.take_alerts() {
+            let backend = terminal.backend_mut();
+            backend.write_all(&alerts)?;
+            backend.flush()?;
+        }
 
         if event::poll(Duration::from_millis(100))? {
             match event::read()? {
+                Event::Key(key) if app.mode() == AppMode::Locked => {
+                    app.last_input = std::time::Instant::now();
+                    app.lock_key(key);
+                }
                 Event::Key(key) => {
-                    match app.mode {
-                        AppMode::Normal => match (key.code, key.modifiers) {
-                            (KeyCode::Char('x'), KeyModifiers::CONTROL) => {
-                                if app.is_modified {
-                                    app.mode = AppMode::ConfirmQuit;
-                                } else {
-                                    app.quit();
-                                }
-                            }
-                            (KeyCode::Char('p'), KeyModifiers::CONTROL) => {
-                                app.enter_prompt_mode();
-                            }
-                            (KeyCode::Char('k'), KeyModifiers::CONTROL) => {
-                                app.textarea.cut();
-                                app.mark_dirty();
-                            }
-                            (KeyCode::Char('u'), KeyModifiers::CONTROL) => {
-                                app.textarea.paste();
-                                app.mark_dirty();
-                            }
-                            (KeyCode::Char('o'), KeyModifiers::CONTROL) => {
-                                if app.filename != "[No Name]" {
-                                    if let Err(e) = app.save_file() {
-                                        app.set_status(&format!("Error: {}", e));
-                                    }
-                                } else {
-                                    app.prompt_save_as();
-                                }
-                            }
-                            (KeyCode::Char('f'), KeyModifiers::CONTROL) => {
-                                app.enter_search_mode();
-                            }
-                            _ => {
-                                if app.textarea.input(key) {
-                                    app.mark_dirty();
-                                }
-                            }
-                        },
-                        AppMode::Prompting => match key.code {
-                            KeyCode::Esc => {
-                                app.exit_prompt_mode();
-                            }
-                            KeyCode::Enter => {
-                                let api_key = app.config.api_key.clone();
-                                let current_code = app.textarea.lines().join("\n");
-                                let filename = app.filename.clone();
-                                let prompt = app.prompt_textarea.lines().join("\n");
-                                let tx = app.ai_response_tx.clone();
-
-                                app.set_processing(true);
-
-                                tokio::spawn(async move {
-                                    let result = ai::request_gemini(api_key, current_code, filename, prompt).await;
-                                    match result {
-                                        Ok(content) => {
-                                            log::info!("Response received successfully.");
-                                            let _ = tx.send(content).await;
-                                        }
-                                        Err(e) => {
-                                            log::error!("Gemini Request Failed: {}", e);
-                                            let _ = tx.send(format!("Error: {}", e)).await;
-                                        }
-                                    }
-                                });
-                            }
-                            _ => {
-                                app.prompt_textarea.input(key);
-                            }
-                        },
-                        AppMode::Setup => match key.code {
-                            KeyCode::Esc => app.quit(),
-                            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit(),
-                            KeyCode::Enter => app.save_config(),
-                            _ => {
-                                app.setup_textarea.input(key);
-                            }
-                        },
-                        AppMode::Processing => {
-                            // Ignore input while processing, or allow quit
-                            if let KeyCode::Char('q') = key.code {
-                                if key.modifiers.contains(KeyModifiers::CONTROL) {
-                                    app.quit();
-                                }
-                            }
-                        },
-                        AppMode::Search => match key.code {
-                            KeyCode::Esc => app.exit_search_mode(),
-                            KeyCode::Enter => {
-                                if let Some(query) = app.search_textarea.lines().first() {
-                                    let query = query.to_string();
-                                    // Simple linear search
-                                    let lines = app.textarea.lines();
-                                    for (i, line) in lines.iter().enumerate() {
-                                        if let Some(col) = line.find(&query) {
-                                            app.textarea.move_cursor(tui_textarea::CursorMove::Jump(i as u16, col as u16));
-                                            break;
-                                        }
-                                    }
-                                }
-                                app.exit_search_mode();
-                            }
-                            _ => {
-                                app.search_textarea.input(key);
-                            }
-                        },
-                        AppMode::SaveAs => match key.code {
-                            KeyCode::Esc => {
-                                app.mode = AppMode::Normal;
-                            }
-                            KeyCode::Enter => {
-                                if let Some(name) = app.filename_input.lines().first() {
-                                    if !name.trim().is_empty() {
-                                        app.filename = name.trim().to_string();
-                                        if let Err(e) = app.save_file() {
-                                            app.set_status(&format!("Error: {}", e));
-                                        }
-                                        app.mode = AppMode::Normal;
-                                    }
+                    app.last_input = std::time::Instant::now();
+                    app.record_key(key);
+                    handle_key(app, key);
+                    if let Some((keys, times)) = app.take_macro_run() {
+                        'replay: for _ in 0..times {
+                            for &key in &keys {
+                                if app.should_quit {
+                                    break 'replay;
                                 }
+                                handle_key(app, key);
                             }
-                            _ => {
-                                app.filename_input.input(key);
-                            }
-                        },
-                        AppMode::ConfirmQuit => match key.code {
-                            KeyCode::Char('y') | KeyCode::Char('Y') => {
-                                // Try to save first
-                                if app.filename == "[No Name]" {
-                                    app.prompt_save_as();
-                                } else {
-                                    if let Err(e) = app.save_file() {
-                                        app.set_status(&format!("Error saving: {}", e));
-                                        app.mode = AppMode::Normal; // Go back to fix
-                                    } else {
-                                        app.quit();
-                                    }
-                                }
-                            }
-                            KeyCode::Char('n') | KeyCode::Char('N') => {
-                                app.quit(); // Quit without saving
-                            }
-                            KeyCode::Esc => {
-                                app.mode = AppMode::Normal;
-                            }
-                            _ => {}
                         }
+                        app.end_macro_run();
                     }
                 }
-                Event::Mouse(mouse) => {
-                    if app.mode == AppMode::Normal {
-                        match mouse.kind {
-                            MouseEventKind::ScrollDown => {
-                                app.textarea.scroll((1, 0));
-                            }
-                            MouseEventKind::ScrollUp => {
-                                app.textarea.scroll((-1, 0));
-                            }
-                            MouseEventKind::Down(MouseButton::Left) => {
-                                app.textarea.input(Input::from(mouse));
+                Event::Mouse(_) if app.mode() == AppMode::Locked => {}
+                Event::Mouse(mouse) if app.mode() == AppMode::Normal => {
+                    app.last_input = std::time::Instant::now();
+                    match mouse.kind {
+                        MouseEventKind::ScrollDown => {
+                            app.scroll_editor(1);
+                        }
+                        MouseEventKind::ScrollUp => {
+                            app.scroll_editor(-1);
+                        }
+                        MouseEventKind::Down(MouseButton::Left) if !app.table_view && mouse.modifiers.contains(KeyModifiers::ALT) => {
+                            let (row, col) = ui::editor_position(app, mouse.column, mouse.row);
+                            app.toggle_cursor(row, col);
+                        }
+                        MouseEventKind::Down(MouseButton::Left) if !app.table_view => {
+                            let (row, col) = ui::editor_position(app, mouse.column, mouse.row);
+                            app.cursors.clear();
+                            app.block = None;
+                            app.buffer.editor.cancel_selection();
+                            app.buffer.editor.jump((row, col));
+                        }
+                        MouseEventKind::Drag(MouseButton::Left) if !app.table_view => {
+                            let (row, col) = ui::editor_position(app, mouse.column, mouse.row);
+                            if !app.buffer.editor.is_selecting() {
+                                app.buffer.editor.start_selection();
                             }
-                            _ => {}
+                            app.buffer.editor.jump((row, col));
                         }
+                        _ => {}
+                    }
+                }
+                Event::FocusGained => app.focused = Some(true),
+                Event::FocusLost => app.focused = Some(false),
+                // Redraw at the new size right away; the layout adapts to it.
+                Event::Resize(_, _) => {
+                    terminal.autoresize()?;
+                    if let Some(preview) = app.preview.as_mut().filter(|p| p.drawn) {
+                        preview.drawn = false;
+                        app.repaint = true;
                     }
                 }
                 _ => {}
@@ -271,4 +646,423 @@ async fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, app: &mu
             return Ok(());
         }
     }
-}
\ No newline at end of file
+}
+
+/// Keys that move around without modifying the buffer.
+/// A key press, by mode. Macros are replayed through here too.
+fn handle_key(app: &mut App<'_>, key: KeyEvent) {
+    // The completion menu and ghost text claim Tab, Esc and such while shown.
+    if app.mode() == AppMode::Normal && app.completion_key(key) {
+        return;
+    }
+    if app.mode() == AppMode::Normal && app.ghost_key(key) {
+        return;
+    }
+    // The vim layer gets first pick of editor keys when enabled.
+    if app.mode() == AppMode::Normal && vim::handle_key(app, key) {
+        return;
+    }
+    match app.mode() {
+        AppMode::Normal => match app.next_action(&key) {
+            Some(action) if app.dispatch(action) => {}
+            None if app.digit_argument(&key) => {}
+            _ if app.read_only => {
+                if is_navigation_key(&key) {
+                    if !app.move_by_screen_row(key) {
+                        app.buffer.editor.input(key);
+                    }
+                } else {
+                    app.set_status("Buffer is read-only");
+                }
+            }
+            _ => {
+                app.type_key(key);
+            }
+        },
+        AppMode::Prompting => match key.code {
+            KeyCode::Esc if app.prompt_pick.is_some() => app.prompt_pick = None,
+            KeyCode::Esc => {
+                app.exit_prompt_mode();
+            }
+            KeyCode::Enter if app.prompt_pick.is_some() => {
+                if let Some(prompt) = app.picked_prompt(&app.prompt_input.text()) {
+                    app.submit_prompt(prompt);
+                }
+            }
+            KeyCode::Enter => {
+                if let Some(prompt) = app.prompt_input.submit() {
+                    app.submit_prompt(prompt);
+                }
+            }
+            KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => app.move_prompt_pick(1),
+            KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::CONTROL) => app.move_prompt_pick(-1),
+            KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                match app.prompt_input.history().and_then(|h| h.last()) {
+                    Some(last) => {
+                        let last = last.to_string();
+                        app.submit_prompt(last);
+                    }
+                    None => app.set_status("No previous prompt"),
+                }
+            }
+            _ => {
+                app.prompt_input.handle_key(key);
+            }
+        },
+        AppMode::Setup => match key.code {
+            KeyCode::Esc => app.quit(),
+            KeyCode::Tab if keychain::is_available() => {
+                app.config.use_keychain = !app.config.use_keychain;
+            }
+            KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit(),
+            KeyCode::Enter => app.save_config(),
+            _ => {
+                app.setup_input.handle_key(key);
+            }
+        },
+        AppMode::Processing => {
+            // Ignore input while processing; Esc cancels, ^Q quits
+            match key.code {
+                KeyCode::Esc => app.cancel_ai_request(),
+                KeyCode::Char('q') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit(),
+                _ => {}
+            }
+        },
+        AppMode::Search => match key.code {
+            KeyCode::Esc => app.exit_search_mode(),
+            KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::ALT) => app.toggle_search_in_selection(),
+            KeyCode::Enter => {
+                if let Some(query) = app.search_input.submit() {
+                    app.exit_search_mode();
+                    app.search_all(query);
+                }
+            }
+            _ => {
+                app.search_input.handle_key(key);
+            }
+        },
+        AppMode::SaveAs => match key.code {
+            KeyCode::Esc => {
+                app.pop_mode();
+            }
+            KeyCode::Enter => {
+                if let Some(name) = app.filename_input.submit() {
+                    app.filename = name.trim().to_string();
+                    app.apply_profile();
+                    app.pop_mode();
+                    // Save As was opened from the quit confirmation.
+                    let quitting = app.mode() == AppMode::ConfirmQuit;
+                    match if quitting { app.save_file() } else { app.format_and_save() } {
+                        Err(e) => app.set_status(&format!("Error: {}", e)),
+                        Ok(()) if quitting => app.request_quit(),
+                        Ok(()) => {}
+                    }
+                }
+            }
+            _ => {
+                app.filename_input.handle_key(key);
+            }
+        },
+        AppMode::ConfirmQuit => match key.code {
+            KeyCode::Char('y') | KeyCode::Char('Y') => {
+                // Try to save first
+                if app.filename == "[No Name]" {
+                    app.prompt_save_as();
+                } else if app.changed_on_disk() {
+                    // Sort that out first, then quit again.
+                    app.disk_diff = None;
+                    app.set_mode(AppMode::FileChanged);
+                } else {
+                    if let Err(e) = app.save_file() {
+                        app.set_status(&format!("Error saving: {}", e));
+                        app.cancel_quit(); // Go back to fix
+                    } else {
+                        app.request_quit();
+                    }
+                }
+            }
+            KeyCode::Char('n') | KeyCode::Char('N') => {
+                app.discard_for_quit(); // Quit without saving this one
+            }
+            KeyCode::Esc => {
+                app.cancel_quit();
+            }
+            _ => {}
+        }
+        AppMode::FrontMatter => {
+            let Some(form) = &mut app.front_matter_form else { return };
+            match key.code {
+                KeyCode::Esc => {
+                    app.front_matter_form = None;
+                    app.pop_mode();
+                }
+                KeyCode::Enter => app.apply_front_matter_form(),
+                KeyCode::Tab | KeyCode::Down => form.focus = (form.focus + 1) % form.inputs.len(),
+                KeyCode::BackTab | KeyCode::Up => {
+                    form.focus = (form.focus + form.inputs.len() - 1) % form.inputs.len();
+                }
+                _ => {
+                    form.inputs[form.focus].handle_key(key);
+                }
+            }
+        }
+        AppMode::Chat => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Enter => {
+                if let Some(question) = app.chat_input.submit() {
+                    app.send_chat(question);
+                }
+            }
+            KeyCode::Char('y') if key.modifiers.contains(KeyModifiers::CONTROL) => app.apply_chat_code(),
+            KeyCode::PageUp => app.chat_scroll += 5,
+            KeyCode::PageDown => app.chat_scroll = app.chat_scroll.saturating_sub(5),
+            _ if app.keymap.action_for(&key) == Some(Action::Chat) => app.close_chat(),
+            _ if app.keymap.action_for(&key) == Some(Action::WidenChat) => app.resize_chat(5),
+            _ if app.keymap.action_for(&key) == Some(Action::NarrowChat) => app.resize_chat(-5),
+            _ => app.chat_input.handle_key(key),
+        }
+        AppMode::DiagnosticDetails => match key.code {
+            KeyCode::Esc | KeyCode::Enter => app.pop_mode(),
+            _ => {}
+        }
+        AppMode::Explain => match key.code {
+            KeyCode::Up => app.explain_scroll = app.explain_scroll.saturating_sub(1),
+            KeyCode::Down => app.explain_scroll += 1,
+            KeyCode::PageUp => app.explain_scroll = app.explain_scroll.saturating_sub(10),
+            KeyCode::PageDown => app.explain_scroll += 10,
+            KeyCode::Esc | KeyCode::Enter => app.close_explanation(),
+            _ => {}
+        }
+        AppMode::Preview => match key.code {
+            KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q') => app.close_preview(),
+            _ => {}
+        }
+        AppMode::ApplyPatch | AppMode::ExportPatch => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Enter => {
+                if let Some(path) = app.patch_input.submit() {
+                    let export = app.mode() == AppMode::ExportPatch;
+                    app.pop_mode();
+                    let path = path.trim();
+                    let result = if export { app.export_patch(path) } else { app.apply_patch(path) };
+                    if let Err(e) = result {
+                        app.set_status(&format!("Patch failed: {}", e));
+                    }
+                }
+            }
+            _ => app.patch_input.handle_key(key),
+        },
+        AppMode::Merge => match key.code {
+            KeyCode::Left | KeyCode::Char('o') => app.merge_take(Some(diff::Take::Ours)),
+            KeyCode::Right | KeyCode::Char('t') => app.merge_take(Some(diff::Take::Theirs)),
+            KeyCode::Char('b') => app.merge_take(Some(diff::Take::Both)),
+            KeyCode::Char('u') => app.merge_take(None),
+            KeyCode::Up | KeyCode::Char('p') => app.move_merge_hunk(-1),
+            KeyCode::Down | KeyCode::Char('n') => app.move_merge_hunk(1),
+            KeyCode::Char('e') | KeyCode::Esc => app.edit_merge(),
+            KeyCode::Enter => app.finish_merge(),
+            _ => {}
+        }
+        AppMode::AiSnapshots => match key.code {
+            KeyCode::Up => app.move_ai_snapshot(-1),
+            KeyCode::Down => app.move_ai_snapshot(1),
+            KeyCode::Enter => app.restore_selected_ai_snapshot(),
+            KeyCode::Esc => app.pop_mode(),
+            _ => {}
+        }
+        AppMode::Locked => app.lock_key(key),
+        AppMode::Welcome => match key.code {
+            _ if app.keymap.action_for(&key) == Some(Action::Quit) => app.quit(),
+            KeyCode::Up => app.move_welcome_selection(-1),
+            KeyCode::Down => app.move_welcome_selection(1),
+            KeyCode::Enter => app.choose_welcome(app.welcome_selected),
+            KeyCode::Char(c @ '1'..='9') if (c as usize - '0' as usize) <= app.recent_files.len() => {
+                app.choose_welcome(c as usize - '1' as usize)
+            }
+            KeyCode::Char(c) => {
+                if let Some(i) = WELCOME_ACTIONS.iter().position(|(key, _)| *key == c) {
+                    app.choose_welcome(app.recent_files.len() + i);
+                }
+            }
+            KeyCode::Esc => app.remove_mode(AppMode::Welcome),
+            _ => {}
+        }
+        AppMode::OpenFile => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Enter => {
+                if let Some(name) = app.filename_input.submit() {
+                    app.remove_mode(AppMode::OpenFile);
+                    app.remove_mode(AppMode::Welcome);
+                    app.open_buffer(name.trim());
+                }
+            }
+            _ => app.filename_input.handle_key(key),
+        }
+        AppMode::SearchResults => match key.code {
+            KeyCode::Up => app.move_search_selection(-1),
+            KeyCode::Down => app.move_search_selection(1),
+            KeyCode::PageUp => app.move_search_selection(-10),
+            KeyCode::PageDown => app.move_search_selection(10),
+            KeyCode::Enter => app.jump_to_search_result(),
+            KeyCode::Char('/') | KeyCode::Char('n') => app.new_search(),
+            KeyCode::Esc => app.close_search_results(),
+            _ => {}
+        }
+        AppMode::Replace => match key.code {
+            KeyCode::Esc => app.cancel_replace(),
+            KeyCode::Enter => app.replace_enter(),
+            KeyCode::Tab | KeyCode::BackTab => app.toggle_replace_field(),
+            KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::ALT) => app.toggle_replace_in_selection(),
+            _ if app.replace.as_ref().is_some_and(|r| r.editing_with) => app.replace_with.handle_key(key),
+            _ => {
+                app.replace_find.handle_key(key);
+                app.check_replace_pattern();
+            }
+        }
+        AppMode::RunCommand => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Tab => app.toggle_command_target(),
+            KeyCode::Enter => {
+                if let Some(command) = app.command_input.submit() {
+                    app.run_command(command);
+                }
+            }
+            _ => app.command_input.handle_key(key),
+        }
+        AppMode::Filter => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Tab => {}
+            KeyCode::Enter => {
+                if let Some(command) = app.command_input.submit() {
+                    app.filter_through(command);
+                }
+            }
+            _ => app.command_input.handle_key(key),
+        }
+        AppMode::Grep => match key.code {
+            KeyCode::Esc => app.pop_mode(),
+            KeyCode::Enter => {
+                if let Some(query) = app.grep_input.submit() {
+                    app.start_grep(query);
+                }
+            }
+            _ => app.grep_input.handle_key(key),
+        }
+        AppMode::GrepResults => match key.code {
+            KeyCode::Up => app.move_grep_selection(-1),
+            KeyCode::Down => app.move_grep_selection(1),
+            KeyCode::PageUp => app.move_grep_selection(-(app.editor_area.height.max(1) as isize)),
+            KeyCode::PageDown => app.move_grep_selection(app.editor_area.height.max(1) as isize),
+            KeyCode::Enter => app.open_grep_hit(),
+            KeyCode::Char('/') | KeyCode::Char('n') => app.prompt_grep(),
+            KeyCode::Esc => app.close_grep(),
+            _ => {}
+        }
+        AppMode::Buffers => match key.code {
+            KeyCode::Up => app.move_buffer_selection(-1),
+            KeyCode::Down => app.move_buffer_selection(1),
+            KeyCode::Enter => app.switch_to_selected_buffer(),
+            KeyCode::Char(c @ '1'..='9') if (c as usize - '0' as usize) <= app.buffers.len() => {
+                app.buffer_selected = c as usize - '1' as usize;
+                app.switch_to_selected_buffer();
+            }
+            KeyCode::Delete | KeyCode::Char('d') => app.close_selected_buffer(),
+            KeyCode::Char('s') => app.save_selected_buffer(),
+            KeyCode::Char('a') => app.save_all_buffers(),
+            KeyCode::Char('o') => app.close_other_buffers(),
+            KeyCode::Esc => app.pop_mode(),
+            _ => app.buffer_close_pending = None,
+        }
+        AppMode::Report => match key.code {
+            KeyCode::Esc | KeyCode::Enter => {
+                app.report = None;
+                app.pop_mode();
+            }
+            _ => {}
+        }
+        AppMode::Bookmarks => match key.code {
+            KeyCode::Up => app.move_bookmark_selection(-1),
+            KeyCode::Down => app.move_bookmark_selection(1),
+            KeyCode::Enter => app.jump_to_bookmark(app.bookmark_selected),
+            KeyCode::Char(c @ '1'..='9') => app.jump_to_bookmark_number(c as usize - '0' as usize),
+            KeyCode::Delete | KeyCode::Char('d') => app.delete_selected_bookmark(),
+            KeyCode::Esc => app.pop_mode(),
+            _ => {}
+        }
+        AppMode::Spelling => match key.code {
+            KeyCode::Up => app.move_spelling_selection(-1),
+            KeyCode::Down => app.move_spelling_selection(1),
+            KeyCode::Enter => app.apply_spelling(app.spelling.as_ref().map_or(0, |s| s.selected)),
+            KeyCode::Char(c @ '1'..='9') => app.apply_spelling(c as usize - '1' as usize),
+            KeyCode::Char('a') => app.accept_spelling(true),
+            KeyCode::Char('i') => app.accept_spelling(false),
+            KeyCode::Esc => {
+                app.spelling = None;
+                app.pop_mode();
+            }
+            _ => {}
+        }
+        AppMode::Outline => match key.code {
+            KeyCode::Up => app.move_outline(-1),
+            KeyCode::Down => app.move_outline(1),
+            KeyCode::PageUp => app.move_outline(-10),
+            KeyCode::PageDown => app.move_outline(10),
+            KeyCode::Enter => app.jump_to_outline(),
+            KeyCode::Esc => app.pop_mode(),
+            _ => {}
+        }
+        AppMode::GitDiff => match key.code {
+            KeyCode::Up => app.head_diff_scroll = app.head_diff_scroll.saturating_sub(1),
+            KeyCode::Down => app.head_diff_scroll += 1,
+            KeyCode::PageUp => app.head_diff_scroll = app.head_diff_scroll.saturating_sub(10),
+            KeyCode::PageDown => app.head_diff_scroll += 10,
+            KeyCode::Home => app.head_diff_scroll = 0,
+            KeyCode::End => app.head_diff_scroll = usize::MAX,
+            KeyCode::Tab | KeyCode::Char('s') => app.toggle_head_diff_layout(),
+            KeyCode::Char('n') => app.jump_hunk(true),
+            KeyCode::Char('p') => app.jump_hunk(false),
+            KeyCode::Esc | KeyCode::Char('q') => app.toggle_head_diff(),
+            _ if app.keymap.action_for(&key) == Some(Action::GitDiff) => app.toggle_head_diff(),
+            _ => {}
+        }
+        AppMode::GitCommit => match key.code {
+            KeyCode::Esc => app.cancel_git_commit(),
+            KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => app.confirm_git_commit(),
+            _ if app.keymap.action_for(&key) == Some(Action::Save) => app.confirm_git_commit(),
+            _ => {
+                if let Some(message) = &mut app.commit_message {
+                    message.input(key);
+                }
+            }
+        }
+        AppMode::Recover => match key.code {
+            KeyCode::Char('r') | KeyCode::Char('R') => app.recover_swap(),
+            KeyCode::Char('d') | KeyCode::Char('D') => app.discard_swap(),
+            KeyCode::Esc => app.skip_recovery(),
+            _ => {}
+        }
+        AppMode::FileChanged => match key.code {
+            KeyCode::Char('r') | KeyCode::Char('R') => {
+                app.reload_from_disk();
+            }
+            KeyCode::Char('o') | KeyCode::Char('O') => {
+                app.overwrite_disk();
+            }
+            KeyCode::Char('d') | KeyCode::Char('D') => {
+                app.toggle_disk_diff();
+            }
+            KeyCode::Esc => {
+                app.ignore_disk_change();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_navigation_key(key: &KeyEvent) -> bool {
+    matches!(
+        key.code,
+        KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+            | KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End
+    )
+}
@@ -0,0 +1,49 @@
+//! Markdown helpers: reading time and the heading outline.
+
+/// Average silent reading speed, in words per minute.
+const WORDS_PER_MINUTE: usize = 200;
+
+pub struct Heading {
+    pub row: usize,
+    pub level: usize,
+    pub title: String,
+}
+
+/// Estimated minutes to read the text, rounded up (at least one).
+pub fn reading_minutes<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> usize {
+    let words: usize = lines.into_iter().map(|l| l.as_ref().split_whitespace().count()).sum();
+    words.div_ceil(WORDS_PER_MINUTE).max(1)
+}
+
+/// ATX headings (`#` to `######`), skipping fenced code blocks where `#`
+/// is usually a comment.
+pub fn headings(lines: &[String]) -> Vec<Heading> {
+    let mut fence: Option<&str> = None;
+    let mut headings = Vec::new();
+
+    for (row, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_start();
+        if let Some(marker) = fence {
+            if trimmed.starts_with(marker) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            fence = Some("```");
+            continue;
+        }
+        if trimmed.starts_with("~~~") {
+            fence = Some("~~~");
+            continue;
+        }
+
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        let rest = &trimmed[level..];
+        if (1..=6).contains(&level) && (rest.is_empty() || rest.starts_with(' ')) {
+            let title = rest.trim().trim_end_matches('#').trim_end().to_string();
+            headings.push(Heading { row, level, title });
+        }
+    }
+    headings
+}
@@ -0,0 +1,40 @@
+//! Getting attention when something finishes in the background, e.g. an AI
+//! answer arriving while you're in another tmux window: a terminal bell,
+//! a desktop notification sent through the terminal, or both.
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alert {
+    #[default]
+    None,
+    Bell,
+    Desktop,
+    Both,
+}
+
+/// What to write to the terminal for `alert`. Notifications use OSC 9
+/// (iTerm2, kitty, WezTerm, Ghostty), or OSC 777 where only that works
+/// (foot, urxvt); inside tmux they're passed through to the outer
+/// terminal, which needs `allow-passthrough on`.
+pub fn escape(alert: Alert, title: &str, message: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    if matches!(alert, Alert::Desktop | Alert::Both) {
+        let clean = |s: &str| s.chars().filter(|c| !c.is_control()).collect::<String>();
+        let term = std::env::var("TERM").unwrap_or_default();
+        let sequence = if term.starts_with("foot") || term.starts_with("rxvt") {
+            format!("\x1b]777;notify;{};{}\x07", clean(title).replace(';', ","), clean(message))
+        } else {
+            format!("\x1b]9;{}: {}\x07", clean(title), clean(message))
+        };
+        if std::env::var_os("TMUX").is_some() {
+            out.extend(format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b")).bytes());
+        } else {
+            out.extend(sequence.bytes());
+        }
+    }
+    if matches!(alert, Alert::Bell | Alert::Both) {
+        out.push(0x07);
+    }
+    out
+}
@@ -0,0 +1,150 @@
+//! Unified-diff patches: parsing them and applying their hunks to lines,
+//! tolerating hunks that moved; hunks that don't fit are handed back as
+//! rejects, like patch(1) does.
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+
+/// How far from its stated position a hunk's context is looked for.
+const MAX_OFFSET: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub header: String,
+    old_start: usize,
+    /// Lines with their ' ', '-' or '+' prefix.
+    pub lines: Vec<String>,
+}
+
+impl Hunk {
+    fn side(&self, keep: char) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter(|l| l.starts_with(' ') || l.starts_with(keep) || l.is_empty())
+            .map(|l| l.get(1..).unwrap_or(""))
+            .collect()
+    }
+}
+
+/// The hunks for one file. A path is None for /dev/null (a created or
+/// deleted file).
+#[derive(Debug, Clone)]
+pub struct FilePatch {
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// The file this patch changes, trying the path as written and then
+    /// without git's `a/`/`b/` prefix.
+    pub fn target(&self) -> Option<PathBuf> {
+        let path = self.new_path.as_ref().or(self.old_path.as_ref())?;
+        let stripped = path.strip_prefix("a/").or_else(|| path.strip_prefix("b/"));
+        match stripped {
+            Some(stripped) if !Path::new(path).exists() => Some(PathBuf::from(stripped)),
+            _ => Some(PathBuf::from(path)),
+        }
+    }
+
+    pub fn deletes(&self) -> bool {
+        self.new_path.is_none()
+    }
+
+    /// Apply the hunks in order, each at its stated position or the
+    /// nearest place its context matches. Returns the patched lines and
+    /// the hunks that didn't apply.
+    pub fn apply(&self, lines: &[String]) -> (Vec<String>, Vec<Hunk>) {
+        let mut lines = lines.to_vec();
+        let mut rejects = Vec::new();
+        // Rows gained by the hunks applied so far.
+        let mut delta: isize = 0;
+        for hunk in &self.hunks {
+            let old = hunk.side('-');
+            let new = hunk.side('+');
+            // A pure insertion's start is the line it goes after.
+            let stated = if old.is_empty() { hunk.old_start } else { hunk.old_start.saturating_sub(1) };
+            let expected = (stated as isize + delta).max(0) as usize;
+            let fits = |at: usize| at + old.len() <= lines.len() && lines[at..at + old.len()].iter().zip(&old).all(|(a, b)| a == b);
+            let found = (0..=MAX_OFFSET).find_map(|offset| {
+                [expected.checked_add(offset), expected.checked_sub(offset)].into_iter().flatten().find(|&at| fits(at))
+            });
+            match found {
+                Some(at) => {
+                    lines.splice(at..at + old.len(), new.iter().map(|l| l.to_string()));
+                    delta += new.len() as isize - old.len() as isize;
+                }
+                None => rejects.push(hunk.clone()),
+            }
+        }
+        (lines, rejects)
+    }
+}
+
+/// Parse the files and hunks of a unified diff (plain or git style).
+/// Anything between files (commit messages, `diff --git`, index lines) is
+/// skipped.
+pub fn parse(text: &str) -> Result<Vec<FilePatch>> {
+    let path_of = |line: &str| {
+        let path = line[4..].split('\t').next().unwrap_or("").trim_end();
+        (path != "/dev/null").then(|| path.to_string())
+    };
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.starts_with("--- ") && lines.peek().is_some_and(|next| next.starts_with("+++ ")) {
+            let new_line = lines.next().unwrap_or_default();
+            files.push(FilePatch { old_path: path_of(line), new_path: path_of(new_line), hunks: Vec::new() });
+        } else if line.starts_with("@@ ") {
+            let file = files.last_mut().ok_or_else(|| anyhow!("Hunk before any file header"))?;
+            let (old_start, old_len, new_len) = parse_header(line).ok_or_else(|| anyhow!("Bad hunk header: {}", line))?;
+            let mut hunk = Hunk { header: line.to_string(), old_start, lines: Vec::new() };
+            let (mut old_left, mut new_left) = (old_len, new_len);
+            while old_left > 0 || new_left > 0 {
+                let Some(body) = lines.next() else { break };
+                match body.chars().next() {
+                    Some('-') => old_left = old_left.saturating_sub(1),
+                    Some('+') => new_left = new_left.saturating_sub(1),
+                    Some('\\') => continue,
+                    // Some tools drop the space of empty context lines.
+                    Some(' ') | None => {
+                        old_left = old_left.saturating_sub(1);
+                        new_left = new_left.saturating_sub(1);
+                    }
+                    Some(_) => return Err(anyhow!("Unexpected line in hunk: {}", body)),
+                }
+                hunk.lines.push(body.to_string());
+            }
+            file.hunks.push(hunk);
+        }
+    }
+    if files.iter().all(|f| f.hunks.is_empty()) {
+        return Err(anyhow!("No hunks found"));
+    }
+    Ok(files)
+}
+
+/// Old start and length and new length from `@@ -a,b +c,d @@`.
+fn parse_header(line: &str) -> Option<(usize, usize, usize)> {
+    let mut parts = line.split_whitespace().skip(1);
+    let range = |part: &str| -> Option<(usize, usize)> {
+        let (start, len) = part.split_once(',').unwrap_or((part, "1"));
+        Some((start.parse().ok()?, len.parse().ok()?))
+    };
+    let (old_start, old_len) = range(parts.next()?.strip_prefix('-')?)?;
+    let (_, new_len) = range(parts.next()?.strip_prefix('+')?)?;
+    Some((old_start, old_len, new_len))
+}
+
+/// Rejected hunks in `.rej` form.
+pub fn rejects_text(path: &Path, rejects: &[Hunk]) -> String {
+    let mut text = format!("--- {0}\n+++ {0}\n", path.display());
+    for hunk in rejects {
+        text.push_str(&hunk.header);
+        text.push('\n');
+        for line in &hunk.lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    text
+}
@@ -0,0 +1,63 @@
+//! Display choices made inside the editor (theme, chat panel, soft wrap
+//! per filetype), restored on the next start. They live in the state
+//! directory (`~/.local/state/neuronano/prefs.json`), apart from the
+//! config the user writes and from `--continue` sessions.
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+
+/// Chat panel width, as a percentage of the screen.
+pub const DEFAULT_CHAT_WIDTH: u16 = 40;
+pub const MIN_CHAT_WIDTH: u16 = 20;
+pub const MAX_CHAT_WIDTH: u16 = 80;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Prefs {
+    /// Last theme picked with Alt+C; overrides `theme` in the config.
+    pub theme: Option<String>,
+    pub show_chat: bool,
+    pub chat_width: u16,
+    pub show_whitespace: Option<bool>,
+    /// Soft wrap as last toggled, by filetype ("text" for plain files).
+    pub soft_wrap: HashMap<String, bool>,
+}
+
+impl Default for Prefs {
+    fn default() -> Self {
+        Self {
+            theme: None,
+            show_chat: false,
+            chat_width: DEFAULT_CHAT_WIDTH,
+            show_whitespace: None,
+            soft_wrap: HashMap::new(),
+        }
+    }
+}
+
+fn dir() -> PathBuf {
+    // No state directory outside Linux: keep them with the config.
+    dirs::state_dir().map(|dir| dir.join("neuronano")).unwrap_or_else(Config::dir)
+}
+
+fn store_path() -> PathBuf {
+    dir().join("prefs.json")
+}
+
+pub fn load() -> Prefs {
+    let mut prefs: Prefs = fs::read_to_string(store_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    prefs.chat_width = prefs.chat_width.clamp(MIN_CHAT_WIDTH, MAX_CHAT_WIDTH);
+    prefs
+}
+
+pub fn save(prefs: &Prefs) -> Result<()> {
+    fs::create_dir_all(dir())?;
+    fs::write(store_path(), serde_json::to_string_pretty(prefs)?)?;
+    Ok(())
+}
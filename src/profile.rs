@@ -0,0 +1,143 @@
+//! Per-filetype feature profiles. Config keys are either a filetype
+//! ("Markdown", "git-commit") or a glob on the file name ("*.sql",
+//! "docs/*.md"); every matching profile is merged when a buffer is opened,
+//! filetype profiles first and then globs from least to most specific.
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::config::LineLengthRule;
+use crate::keymap::{Action, KeySpec};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Profile {
+    pub line_length: Option<LineLengthRule>,
+    /// Interpreter for `# %%` cells, overriding `cell_interpreters`.
+    pub interpreter: Option<String>,
+    /// Open CSV/TSV buffers straight in table view.
+    pub table_view: Option<bool>,
+    /// Shell command the buffer is piped through before saving (e.g. "rustfmt --emit stdout").
+    pub formatter: Option<String>,
+    /// Language server command line (e.g. "pyright-langserver --stdio"),
+    /// run when `lsp` is on.
+    pub language_server: Option<String>,
+    /// Extra abbreviations for this filetype, layered over the global ones.
+    pub abbreviations: HashMap<String, String>,
+    /// Capitalize the first letter of sentences while typing.
+    pub auto_capitalize: Option<bool>,
+    /// Turn straight quotes, `--` and `...` into typographic ones while typing.
+    pub smart_punctuation: Option<bool>,
+    /// Override `tab_width` and `insert_spaces`.
+    pub tab_width: Option<u8>,
+    pub insert_spaces: Option<bool>,
+    /// Overrides `auto_indent`.
+    pub auto_indent: Option<bool>,
+    /// Overrides `indent_after` (e.g. `[":"]` for Python).
+    pub indent_after: Option<Vec<String>>,
+    /// Overrides `spell_check`.
+    pub spell_check: Option<bool>,
+    /// Key overrides that only apply to these files, layered over `keybindings`.
+    pub keybindings: HashMap<Action, KeySpec>,
+}
+
+impl Profile {
+    /// Overlay the settings `other` defines on top of ours.
+    pub fn merge(&mut self, other: &Profile) {
+        if other.line_length.is_some() {
+            self.line_length = other.line_length;
+        }
+        if other.interpreter.is_some() {
+            self.interpreter = other.interpreter.clone();
+        }
+        if other.table_view.is_some() {
+            self.table_view = other.table_view;
+        }
+        if other.formatter.is_some() {
+            self.formatter = other.formatter.clone();
+        }
+        if other.language_server.is_some() {
+            self.language_server = other.language_server.clone();
+        }
+        self.abbreviations.extend(other.abbreviations.clone());
+        if other.auto_capitalize.is_some() {
+            self.auto_capitalize = other.auto_capitalize;
+        }
+        if other.smart_punctuation.is_some() {
+            self.smart_punctuation = other.smart_punctuation;
+        }
+        if other.tab_width.is_some() {
+            self.tab_width = other.tab_width;
+        }
+        if other.insert_spaces.is_some() {
+            self.insert_spaces = other.insert_spaces;
+        }
+        if other.auto_indent.is_some() {
+            self.auto_indent = other.auto_indent;
+        }
+        if other.indent_after.is_some() {
+            self.indent_after = other.indent_after.clone();
+        }
+        if other.spell_check.is_some() {
+            self.spell_check = other.spell_check;
+        }
+        self.keybindings.extend(other.keybindings.clone());
+    }
+}
+
+/// Keys with wildcards or a directory part are globs; anything else
+/// ("c.h", "Makefile") names a filetype.
+fn is_glob(key: &str) -> bool {
+    key.contains(['*', '?', '/'])
+}
+
+pub fn resolve(profiles: &HashMap<String, Profile>, filename: &str, filetype: Option<&str>) -> Profile {
+    let mut resolved = Profile::default();
+
+    if let Some(profile) = filetype.and_then(|ft| profiles.get(ft)) {
+        resolved.merge(profile);
+    }
+
+    let name = std::path::Path::new(filename)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(filename);
+    let mut globs: Vec<(&String, &Profile)> = profiles
+        .iter()
+        .filter(|(key, _)| is_glob(key))
+        .filter(|(key, _)| {
+            // Patterns with a directory part match the whole path.
+            let subject = if key.contains('/') { filename } else { name };
+            glob_match(key, subject)
+        })
+        .collect();
+    globs.sort_by_key(|(key, _)| key.len());
+    for (_, profile) in globs {
+        resolved.merge(profile);
+    }
+
+    resolved
+}
+
+/// Minimal wildcard matching: `*` matches any run of characters, `?` one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
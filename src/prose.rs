@@ -0,0 +1,50 @@
+//! As-you-type helpers for prose: sentence capitalization and typographic
+//! punctuation. Both work on the text left of the cursor on the current line.
+
+/// Characters after which a quote opens rather than closes.
+const OPENERS: &str = "([{“‘—–";
+
+/// Replacement for typing `c` after `before`: how many chars before the
+/// cursor to drop, and what to insert instead of them plus `c`.
+pub fn smart_punctuation(before: &str, c: char) -> Option<(usize, String)> {
+    let last = before.chars().last();
+    let opens = last.is_none_or(|p| p.is_whitespace() || OPENERS.contains(p));
+
+    match c {
+        '"' => Some((0, if opens { "“" } else { "”" }.to_string())),
+        '\'' => Some((0, if opens { "‘" } else { "’" }.to_string())),
+        '-' if last == Some('-') => Some((1, "–".to_string())),
+        '-' if last == Some('–') => Some((1, "—".to_string())),
+        '.' if before.ends_with("..") => Some((2, "…".to_string())),
+        _ => None,
+    }
+}
+
+fn ends_sentence(text: &str) -> bool {
+    text.trim_end().ends_with(['.', '!', '?', '…'])
+}
+
+/// Markdown line prefixes that don't count as sentence content.
+fn is_line_prefix(text: &str) -> bool {
+    let text = text.trim();
+    text.is_empty()
+        || text.chars().all(|c| c == '#' || c == '>')
+        || matches!(text, "-" | "*" | "+")
+        || (text.ends_with('.') && text[..text.len() - 1].chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Uppercase `c` if it starts a sentence.
+pub fn capitalize(before: &str, previous_line: Option<&str>, c: char) -> Option<char> {
+    if !c.is_lowercase() {
+        return None;
+    }
+    let starts_sentence = if is_line_prefix(before) {
+        if !before.is_empty() && !before.ends_with(char::is_whitespace) {
+            return None;
+        }
+        previous_line.is_none_or(|p| p.trim().is_empty() || ends_sentence(p))
+    } else {
+        before.ends_with(char::is_whitespace) && ends_sentence(before)
+    };
+    starts_sentence.then(|| c.to_uppercase().next().unwrap_or(c))
+}
@@ -0,0 +1,34 @@
+//! Recently opened files, newest first, for the welcome screen. Kept in
+//! `~/.config/neuronano/recent.json`.
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::config::Config;
+
+/// Files remembered.
+const MAX_RECENT: usize = 50;
+
+fn store_path() -> PathBuf {
+    Config::dir().join("recent.json")
+}
+
+fn load_all() -> Vec<String> {
+    fs::read_to_string(store_path()).ok().and_then(|content| serde_json::from_str(&content).ok()).unwrap_or_default()
+}
+
+/// The recent files that still exist.
+pub fn load() -> Vec<String> {
+    load_all().into_iter().filter(|file| Path::new(file).is_file()).collect()
+}
+
+/// Put `file` first.
+pub fn add(file: &Path) -> Result<()> {
+    let file = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().into_owned();
+    let mut files = load_all();
+    files.retain(|f| *f != file);
+    files.insert(0, file);
+    files.truncate(MAX_RECENT);
+    fs::create_dir_all(Config::dir())?;
+    fs::write(store_path(), serde_json::to_string_pretty(&files)?)?;
+    Ok(())
+}
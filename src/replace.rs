@@ -0,0 +1,47 @@
+//! Regex replace, line by line: `$1`/`${name}` in the replacement stand
+//! for capture groups, as in the `regex` crate.
+use std::ops::Range;
+use regex::Regex;
+
+/// Where the text is allowed to change, as (row, col) positions.
+pub type Scope = ((usize, usize), (usize, usize));
+
+/// A line after replacing, with the char ranges of the old text that
+/// matched and of the new text that went in.
+pub struct Replaced {
+    pub text: String,
+    pub old: Vec<Range<usize>>,
+    pub new: Vec<Range<usize>>,
+}
+
+/// Row `row` with the matches of `re` inside `scope` replaced; None when
+/// nothing matches there.
+pub fn line(re: &Regex, text: &str, with: &str, row: usize, scope: Option<Scope>) -> Option<Replaced> {
+    let col = |byte: usize| text[..byte].chars().count();
+    let mut new = String::new();
+    let (mut old_ranges, mut new_ranges) = (Vec::new(), Vec::new());
+    let mut last = 0;
+    for caps in re.captures_iter(text) {
+        let m = caps.get(0)?;
+        // An empty match is a position, not text: skip those.
+        if m.is_empty() {
+            continue;
+        }
+        if let Some((start, end)) = scope {
+            if (row, col(m.start())) < start || (row, col(m.end())) > end {
+                continue;
+            }
+        }
+        new.push_str(&text[last..m.start()]);
+        let at = new.chars().count();
+        caps.expand(with, &mut new);
+        new_ranges.push(at..new.chars().count());
+        old_ranges.push(col(m.start())..col(m.end()));
+        last = m.end();
+    }
+    if old_ranges.is_empty() {
+        return None;
+    }
+    new.push_str(&text[last..]);
+    Some(Replaced { text: new, old: old_ranges, new: new_ranges })
+}
@@ -0,0 +1,225 @@
+//! Semantic project search (`--ask`): the files of the project are cut
+//! into chunks of lines and embedded with Gemini, the vectors kept in a
+//! gzipped index under `~/.config/neuronano/index/`, and a question is
+//! answered with the chunks closest to it. Only files that changed since
+//! the last run are embedded again.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use crate::ai::{self, Gemini};
+use crate::config::Config;
+use crate::crypto::Cipher;
+
+/// Lines per chunk.
+const CHUNK_LINES: usize = 40;
+/// Chunk text beyond this is cut before embedding.
+const CHUNK_CHARS: usize = 8000;
+/// Files bigger than this are skipped (generated code, data).
+const MAX_FILE_BYTES: u64 = 512 * 1024;
+/// Texts per embedding request, Gemini's limit.
+const BATCH: usize = 100;
+pub const DIMENSIONS: usize = 768;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Chunk {
+    /// First and last line, 1-based.
+    start: usize,
+    end: usize,
+    vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct IndexedFile {
+    /// Modification time in seconds when it was embedded.
+    modified: u64,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    /// Vectors of different models don't compare; a new one starts over.
+    model: String,
+    /// By path relative to the project root.
+    files: HashMap<String, IndexedFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub file: String,
+    pub start: usize,
+    pub end: usize,
+    /// Cosine similarity to the question.
+    pub score: f32,
+}
+
+impl Hit {
+    /// The hit's lines, read back from the file.
+    pub fn text(&self, root: &Path) -> String {
+        let text = fs::read_to_string(root.join(&self.file)).unwrap_or_default();
+        text.lines().skip(self.start - 1).take(self.end + 1 - self.start).collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Where the index of the project at `root` is kept.
+fn index_path(root: &Path) -> PathBuf {
+    // FNV-1a, so the name stays the same across builds.
+    let hash = root.to_string_lossy().bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    let name = root.file_name().map_or_else(|| "root".to_string(), |n| n.to_string_lossy().into_owned());
+    Config::dir().join("index").join(format!("{}-{:016x}.json.gz", name, hash))
+}
+
+impl Index {
+    pub fn load(root: &Path) -> Self {
+        let mut json = Vec::new();
+        File::open(index_path(root))
+            .and_then(|file| GzDecoder::new(file).read_to_end(&mut json))
+            .ok()
+            .and_then(|_| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let path = index_path(root);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = GzEncoder::new(File::create(&path)?, Compression::default());
+        serde_json::to_writer(&mut writer, self)?;
+        writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.files.values().map(|f| f.chunks.len()).sum()
+    }
+
+    /// Embed the files of the project that are new or changed since the
+    /// last update and forget the ones that are gone. Returns how many
+    /// files were embedded.
+    pub async fn update(&mut self, root: &Path, gemini: &Gemini, model: &str) -> Result<usize> {
+        if self.model != model {
+            self.files.clear();
+            self.model = model.to_string();
+        }
+        let files = project_files(root, MAX_FILE_BYTES);
+        let paths: HashSet<&String> = files.iter().map(|(path, _)| path).collect();
+        self.files.retain(|path, _| paths.contains(path));
+
+        // Files go in only once all their chunks are embedded, so a failed
+        // update is picked up again next time.
+        let mut fresh: HashMap<String, IndexedFile> = HashMap::new();
+        // (file, start, end, text) of each chunk to embed.
+        let mut pending = Vec::new();
+        for (path, modified) in &files {
+            if self.files.get(path).is_some_and(|f| f.modified == *modified) {
+                continue;
+            }
+            let Ok(text) = fs::read_to_string(root.join(path)) else { continue };
+            let lines: Vec<&str> = text.lines().collect();
+            fresh.insert(path.clone(), IndexedFile { modified: *modified, chunks: Vec::new() });
+            for (i, chunk) in lines.chunks(CHUNK_LINES).enumerate() {
+                let body: String = chunk.join("\n").chars().take(CHUNK_CHARS).collect();
+                if body.trim().is_empty() {
+                    continue;
+                }
+                let start = i * CHUNK_LINES + 1;
+                pending.push((path.clone(), start, start + chunk.len() - 1, format!("{}\n{}", path, body)));
+            }
+        }
+
+        for batch in pending.chunks(BATCH) {
+            let texts: Vec<String> = batch.iter().map(|(.., text)| text.clone()).collect();
+            let vectors = ai::embed(gemini, model, &texts, "RETRIEVAL_DOCUMENT", DIMENSIONS).await?;
+            for ((path, start, end, _), vector) in batch.iter().zip(vectors) {
+                if let Some(file) = fresh.get_mut(path) {
+                    file.chunks.push(Chunk { start: *start, end: *end, vector });
+                }
+            }
+        }
+        let count = fresh.len();
+        self.files.extend(fresh);
+        Ok(count)
+    }
+
+    /// The `n` chunks closest to `query`, best first.
+    pub fn search(&self, query: &[f32], n: usize) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = self
+            .files
+            .iter()
+            .flat_map(|(file, indexed)| {
+                indexed.chunks.iter().map(move |chunk| Hit { file: file.clone(), start: chunk.start, end: chunk.end, score: cosine(query, &chunk.vector) })
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(n);
+        hits
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// Text files of the project with their modification time: what git
+/// tracks (plus untracked files it doesn't ignore) in a repository,
+/// otherwise everything but hidden files, `target` and `node_modules`.
+/// Encrypted, binary and files over `max_bytes` are left out.
+pub fn project_files(root: &Path, max_bytes: u64) -> Vec<(String, u64)> {
+    let listed = Command::new("git")
+        .args(["ls-files", "--cached", "--others", "--exclude-standard", "-z"])
+        .current_dir(root)
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).split('\0').filter(|p| !p.is_empty()).map(String::from).collect());
+    let paths: Vec<String> = listed.unwrap_or_else(|| {
+        let mut paths = Vec::new();
+        walk(root, root, &mut paths);
+        paths
+    });
+    paths
+        .into_iter()
+        .filter(|path| Cipher::for_path(Path::new(path)).is_none())
+        .filter_map(|path| {
+            let meta = fs::metadata(root.join(&path)).ok().filter(|m| m.is_file() && m.len() <= max_bytes)?;
+            let mut head = [0; 1024];
+            let n = File::open(root.join(&path)).and_then(|mut f| f.read(&mut head)).ok()?;
+            if head[..n].contains(&0) {
+                return None;
+            }
+            let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+            Some((path, modified))
+        })
+        .collect()
+}
+
+fn walk(root: &Path, dir: &Path, paths: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') || name == "target" || name == "node_modules" {
+            continue;
+        }
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => walk(root, &path, paths),
+            Ok(t) if t.is_file() => {
+                if let Ok(relative) = path.strip_prefix(root) {
+                    paths.push(relative.to_string_lossy().into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+}
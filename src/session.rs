@@ -0,0 +1,41 @@
+//! The files open when neuronano last quit, with their cursors and the
+//! search in progress, for `--continue`. Kept in
+//! `~/.config/neuronano/session.json`.
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionFile {
+    pub path: String,
+    pub row: usize,
+    pub col: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Session {
+    pub files: Vec<SessionFile>,
+    /// Index into `files` of the one that was shown.
+    pub active: usize,
+    /// Query of the search whose matches were listed.
+    pub search: Option<String>,
+}
+
+fn store_path() -> PathBuf {
+    Config::dir().join("session.json")
+}
+
+pub fn load() -> Result<Session> {
+    let path = store_path();
+    let content = fs::read_to_string(&path).with_context(|| format!("no session in {}", path.display()))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn save(session: &Session) -> Result<()> {
+    fs::create_dir_all(Config::dir())?;
+    fs::write(store_path(), serde_json::to_string_pretty(session)?)?;
+    Ok(())
+}
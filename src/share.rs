@@ -0,0 +1,491 @@
+//! Experimental shared editing: one neuronano hosts its buffer over TCP
+//! (`--share`), others join it (`--join`) and see each other's cursors,
+//! or just watch (`--follow`): read-only, with the view following the
+//! host's cursor.
+//!
+//! The buffer is an Automerge text, a CRDT: everyone edits their own copy
+//! and the copies sync through the host, so edits made at the same time
+//! all end up in every copy, in the same order, and none is turned down.
+//! Messages are JSON, one per line, with Automerge's sync messages in
+//! base64. Joining takes the token the host shows; nothing is encrypted,
+//! so use a trusted network or an SSH tunnel all the same.
+use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use automerge::sync::{self, SyncDoc};
+use automerge::transaction::Transactable;
+use automerge::{AutoCommit, ObjId, ObjType, PatchAction, ReadDoc, TextEncoding, Value, ROOT};
+use base64::Engine;
+use ropey::Rope;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use crate::buffer::Change;
+
+/// Peer id of the host.
+const HOST: u64 = 0;
+/// Key of the shared text in the document.
+const TEXT: &str = "text";
+/// Random bytes in a generated session token.
+const TOKEN_BYTES: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// First message of a joiner. Observers only watch.
+    Hello {
+        name: String,
+        #[serde(default)]
+        observer: bool,
+        #[serde(default)]
+        token: String,
+    },
+    /// The host turned down the joiner's token.
+    Denied,
+    /// An Automerge sync message, base64.
+    Sync { data: String },
+    Cursor { id: u64, name: String, row: usize, col: usize },
+    Left { id: u64 },
+}
+
+/// Another participant's cursor.
+pub struct RemoteCursor {
+    pub name: String,
+    pub row: usize,
+    pub col: usize,
+}
+
+/// What the editor has to do after `Session::poll`.
+pub enum Update {
+    /// Chars `from..to` of the buffer replaced by `text`.
+    Splice { from: usize, to: usize, text: String },
+    /// The host's cursor moved, for observers to follow.
+    Follow { row: usize, col: usize },
+    /// The whole shared text, when a joiner first gets it.
+    Load(String),
+    Status(String),
+    /// The session is over, and why.
+    Closed(String),
+}
+
+enum Event {
+    Joined { id: u64, tx: mpsc::UnboundedSender<Message> },
+    Received { id: u64, message: Message },
+    Disconnected { id: u64 },
+}
+
+struct Peer {
+    name: String,
+    tx: mpsc::UnboundedSender<Message>,
+    sync: sync::State,
+    /// Said hello with the right token; nothing else counts before.
+    joined: bool,
+    /// Watching only.
+    observer: bool,
+}
+
+enum Role {
+    Host {
+        peers: HashMap<u64, Peer>,
+        token: String,
+    },
+    Guest {
+        tx: mpsc::UnboundedSender<Message>,
+        sync: sync::State,
+        /// Watching only: nothing is sent, the host's cursor is followed.
+        observer: bool,
+    },
+}
+
+pub struct Session {
+    role: Role,
+    name: String,
+    doc: AutoCommit,
+    /// The shared text; a joiner has none until the first sync brings it.
+    text: Option<ObjId>,
+    /// The text as the document has it, to turn the editor's row changes
+    /// into char splices.
+    mirror: Rope,
+    events: mpsc::UnboundedReceiver<Event>,
+    pub cursors: HashMap<u64, RemoteCursor>,
+    /// Last cursor position sent, to send only moves.
+    sent_cursor: Option<(usize, usize)>,
+    /// Where the session listens or what it joined, for the status bar.
+    pub address: String,
+}
+
+impl Session {
+    /// Listen on `address` for joiners, sharing `text` (the buffer). A bare
+    /// port listens on loopback only. Joiners must bring `token`.
+    pub async fn host(address: &str, name: &str, token: String, text: &str) -> Result<Self> {
+        let listener = TcpListener::bind(with_default_host(address)).await?;
+        let address = listener.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| address.to_string());
+        let (events_tx, events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut next_id = HOST + 1;
+            while let Ok((stream, peer)) = listener.accept().await {
+                log::info!("Share: connection from {}", peer);
+                let id = next_id;
+                next_id += 1;
+                let tx = connect(stream, id, events_tx.clone());
+                if events_tx.send(Event::Joined { id, tx }).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut doc = AutoCommit::new_with_encoding(TextEncoding::UnicodeCodePoint);
+        let obj = doc.put_object(ROOT, TEXT, ObjType::Text)?;
+        doc.splice_text(&obj, 0, 0, text)?;
+        doc.update_diff_cursor();
+        let mut session = Self::new(Role::Host { peers: HashMap::new(), token }, name, doc, events, address);
+        session.text = Some(obj);
+        session.mirror = Rope::from_str(text);
+        Ok(session)
+    }
+
+    /// Connect to a host with its `token`; its buffer arrives as an
+    /// `Update::Load`. An `observer` only watches.
+    pub async fn join(address: &str, name: &str, token: &str, observer: bool) -> Result<Self> {
+        let stream = TcpStream::connect(with_default_host(address)).await?;
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let tx = connect(stream, HOST, events_tx);
+        let _ = tx.send(Message::Hello { name: name.to_string(), observer, token: token.to_string() });
+        let role = Role::Guest { tx, sync: sync::State::new(), observer };
+        let doc = AutoCommit::new_with_encoding(TextEncoding::UnicodeCodePoint);
+        let mut session = Self::new(role, name, doc, events, address.to_string());
+        session.flush();
+        Ok(session)
+    }
+
+    fn new(role: Role, name: &str, doc: AutoCommit, events: mpsc::UnboundedReceiver<Event>, address: String) -> Self {
+        Self {
+            role,
+            name: name.to_string(),
+            doc,
+            text: None,
+            mirror: Rope::new(),
+            events,
+            cursors: HashMap::new(),
+            sent_cursor: None,
+            address,
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        matches!(self.role, Role::Host { .. })
+    }
+
+    pub fn is_observer(&self) -> bool {
+        matches!(self.role, Role::Guest { observer: true, .. })
+    }
+
+    /// The token joiners need, on the host.
+    pub fn token(&self) -> Option<&str> {
+        match &self.role {
+            Role::Host { token, .. } => Some(token),
+            Role::Guest { .. } => None,
+        }
+    }
+
+    /// Observers connected to this host.
+    pub fn watchers(&self) -> usize {
+        match &self.role {
+            Role::Host { peers, .. } => peers.values().filter(|p| p.joined && p.observer).count(),
+            Role::Guest { .. } => 0,
+        }
+    }
+
+    /// Put edits made in this editor in the document and send them.
+    pub fn send_changes(&mut self, changes: Vec<Change>) {
+        // Before the first sync there is nothing to edit yet.
+        let Some(obj) = self.text.clone().filter(|_| !self.is_observer()) else { return };
+        for change in &changes {
+            let Some((from, to, text)) = splice_of(&self.mirror, change) else {
+                log::warn!("Share: an edit doesn't fit the shared text");
+                continue;
+            };
+            if let Err(e) = self.doc.splice_text(&obj, from, (to - from) as isize, &text) {
+                log::warn!("Share: can't apply an edit: {}", e);
+                continue;
+            }
+            self.mirror.remove(from..to);
+            self.mirror.insert(from, &text);
+        }
+        // Our own edits aren't news to us.
+        self.doc.update_diff_cursor();
+        self.flush();
+    }
+
+    pub fn send_cursor(&mut self, row: usize, col: usize) {
+        if self.sent_cursor == Some((row, col)) {
+            return;
+        }
+        self.sent_cursor = Some((row, col));
+        let message = Message::Cursor { id: HOST, name: self.name.clone(), row, col };
+        match &self.role {
+            Role::Host { peers, .. } => {
+                for peer in peers.values().filter(|p| p.joined) {
+                    let _ = peer.tx.send(message.clone());
+                }
+            }
+            Role::Guest { observer: true, .. } => {}
+            Role::Guest { tx, .. } => {
+                let _ = tx.send(message);
+            }
+        }
+    }
+
+    /// Handle what arrived from the network.
+    pub fn poll(&mut self) -> Vec<Update> {
+        let mut updates = Vec::new();
+        let mut received = false;
+        while let Ok(event) = self.events.try_recv() {
+            received = true;
+            if self.is_host() {
+                self.host_event(event, &mut updates);
+            } else {
+                self.guest_event(event, &mut updates);
+            }
+        }
+        if received {
+            self.take_remote_edits(&mut updates);
+            self.flush();
+        }
+        updates
+    }
+
+    fn host_event(&mut self, event: Event, updates: &mut Vec<Update>) {
+        let Role::Host { peers, token } = &mut self.role else { return };
+        match event {
+            Event::Joined { id, tx } => {
+                let peer = Peer { name: format!("guest {}", id), tx, sync: sync::State::new(), joined: false, observer: false };
+                peers.insert(id, peer);
+            }
+            Event::Disconnected { id } => {
+                if let Some(peer) = peers.remove(&id).filter(|p| p.joined) {
+                    updates.push(Update::Status(format!("{} left the session", peer.name)));
+                }
+                self.cursors.remove(&id);
+                for peer in peers.values().filter(|p| p.joined) {
+                    let _ = peer.tx.send(Message::Left { id });
+                }
+            }
+            Event::Received { id, message } => {
+                let Some(peer) = peers.get_mut(&id) else { return };
+                match message {
+                    Message::Hello { name, observer, token: given } => {
+                        if !same_token(&given, token) {
+                            log::warn!("Share: {} brought a wrong token", name);
+                            let _ = peer.tx.send(Message::Denied);
+                            peers.remove(&id);
+                            updates.push(Update::Status("Turned down a joiner with a wrong token".to_string()));
+                            return;
+                        }
+                        peer.name = name.clone();
+                        peer.observer = observer;
+                        peer.joined = true;
+                        if let Some((row, col)) = self.sent_cursor {
+                            let _ = peer.tx.send(Message::Cursor { id: HOST, name: self.name.clone(), row, col });
+                        }
+                        let verb = if observer { "is watching" } else { "joined the session" };
+                        updates.push(Update::Status(format!("{} {}", name, verb)));
+                    }
+                    Message::Sync { data } if peer.joined => receive(&mut self.doc, &mut peer.sync, &data),
+                    Message::Cursor { name, row, col, .. } if peer.joined => {
+                        let message = Message::Cursor { id, name: name.clone(), row, col };
+                        for (other, peer) in peers.iter() {
+                            if *other != id && peer.joined {
+                                let _ = peer.tx.send(message.clone());
+                            }
+                        }
+                        self.cursors.insert(id, RemoteCursor { name, row, col });
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn guest_event(&mut self, event: Event, updates: &mut Vec<Update>) {
+        let Role::Guest { sync, observer, .. } = &mut self.role else { return };
+        let message = match event {
+            Event::Received { message, .. } => message,
+            Event::Disconnected { .. } => {
+                updates.push(Update::Closed("The shared session has ended".to_string()));
+                return;
+            }
+            Event::Joined { .. } => return,
+        };
+        match message {
+            Message::Denied => updates.push(Update::Closed("The host turned down the session token".to_string())),
+            Message::Sync { data } => receive(&mut self.doc, sync, &data),
+            Message::Cursor { id, name, row, col } => {
+                if *observer && id == HOST {
+                    updates.push(Update::Follow { row, col });
+                }
+                self.cursors.insert(id, RemoteCursor { name, row, col });
+            }
+            Message::Left { id } => {
+                self.cursors.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Turn what the last syncs changed in the text into updates for the
+    /// editor, keeping the mirror in step.
+    fn take_remote_edits(&mut self, updates: &mut Vec<Update>) {
+        let Some(obj) = &self.text else {
+            // A joiner gets the whole text with its first sync.
+            if let Ok(Some((Value::Object(ObjType::Text), obj))) = self.doc.get(ROOT, TEXT) {
+                let text = self.doc.text(&obj).unwrap_or_default();
+                self.doc.update_diff_cursor();
+                self.mirror = Rope::from_str(&text);
+                self.text = Some(obj);
+                updates.push(Update::Load(text));
+            }
+            return;
+        };
+        for patch in self.doc.diff_incremental() {
+            if patch.obj != *obj {
+                continue;
+            }
+            let (from, to, text) = match patch.action {
+                PatchAction::SpliceText { index, value, .. } => (index, index, value.make_string()),
+                PatchAction::DeleteSeq { index, length } => (index, index + length, String::new()),
+                _ => continue,
+            };
+            let to = to.min(self.mirror.len_chars());
+            let from = from.min(to);
+            self.mirror.remove(from..to);
+            self.mirror.insert(from, &text);
+            updates.push(Update::Splice { from, to, text });
+        }
+    }
+
+    /// Send each participant what it's missing of the document.
+    fn flush(&mut self) {
+        match &mut self.role {
+            Role::Host { peers, .. } => {
+                for peer in peers.values_mut().filter(|p| p.joined) {
+                    if let Some(message) = self.doc.sync().generate_sync_message(&mut peer.sync) {
+                        let _ = peer.tx.send(Message::Sync { data: encode(message) });
+                    }
+                }
+            }
+            Role::Guest { tx, sync, .. } => {
+                if let Some(message) = self.doc.sync().generate_sync_message(sync) {
+                    let _ = tx.send(Message::Sync { data: encode(message) });
+                }
+            }
+        }
+    }
+}
+
+/// A random token for a session.
+pub fn new_token() -> Result<String> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow!("no randomness for a session token: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Compare tokens without giving away how much of one matched.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// `address`, with loopback as the host when it's just a port.
+fn with_default_host(address: &str) -> String {
+    match address.strip_prefix(':').unwrap_or(address) {
+        port if port.parse::<u16>().is_ok() => format!("127.0.0.1:{}", port),
+        _ => address.to_string(),
+    }
+}
+
+fn encode(message: sync::Message) -> String {
+    base64::engine::general_purpose::STANDARD.encode(message.encode())
+}
+
+/// Apply a sync message; a broken one is logged and dropped.
+fn receive(doc: &mut AutoCommit, state: &mut sync::State, data: &str) {
+    let message = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| sync::Message::decode(&bytes).map_err(|e| e.to_string()));
+    let result = message.and_then(|message| doc.sync().receive_sync_message(state, message).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        log::warn!("Share: bad sync message: {}", e);
+    }
+}
+
+/// A change of whole rows as chars `from..to` of `text` (the rows joined
+/// with newlines) replaced, trimmed to the chars that differ so edits made
+/// at the same time merge by char. None when it doesn't fit `text`.
+fn splice_of(text: &Rope, change: &Change) -> Option<(usize, usize, String)> {
+    let rows = text.len_lines();
+    if change.start + change.old.len() > rows {
+        return None;
+    }
+    let (from, to, new) = if change.old.is_empty() {
+        if change.new.is_empty() {
+            return None;
+        }
+        // New rows before `start`, or after the last one.
+        let joined = change.new.join("\n");
+        if change.start < rows {
+            let at = text.line_to_char(change.start);
+            (at, at, joined + "\n")
+        } else {
+            (text.len_chars(), text.len_chars(), format!("\n{}", joined))
+        }
+    } else {
+        let from = text.line_to_char(change.start);
+        let to = from + change.old.iter().map(|row| row.chars().count()).sum::<usize>() + change.old.len() - 1;
+        match change.new.is_empty() {
+            // Rows removed outright take a line break with them.
+            true if to < text.len_chars() => (from, to + 1, String::new()),
+            true => (from.saturating_sub(1), to, String::new()),
+            false => (from, to, change.new.join("\n")),
+        }
+    };
+    if to > text.len_chars() {
+        return None;
+    }
+    let old: Vec<char> = text.slice(from..to).chars().collect();
+    let new: Vec<char> = new.chars().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let room = old.len().min(new.len()) - prefix;
+    let suffix = old.iter().rev().zip(new.iter().rev()).take(room).take_while(|(a, b)| a == b).count();
+    Some((from + prefix, to - suffix, new[prefix..new.len() - suffix].iter().collect()))
+}
+
+/// Spawn the reader and writer of a connection; returns where to queue
+/// outgoing messages.
+fn connect(stream: TcpStream, id: u64, events: mpsc::UnboundedSender<Event>) -> mpsc::UnboundedSender<Message> {
+    let (read, mut write) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            let Ok(mut line) = serde_json::to_string(&message) else { continue };
+            line.push('\n');
+            if write.write_all(line.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str(&line) {
+                Ok(message) => {
+                    if events.send(Event::Received { id, message }).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => log::warn!("Share: bad message from peer {}: {}", id, e),
+            }
+        }
+        let _ = events.send(Event::Disconnected { id });
+    });
+    tx
+}
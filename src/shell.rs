@@ -0,0 +1,49 @@
+//! Shell commands run from the editor: `sh -c` in the working directory,
+//! optionally fed some text, with the output collected.
+use std::process::Stdio;
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+    pub success: bool,
+}
+
+impl Output {
+    /// The last thing the command said on stderr, for the status line.
+    pub fn complaint(&self) -> Option<&str> {
+        self.stderr.lines().map(str::trim).rfind(|l| !l.is_empty())
+    }
+}
+
+/// Run `command` with `input` on stdin (or nothing to read). The process
+/// is killed if the task is dropped.
+pub async fn run(command: &str, input: Option<String>) -> Result<Output> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // Fed from its own task while the output is read, or a command that
+    // writes before it has read everything (cat, tr) fills its stdout
+    // pipe and waits on us forever.
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        tokio::spawn(async move {
+            // Commands that don't read all of it close the pipe early; that's fine.
+            let _ = stdin.write_all(input.as_bytes()).await;
+        });
+    }
+
+    let output = child.wait_with_output().await?;
+    Ok(Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        success: output.status.success(),
+    })
+}
@@ -0,0 +1,395 @@
+//! Spell checking with hunspell dictionaries (`<language>.dic` and `.aff`
+//! from the usual system directories or `~/.config/neuronano/dictionaries/`),
+//! or a plain word list like `/usr/share/dict/words` for English. Affixes
+//! are expanded once when the dictionary loads, so checking a word is a
+//! lookup. Words added by the user go to `~/.config/neuronano/words.txt`.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use encoding_rs::{Encoding, UTF_8};
+use crate::config::Config;
+
+/// Fallback for English when no hunspell dictionary is installed.
+const WORD_LIST: &str = "/usr/share/dict/words";
+
+/// Where `<language>.dic` is looked for, in order.
+fn dictionary_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![Config::dir().join("dictionaries")];
+    if let Some(home) = dirs::home_dir() {
+        dirs.push(home.join("Library/Spelling"));
+    }
+    for dir in ["/usr/share/hunspell", "/usr/share/myspell", "/usr/share/myspell/dicts", "/usr/local/share/hunspell", "/Library/Spelling"] {
+        dirs.push(PathBuf::from(dir));
+    }
+    dirs
+}
+
+fn personal_path() -> PathBuf {
+    Config::dir().join("words.txt")
+}
+
+pub struct Dictionary {
+    words: HashSet<String>,
+}
+
+impl Dictionary {
+    /// The dictionary for `language` (e.g. "en_US") plus the personal words.
+    pub fn load(language: &str) -> Result<Self> {
+        let mut words = HashSet::new();
+        let dic = dictionary_dirs().into_iter().map(|dir| dir.join(format!("{}.dic", language))).find(|p| p.is_file());
+        match dic {
+            Some(dic) => load_hunspell(&dic, &mut words)?,
+            None if language.starts_with("en") && Path::new(WORD_LIST).is_file() => {
+                words.extend(fs::read_to_string(WORD_LIST)?.lines().map(str::trim).filter(|w| !w.is_empty()).map(String::from));
+            }
+            None => bail!("no {} dictionary (put {}.dic and {}.aff in {})", language, language, language, Config::dir().join("dictionaries").display()),
+        }
+        if let Ok(personal) = fs::read_to_string(personal_path()) {
+            words.extend(personal.lines().map(str::trim).filter(|w| !w.is_empty()).map(String::from));
+        }
+        Ok(Self { words })
+    }
+
+    /// Spelled right: as written, or lowercased when it's capitalized or
+    /// all caps (the start of a sentence, a heading). A lowercase proper
+    /// name is wrong.
+    pub fn check(&self, word: &str) -> bool {
+        let word = word.replace('’', "'");
+        if self.words.contains(&word) {
+            return true;
+        }
+        let mut chars = word.chars();
+        let first_upper = chars.next().is_some_and(char::is_uppercase);
+        let rest_lower = chars.clone().all(|c| !c.is_uppercase());
+        let all_upper = first_upper && chars.all(|c| !c.is_lowercase());
+        if !(first_upper && (rest_lower || all_upper)) {
+            return false;
+        }
+        let lower = word.to_lowercase();
+        self.words.contains(&lower) || (all_upper && self.words.contains(&capitalize(&lower)))
+    }
+
+    /// Accept `word` for the rest of the session.
+    pub fn add(&mut self, word: &str) {
+        self.words.insert(word.replace('’', "'"));
+    }
+
+    /// Up to `n` dictionary words closest to `word`, best first, in its
+    /// capitalization.
+    pub fn suggest(&self, word: &str, n: usize) -> Vec<String> {
+        let lower = word.to_lowercase();
+        let len = lower.chars().count();
+        // Short words are easily a few edits from anything.
+        let max_distance = (1 + len / 4).min(3);
+        let mut scored: Vec<(usize, &String)> = self
+            .words
+            .iter()
+            .filter(|w| w.chars().count().abs_diff(len) <= 2)
+            .map(|w| (strsim::damerau_levenshtein(&lower, &w.to_lowercase()), w))
+            .filter(|(distance, _)| *distance <= max_distance)
+            .collect();
+        scored.sort();
+        let capitalized = word.chars().next().is_some_and(char::is_uppercase);
+        let mut suggestions: Vec<String> = Vec::new();
+        for (_, w) in scored {
+            let w = if capitalized { capitalize(w) } else { w.clone() };
+            if !suggestions.contains(&w) {
+                suggestions.push(w);
+            }
+            if suggestions.len() == n {
+                break;
+            }
+        }
+        suggestions
+    }
+}
+
+/// Add `word` to the personal word list for good.
+pub fn add_personal(word: &str) -> Result<()> {
+    fs::create_dir_all(Config::dir())?;
+    let mut file = OpenOptions::new().create(true).append(true).open(personal_path())?;
+    writeln!(file, "{}", word)?;
+    Ok(())
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars.next().map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+}
+
+/// One position of an affix condition like `[^aeiou]y`.
+enum Class {
+    Any,
+    Char(char),
+    Set(Vec<char>, bool),
+}
+
+impl Class {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Class::Any => true,
+            Class::Char(x) => *x == c,
+            Class::Set(set, negated) => set.contains(&c) != *negated,
+        }
+    }
+}
+
+fn parse_condition(condition: &str) -> Vec<Class> {
+    let mut classes = Vec::new();
+    let mut chars = condition.chars();
+    while let Some(c) = chars.next() {
+        classes.push(match c {
+            '.' => Class::Any,
+            '[' => {
+                let set: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                match set.strip_prefix('^') {
+                    Some(set) => Class::Set(set.chars().collect(), true),
+                    None => Class::Set(set.chars().collect(), false),
+                }
+            }
+            c => Class::Char(c),
+        });
+    }
+    classes
+}
+
+struct Rule {
+    strip: String,
+    add: String,
+    condition: Vec<Class>,
+}
+
+struct Affixes {
+    prefix: bool,
+    /// Combines with affixes of the other kind.
+    cross: bool,
+    rules: Vec<Rule>,
+}
+
+impl Affixes {
+    /// Every form `word` takes with these affixes.
+    fn apply(&self, word: &str) -> Vec<String> {
+        let chars: Vec<char> = word.chars().collect();
+        let mut forms = Vec::new();
+        for rule in &self.rules {
+            let n = rule.condition.len();
+            if n > chars.len() {
+                continue;
+            }
+            let checked = if self.prefix { &chars[..n] } else { &chars[chars.len() - n..] };
+            if !rule.condition.iter().zip(checked).all(|(class, &c)| class.matches(c)) {
+                continue;
+            }
+            if self.prefix {
+                if let Some(rest) = word.strip_prefix(rule.strip.as_str()) {
+                    forms.push(format!("{}{}", rule.add, rest));
+                }
+            } else if let Some(rest) = word.strip_suffix(rule.strip.as_str()) {
+                forms.push(format!("{}{}", rest, rule.add));
+            }
+        }
+        forms
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FlagType {
+    Char,
+    Long,
+    Num,
+}
+
+fn split_flags(flags: &str, flag_type: FlagType) -> Vec<String> {
+    match flag_type {
+        FlagType::Char => flags.chars().map(String::from).collect(),
+        FlagType::Long => flags.chars().collect::<Vec<_>>().chunks(2).map(|c| c.iter().collect()).collect(),
+        FlagType::Num => flags.split(',').map(|f| f.trim().to_string()).collect(),
+    }
+}
+
+/// Read `path` in the encoding the `.aff` file declares.
+fn read_encoded(path: &Path, encoding: &'static Encoding) -> Result<String> {
+    Ok(encoding.decode(&fs::read(path)?).0.into_owned())
+}
+
+/// Add every word of the hunspell dictionary `dic` (with its `.aff`) in
+/// all its affixed forms. Compounding and the rarer options are ignored.
+fn load_hunspell(dic: &Path, words: &mut HashSet<String>) -> Result<()> {
+    let aff_path = dic.with_extension("aff");
+    let raw = fs::read(&aff_path).unwrap_or_default();
+    let encoding = String::from_utf8_lossy(&raw)
+        .lines()
+        .find_map(|l| l.strip_prefix("SET ").map(|s| s.trim().to_string()))
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    let aff = encoding.decode(&raw).0.into_owned();
+
+    let mut flag_type = FlagType::Char;
+    let mut affixes: HashMap<String, Affixes> = HashMap::new();
+    for line in aff.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["FLAG", "long", ..] => flag_type = FlagType::Long,
+            ["FLAG", "num", ..] => flag_type = FlagType::Num,
+            [kind @ ("PFX" | "SFX"), flag, rest @ ..] if rest.len() >= 2 => {
+                if let Some(affix) = affixes.get_mut(*flag) {
+                    let strip = if rest[0] == "0" { "" } else { rest[0] };
+                    let add = rest[1].split('/').next().unwrap_or("");
+                    affix.rules.push(Rule {
+                        strip: strip.to_string(),
+                        add: if add == "0" { String::new() } else { add.to_string() },
+                        condition: parse_condition(rest.get(2).copied().unwrap_or(".")),
+                    });
+                } else {
+                    affixes.insert(flag.to_string(), Affixes { prefix: *kind == "PFX", cross: rest[0] == "Y", rules: Vec::new() });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The first line is the word count.
+    for line in read_encoded(dic, encoding)?.lines().skip(1) {
+        let Some(entry) = line.split_whitespace().next() else { continue };
+        let (word, flags) = entry.split_once('/').unwrap_or((entry, ""));
+        words.insert(word.to_string());
+        let classes: Vec<&Affixes> = split_flags(flags, flag_type).iter().filter_map(|f| affixes.get(f)).collect();
+        let mut crossing = Vec::new();
+        for affix in classes.iter().filter(|a| !a.prefix) {
+            for form in affix.apply(word) {
+                if affix.cross {
+                    crossing.push(form.clone());
+                }
+                words.insert(form);
+            }
+        }
+        for affix in classes.iter().filter(|a| a.prefix) {
+            words.extend(affix.apply(word));
+            if affix.cross {
+                for form in &crossing {
+                    words.extend(affix.apply(form));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What of a buffer gets checked.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scope {
+    /// All text; with Markdown, not code blocks, `code` or link targets.
+    Prose { markdown: bool },
+    /// Only what follows this line comment leader.
+    Comments(String),
+}
+
+/// Misspelled words of `lines[rows]`, as (row, start, end) char ranges.
+pub fn misspelled(dictionary: &Dictionary, lines: &[String], rows: std::ops::Range<usize>, scope: &Scope) -> Vec<(usize, usize, usize)> {
+    let mut found = Vec::new();
+    // Fences opened above the first row count too.
+    let mut in_fence = false;
+    for (row, line) in lines.iter().enumerate().take(rows.end) {
+        let fence = matches!(scope, Scope::Prose { markdown: true }) && {
+            let trimmed = line.trim_start();
+            trimmed.starts_with("```") || trimmed.starts_with("~~~")
+        };
+        if fence {
+            in_fence = !in_fence;
+        }
+        if row < rows.start || fence || in_fence {
+            continue;
+        }
+        let chars = checked_text(line, scope);
+        for (start, end) in words(&chars) {
+            let word: String = chars[start..end].iter().collect();
+            if !dictionary.check(&word) {
+                found.push((row, start, end));
+            }
+        }
+    }
+    found
+}
+
+/// The chars of `line` with what isn't checked blanked out, so indexes
+/// stay those of the line.
+fn checked_text(line: &str, scope: &Scope) -> Vec<char> {
+    let mut chars: Vec<char> = line.chars().collect();
+    match scope {
+        Scope::Comments(leader) => {
+            let from = line.find(leader.as_str()).map_or(chars.len(), |at| line[..at].chars().count() + leader.chars().count());
+            chars[..from].fill(' ');
+        }
+        Scope::Prose { markdown: true } => {
+            // `code` spans and the target of [text](target).
+            let mut i = 0;
+            while i < chars.len() {
+                let close = match chars[i] {
+                    '`' => Some('`'),
+                    '(' if i > 0 && chars[i - 1] == ']' => Some(')'),
+                    _ => None,
+                };
+                if let Some(close) = close {
+                    let end = chars[i + 1..].iter().position(|&c| c == close).map_or(chars.len(), |p| i + 1 + p + 1);
+                    chars[i..end].fill(' ');
+                    i = end;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        Scope::Prose { markdown: false } => {}
+    }
+    chars
+}
+
+/// Char ranges of the words worth checking: runs of letters (with inner
+/// apostrophes), leaving out URLs, paths, identifiers, words glued to
+/// digits or dots, camelCase and single letters.
+fn words(chars: &[char]) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let chunk_start = i;
+        while i < chars.len() && !chars[i].is_whitespace() {
+            i += 1;
+        }
+        let chunk: String = chars[chunk_start..i].iter().collect();
+        if chunk.contains(['@', '/', '\\', '_', '<', '>', '=', '{', '}', '$', '#']) || chunk.contains("::") {
+            continue;
+        }
+        let is_apostrophe = |c: char| c == '\'' || c == '’';
+        let mut j = chunk_start;
+        while j < i {
+            if !chars[j].is_alphabetic() {
+                j += 1;
+                continue;
+            }
+            let start = j;
+            while j < i && (chars[j].is_alphabetic() || (is_apostrophe(chars[j]) && j + 1 < i && chars[j + 1].is_alphabetic())) {
+                j += 1;
+            }
+            let before = (start > chunk_start).then(|| chars[start - 1]);
+            let after = chars[..i].get(j).copied();
+            let glued = |c: Option<char>, beyond: Option<char>| {
+                c.is_some_and(|c| c.is_ascii_digit() || (c == '.' && beyond.is_some_and(char::is_alphanumeric)))
+            };
+            let word = &chars[start..j];
+            let camel = word.iter().skip(1).any(|c| c.is_uppercase()) && word.iter().any(|c| c.is_lowercase());
+            if word.len() > 1
+                && !camel
+                && !glued(before, (start > chunk_start + 1).then(|| chars[start - 2]))
+                && !glued(after, chars[..i].get(j + 1).copied())
+            {
+                found.push((start, j));
+            }
+        }
+    }
+    found
+}
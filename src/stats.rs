@@ -0,0 +1,44 @@
+//! Running totals of AI token use and estimated cost, kept next to the
+//! config file.
+use std::fs;
+use std::path::PathBuf;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use crate::ai::Usage;
+use crate::config::Config;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Stats {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub output_tokens: u64,
+    /// Estimated, in USD.
+    pub cost: f64,
+}
+
+impl Stats {
+    pub fn path() -> PathBuf {
+        Config::dir().join("stats.json")
+    }
+
+    pub fn load() -> Self {
+        fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(Config::dir())?;
+        fs::write(Self::path(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, usage: Usage, cost: f64) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.output_tokens += usage.output_tokens;
+        self.cost += cost;
+    }
+}
@@ -0,0 +1,82 @@
+//! Crash recovery: unsaved changes are written to `.name.swp` next to the
+//! file now and then, and removed on save and on a clean exit. One left
+//! behind is offered back the next time the file is opened.
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::Result;
+use crate::fileio;
+
+/// First line of a swap file, followed by the pid that wrote it.
+const MAGIC: &str = "neuronano swap";
+
+/// `dir/.name.swp` for `dir/name`.
+pub fn path_for(file: &Path) -> PathBuf {
+    let name = file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    file.with_file_name(format!(".{}.swp", name))
+}
+
+/// A swap file found next to a file being opened.
+pub struct Found {
+    pub path: PathBuf,
+    /// The neuronano that wrote it.
+    pub pid: u32,
+    pub text: String,
+    pub written: Option<SystemTime>,
+}
+
+impl Found {
+    /// The process that wrote it is still editing the file.
+    pub fn in_use(&self) -> bool {
+        self.pid != std::process::id() && is_running(self.pid)
+    }
+}
+
+fn is_running(pid: u32) -> bool {
+    // Without /proc there's no telling; assume it crashed.
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// The pid in the header of the swap file at `swap`.
+fn owner(swap: &Path) -> Option<u32> {
+    let mut head = [0u8; 64];
+    let read = fs::File::open(swap).ok()?.read(&mut head).ok()?;
+    let head = std::str::from_utf8(&head[..read]).ok()?.lines().next()?.to_string();
+    head.strip_prefix(MAGIC)?.trim().parse().ok()
+}
+
+pub fn find(file: &Path) -> Option<Found> {
+    let path = path_for(file);
+    let content = fs::read_to_string(&path).ok()?;
+    let (head, text) = content.split_once('\n').unwrap_or((&content, ""));
+    let pid = head.strip_prefix(MAGIC)?.trim().parse().ok()?;
+    let written = fs::metadata(&path).and_then(|m| m.modified()).ok();
+    Some(Found { text: text.to_string(), path, pid, written })
+}
+
+/// Write `text` as the swap of `file`, readable by whoever may read the
+/// file. Leaves alone a swap another running neuronano keeps.
+pub fn write(file: &Path, text: &str) -> Result<()> {
+    let swap = path_for(file);
+    if owner(&swap).is_some_and(|pid| pid != std::process::id() && is_running(pid)) {
+        return Ok(());
+    }
+    let fresh = !swap.exists();
+    fileio::write_atomic(&swap, format!("{} {}\n{}", MAGIC, std::process::id(), text).as_bytes())?;
+    if fresh {
+        if let Ok(meta) = fs::metadata(file) {
+            fs::set_permissions(&swap, meta.permissions())?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete `swap` if we wrote it.
+pub fn remove(swap: &Path) {
+    if owner(swap) == Some(std::process::id()) {
+        if let Err(e) = fs::remove_file(swap) {
+            log::error!("Failed to remove {}: {}", swap.display(), e);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! CSV/TSV helpers for the aligned table view.
+use std::ops::Range;
+
+/// Field delimiter for delimited data files, by extension.
+pub fn delimiter_for(filename: &str) -> Option<char> {
+    let ext = std::path::Path::new(filename).extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "csv" => Some(','),
+        "tsv" | "tab" => Some('\t'),
+        _ => None,
+    }
+}
+
+/// Char ranges of each field in `line`, delimiters excluded. Delimiters
+/// inside double-quoted fields don't split.
+pub fn field_ranges(line: &str, delimiter: char) -> Vec<Range<usize>> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+
+    for (i, c) in line.chars().enumerate() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == delimiter && !in_quotes {
+            fields.push(start..i);
+            start = i + 1;
+        }
+    }
+    fields.push(start..line.chars().count());
+    fields
+}
+
+/// Index of the field containing char column `col`.
+pub fn field_at(fields: &[Range<usize>], col: usize) -> usize {
+    fields
+        .iter()
+        .position(|r| col <= r.end)
+        .unwrap_or(fields.len().saturating_sub(1))
+}
+
+/// Field text as displayed in the table (surrounding quotes stripped).
+pub fn field_text(line: &str, range: &Range<usize>) -> String {
+    let text: String = line.chars().skip(range.start).take(range.len()).collect();
+    match text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(inner) => inner.replace("\"\"", "\""),
+        None => text,
+    }
+}
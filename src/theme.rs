@@ -0,0 +1,264 @@
+//! Color themes. A theme is picked by name from the built-in presets or from
+//! user-defined themes in the config file.
+use ratatui::style::Color;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Built-in theme names, in switcher order.
+pub const PRESETS: [&str; 3] = ["dark", "light", "solarized"];
+
+/// How many colors the terminal can show.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSupport {
+    #[serde(alias = "24bit")]
+    TrueColor,
+    #[serde(rename = "256")]
+    Ansi256,
+    #[serde(rename = "16")]
+    Ansi16,
+}
+
+impl ColorSupport {
+    /// Guess from the environment: COLORTERM for true color, TERM for 256
+    /// colors; anything else gets the basic 16.
+    pub fn detect() -> Self {
+        let colorterm = std::env::var("COLORTERM").unwrap_or_default();
+        let term = std::env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" || term.ends_with("-direct") {
+            Self::TrueColor
+        } else if term.contains("256color") || std::env::var_os("WT_SESSION").is_some() {
+            Self::Ansi256
+        } else {
+            Self::Ansi16
+        }
+    }
+
+    /// Nearest color the terminal can show.
+    pub fn fit(self, color: Color) -> Color {
+        let rgb = match (self, color) {
+            (Self::TrueColor, _) => return color,
+            (_, Color::Rgb(r, g, b)) => (r, g, b),
+            (Self::Ansi16, Color::Indexed(i)) if i >= 16 => indexed_rgb(i),
+            _ => return color,
+        };
+        match self {
+            Self::Ansi256 => nearest_256(rgb),
+            _ => nearest_16(rgb),
+        }
+    }
+}
+
+/// Levels of the 6x6x6 cube in the xterm 256-color palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// The 16 ANSI colors with their usual xterm values.
+const ANSI_16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+/// RGB value of a 256-palette entry past the basic 16.
+fn indexed_rgb(i: u8) -> (u8, u8, u8) {
+    if i >= 232 {
+        let level = 8 + (i - 232) * 10;
+        (level, level, level)
+    } else {
+        let i = i - 16;
+        (CUBE_LEVELS[(i / 36) as usize], CUBE_LEVELS[(i / 6 % 6) as usize], CUBE_LEVELS[(i % 6) as usize])
+    }
+}
+
+/// Closest cube color or gray in the 256-color palette.
+fn nearest_256(rgb: (u8, u8, u8)) -> Color {
+    let level = |v: u8| (0..6).min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs()).unwrap_or(0);
+    let cube = 16 + 36 * level(rgb.0) + 6 * level(rgb.1) + level(rgb.2);
+    let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let gray = 232 + ((average.saturating_sub(3)) / 10).min(23) as usize;
+    [cube, gray]
+        .into_iter()
+        .min_by_key(|&i| distance(rgb, indexed_rgb(i as u8)))
+        .map(|i| Color::Indexed(i as u8))
+        .unwrap_or(Color::Reset)
+}
+
+fn nearest_16(rgb: (u8, u8, u8)) -> Color {
+    ANSI_16.iter().min_by_key(|(_, c)| distance(rgb, *c)).map(|(color, _)| *color).unwrap_or(Color::Reset)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Theme {
+    pub header_fg: Color,
+    pub header_bg: Color,
+    pub footer_fg: Color,
+    pub footer_bg: Color,
+    pub status_fg: Color,
+    pub status_bg: Color,
+    pub popup_fg: Color,
+    pub popup_bg: Color,
+    pub processing_fg: Color,
+    pub processing_bg: Color,
+    pub warning_fg: Color,
+    pub warning_bg: Color,
+    pub border: Color,
+    /// Titles and other highlighted text.
+    pub accent: Color,
+    /// Line numbers, separators and other low-key decorations.
+    pub muted: Color,
+    /// Columns past the soft line-length limit.
+    pub overlong: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            header_fg: Color::Black,
+            header_bg: Color::Cyan,
+            footer_fg: Color::Black,
+            footer_bg: Color::White,
+            status_fg: Color::Black,
+            status_bg: Color::Yellow,
+            popup_fg: Color::White,
+            popup_bg: Color::DarkGray,
+            processing_fg: Color::White,
+            processing_bg: Color::Blue,
+            warning_fg: Color::White,
+            warning_bg: Color::Red,
+            border: Color::White,
+            accent: Color::Cyan,
+            muted: Color::DarkGray,
+            overlong: Color::LightRed,
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            header_fg: Color::White,
+            header_bg: Color::Blue,
+            footer_fg: Color::White,
+            footer_bg: Color::DarkGray,
+            status_fg: Color::Black,
+            status_bg: Color::LightYellow,
+            popup_fg: Color::Black,
+            popup_bg: Color::Gray,
+            processing_fg: Color::White,
+            processing_bg: Color::Blue,
+            warning_fg: Color::White,
+            warning_bg: Color::Red,
+            border: Color::DarkGray,
+            accent: Color::Blue,
+            muted: Color::Gray,
+            overlong: Color::Red,
+        }
+    }
+
+    pub fn solarized() -> Self {
+        let base03 = Color::Rgb(0x00, 0x2b, 0x36);
+        let base02 = Color::Rgb(0x07, 0x36, 0x42);
+        let base01 = Color::Rgb(0x58, 0x6e, 0x75);
+        let base1 = Color::Rgb(0x93, 0xa1, 0xa1);
+        let yellow = Color::Rgb(0xb5, 0x89, 0x00);
+        let red = Color::Rgb(0xdc, 0x32, 0x2f);
+        let blue = Color::Rgb(0x26, 0x8b, 0xd2);
+        let cyan = Color::Rgb(0x2a, 0xa1, 0x98);
+
+        Self {
+            header_fg: base03,
+            header_bg: cyan,
+            footer_fg: base1,
+            footer_bg: base02,
+            status_fg: base03,
+            status_bg: yellow,
+            popup_fg: base1,
+            popup_bg: base02,
+            processing_fg: base03,
+            processing_bg: blue,
+            warning_fg: base03,
+            warning_bg: red,
+            border: base01,
+            accent: cyan,
+            muted: base01,
+            overlong: red,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "solarized" => Some(Self::solarized()),
+            _ => None,
+        }
+    }
+
+    /// Resolve a theme name; user themes shadow presets. Unknown names fall
+    /// back to the dark theme.
+    pub fn resolve(name: &str, custom: &HashMap<String, Theme>) -> Self {
+        custom
+            .get(name)
+            .copied()
+            .or_else(|| Self::preset(name))
+            .unwrap_or_else(|| {
+                log::warn!("Unknown theme '{}', using 'dark'", name);
+                Self::dark()
+            })
+    }
+
+    /// The theme with every color mapped to what the terminal can show.
+    pub fn fit(self, colors: ColorSupport) -> Self {
+        let fit = |c| colors.fit(c);
+        Self {
+            header_fg: fit(self.header_fg),
+            header_bg: fit(self.header_bg),
+            footer_fg: fit(self.footer_fg),
+            footer_bg: fit(self.footer_bg),
+            status_fg: fit(self.status_fg),
+            status_bg: fit(self.status_bg),
+            popup_fg: fit(self.popup_fg),
+            popup_bg: fit(self.popup_bg),
+            processing_fg: fit(self.processing_fg),
+            processing_bg: fit(self.processing_bg),
+            warning_fg: fit(self.warning_fg),
+            warning_bg: fit(self.warning_bg),
+            border: fit(self.border),
+            accent: fit(self.accent),
+            muted: fit(self.muted),
+            overlong: fit(self.overlong),
+        }
+    }
+
+    /// All selectable theme names: presets first, then user themes.
+    pub fn names(custom: &HashMap<String, Theme>) -> Vec<String> {
+        let mut names: Vec<String> = PRESETS.iter().map(|s| s.to_string()).collect();
+        let mut extra: Vec<String> = custom.keys().filter(|k| !names.contains(k)).cloned().collect();
+        extra.sort();
+        names.extend(extra);
+        names
+    }
+}
@@ -2,20 +2,159 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Clear, Paragraph},
+    widgets::{Block, Borders, Clear, Paragraph, Widget, Wrap},
     Frame,
 };
-use crate::app::{App, AppMode};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use crossterm::{
+    cursor::{SetCursorStyle, Show},
+    event::{
+        DisableMouseCapture, EnableMouseCapture, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+        PushKeyboardEnhancementFlags,
+    },
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, is_raw_mode_enabled, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use crate::app::{App, AppMode, Diagnostic, Severity, COMPLETION_ROWS, WELCOME_ACTIONS};
+use crate::vim::VimMode;
+use crate::chat::Role;
+use crate::diff::{self, DiffLine, Kind as DiffKind, Mark, Take};
+use crate::keychain;
+use crate::fold;
+use crate::grep;
+use crate::lsp;
+use crate::frontmatter;
+use crate::keymap::Action;
+use crate::markdown;
+use crate::table;
+use crate::wrap;
+use crate::theme::Theme;
+
+/// Terminal cursor for whatever has the keyboard: a bar where text is
+/// typed, a block over read-only text or a selection, none in popups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Bar,
+    Block,
+    Hidden,
+}
+
+impl CursorShape {
+    pub fn style(self) -> Option<SetCursorStyle> {
+        match self {
+            CursorShape::Bar => Some(SetCursorStyle::SteadyBar),
+            CursorShape::Block => Some(SetCursorStyle::SteadyBlock),
+            CursorShape::Hidden => None,
+        }
+    }
+}
+
+fn cursor_shape(app: &App) -> CursorShape {
+    match app.mode() {
+        AppMode::Normal if app.table_view => CursorShape::Hidden,
+        AppMode::Normal => {
+            let inserting = app.vim.as_ref().is_none_or(|vim| vim.mode == VimMode::Insert);
+            if app.read_only || app.buffer.editor.is_selecting() || !inserting {
+                CursorShape::Block
+            } else {
+                CursorShape::Bar
+            }
+        }
+        AppMode::Prompting
+        | AppMode::Setup
+        | AppMode::Search
+        | AppMode::Replace
+        | AppMode::SaveAs
+        | AppMode::ApplyPatch
+        | AppMode::ExportPatch
+        | AppMode::FrontMatter
+        | AppMode::Locked
+        | AppMode::OpenFile
+        | AppMode::Grep
+        | AppMode::RunCommand
+        | AppMode::Filter
+        | AppMode::Chat => {
+            CursorShape::Bar
+        }
+        AppMode::Processing
+        | AppMode::ConfirmQuit
+        | AppMode::FileChanged
+        | AppMode::Recover
+        | AppMode::GitCommit
+        | AppMode::GitDiff
+        | AppMode::Outline
+        | AppMode::Bookmarks
+        | AppMode::Spelling
+        | AppMode::Buffers
+        | AppMode::GrepResults
+        | AppMode::SearchResults
+        | AppMode::Report
+        | AppMode::Welcome
+        | AppMode::Explain
+        | AppMode::DiagnosticDetails
+        | AppMode::AiSnapshots
+        | AppMode::Merge
+        | AppMode::Preview => {
+            CursorShape::Hidden
+        }
+    }
+}
+
+/// Give the terminal back for the duration of `f`, e.g. to a tool asking
+/// for a passphrase. The screen needs a full repaint afterwards.
+pub fn suspended<T>(keyboard_enhanced: bool, f: impl FnOnce() -> T) -> T {
+    // Nothing to give back without the UI up (e.g. `--apply`).
+    if !is_raw_mode_enabled().unwrap_or(false) {
+        return f();
+    }
+    let mut tty = std::fs::OpenOptions::new().write(true).open("/dev/tty").ok();
+    if let Some(tty) = &mut tty {
+        if keyboard_enhanced {
+            let _ = execute!(tty, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(tty, LeaveAlternateScreen, DisableMouseCapture, Show);
+    }
+    let _ = disable_raw_mode();
+    let result = f();
+    let _ = enable_raw_mode();
+    if let Some(tty) = &mut tty {
+        let _ = execute!(tty, EnterAlternateScreen, EnableMouseCapture);
+        if keyboard_enhanced {
+            let flags = KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES | KeyboardEnhancementFlags::REPORT_ALTERNATE_KEYS;
+            let _ = execute!(tty, PushKeyboardEnhancementFlags(flags));
+        }
+    }
+    result
+}
+
+/// Below this size only a "too small" notice is drawn.
+const MIN_WIDTH: u16 = 24;
+const MIN_HEIGHT: u16 = 6;
+/// Below this height the footer shrinks to one row; below this width the
+/// line numbers go.
+const COMPACT_HEIGHT: u16 = 12;
+const NARROW_WIDTH: u16 = 40;
 
 pub fn ui(f: &mut Frame, app: &mut App) {
+    let size = f.area();
+    if size.width < MIN_WIDTH || size.height < MIN_HEIGHT {
+        app.cursor_shape = CursorShape::Hidden;
+        let text = format!("Terminal too small\n{}x{}, need {}x{}", size.width, size.height, MIN_WIDTH, MIN_HEIGHT);
+        let y = size.y + size.height.saturating_sub(2) / 2;
+        let area = Rect { y, height: size.height.min(2), ..size };
+        f.render_widget(Paragraph::new(text).alignment(ratatui::layout::Alignment::Center), area);
+        return;
+    }
+    app.cursor_shape = cursor_shape(app);
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Length(1), // Header
             Constraint::Min(0),    // Editor
-            Constraint::Length(2), // Footer
+            Constraint::Length(if size.height < COMPACT_HEIGHT { 1 } else { 2 }), // Footer
         ])
-        .split(f.area());
+        .split(size);
 
     render_header(f, app, chunks[0]);
 
@@ -25,53 +164,1610 @@ pub fn ui(f: &mut Frame, app: &mut App) {
             "Rust" => Color::LightRed, // Orange-ish
             "JSON" => Color::Green,
             "Markdown" => Color::Blue,
-            _ => Color::White,
+            _ => app.theme.border,
+        }
+    } else {
+        app.theme.border
+    };
+
+    // A write-protected file says so above the text for as long as it's open.
+    let body = match &app.write_protected {
+        Some(reason) => {
+            let split = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0)])
+                .split(chunks[1]);
+            render_write_protected_banner(f, app, reason, split[0]);
+            split[1]
         }
+        None => chunks[1],
+    };
+
+    // The chat panel takes the right side of the editor area.
+    let main_area = if app.show_chat {
+        let split = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(20), Constraint::Percentage(app.prefs.chat_width)])
+            .split(body);
+        render_chat(f, app, split[1]);
+        split[0]
     } else {
-        Color::White
+        body
     };
 
-    app.textarea.set_block(Block::default().borders(Borders::ALL).style(Style::default().fg(border_color)));
-    f.render_widget(&app.textarea, chunks[1]);
+    // Cell results, diagnostics and search matches take the bottom of the
+    // editor area.
+    let show_output = app.show_cell_output && app.cell_output.is_some();
+    let editor_area = if show_output || app.show_diagnostics || app.search_results.is_some() {
+        let mut constraints = vec![Constraint::Min(3)];
+        if show_output {
+            constraints.push(Constraint::Percentage(33));
+        }
+        if app.search_results.is_some() {
+            constraints.push(Constraint::Percentage(25));
+        }
+        if app.show_diagnostics {
+            constraints.push(Constraint::Percentage(25));
+        }
+        let split = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints)
+            .split(main_area);
+        if show_output {
+            render_cell_output(f, app, split[1]);
+        }
+        if app.search_results.is_some() {
+            let at = if app.show_diagnostics { split.len() - 2 } else { split.len() - 1 };
+            render_search_results(f, app, split[at]);
+        }
+        if app.show_diagnostics {
+            render_diagnostics(f, app, split[split.len() - 1]);
+        }
+        split[0]
+    } else {
+        main_area
+    };
+
+    let mut block = Block::default().borders(Borders::ALL).style(Style::default().fg(border_color));
+    if let Some(session) = &app.share {
+        let mut names: Vec<&str> = session.cursors.values().map(|c| c.name.as_str()).collect();
+        names.sort();
+        let mut title = if session.is_observer() {
+            format!(" Following {} ", session.address)
+        } else if names.is_empty() {
+            match session.token() {
+                Some(token) => format!(" Shared on {} · token {} ", session.address, token),
+                None => format!(" Shared on {} ", session.address),
+            }
+        } else {
+            format!(" With {} ", names.join(", "))
+        };
+        if session.watchers() > 0 {
+            title.push_str(&format!("· {} watching ", session.watchers()));
+        }
+        block = block.title_bottom(Line::from(title).right_aligned());
+    }
+    if size.width < NARROW_WIDTH {
+        app.buffer.editor.remove_line_number();
+    } else {
+        app.buffer.editor.set_line_number_style(Style::default().fg(app.theme.muted));
+    }
+    // The terminal cursor stands in for the drawn one while the editor has focus.
+    let editor_focused = app.mode() == AppMode::Normal && app.cursor_shape != CursorShape::Hidden;
+    app.buffer.editor.set_cursor_style(if editor_focused {
+        Style::default()
+    } else {
+        Style::default().add_modifier(Modifier::REVERSED)
+    });
+    let editor_inner = block.inner(editor_area);
+    app.buffer.editor.set_block(block);
+    app.editor_area = editor_inner;
+    sync_editor_scroll(app, editor_inner);
+    if app.table_view {
+        render_table_view(f, app, editor_area, editor_inner);
+    } else {
+        if app.screen_row_view() {
+            render_wrapped(f, app, editor_area, editor_inner);
+        } else {
+            f.render_widget(&app.buffer.editor, editor_area);
+        }
+        // Large files get the plain text.
+        if !app.large_file {
+            render_whitespace(f, app, editor_inner);
+            render_line_length_marks(f, app, editor_inner);
+            render_spelling_marks(f, app, editor_inner);
+            render_front_matter_marks(f, app, editor_inner);
+            render_bracket_match(f, app, editor_inner);
+        }
+        if !app.large_file {
+            render_diagnostic_marks(f, app, editor_inner);
+        }
+        render_bookmark_marks(f, app, editor_inner);
+        render_git_marks(f, app, editor_inner);
+        render_ghost_text(f, app, editor_inner);
+        render_completion_menu(f, app, editor_inner);
+        render_shared_cursors(f, app, editor_inner);
+        render_extra_cursors(f, app, editor_inner);
+        render_block(f, app, editor_inner);
+        if editor_focused {
+            place_editor_cursor(f, app, editor_inner);
+        }
+    }
     render_footer(f, app, chunks[2]);
 
-    if app.mode == AppMode::Prompting {
-        render_ai_popup(f, app);
-    } else if app.mode == AppMode::Setup {
-        render_setup_screen(f, app);
-    } else if app.mode == AppMode::Processing {
-        render_processing_popup(f);
-    } else if app.mode == AppMode::Search {
-        render_search_bar(f, app);
-    } else if app.mode == AppMode::SaveAs {
-        render_save_as_popup(f, app);
-    } else if app.mode == AppMode::ConfirmQuit {
-        render_confirm_quit_popup(f);
+    // Draw every layer of the modal stack, bottom to top.
+    for mode in app.modes().to_vec() {
+        match mode {
+            AppMode::Normal => {}
+            AppMode::Prompting => render_ai_popup(f, app),
+            AppMode::Setup => render_setup_screen(f, app),
+            AppMode::Processing => render_processing_popup(f, app),
+            AppMode::Search => render_search_bar(f, app),
+            AppMode::Replace => render_replace_bar(f, app),
+            AppMode::SaveAs | AppMode::OpenFile => render_save_as_popup(f, app),
+            AppMode::Welcome => render_welcome_screen(f, app, chunks[1]),
+            AppMode::ConfirmQuit => render_confirm_quit_popup(f, app),
+            AppMode::FileChanged => render_file_changed_popup(f, app),
+            AppMode::Recover => render_recover_popup(f, app),
+            AppMode::GitCommit => render_git_commit_popup(f, app),
+            AppMode::GitDiff => render_head_diff(f, app),
+            AppMode::Outline => render_outline_popup(f, app),
+            AppMode::Bookmarks => render_bookmarks_popup(f, app),
+            AppMode::Spelling => render_spelling_popup(f, app),
+            AppMode::Buffers => render_buffers_popup(f, app),
+            // Drawn with the panel above.
+            AppMode::SearchResults => {}
+            AppMode::Grep => render_grep_popup(f, app),
+            AppMode::RunCommand | AppMode::Filter => render_command_popup(f, app),
+            AppMode::GrepResults => render_grep_results_popup(f, app),
+            AppMode::Report => render_report_popup(f, app),
+            AppMode::Locked => render_lock_screen(f, app),
+            AppMode::FrontMatter => render_front_matter_popup(f, app),
+            // Drawn with the panel above.
+            AppMode::Chat => {}
+            AppMode::Explain => render_explain_popup(f, app),
+            AppMode::DiagnosticDetails => render_diagnostic_details(f, app),
+            AppMode::AiSnapshots => render_ai_snapshots_popup(f, app),
+            AppMode::Preview => render_preview_popup(f, app),
+            AppMode::Merge => render_merge_view(f, app),
+            AppMode::ApplyPatch | AppMode::ExportPatch => render_patch_popup(f, app),
+        }
+    }
+}
+
+/// Same rule the editor widget uses: the view only moves once the cursor leaves it.
+pub fn next_scroll_top(prev_top: u16, cursor: u16, len: u16) -> u16 {
+    if cursor < prev_top {
+        cursor
+    } else if prev_top + len <= cursor {
+        cursor + 1 - len
+    } else {
+        prev_top
+    }
+}
+
+/// Width of the line number gutter ("  12 ") drawn by the editor widget.
+pub fn gutter_width(app: &App) -> u16 {
+    if app.buffer.editor.line_number_style().is_none() {
+        return 0;
+    }
+    app.buffer.editor.len_lines().to_string().len() as u16 + 2
+}
+
+/// Mirror the editor widget's scroll position so overlays know which part of the
+/// buffer is on screen. Must run right before the editor is rendered.
+fn sync_editor_scroll(app: &mut App, inner: Rect) {
+    app.unfold_at_cursor();
+    if app.screen_row_view() {
+        return sync_wrapped_scroll(app, inner);
+    }
+    let (row, col) = app.buffer.editor.cursor();
+    let gutter = gutter_width(app);
+    let col = col as u16;
+    let cursor_col = if col <= gutter { col * 2 } else { col + gutter };
+
+    app.editor_scroll = (
+        next_scroll_top(app.editor_scroll.0, row as u16, inner.height),
+        next_scroll_top(app.editor_scroll.1, cursor_col, inner.width),
+    );
+}
+
+/// Soft wrap (and folds) version of `sync_editor_scroll`: the top is a
+/// screen row (`editor_scroll.0` plus `wrap_top`), moved only as far as it
+/// takes to keep the cursor's screen row in view. Unwrapped lines scroll
+/// sideways by `editor_scroll.1` columns.
+fn sync_wrapped_scroll(app: &mut App, inner: Rect) {
+    let editor = &app.buffer.editor;
+    let rows_of = |row: usize| app.view_rows(row).len();
+    let (row, col) = app.buffer.editor.cursor();
+    let cursor = (row, wrap::row_of(&app.view_rows(row), col));
+    let left = if app.buffer.soft_wrap {
+        0
+    } else {
+        let columns = display_columns(&editor.line(row), app.buffer.editor.tab_length() as usize);
+        let x = columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, width)| start + width), |(start, _)| *start);
+        next_scroll_top(app.editor_scroll.1, x as u16, (app.wrap_width() as u16).max(1))
+    };
+
+    let top_row = fold::shown(&app.folds, (app.editor_scroll.0 as usize).min(editor.len_lines() - 1));
+    let mut top = (top_row, app.wrap_top.min(rows_of(top_row) - 1));
+    if cursor < top {
+        top = cursor;
+    } else {
+        // Walk up from the cursor a screenful; stopping short of the top
+        // means the cursor is on screen.
+        let mut at = cursor;
+        for _ in 1..inner.height.max(1) {
+            if at <= top {
+                break;
+            }
+            at = if at.1 > 0 {
+                (at.0, at.1 - 1)
+            } else {
+                let above = fold::prev_visible(&app.folds, at.0);
+                (above, rows_of(above) - 1)
+            };
+        }
+        top = top.max(at);
+    }
+    app.editor_scroll = (top.0 as u16, left);
+    app.wrap_top = top.1;
+}
+
+/// The editor with long lines wrapped or blocks folded, drawn in place of
+/// the editor widget (which can't do either). Records the screen rows for
+/// overlays and the mouse.
+fn render_wrapped(f: &mut Frame, app: &mut App, area: Rect, inner: Rect) {
+    // Render the editor widget off-screen so its viewport (used for
+    // PageUp/PageDown) keeps tracking the cursor.
+    let mut scratch = ratatui::buffer::Buffer::empty(area);
+    (&app.buffer.editor).render(area, &mut scratch);
+    if let Some(block) = app.buffer.editor.block() {
+        f.render_widget(block.clone(), area);
+    }
+
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    let gutter = gutter_width(app) as usize;
+    let left = app.editor_scroll.1 as usize;
+    let mut screen_rows = Vec::new();
+    let mut row = app.editor_scroll.0 as usize;
+    let mut skip = app.wrap_top;
+    while screen_rows.len() < inner.height as usize && row < app.buffer.editor.len_lines() {
+        let rows = app.view_rows(row);
+        screen_rows.extend(rows.into_iter().skip(skip).take(inner.height as usize - screen_rows.len()));
+        row = fold::next_visible(&app.folds, row);
+        skip = 0;
+    }
+
+    let editor = &app.buffer.editor;
+    let selection_style = editor.selection_style();
+    let (cursor_row, _) = editor.cursor();
+    let selection = editor.selection_range();
+    let digits = gutter.saturating_sub(2);
+    for (y, screen_row) in screen_rows.iter().enumerate() {
+        let y = inner.y + y as u16;
+        let line = &editor.line(screen_row.row);
+        if let (Some(style), true) = (editor.line_number_style(), screen_row.start == 0) {
+            f.buffer_mut().set_string(inner.x, y, format!(" {:>w$} ", screen_row.row + 1, w = digits), style);
+        }
+        // Search matches, as char ranges.
+        let matches: Vec<(usize, usize)> = editor
+            .search_pattern()
+            .map(|pattern| {
+                pattern
+                    .find_iter(line)
+                    .map(|m| (line[..m.start()].chars().count(), line[..m.end()].chars().count()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let columns = display_columns(line, tab_len);
+        let base = columns.get(screen_row.start).map_or(0, |(start, _)| *start);
+        let mut row_style = editor.style();
+        if screen_row.row == cursor_row {
+            row_style = row_style.patch(editor.cursor_line_style());
+        }
+        for (col, c) in line.chars().enumerate().take(screen_row.end).skip(screen_row.start) {
+            let (start, w) = columns[col];
+            let Some(x) = (gutter + start - base).checked_sub(left).filter(|x| *x >= gutter) else { continue };
+            if x + w > inner.width as usize {
+                break;
+            }
+            let mut style = row_style;
+            if selection.is_some_and(|(from, to)| from <= (screen_row.row, col) && (screen_row.row, col) < to) {
+                style = style.patch(selection_style);
+            } else if matches.iter().any(|(from, to)| (*from..*to).contains(&col)) {
+                style = style.patch(editor.search_style());
+            }
+            let text = if c == '\t' { " ".repeat(w) } else { c.to_string() };
+            f.buffer_mut().set_stringn(inner.x + x as u16, y, text, w.max(1), style);
+        }
+        let fold = app.folds.iter().find(|fold| fold.start == screen_row.row);
+        if let (Some(fold), true) = (fold, screen_row.end == columns.len()) {
+            let line_end = columns.last().map_or(0, |(start, w)| start + w);
+            let x = (gutter + line_end - base).saturating_sub(left).max(gutter) + 1;
+            let label = format!("… {} lines", fold.hidden());
+            if x < inner.width as usize {
+                let style = Style::default().fg(app.theme.muted).add_modifier(Modifier::ITALIC);
+                f.buffer_mut().set_stringn(inner.x + x as u16, y, label, inner.width as usize - x, style);
+            }
+        }
+    }
+    app.screen_rows = screen_rows;
+}
+
+/// Screen cell of display column `x` of buffer `row`, if it's on screen.
+fn editor_cell(app: &App, inner: Rect, row: usize, x: usize) -> Option<(u16, u16)> {
+    let gutter = gutter_width(app) as usize;
+    if app.screen_row_view() {
+        let columns = display_columns(&app.buffer.editor.line(row), app.buffer.editor.tab_length() as usize);
+        let column_x = |col: usize| columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, w)| start + w), |(start, _)| *start);
+        // The last screen row holding `x`; the end of the line belongs to
+        // the last row.
+        let (y, screen_row) = app
+            .screen_rows
+            .iter()
+            .enumerate()
+            .rfind(|(_, r)| r.row == row && column_x(r.start) <= x)?;
+        if !app.buffer.soft_wrap {
+            let x = (gutter + x).checked_sub(app.editor_scroll.1 as usize).filter(|x| *x >= gutter && *x < inner.width as usize)?;
+            return Some((inner.x + x as u16, inner.y + y as u16));
+        }
+        // Hanging whitespace (and the cursor after it) sticks to the edge.
+        let x = (gutter + x - column_x(screen_row.start)).min(inner.width.saturating_sub(1) as usize);
+        return Some((inner.x + x as u16, inner.y + y as u16));
+    }
+    let (top_row, top_col) = app.editor_scroll;
+    if row < top_row as usize || row >= top_row as usize + inner.height as usize {
+        return None;
+    }
+    let x = (gutter + x).checked_sub(top_col as usize)?;
+    (x < inner.width as usize).then(|| (inner.x + x as u16, inner.y + (row - top_row as usize) as u16))
+}
+
+fn place_editor_cursor(f: &mut Frame, app: &App, inner: Rect) {
+    let (row, col) = app.buffer.editor.cursor();
+    if app.screen_row_view() {
+        let columns = display_columns(&app.buffer.editor.line(row), app.buffer.editor.tab_length() as usize);
+        let x = columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, width)| start + width), |(start, _)| *start);
+        if let Some(position) = editor_cell(app, inner, row, x) {
+            f.set_cursor_position(position);
+        }
+        return;
+    }
+    let (top_row, top_col) = app.editor_scroll;
+    if row < top_row as usize || row >= top_row as usize + inner.height as usize {
+        return;
+    }
+    let columns = display_columns(&app.buffer.editor.line(row), app.buffer.editor.tab_length() as usize);
+    let start = columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, width)| start + width), |(start, _)| *start);
+    let Some(x) = (gutter_width(app) as usize + start).checked_sub(top_col as usize) else { return };
+    if x < inner.width as usize {
+        f.set_cursor_position((inner.x + x as u16, inner.y + (row - top_row as usize) as u16));
+    }
+}
+
+/// Display columns (start, width) of each char in a line, expanding tabs.
+pub fn display_columns(line: &str, tab_len: usize) -> Vec<(usize, usize)> {
+    let mut col = 0;
+    line.chars()
+        .map(|c| {
+            let width = if c == '\t' {
+                if tab_len == 0 { 0 } else { tab_len - col % tab_len }
+            } else {
+                c.width().unwrap_or(0)
+            };
+            let start = col;
+            col += width;
+            (start, width)
+        })
+        .collect()
+}
+
+/// Buffer position (row, char col) under a screen cell of the editor, as
+/// drawn in the last frame. Positions outside the text are clamped to it.
+pub fn editor_position(app: &App, x: u16, y: u16) -> (usize, usize) {
+    let area = app.editor_area;
+    let editor = &app.buffer.editor;
+    let y = y.clamp(area.y, (area.y + area.height).saturating_sub(1).max(area.y));
+    let wrapped = if app.screen_row_view() {
+        app.screen_rows.get((y - area.y) as usize).or(app.screen_rows.last())
+    } else {
+        None
+    };
+    let row = match wrapped {
+        Some(screen_row) => screen_row.row,
+        None => (app.editor_scroll.0 as usize + (y - area.y) as usize).min(editor.len_lines() - 1),
+    };
+
+    let x = x.saturating_sub(area.x) as usize + app.editor_scroll.1 as usize;
+    let columns = display_columns(&editor.line(row), app.buffer.editor.tab_length() as usize);
+    let (first, last) = wrapped.map_or((0, columns.len()), |r| (r.start, r.end));
+    let Some(target) = x.checked_sub(gutter_width(app) as usize) else { return (row, first) };
+    let target = target + columns.get(first).map_or(0, |(start, _)| *start);
+    let col = (first..last)
+        .find(|&col| target < columns[col].0 + columns[col].1)
+        .unwrap_or(last);
+    (row, col)
+}
+
+/// Apply `style` to the screen cells showing display columns `cols` of buffer `row`.
+fn style_editor_cells(f: &mut Frame, app: &App, inner: Rect, row: usize, cols: std::ops::Range<usize>, style: Style) {
+    for col in cols {
+        if let Some(position) = editor_cell(app, inner, row, col) {
+            if let Some(cell) = f.buffer_mut().cell_mut(position) {
+                cell.set_style(style);
+            }
+        }
+    }
+}
+
+/// Tabs as `→`, trailing whitespace highlighted and, with `show_eol`, `¬`
+/// after each line, so whitespace damage (e.g. from an AI rewrite) shows.
+fn render_whitespace(f: &mut Frame, app: &App, inner: Rect) {
+    if !app.config.show_whitespace {
+        return;
+    }
+    let marker = Style::default().fg(app.theme.muted);
+    let trailing = Style::default().bg(app.theme.overlong);
+    let editor = &app.buffer.editor;
+    for row in app.visible_rows() {
+        let line = &editor.line(row);
+        let columns = display_columns(line, app.buffer.editor.tab_length() as usize);
+        let content_end = line.trim_end().chars().count();
+        for (col, c) in line.chars().enumerate() {
+            let (start, width) = columns[col];
+            if col >= content_end {
+                style_editor_cells(f, app, inner, row, start..start + width.max(1), trailing);
+            }
+            if c == '\t' && width > 0 {
+                if let Some(cell) = editor_cell(app, inner, row, start).and_then(|p| f.buffer_mut().cell_mut(p)) {
+                    cell.set_char('→').set_style(marker);
+                }
+            }
+        }
+        if app.config.show_eol {
+            let end = columns.last().map_or(0, |(start, width)| start + width);
+            // Wrapped rows pin the end to the edge; don't cover text there.
+            let cell = editor_cell(app, inner, row, end).and_then(|p| f.buffer_mut().cell_mut(p));
+            if let Some(cell) = cell.filter(|c| c.symbol() == " ") {
+                cell.set_char('¬').set_style(marker);
+            }
+        }
+    }
+}
+
+fn render_line_length_marks(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(rule) = app.line_length_rule() else { return };
+    let style = Style::default().fg(app.theme.overlong).add_modifier(Modifier::UNDERLINED);
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    let rows = app.visible_rows();
+
+    for (row, line) in (rows.start..).zip(app.buffer.editor.rows(rows)) {
+        let columns = display_columns(&line, tab_len);
+        let Some(end) = columns.last().map(|(start, width)| start + width) else { continue };
+        if end > rule.max {
+            style_editor_cells(f, app, inner, row, rule.max..end, style);
+        }
+    }
+}
+
+/// Misspelled words are underlined in the warning color, keeping their
+/// syntax colors.
+fn render_spelling_marks(f: &mut Frame, app: &App, inner: Rect) {
+    let style = Style::default().add_modifier(Modifier::UNDERLINED).underline_color(app.theme.overlong);
+    let editor = &app.buffer.editor;
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    for (row, start, end) in app.misspelled(app.visible_rows()) {
+        let columns = display_columns(&editor.line(row), tab_len);
+        let (last, width) = columns[end - 1];
+        style_editor_cells(f, app, inner, row, columns[start].0..last + width, style);
+    }
+}
+
+/// Tint the front matter block; a parse error is marked on its row (or
+/// the closing fence when the parser can't tell).
+fn render_front_matter_marks(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(fm) = app.front_matter() else { return };
+    let editor = &app.buffer.editor;
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    let error = frontmatter::validate(&editor.rows(0..fm.end + 1), &fm).map(|(row, _)| row.unwrap_or(fm.end).min(fm.end));
+    let top_row = app.editor_scroll.0 as usize;
+
+    for row in (fm.start..=fm.end).skip_while(|r| *r < top_row).take(inner.height as usize) {
+        let end = display_columns(&editor.line(row), tab_len).last().map(|(start, width)| start + width).unwrap_or(0);
+        let style = if error == Some(row) {
+            Style::default().fg(app.theme.overlong).add_modifier(Modifier::UNDERLINED)
+        } else {
+            Style::default().fg(app.theme.muted).add_modifier(Modifier::ITALIC)
+        };
+        style_editor_cells(f, app, inner, row, 0..end.max(1), style);
+    }
+}
+
+/// Bookmark numbers in the first column of the line number gutter.
+fn severity_style(severity: Severity, theme: &Theme) -> (&'static str, Color) {
+    match severity {
+        Severity::Error => ("E", Color::Red),
+        Severity::Warning => ("W", Color::Yellow),
+        Severity::Info => ("I", Color::Blue),
+        Severity::Hint => ("H", theme.muted),
+    }
+}
+
+/// Diagnostics underlined where they point, with their severity in the
+/// first gutter column (where a bookmark drawn later wins).
+fn render_diagnostic_marks(f: &mut Frame, app: &App, inner: Rect) {
+    let editor = &app.buffer.editor;
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    let visible = app.visible_rows();
+    let mut shown: Vec<&Diagnostic> = app
+        .diagnostics
+        .iter()
+        .filter(|d| d.row < visible.end && d.end.0.max(d.row) >= visible.start && d.row < editor.len_lines())
+        .collect();
+    // The most severe last, so it's the one that shows.
+    shown.sort_by_key(|d| std::cmp::Reverse(d.severity));
+    for d in shown {
+        let (sign, color) = severity_style(d.severity, &app.theme);
+        let style = Style::default().add_modifier(Modifier::UNDERLINED).underline_color(color);
+        let last = d.end.0.max(d.row).min(editor.len_lines() - 1);
+        let rows = d.row.max(visible.start)..=last.min(visible.end.saturating_sub(1));
+        for (row, line) in (*rows.start()..).zip(editor.rows(*rows.start()..*rows.end() + 1)) {
+            let columns = display_columns(&line, tab_len);
+            let line_end = columns.last().map_or(0, |(start, width)| start + width);
+            let start = if row == d.row { columns.get(d.col).map_or(line_end, |c| c.0) } else { 0 };
+            let end = if row == last { columns.get(d.end.1).map_or(line_end, |c| c.0) } else { line_end };
+            // Empty ranges (e.g. a missing `;` at the end) still get a cell.
+            style_editor_cells(f, app, inner, row, start..end.max(start + 1), style);
+        }
+        if gutter_width(app) == 0 {
+            continue;
+        }
+        let y = if app.screen_row_view() {
+            app.screen_rows.iter().position(|r| r.row == d.row)
+        } else {
+            d.row.checked_sub(app.editor_scroll.0 as usize).filter(|y| *y < inner.height as usize)
+        };
+        if let Some(y) = y {
+            f.buffer_mut().set_string(inner.x, inner.y + y as u16, sign, Style::default().fg(color).add_modifier(Modifier::BOLD));
+        }
+    }
+}
+
+/// Everything reported on the cursor line.
+fn render_diagnostic_details(f: &mut Frame, app: &App) {
+    let row = app.buffer.editor.cursor().0;
+    let area = centered_rect(70, 50, f.area());
+    f.render_widget(Clear, area);
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(format!(" Line {} ", row + 1));
+    let mut lines = Vec::new();
+    for d in app.diagnostics.iter().filter(|d| d.row == row) {
+        if !lines.is_empty() {
+            lines.push(Line::default());
+        }
+        let (_, color) = severity_style(d.severity, &app.theme);
+        let label = format!("{:?}", d.severity).to_lowercase();
+        let heading = match &d.source {
+            Some(source) => format!("{} from {}, column {}", label, source, d.col + 1),
+            None => format!("{}, column {}", label, d.col + 1),
+        };
+        lines.push(Line::styled(heading, Style::default().fg(color).add_modifier(Modifier::BOLD)));
+        lines.extend(d.message.lines().map(|l| Line::raw(l.to_string())));
+    }
+    f.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }).block(block), area);
+}
+
+fn render_bookmark_marks(f: &mut Frame, app: &App, inner: Rect) {
+    if gutter_width(app) == 0 {
+        return;
+    }
+    let style = Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD);
+    for bookmark in &app.bookmarks {
+        let y = if app.screen_row_view() {
+            app.screen_rows.iter().position(|r| r.row == bookmark.row)
+        } else {
+            bookmark.row.checked_sub(app.editor_scroll.0 as usize).filter(|y| *y < inner.height as usize)
+        };
+        let Some(y) = y else { continue };
+        let label = if bookmark.number < 10 { bookmark.number.to_string() } else { "*".to_string() };
+        f.buffer_mut().set_string(inner.x, inner.y + y as u16, label, style);
+    }
+}
+
+/// Lines changed since the last commit, in the last column of the line
+/// number gutter: + added, ~ changed, - lines removed above.
+fn render_git_marks(f: &mut Frame, app: &App, inner: Rect) {
+    let gutter = gutter_width(app);
+    if gutter == 0 || app.git_marks.is_empty() {
+        return;
+    }
+    let rows: Vec<(usize, usize)> = if app.screen_row_view() {
+        // Each line's first screen row.
+        app.screen_rows.iter().enumerate().filter(|(_, r)| r.start == 0).map(|(y, r)| (y, r.row)).collect()
+    } else {
+        let top = app.editor_scroll.0 as usize;
+        (0..inner.height as usize).map(|y| (y, top + y)).collect()
+    };
+    for (y, row) in rows {
+        let Some(Some(mark)) = app.git_marks.get(row) else { continue };
+        let (label, color) = match mark {
+            Mark::Added => ("+", Color::Green),
+            Mark::Modified => ("~", Color::Yellow),
+            Mark::Deleted => ("-", Color::Red),
+        };
+        let style = Style::default().fg(color).add_modifier(Modifier::BOLD);
+        f.buffer_mut().set_string(inner.x + gutter - 1, inner.y + y as u16, label, style);
+    }
+}
+
+/// The bracket at the cursor and its partner.
+fn render_bracket_match(f: &mut Frame, app: &App, inner: Rect) {
+    let Some((at, partner)) = app.matching_bracket() else { return };
+    let style = Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD | Modifier::UNDERLINED);
+    let editor = &app.buffer.editor;
+    let tab_len = app.buffer.editor.tab_length() as usize;
+    for (row, col) in [at, partner] {
+        let start = display_columns(&editor.line(row), tab_len)[col].0;
+        style_editor_cells(f, app, inner, row, start..start + 1, style);
     }
 }
 
+/// Other participants' cursors in a shared session.
+fn render_shared_cursors(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(session) = &app.share else { return };
+    let editor = &app.buffer.editor;
+    let style = Style::default().bg(app.theme.accent).fg(app.theme.popup_bg);
+    for cursor in session.cursors.values() {
+        let Some(line) = editor.get_line(cursor.row) else { continue };
+        let columns = display_columns(&line, app.buffer.editor.tab_length() as usize);
+        let start = columns.get(cursor.col).map_or_else(
+            || columns.last().map_or(0, |(start, width)| start + width),
+            |(start, _)| *start,
+        );
+        style_editor_cells(f, app, inner, cursor.row, start..start + 1, style);
+    }
+}
+
+fn render_extra_cursors(f: &mut Frame, app: &App, inner: Rect) {
+    let editor = &app.buffer.editor;
+    let style = Style::default().add_modifier(Modifier::REVERSED);
+    for &(row, col) in &app.cursors {
+        let Some(line) = editor.get_line(row) else { continue };
+        let columns = display_columns(&line, app.buffer.editor.tab_length() as usize);
+        let start = columns.get(col).map_or_else(|| columns.last().map_or(0, |(start, width)| start + width), |(start, _)| *start);
+        style_editor_cells(f, app, inner, row, start..start + 1, style);
+    }
+}
+
+/// The block selection, or for an empty one a cursor on each row.
+fn render_block(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(block) = app.block else { return };
+    let editor = &app.buffer.editor;
+    let cols = block.cols();
+    let line_end = |columns: &[(usize, usize)]| columns.last().map_or(0, |(start, width)| start + width);
+    for row in block.rows() {
+        let Some(line) = editor.get_line(row) else { continue };
+        let columns = display_columns(&line, app.buffer.editor.tab_length() as usize);
+        if columns.len() < cols.start || (cols.is_empty() && row == block.head.0) {
+            continue;
+        }
+        let start = columns.get(cols.start).map_or_else(|| line_end(&columns), |(start, _)| *start);
+        if cols.is_empty() {
+            style_editor_cells(f, app, inner, row, start..start + 1, Style::default().add_modifier(Modifier::REVERSED));
+        } else {
+            // The editor's selection colour.
+            let end = columns.get(cols.end).map_or_else(|| line_end(&columns), |(start, _)| *start);
+            style_editor_cells(f, app, inner, row, start..end, Style::default().bg(Color::LightBlue));
+        }
+    }
+}
+
+/// Dimmed inline completion from the cursor on. Only its first line is
+/// drawn, with a count of the rest.
+fn render_ghost_text(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(ghost) = &app.ghost else { return };
+    let line = &app.buffer.editor.line(ghost.row);
+    let columns = display_columns(line, app.buffer.editor.tab_length() as usize);
+    let start = columns.get(ghost.col).map_or_else(
+        || columns.last().map_or(0, |(start, width)| start + width),
+        |(start, _)| *start,
+    );
+
+    let mut text = ghost.text.lines().next().unwrap_or("").to_string();
+    let more = ghost.text.lines().count().saturating_sub(1);
+    if more > 0 {
+        text.push_str(&format!("  (+{} lines)", more));
+    }
+
+    let style = Style::default().fg(app.theme.muted).add_modifier(Modifier::ITALIC);
+    let Some((x, y)) = editor_cell(app, inner, ghost.row, start) else { return };
+    let mut x = x as usize;
+    for c in text.chars() {
+        let width = c.width().unwrap_or(0);
+        if x + width > (inner.x + inner.width) as usize {
+            break;
+        }
+        if let Some(cell) = f.buffer_mut().cell_mut((x as u16, y)) {
+            cell.set_char(c).set_style(style);
+        }
+        x += width;
+    }
+}
+
+/// The language server's completions under the word being typed (above it
+/// near the bottom), a kind letter before each.
+fn render_completion_menu(f: &mut Frame, app: &App, inner: Rect) {
+    let Some(menu) = app.completion.as_ref().filter(|m| !m.shown.is_empty() && app.mode() == AppMode::Normal) else { return };
+    let columns = display_columns(&app.buffer.editor.line(menu.row), app.buffer.editor.tab_length() as usize);
+    let start = columns.get(menu.start).map_or_else(|| columns.last().map_or(0, |(start, width)| start + width), |(start, _)| *start);
+    let Some((x, y)) = editor_cell(app, inner, menu.row, start) else { return };
+
+    let rows = menu.shown.len().min(COMPLETION_ROWS);
+    let top = menu.selected.saturating_sub(rows - 1);
+    let items: Vec<&lsp::CompletionItem> = menu.shown[top..top + rows].iter().map(|&i| &menu.items[i]).collect();
+    let label_width = items.iter().map(|item| item.label.width()).max().unwrap_or(0).min(40);
+    let detail_width = items.iter().filter_map(|item| item.detail.as_ref()).map(|d| d.width()).max().unwrap_or(0).min(30);
+    let screen = f.area();
+    let width = ((3 + label_width + if detail_width > 0 { detail_width + 2 } else { 0 }) as u16 + 2).min(screen.width);
+    let height = rows as u16 + 2;
+    let y = if y + 1 + height <= screen.bottom() || y < height { y + 1 } else { y - height };
+    let area = Rect { x: x.saturating_sub(4).min(screen.right().saturating_sub(width)), y, width, height: height.min(screen.bottom().saturating_sub(y)) };
+    f.render_widget(Clear, area);
+
+    let lines: Vec<Line> = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let style = match top + i == menu.selected {
+                true => Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED),
+                false => Style::default(),
+            };
+            let mut spans = vec![
+                Span::styled(format!(" {} ", lsp::kind_icon(item.kind)), style.fg(app.theme.accent).add_modifier(Modifier::BOLD)),
+                Span::styled(fit_width(&item.label, label_width), style),
+            ];
+            if detail_width > 0 {
+                let detail = item.detail.as_deref().unwrap_or_default().lines().next().unwrap_or_default();
+                spans.push(Span::styled(format!("  {}", fit_width(detail, detail_width)), style.fg(app.theme.muted)));
+            }
+            Line::from(spans)
+        })
+        .collect();
+    let block = Block::default().borders(Borders::ALL).style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg));
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Widest a table column gets before its cells are truncated.
+const MAX_COLUMN_WIDTH: usize = 40;
+
+/// Pad or truncate (with an ellipsis) `text` to exactly `width` cells.
+fn fit_width(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return format!("{}{}", text, " ".repeat(width - text.width()));
+    }
+    let mut out = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        out.push(c);
+        used += w;
+    }
+    out.push('…');
+    format!("{}{}", out, " ".repeat(width.saturating_sub(used + 1)))
+}
+
+/// Aligned, read-through view of a CSV/TSV buffer. Editing still goes to the
+/// editor underneath; the current cell is highlighted.
+fn render_table_view(f: &mut Frame, app: &App, area: Rect, inner: Rect) {
+    // Render the editor widget off-screen so its internal viewport (used for
+    // PageUp/PageDown) keeps tracking the cursor.
+    let mut scratch = ratatui::buffer::Buffer::empty(area);
+    (&app.buffer.editor).render(area, &mut scratch);
+    if let Some(block) = app.buffer.editor.block() {
+        f.render_widget(block.clone(), area);
+    }
+
+    let Some(delimiter) = app.csv_delimiter() else { return };
+    let editor = &app.buffer.editor;
+    let (cursor_row, cursor_col) = app.buffer.editor.cursor();
+    let cursor_field = table::field_at(&table::field_ranges(&editor.line(cursor_row), delimiter), cursor_col);
+
+    let top = app.editor_scroll.0 as usize;
+    let visible: Vec<(usize, Vec<String>)> = (top..)
+        .zip(editor.rows(top..top + inner.height as usize))
+        .map(|(row, line)| {
+            let fields = table::field_ranges(&line, delimiter);
+            (row, fields.iter().map(|r| table::field_text(&line, r)).collect())
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = Vec::new();
+    for (_, fields) in &visible {
+        for (i, field) in fields.iter().enumerate() {
+            let width = field.width().clamp(1, MAX_COLUMN_WIDTH);
+            match widths.get_mut(i) {
+                Some(w) => *w = (*w).max(width),
+                None => widths.push(width),
+            }
+        }
+    }
+
+    // Scroll horizontally just enough to keep the current column on screen.
+    let separator = " │ ";
+    let column_start: usize = widths.iter().take(cursor_field).map(|w| w + separator.width()).sum();
+    let column_end = column_start + widths.get(cursor_field).copied().unwrap_or(0);
+    let offset = column_end.saturating_sub(inner.width as usize);
+
+    let rendered: Vec<Line> = visible
+        .iter()
+        .map(|(row, fields)| {
+            let mut spans = Vec::new();
+            for (i, field) in fields.iter().enumerate() {
+                let mut style = Style::default();
+                if *row == 0 {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if *row == cursor_row {
+                    style = style.bg(app.theme.muted);
+                    if i == cursor_field {
+                        style = style.add_modifier(Modifier::REVERSED);
+                    }
+                }
+                spans.push(Span::styled(fit_width(field, widths[i]), style));
+                if i + 1 < fields.len() {
+                    spans.push(Span::styled(separator, Style::default().fg(app.theme.muted)));
+                }
+            }
+            Line::from(spans)
+        })
+        .collect();
+
+    let paragraph = Paragraph::new(rendered).scroll((0, offset as u16));
+    f.render_widget(paragraph, inner);
+}
+
+fn render_cell_output(f: &mut Frame, app: &App, area: Rect) {
+    let Some(output) = &app.cell_output else { return };
+    let (title, color) = if output.running {
+        (" Output (running) ", app.theme.accent)
+    } else if output.success {
+        (" Output ", app.theme.border)
+    } else {
+        (" Output (failed) ", app.theme.overlong)
+    };
+
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .style(Style::default().fg(color));
+    // Keep the tail of long outputs visible.
+    let height = area.height.saturating_sub(2) as usize;
+    let lines: Vec<&str> = output.text.lines().collect();
+    let skip = lines.len().saturating_sub(height);
+    let text: Vec<Line> = lines[skip..].iter().map(|l| Line::from(l.to_string())).collect();
+
+    f.render_widget(Paragraph::new(text).block(block), area);
+}
+
+fn render_diagnostics(f: &mut Frame, app: &App, area: Rect) {
+    let title = if app.links_pending > 0 {
+        format!(" Diagnostics ({}, checking {} more) ", app.diagnostics.len(), app.links_pending)
+    } else {
+        format!(" Diagnostics ({}) ", app.diagnostics.len())
+    };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .style(Style::default().fg(app.theme.border));
+
+    let height = area.height.saturating_sub(2) as usize;
+    let selected = app.diagnostic_selected.unwrap_or(0);
+    let skip = (selected + 1).saturating_sub(height);
+    let lines: Vec<Line> = app
+        .diagnostics
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, d)| {
+            let style = if app.diagnostic_selected == Some(i) {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            let mut spans = vec![Span::styled(format!("{:>5}:{:<4} ", d.row + 1, d.col + 1), Style::default().fg(app.theme.muted))];
+            if let Some(source) = &d.source {
+                let (_, color) = severity_style(d.severity, &app.theme);
+                spans.push(Span::styled(format!("{}: ", source), Style::default().fg(color)));
+            }
+            // Shift+F8 shows the rest of long messages.
+            spans.push(Span::styled(d.message.lines().next().unwrap_or_default().to_string(), style));
+            Line::from(spans)
+        })
+        .collect();
+
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Matches of the last search with their lines, the match picked out.
+fn render_search_results(f: &mut Frame, app: &App, area: Rect) {
+    let Some(results) = &app.search_results else { return };
+    let focused = app.mode() == AppMode::SearchResults;
+    let block = Block::default()
+        .title(format!(
+            " Matches for \"{}\"{} ({}) ",
+            results.query,
+            if results.scope.is_some() { " in the selection" } else { "" },
+            results.matches.len()
+        ))
+        .borders(Borders::ALL)
+        .style(Style::default().fg(if focused { app.theme.accent } else { app.theme.border }));
+
+    let height = area.height.saturating_sub(2) as usize;
+    let skip = (results.selected + 1).saturating_sub(height);
+    let editor = &app.buffer.editor;
+    let query_chars = results.query.chars().count();
+    let items: Vec<Line> = results
+        .matches
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .take(height)
+        .map(|(i, &(row, col))| {
+            let mut style = Style::default();
+            if i == results.selected {
+                style = style.fg(app.theme.accent).add_modifier(Modifier::BOLD);
+                if focused {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+            }
+            let chars: Vec<char> = editor.get_line(row).map_or(Vec::new(), |l| l.chars().collect());
+            let indent = chars.iter().take_while(|c| c.is_whitespace()).count().min(col);
+            let start = col.min(chars.len());
+            let end = (col + query_chars).min(chars.len());
+            let text = |range: std::ops::Range<usize>| chars[range].iter().map(|&c| if c == '\t' { ' ' } else { c }).collect::<String>();
+            Line::from(vec![
+                Span::styled(format!("{:>5}:{:<4} ", row + 1, col + 1), Style::default().fg(app.theme.muted)),
+                Span::styled(text(indent..start), style),
+                Span::styled(text(start..end), style.add_modifier(Modifier::UNDERLINED)),
+                Span::styled(text(end..chars.len()), style),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(items).block(block), area);
+}
+
+/// Conversation on top, question input at the bottom. Messages are wrapped
+/// here so the view can stick to the newest one.
+fn render_chat(f: &mut Frame, app: &mut App, area: Rect) {
+    let title = if app.chat_task.is_some() { " AI Chat (thinking...) " } else { " AI Chat " };
+    let focused = app.mode() == AppMode::Chat;
+    let border = if focused { app.theme.accent } else { app.theme.border };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .style(Style::default().fg(border));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let split = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(3)])
+        .split(inner);
+    let width = split[0].width.max(1) as usize;
+
+    let mut lines: Vec<Line> = Vec::new();
+    for message in &app.chat {
+        let (label, color) = match message.role {
+            Role::User => ("You", app.theme.accent),
+            Role::Model => ("AI", app.theme.header_fg),
+            Role::Error => ("Error", app.theme.warning_fg),
+        };
+        lines.push(Line::styled(label, Style::default().fg(color).add_modifier(Modifier::BOLD)));
+        let mut in_code = false;
+        for text in message.text.lines() {
+            let fence = text.trim_start().starts_with("```");
+            let style = if in_code || fence { Style::default().fg(app.theme.muted) } else { Style::default() };
+            if fence {
+                in_code = !in_code;
+            }
+            for row in wrap_chars(text, width) {
+                lines.push(Line::styled(row, style));
+            }
+        }
+        lines.push(Line::raw(""));
+    }
+    if app.chat.is_empty() {
+        lines.push(Line::styled("Ask a question about this file.", Style::default().fg(app.theme.muted)));
+    }
+
+    let height = split[0].height as usize;
+    app.chat_scroll = app.chat_scroll.min(lines.len().saturating_sub(height));
+    let top = lines.len().saturating_sub(height + app.chat_scroll);
+    f.render_widget(Paragraph::new(lines).scroll((top as u16, 0)), split[0]);
+
+    let style = Style::default().fg(app.theme.popup_fg);
+    app.chat_input.render(f, split[1], style, focused);
+}
+
+/// Break `text` into rows of at most `width` display columns.
+fn wrap_chars(text: &str, width: usize) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > width && used > 0 {
+            rows.push(String::new());
+            used = 0;
+        }
+        rows.last_mut().unwrap().push(c);
+        used += w;
+    }
+    rows
+}
+
 fn render_save_as_popup(f: &mut Frame, app: &mut App) {
     let area = centered_rect(50, 20, f.area());
     f.render_widget(Clear, area);
-    f.render_widget(&app.filename_input, area);
+    let style = Style::default().fg(app.theme.popup_fg).bg(app.theme.popup_bg);
+    app.filename_input.render(f, area, style, true);
+}
+
+fn render_patch_popup(f: &mut Frame, app: &mut App) {
+    let area = centered_rect(50, 20, f.area());
+    f.render_widget(Clear, area);
+    let style = Style::default().fg(app.theme.popup_fg).bg(app.theme.popup_bg);
+    app.patch_input.render(f, area, style, true);
 }
 
-fn render_confirm_quit_popup(f: &mut Frame) {
+fn render_confirm_quit_popup(f: &mut Frame, app: &App) {
     let area = centered_rect(40, 10, f.area());
     f.render_widget(Clear, area);
     
     let block = Block::default()
         .borders(Borders::ALL)
-        .style(Style::default().bg(Color::Red).fg(Color::White))
+        .style(Style::default().bg(app.theme.warning_bg).fg(app.theme.warning_fg))
         .title(" Warning ");
     
-    let text = Paragraph::new("⚠️  Unsaved Changes!\nSave before quitting?\n\n(Y)es / (N)o / (E)sc Cancel")
+    // With several buffers, which one is being asked about.
+    let name = if app.buffers.len() > 1 { format!("{}\n", app.filename) } else { String::new() };
+    let text = Paragraph::new(format!("⚠️  Unsaved Changes!\n{}Save before quitting?\n\n(Y)es / (N)o / (E)sc Cancel", name))
         .alignment(ratatui::layout::Alignment::Center)
         .block(block);
         
     f.render_widget(text, area);
 }
 
+fn render_file_changed_popup(f: &mut Frame, app: &App) {
+    let warning = Style::default().bg(app.theme.warning_bg).fg(app.theme.warning_fg);
+    let choices = "(R)eload / (O)verwrite / (D)iff / (E)sc Keep editing";
+
+    let Some(diff) = &app.disk_diff else {
+        let area = centered_rect(50, 10, f.area());
+        f.render_widget(Clear, area);
+        let block = Block::default().borders(Borders::ALL).style(warning).title(" Warning ");
+        let text = Paragraph::new(format!("⚠️  File changed on disk\n{}\n\n{}", app.filename, choices))
+            .alignment(ratatui::layout::Alignment::Center)
+            .block(block);
+        f.render_widget(text, area);
+        return;
+    };
+
+    let area = centered_rect(80, 70, f.area());
+    f.render_widget(Clear, area);
+    let lines = diff_text(diff, &app.theme);
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" File changed on disk: - on disk, + buffer ")
+        .title_bottom(format!(" {} ", choices));
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn render_recover_popup(f: &mut Frame, app: &App) {
+    let Some(found) = &app.recovery else { return };
+    let warning = Style::default().bg(app.theme.warning_bg).fg(app.theme.warning_fg);
+    let area = centered_rect(60, 12, f.area());
+    f.render_widget(Clear, area);
+    let minutes = found.written.and_then(|t| t.elapsed().ok()).map_or(0, |age| age.as_secs() / 60);
+    let age = match minutes {
+        0 => "just now".to_string(),
+        1..=119 => format!("{} min ago", minutes),
+        _ if minutes < 48 * 60 => format!("{} hours ago", minutes / 60),
+        _ => format!("{} days ago", minutes / (24 * 60)),
+    };
+    let block = Block::default().borders(Borders::ALL).style(warning).title(" Unsaved changes found ");
+    let text = Paragraph::new(format!(
+        "{}\nwas left with unsaved changes ({}).\n\n(R)ecover / (D)elete them / (E)sc Open as saved",
+        app.filename, age
+    ))
+    .alignment(ratatui::layout::Alignment::Center)
+    .block(block);
+    f.render_widget(text, area);
+}
+
+/// The buffer against git HEAD, unified or side by side.
+fn render_head_diff(f: &mut Frame, app: &mut App) {
+    let Some(diff) = &app.head_diff else { return };
+    let area = centered_rect(90, 90, f.area());
+    f.render_widget(Clear, area);
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(format!(" {}: - HEAD, + buffer ", app.filename));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    if !app.head_diff_side_by_side {
+        let lines = diff_text(diff, &app.theme);
+        app.head_diff_scroll = app.head_diff_scroll.min(lines.len().saturating_sub(inner.height as usize));
+        f.render_widget(Paragraph::new(lines).scroll((app.head_diff_scroll as u16, 0)), inner);
+        return;
+    }
+    let rows = diff::side_by_side(diff);
+    app.head_diff_scroll = app.head_diff_scroll.min(rows.len().saturating_sub(inner.height as usize));
+    let side = |right: bool| -> Vec<Line<'static>> {
+        rows.iter()
+            .skip(app.head_diff_scroll)
+            .take(inner.height as usize)
+            .map(|&(old, new)| if right { new } else { old })
+            .map(|line| line.map_or_else(Line::default, |line| diff_text(std::slice::from_ref(line), &app.theme).remove(0)))
+            .collect()
+    };
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Length(1), Constraint::Percentage(50)])
+        .split(inner);
+    f.render_widget(Paragraph::new(side(false)), columns[0]);
+    let divider = Style::default().fg(app.theme.muted);
+    f.render_widget(Block::default().borders(Borders::LEFT).border_style(divider), columns[1]);
+    f.render_widget(Paragraph::new(side(true)), columns[2]);
+}
+
+fn render_git_commit_popup(f: &mut Frame, app: &mut App) {
+    let theme = app.theme;
+    let area = centered_rect(70, 50, f.area());
+    f.render_widget(Clear, area);
+    let name = std::path::Path::new(&app.filename).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(theme.popup_bg).fg(theme.popup_fg))
+        .title(format!(" Commit {} ", name))
+        .title_bottom(" ^S Commit  Esc Cancel ");
+    match &mut app.commit_message {
+        Some(message) => {
+            // The TextArea draws its own cursor here; see `cursor_shape`.
+            message.set_block(block);
+            message.set_cursor_line_style(Style::default());
+            message.set_style(Style::default().bg(theme.popup_bg).fg(theme.popup_fg));
+            f.render_widget(&*message, area);
+        }
+        None => {
+            let waiting = Paragraph::new(Line::styled("Drafting a message from the diff...", Style::default().fg(theme.muted)));
+            f.render_widget(waiting.block(block), area);
+        }
+    }
+}
+
+/// Colored diff rows; the changed words within a changed line are shown
+/// reversed.
+fn diff_text(diff: &[DiffLine], theme: &Theme) -> Vec<Line<'static>> {
+    diff.iter()
+        .map(|line| {
+            let style = match line.kind {
+                DiffKind::Hunk => Style::default().fg(theme.accent),
+                DiffKind::Added => Style::default().fg(Color::Green),
+                DiffKind::Removed => Style::default().fg(Color::Red),
+                DiffKind::Header | DiffKind::Context => Style::default(),
+            };
+            let spans: Vec<Span> = line
+                .segments
+                .iter()
+                .map(|(emphasized, text)| {
+                    let style = if *emphasized { style.add_modifier(Modifier::REVERSED) } else { style };
+                    Span::styled(text.clone(), style)
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect()
+}
+
+fn render_outline_popup(f: &mut Frame, app: &App) {
+    let area = centered_rect(50, 60, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" Outline ");
+    let height = block.inner(area).height as usize;
+    // Keep the selection in view.
+    let skip = (app.outline_selected + 1).saturating_sub(height);
+
+    let lines: Vec<Line> = app
+        .outline
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, heading)| {
+            let text = format!("{}{}", "  ".repeat(heading.level - 1), heading.title);
+            let style = if i == app.outline_selected {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else if heading.level == 1 {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            Line::from(Span::styled(text, style))
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Bookmarks by number, with the line each is on.
+fn render_bookmarks_popup(f: &mut Frame, app: &App) {
+    let area = centered_rect(60, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" Bookmarks ");
+    let height = block.inner(area).height as usize;
+    let skip = (app.bookmark_selected + 1).saturating_sub(height);
+    let editor = &app.buffer.editor;
+
+    let items: Vec<Line> = app
+        .bookmarks
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, bookmark)| {
+            let line = editor.get_line(bookmark.row).unwrap_or_default();
+            let text = line.trim();
+            let style = if i == app.bookmark_selected {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::from(vec![
+                Span::styled(format!("{:>2}  ", bookmark.number), style.add_modifier(Modifier::BOLD)),
+                Span::styled(format!("{:>5}  {}", bookmark.row + 1, text), style),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(items).block(block), area);
+}
+
+fn render_command_popup(f: &mut Frame, app: &mut App) {
+    let area = centered_rect(60, 20, f.area());
+    f.render_widget(Clear, area);
+    let style = Style::default().fg(app.theme.popup_fg).bg(app.theme.popup_bg);
+    app.command_input.render(f, area, style, true);
+}
+
+fn render_grep_popup(f: &mut Frame, app: &mut App) {
+    let area = centered_rect(50, 20, f.area());
+    f.render_widget(Clear, area);
+    let style = Style::default().fg(app.theme.popup_fg).bg(app.theme.popup_bg);
+    app.grep_input.render(f, area, style, true);
+}
+
+/// Hits of a find in files as `path:line: text`, the match picked out.
+fn render_grep_results_popup(f: &mut Frame, app: &App) {
+    let Some(grep) = &app.grep else { return };
+    let area = centered_rect(80, 70, f.area());
+    f.render_widget(Clear, area);
+
+    let progress = if grep.files.is_some() { "" } else { ", searching..." };
+    let more = if grep.hits.len() == grep::MAX_HITS { "+" } else { "" };
+    let title = format!(" Find in files: {} ({}{} hits{}) ", grep.query, grep.hits.len(), more, progress);
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(title);
+    let height = block.inner(area).height as usize;
+    let skip = (grep.selected + 1).saturating_sub(height);
+    let query_chars = grep.query.chars().count();
+
+    let items: Vec<Line> = grep
+        .hits
+        .iter()
+        .enumerate()
+        .skip(skip)
+        .take(height)
+        .map(|(i, hit)| {
+            let style = if i == grep.selected {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            let chars: Vec<char> = hit.line.chars().collect();
+            let indent = chars.iter().take_while(|c| c.is_whitespace()).count().min(hit.col);
+            let start = hit.col.min(chars.len());
+            let end = (hit.col + query_chars).min(chars.len());
+            let text = |range: std::ops::Range<usize>| chars[range].iter().map(|&c| if c == '\t' { ' ' } else { c }).collect::<String>();
+            Line::from(vec![
+                Span::styled(format!("{}:{}: ", hit.path, hit.row + 1), style.add_modifier(Modifier::BOLD)),
+                Span::styled(text(indent..start), style),
+                Span::styled(text(start..end), style.fg(app.theme.accent).add_modifier(Modifier::BOLD)),
+                Span::styled(text(end..chars.len()), style),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(items).block(block), area);
+}
+
+/// The misspelled word and numbered suggestions for it.
+fn render_spelling_popup(f: &mut Frame, app: &App) {
+    let Some(spelling) = &app.spelling else { return };
+    let area = centered_rect(40, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(format!(" {} ", spelling.word));
+    let items: Vec<Line> = if spelling.suggestions.is_empty() {
+        vec![Line::styled("No suggestions", Style::default().fg(app.theme.muted))]
+    } else {
+        spelling
+            .suggestions
+            .iter()
+            .enumerate()
+            .map(|(i, suggestion)| {
+                let style = if i == spelling.selected {
+                    Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default()
+                };
+                Line::from(vec![
+                    Span::styled(format!("{:>2}  ", i + 1), style.add_modifier(Modifier::BOLD)),
+                    Span::styled(suggestion.as_str(), style),
+                ])
+            })
+            .collect()
+    };
+    f.render_widget(Paragraph::new(items).block(block), area);
+}
+
+/// What a save all or close others couldn't do, one buffer per line.
+fn render_report_popup(f: &mut Frame, app: &App) {
+    let Some((title, lines)) = &app.report else { return };
+    let area = centered_rect(60, 40, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.warning_bg).fg(app.theme.warning_fg))
+        .title(title.as_str());
+    let items: Vec<Line> = lines.iter().map(|line| Line::raw(line.as_str())).collect();
+    f.render_widget(Paragraph::new(items).wrap(Wrap { trim: false }).block(block), area);
+}
+
+/// Open buffers, `*` marking unsaved ones and `>` the one shown.
+fn render_buffers_popup(f: &mut Frame, app: &App) {
+    let area = centered_rect(60, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" Buffers ");
+    let height = block.inner(area).height as usize;
+    let skip = (app.buffer_selected + 1).saturating_sub(height);
+
+    let items: Vec<Line> = app
+        .buffer_entries()
+        .into_iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, (name, modified))| {
+            let style = if i == app.buffer_selected {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            let shown = if i == app.active_buffer { ">" } else { " " };
+            let dirty = if modified { "*" } else { " " };
+            let number = if i < 9 { (i + 1).to_string() } else { " ".to_string() };
+            Line::from(vec![
+                Span::styled(format!("{:>2} {}{} ", number, shown, dirty), style.add_modifier(Modifier::BOLD)),
+                Span::styled(name.to_string(), style),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(items).block(block), area);
+}
+
+/// Recent files, then the open/new/settings actions, and a tip.
+fn render_welcome_screen(f: &mut Frame, app: &App, area: Rect) {
+    f.render_widget(Clear, area);
+
+    let heading = Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD);
+    let entry = |i: usize, key: String, text: String| {
+        let style = if i == app.welcome_selected { Style::default().add_modifier(Modifier::REVERSED) } else { Style::default() };
+        Line::from(vec![Span::styled(format!(" {} ", key), style.add_modifier(Modifier::BOLD)), Span::styled(format!(" {}", text), style)])
+    };
+    let home = dirs::home_dir().map(|h| h.to_string_lossy().into_owned()).filter(|h| h.len() > 1);
+
+    let mut lines = vec![Line::from(Span::styled("NeuroNano", heading)), Line::default()];
+    if !app.recent_files.is_empty() {
+        lines.push(Line::from(Span::styled("Recent files", heading)));
+        for (i, file) in app.recent_files.iter().enumerate() {
+            let shown = match &home {
+                Some(home) if file.starts_with(home.as_str()) => format!("~{}", &file[home.len()..]),
+                _ => file.clone(),
+            };
+            let key = if i < 9 { (i + 1).to_string() } else { " ".to_string() };
+            lines.push(entry(i, key, shown));
+        }
+        lines.push(Line::default());
+    }
+    for (i, (key, label)) in WELCOME_ACTIONS.iter().enumerate() {
+        lines.push(entry(app.recent_files.len() + i, key.to_string(), label.to_string()));
+    }
+    if let Some(tip) = app.welcome_tip() {
+        lines.push(Line::default());
+        lines.push(Line::from(vec![Span::styled("Tip: ", heading), Span::styled(tip, Style::default().fg(app.theme.muted))]));
+    }
+
+    let width = lines.iter().map(|l| l.width() as u16).max().unwrap_or(0).min(area.width);
+    let height = (lines.len() as u16).min(area.height);
+    let x = area.x + (area.width - width) / 2;
+    let y = area.y + (area.height - height) / 2;
+    // The selection stays visible on short terminals.
+    let skip = (app.welcome_selected + 4).saturating_sub(height as usize);
+    f.render_widget(
+        Paragraph::new(lines).scroll((skip as u16, 0)),
+        Rect { x, y, width, height },
+    );
+}
+
+/// Snapshots from before each AI answer, newest first.
+fn render_ai_snapshots_popup(f: &mut Frame, app: &App) {
+    let area = centered_rect(60, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" Revert AI change ");
+    let height = block.inner(area).height as usize;
+    let skip = (app.ai_snapshot_selected + 1).saturating_sub(height);
+
+    let lines: Vec<Line> = app
+        .ai_snapshots
+        .iter()
+        .rev()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, snapshot)| {
+            let minutes = snapshot.taken.elapsed().as_secs() / 60;
+            let age = if minutes == 0 { "just now".to_string() } else { format!("{} min ago", minutes) };
+            let style = if i == app.ai_snapshot_selected {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::from(vec![
+                Span::styled(format!("{:>12}  ", age), Style::default().fg(app.theme.muted)),
+                Span::styled(snapshot.prompt.clone(), style),
+            ])
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Base, ours and theirs of the selected merge hunk side by side, with
+/// what it puts in the result below.
+fn render_merge_view(f: &mut Frame, app: &App) {
+    let Some(view) = &app.merge else { return };
+    let area = centered_rect(96, 90, f.area());
+    f.render_widget(Clear, area);
+    let popup = Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg);
+    let count = view.merge.hunks().count();
+    let title = format!(" Merge · hunk {}/{} · {} conflicts left ", (view.selected + 1).min(count), count, view.merge.conflicts());
+    let block = Block::default().borders(Borders::ALL).style(popup).title(title);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let Some(hunk) = view.merge.hunks().nth(view.selected) else { return };
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(60), Constraint::Min(3)])
+        .split(inner);
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Ratio(1, 3), Constraint::Ratio(1, 3), Constraint::Ratio(1, 3)])
+        .split(rows[0]);
+    let taken = |takes: &[Take]| hunk.take.is_some_and(|t| takes.contains(&t));
+    let panes = [
+        (" Base ", &hunk.base, false),
+        (" Ours ← ", &hunk.ours, taken(&[Take::Ours, Take::Both])),
+        (" Theirs → ", &hunk.theirs, taken(&[Take::Theirs, Take::Both])),
+    ];
+    for ((title, lines, taken), column) in panes.into_iter().zip(columns.iter()) {
+        let border = if taken { Style::default().fg(app.theme.accent) } else { Style::default().fg(app.theme.muted) };
+        let text: Vec<Line> = lines.iter().map(|l| Line::from(l.clone())).collect();
+        let block = Block::default().borders(Borders::ALL).border_style(border).title(title);
+        f.render_widget(Paragraph::new(text).block(block), *column);
+    }
+
+    let status = match hunk.take {
+        Some(Take::Ours) => " Result: ours ",
+        Some(Take::Theirs) => " Result: theirs ",
+        Some(Take::Both) => " Result: ours, then theirs ",
+        None => " Result: conflict ",
+    };
+    let text: Vec<Line> = hunk.result().into_iter().map(Line::from).collect();
+    let block = Block::default().borders(Borders::ALL).border_style(Style::default().fg(app.theme.muted)).title(status);
+    f.render_widget(Paragraph::new(text).block(block), rows[1]);
+}
+
+fn render_explain_popup(f: &mut Frame, app: &mut App) {
+    let area = centered_rect(70, 70, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(" Explanation ");
+    let inner = block.inner(area);
+    let width = inner.width.max(1) as usize;
+
+    let lines: Vec<Line> = match &app.explanation {
+        Some(text) => text.lines().flat_map(|line| wrap_chars(line, width)).map(Line::raw).collect(),
+        None => vec![Line::styled("Thinking...", Style::default().fg(app.theme.muted))],
+    };
+    app.explain_scroll = app.explain_scroll.min(lines.len().saturating_sub(inner.height as usize));
+    f.render_widget(Paragraph::new(lines).block(block).scroll((app.explain_scroll as u16, 0)), area);
+}
+
+fn render_preview_popup(f: &mut Frame, app: &mut App) {
+    let Some(preview) = &app.preview else { return };
+    let area = centered_rect(80, 80, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg))
+        .title(preview.title.clone());
+    let inner = block.inner(area);
+    f.render_widget(Paragraph::new(preview.text.clone()).wrap(Wrap { trim: false }).block(block), area);
+    app.preview_area = inner;
+}
+
+fn render_front_matter_popup(f: &mut Frame, app: &mut App) {
+    let theme = app.theme;
+    let Some(form) = &mut app.front_matter_form else { return };
+    let area = centered_rect(60, 50, f.area());
+    f.render_widget(Clear, area);
+
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .style(Style::default().bg(theme.popup_bg).fg(theme.popup_fg))
+        .title(" Front matter ")
+        .title_bottom(" Tab Next field  Enter Apply  Esc Cancel ");
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(form.inputs.iter().map(|_| Constraint::Length(3)))
+        .split(inner);
+    for (i, (input, row)) in form.inputs.iter_mut().zip(rows.iter()).enumerate() {
+        let border = if i == form.focus { theme.accent } else { theme.muted };
+        input.render(f, *row, Style::default().fg(border).bg(theme.popup_bg), i == form.focus);
+    }
+}
+
 fn render_setup_screen(f: &mut Frame, app: &mut App) {
     f.render_widget(Clear, f.area());
 
@@ -85,33 +1781,64 @@ fn render_setup_screen(f: &mut Frame, app: &mut App) {
         ])
         .split(f.area());
 
-    let instructions = Paragraph::new(vec![
-        Line::from(Span::styled("Welcome to NeuroNano!", Style::default().add_modifier(Modifier::BOLD).fg(Color::Cyan))),
+    let mut lines = vec![
+        Line::from(Span::styled("Welcome to NeuroNano!", Style::default().add_modifier(Modifier::BOLD).fg(app.theme.accent))),
         Line::from("To start, please get an API Key from https://aistudio.google.com/app/apikey"),
-    ])
+    ];
+    if keychain::is_available() {
+        let checkbox = if app.config.use_keychain { "[x]" } else { "[ ]" };
+        lines.push(Line::from(format!("{} Save key to OS keychain (Tab to toggle)", checkbox)));
+    }
+
+    let instructions = Paragraph::new(lines)
     .alignment(ratatui::layout::Alignment::Center)
     .block(Block::default().borders(Borders::NONE));
 
     f.render_widget(instructions, chunks[1]);
 
-    let block = Block::default()
-        .title(" API Key ")
-        .borders(Borders::ALL)
-        .style(Style::default().fg(Color::White));
-    
-    app.setup_textarea.set_block(block);
-    f.render_widget(&app.setup_textarea, chunks[2]);
+    let style = Style::default().fg(app.theme.border);
+    app.setup_input.render(f, chunks[2], style, true);
+}
+
+/// Nothing of the buffers shows while locked: just how to unlock.
+fn render_lock_screen(f: &mut Frame, app: &mut App) {
+    f.render_widget(Clear, f.area());
+    f.render_widget(Block::default().style(Style::default().bg(Color::Black)), f.area());
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(40), Constraint::Length(2), Constraint::Length(3), Constraint::Min(0)])
+        .split(f.area());
+    let (message, input) = if app.choosing_lock_passphrase {
+        ("Secure mode: the idle lock will ask for this passphrase.", true)
+    } else if app.has_lock_passphrase() {
+        ("Locked. Type the passphrase and press Enter.", true)
+    } else {
+        ("Locked. Press any key.", false)
+    };
+    f.render_widget(
+        Paragraph::new(Span::styled(message, Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD)))
+            .alignment(ratatui::layout::Alignment::Center),
+        chunks[1],
+    );
+    if input {
+        let area = centered_rect(50, 100, chunks[2]);
+        app.lock_input.render(f, area, Style::default().fg(app.theme.border), true);
+    }
 }
 
-fn render_processing_popup(f: &mut Frame) {
+fn render_processing_popup(f: &mut Frame, app: &App) {
     let area = centered_rect(40, 10, f.area());
     f.render_widget(Clear, area);
     
     let block = Block::default()
         .borders(Borders::ALL)
-        .style(Style::default().bg(Color::Blue).fg(Color::White));
+        .style(Style::default().bg(app.theme.processing_bg).fg(app.theme.processing_fg));
     
-    let text = Paragraph::new("🧠 NeuroNano is thinking...")
+    let text = match &app.ai_progress {
+        Some(note) => format!("🧠 NeuroNano is thinking...\n{}\n(Esc to cancel)", note),
+        None => "🧠 NeuroNano is thinking...\n\n(Esc to cancel)".to_string(),
+    };
+    let text = Paragraph::new(text)
         .alignment(ratatui::layout::Alignment::Center)
         .block(block);
         
@@ -129,26 +1856,154 @@ fn render_search_bar(f: &mut Frame, app: &mut App) {
         .split(f.area());
 
     // We render the search bar just above the footer
-    f.render_widget(&app.search_textarea, chunks[1]);
+    f.render_widget(Clear, chunks[1]);
+    app.search_input.render(f, chunks[1], Style::default(), true);
+}
+
+/// The two replace inputs above the footer, and over them what the rows on
+/// screen would become.
+fn render_replace_bar(f: &mut Frame, app: &mut App) {
+    let preview = app.replace_preview();
+    let shown = preview.len().min(5);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(if app.replace_find.text().is_empty() { 0 } else { shown.max(1) as u16 * 2 + 2 }),
+            Constraint::Length(3), // Pattern
+            Constraint::Length(3), // Replacement
+            Constraint::Length(2), // Footer
+        ])
+        .split(f.area());
+
+    if chunks[1].height > 0 {
+        let matches: usize = preview.iter().map(|(_, r)| r.old.len()).sum();
+        let block = Block::default()
+            .title(format!(" Preview: {} matches on screen ", matches))
+            .borders(Borders::ALL)
+            .style(Style::default().fg(app.theme.border));
+        let editor = &app.buffer.editor;
+        let muted = Style::default().fg(app.theme.muted);
+        // The line with the given char ranges picked out.
+        let marked = |text: &str, ranges: &[std::ops::Range<usize>], base: Style, mark: Style| {
+            let chars: Vec<char> = text.chars().map(|c| if c == '\t' { ' ' } else { c }).collect();
+            let mut spans = Vec::new();
+            let mut at = 0;
+            for range in ranges {
+                spans.push(Span::styled(chars[at..range.start].iter().collect::<String>(), base));
+                spans.push(Span::styled(chars[range.clone()].iter().collect::<String>(), mark));
+                at = range.end;
+            }
+            spans.push(Span::styled(chars[at..].iter().collect::<String>(), base));
+            spans
+        };
+        let mut items: Vec<Line> = Vec::new();
+        for (row, replaced) in preview.iter().take(shown) {
+            let mut old = vec![Span::styled(format!("{:>5} - ", row + 1), muted)];
+            old.extend(marked(&editor.line(*row), &replaced.old, muted, muted.add_modifier(Modifier::CROSSED_OUT)));
+            items.push(Line::from(old));
+            let mut new = vec![Span::styled(format!("{:>5} + ", row + 1), muted)];
+            new.extend(marked(&replaced.text, &replaced.new, Style::default(), Style::default().fg(app.theme.accent).add_modifier(Modifier::BOLD)));
+            items.push(Line::from(new));
+        }
+        if items.is_empty() {
+            items.push(Line::styled("No matches on screen", muted));
+        }
+        f.render_widget(Clear, chunks[1]);
+        f.render_widget(Paragraph::new(items).block(block), chunks[1]);
+    }
+
+    let editing_with = app.replace.as_ref().is_some_and(|r| r.editing_with);
+    let style = |focused: bool| Style::default().fg(if focused { app.theme.accent } else { app.theme.border });
+    let (find_style, with_style) = (style(!editing_with), style(editing_with));
+    f.render_widget(Clear, chunks[2]);
+    app.replace_find.render(f, chunks[2], find_style, !editing_with);
+    f.render_widget(Clear, chunks[3]);
+    app.replace_with.render(f, chunks[3], with_style, editing_with);
+}
+
+fn render_write_protected_banner(f: &mut Frame, app: &App, reason: &str, area: Rect) {
+    let style = Style::default().bg(app.theme.warning_bg).fg(app.theme.warning_fg);
+    let text = format!(
+        " Read-only: {}. {} edits anyway, {} saves under another name ",
+        reason,
+        app.keymap.label(Action::ToggleReadOnly),
+        app.keymap.label(Action::Save)
+    );
+    f.render_widget(Paragraph::new(text).style(style), area);
 }
 
 fn render_header(f: &mut Frame, app: &App, area: Rect) {
-    let header_style = Style::default().fg(Color::Black).bg(Color::Cyan);
-    let modified_indicator = if app.is_modified { " [+]" } else { "" };
+    let header_style = Style::default().fg(app.theme.header_fg).bg(app.theme.header_bg);
+    let modified_indicator = if app.buffer.modified { " [+]" } else { "" };
+    let mut mode_indicator = if app.tail.is_some() {
+        " [Tail]".to_string()
+    } else if app.read_only {
+        " [Read-only]".to_string()
+    } else {
+        String::new()
+    };
+    if let Some(loading) = &app.loading {
+        mode_indicator.push_str(&format!(" [Loading {}%]", loading.percent()));
+    } else if app.large_file {
+        mode_indicator.push_str(" [Large]");
+    }
+    if app.buffers.len() > 1 {
+        mode_indicator.push_str(&format!(" ({}/{})", app.active_buffer + 1, app.buffers.len()));
+    }
+    if app.recording.is_some() {
+        mode_indicator.push_str(" [Recording]");
+    }
+    if let Some(vim) = &app.vim {
+        mode_indicator.push_str(&format!(" -- {} --", vim.label()));
+    }
     let header_text = Line::from(vec![
         Span::styled("  NeuroNano  ", header_style.add_modifier(Modifier::BOLD)),
-        Span::styled(format!("  {}{}", app.filename, modified_indicator), header_style),
+        Span::styled(format!("  {}{}{}", app.filename, modified_indicator, mode_indicator), header_style),
     ]);
     
     let block = Block::default().style(header_style);
     let paragraph = Paragraph::new(header_text).block(block);
     f.render_widget(paragraph, area);
+
+    // Right-aligned cursor and file info.
+    let (row, col) = app.buffer.editor.cursor();
+    let total = app.buffer.editor.len_lines();
+    let language = app.detect_language().unwrap_or_else(|| "Plain Text".to_string());
+    let autosaved = match app.autosaved_at {
+        Some(at) if at.elapsed().as_secs() < 3 => "autosaved  ",
+        _ => "",
+    };
+    let info = format!(
+        "{}{}:{}  {} lines  {}%  {}  {}  {}{}  ",
+        autosaved,
+        row + 1,
+        col + 1,
+        total,
+        (row + 1) * 100 / total.max(1),
+        language,
+        app.encoding.name(),
+        app.line_ending.label(),
+        if app.final_newline { "" } else { " noeol" },
+    );
+    f.render_widget(
+        Paragraph::new(Span::styled(info, header_style)).alignment(ratatui::layout::Alignment::Right),
+        area,
+    );
 }
 
 fn render_footer(f: &mut Frame, app: &App, area: Rect) {
-    let footer_style = Style::default().fg(Color::Black).bg(Color::White);
+    let footer_style = Style::default().fg(app.theme.footer_fg).bg(app.theme.footer_bg);
     
-    // Split footer into Status Message (Top) and Shortcuts (Bottom) if there is a message
+    // Split footer into Status Message (Top) and Shortcuts (Bottom) if there
+    // is a message; a one-row footer shows just the message.
+    if area.height < 2 {
+        if let Some(msg) = &app.status_message {
+            let msg_style = Style::default().fg(app.theme.status_fg).bg(app.theme.status_bg).add_modifier(Modifier::BOLD);
+            f.render_widget(Paragraph::new(Span::styled(format!(" {} ", msg), msg_style)), area);
+            return;
+        }
+    }
     let (msg_area, shortcuts_area) = if app.status_message.is_some() {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
@@ -161,32 +2016,61 @@ fn render_footer(f: &mut Frame, app: &App, area: Rect) {
 
     if let Some(area) = msg_area {
         if let Some(msg) = &app.status_message {
-            let msg_style = Style::default().fg(Color::Black).bg(Color::Yellow).add_modifier(Modifier::BOLD);
+            let msg_style = Style::default().fg(app.theme.status_fg).bg(app.theme.status_bg).add_modifier(Modifier::BOLD);
             let paragraph = Paragraph::new(Span::styled(format!(" {} ", msg), msg_style));
             f.render_widget(paragraph, area);
         }
     }
 
-    let shortcuts = match app.mode {
-        AppMode::Normal => Line::from(vec![
-            Span::styled("^X", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Exit  "),
-            Span::styled("^O", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Save  "),
-            Span::styled("^K", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Cut  "),
-            Span::styled("^U", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Paste  "),
-            Span::styled("^F", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Search  "),
-            Span::styled("^P", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" AI Prompt  "),
+    let shortcuts = match app.mode() {
+        AppMode::Normal if app.ghost.is_some() => Line::from(vec![
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Accept completion  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Dismiss  "),
         ]),
+        AppMode::Normal => {
+            let mut hints = vec![
+                (Action::Quit, " Exit  "),
+                (Action::Save, " Save  "),
+                (Action::Cut, " Cut  "),
+                (Action::Paste, " Paste  "),
+                (Action::Search, " Search  "),
+                (Action::AiPrompt, " AI Prompt  "),
+                (Action::Chat, " Chat  "),
+            ];
+            if app.csv_delimiter().is_some() {
+                hints.push((Action::ToggleTableView, " Table  "));
+            }
+            if app.is_markdown() {
+                hints.push((Action::Outline, " Outline  "));
+                hints.push((Action::CheckLinks, " Links  "));
+            }
+            Line::from(
+                hints
+                    .into_iter()
+                    .flat_map(|(action, label)| {
+                        [
+                            Span::styled(app.keymap.label(action), Style::default().add_modifier(Modifier::BOLD)),
+                            Span::raw(label),
+                        ]
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        }
         AppMode::Prompting => Line::from(vec![
             Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Cancel  "),
             Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Generate  "),
+            Span::styled("^J", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" New line  "),
+            Span::styled("Up/Down", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" History  "),
+            Span::styled("^R", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Rerun last  "),
+            Span::styled("^N/^P", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Saved prompts  "),
         ]),
         AppMode::Setup => Line::from(vec![
             Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
@@ -195,20 +2079,54 @@ fn render_footer(f: &mut Frame, app: &App, area: Rect) {
             Span::raw(" Save & Start  "),
         ]),
         AppMode::Processing => Line::from(vec![
-            Span::raw(" Processing... Please wait. "),
-        ]),
-        AppMode::Search => Line::from(vec![
+            Span::raw(" Processing... Please wait.  "),
             Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Cancel  "),
-            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
-            Span::raw(" Find  "),
         ]),
+        AppMode::Replace => {
+            let editing_with = app.replace.as_ref().is_some_and(|r| r.editing_with);
+            let mut spans = vec![
+                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" Cancel  "),
+                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(if editing_with { " Replace all  " } else { " Next  " }),
+                Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" Switch field  "),
+            ];
+            if let Some(state) = app.replace.as_ref().filter(|r| r.scope.is_some()) {
+                spans.push(Span::styled("M-S", Style::default().add_modifier(Modifier::BOLD)));
+                spans.push(Span::raw(if state.in_selection { " Whole buffer  " } else { " In selection  " }));
+            }
+            Line::from(spans)
+        }
+        AppMode::Search => {
+            let mut spans = vec![
+                Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" Cancel  "),
+                Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(" Find  "),
+            ];
+            if app.search_scope.is_some() {
+                let scope = if app.search_in_selection { " Whole buffer  " } else { " In selection  " };
+                spans.push(Span::styled("M-S", Style::default().add_modifier(Modifier::BOLD)));
+                spans.push(Span::raw(scope));
+            }
+            Line::from(spans)
+        }
         AppMode::SaveAs => Line::from(vec![
             Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Cancel  "),
             Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Save  "),
         ]),
+        AppMode::ApplyPatch | AppMode::ExportPatch => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Complete  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(if app.mode() == AppMode::ApplyPatch { " Apply  " } else { " Export  " }),
+        ]),
         AppMode::ConfirmQuit => Line::from(vec![
             Span::styled("Y", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Yes  "),
@@ -217,25 +2135,249 @@ fn render_footer(f: &mut Frame, app: &App, area: Rect) {
             Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
             Span::raw(" Cancel  "),
         ]),
+        AppMode::FrontMatter => Line::from(vec![
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Next field  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Apply  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+        ]),
+        AppMode::Chat => Line::from(vec![
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Ask  "),
+            Span::styled("^Y", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Apply code  "),
+            Span::styled("PgUp/PgDn", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Scroll  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Editor  "),
+            Span::styled(app.keymap.label(Action::Chat), Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close chat  "),
+        ]),
+        AppMode::Merge => Line::from(vec![
+            Span::styled("←/o", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Ours  "),
+            Span::styled("→/t", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Theirs  "),
+            Span::styled("b", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Both  "),
+            Span::styled("u", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Unresolve  "),
+            Span::styled("↑/↓", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Hunk  "),
+            Span::styled("e", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Edit  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Save & quit  "),
+        ]),
+        AppMode::AiSnapshots => Line::from(vec![
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Revert to before  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        AppMode::Preview => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        AppMode::DiagnosticDetails => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        AppMode::Explain => Line::from(vec![
+            Span::styled("Up/Down", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Scroll  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        // Covered by the lock screen.
+        AppMode::Locked => Line::default(),
+        AppMode::Welcome => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" New file  "),
+            Span::styled("Enter/1-9", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Open  "),
+            Span::styled(app.keymap.label(Action::Quit), Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Exit  "),
+        ]),
+        AppMode::OpenFile => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Complete  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Open  "),
+        ]),
+        AppMode::SearchResults => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Go to  "),
+            Span::styled("/", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" New search  "),
+        ]),
+        AppMode::RunCommand => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Run  "),
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(if app.command_to_new_buffer { " At cursor  " } else { " New buffer  " }),
+        ]),
+        AppMode::Filter => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Filter  "),
+        ]),
+        AppMode::Grep => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Find  "),
+        ]),
+        AppMode::GrepResults => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Open  "),
+            Span::styled("/", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" New search  "),
+        ]),
+        AppMode::Buffers => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter/1-9", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Switch  "),
+            Span::styled("S", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Save  "),
+            Span::styled("A", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Save all  "),
+            Span::styled("D", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close buffer  "),
+            Span::styled("O", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close others  "),
+        ]),
+        AppMode::Report => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        AppMode::Spelling => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter/1-9", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Replace  "),
+            Span::styled("A", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Add to dictionary  "),
+            Span::styled("I", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Ignore  "),
+        ]),
+        AppMode::Bookmarks => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter/1-9", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Jump  "),
+            Span::styled("D", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Delete  "),
+        ]),
+        AppMode::Outline => Line::from(vec![
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+            Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Jump  "),
+        ]),
+        AppMode::GitDiff => Line::from(vec![
+            Span::styled("Tab", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(if app.head_diff_side_by_side { " Unified  " } else { " Side by side  " }),
+            Span::styled("N/P", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Next/Prev hunk  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Close  "),
+        ]),
+        AppMode::GitCommit => Line::from(vec![
+            Span::styled("^S", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Commit  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Cancel  "),
+        ]),
+        AppMode::Recover => Line::from(vec![
+            Span::styled("R", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Recover  "),
+            Span::styled("D", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Delete  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Open as saved  "),
+        ]),
+        AppMode::FileChanged => Line::from(vec![
+            Span::styled("R", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Reload  "),
+            Span::styled("O", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Overwrite  "),
+            Span::styled("D", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Diff  "),
+            Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(" Keep editing  "),
+        ]),
     };
 
     let block = Block::default().style(footer_style);
     let paragraph = Paragraph::new(shortcuts).block(block);
     f.render_widget(paragraph, shortcuts_area);
+
+    if app.mode() == AppMode::Normal && app.is_markdown() {
+        let minutes = markdown::reading_minutes(app.buffer.editor.iter());
+        f.render_widget(
+            Paragraph::new(format!("~{} min read  ", minutes)).alignment(ratatui::layout::Alignment::Right),
+            shortcuts_area,
+        );
+    }
 }
 
 fn render_ai_popup(f: &mut Frame, app: &mut App) {
-    let area = centered_rect(60, 20, f.area());
-    
+    let saved = app.config.prompts.len() as u16;
+    // The input grows with its lines, up to half the screen.
+    let input_height = (app.prompt_input.line_count() as u16 + 2).min(f.area().height / 2).max(3);
+    let area = if saved == 0 {
+        let area = centered_rect(60, 20, f.area());
+        Rect { height: area.height.max(input_height), ..area }
+    } else {
+        // Room for the input and the saved prompts under it.
+        let area = centered_rect(60, 60, f.area());
+        Rect { height: area.height.min(input_height + saved + 2), ..area }
+    };
+
     f.render_widget(Clear, area); // Clear the area so the editor doesn't show through
 
-    let block = Block::default()
-        .title("✨ AI Magic Prompt")
-        .borders(Borders::ALL)
-        .style(Style::default().bg(Color::DarkGray).fg(Color::White));
-    
-    app.prompt_textarea.set_block(block);
-    f.render_widget(&app.prompt_textarea, area);
+    let tokens = app.estimate_prompt_tokens();
+    let cost = app.ai_cost(tokens as u64, 0);
+    app.prompt_input.set_note(Some(format!("~{} tokens, ~${:.4} to send", tokens, cost)));
+    let style = Style::default().bg(app.theme.popup_bg).fg(app.theme.popup_fg);
+    let input_area = Rect { height: area.height.min(input_height), ..area };
+    app.prompt_input.render(f, input_area, style, app.prompt_pick.is_none());
+    if saved == 0 || area.height <= input_height {
+        return;
+    }
+
+    let list_area = Rect { y: area.y + input_height, height: area.height - input_height, ..area };
+    let block = Block::default().borders(Borders::ALL).style(style).title(" Saved prompts (^N/^P) ");
+    let height = block.inner(list_area).height as usize;
+    let skip = app.prompt_pick.map_or(0, |i| (i + 1).saturating_sub(height));
+    let lines: Vec<Line> = app
+        .saved_prompts()
+        .into_iter()
+        .enumerate()
+        .skip(skip)
+        .map(|(i, name)| {
+            let style = if app.prompt_pick == Some(i) {
+                Style::default().fg(app.theme.accent).add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::from(Span::styled(name.clone(), style))
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines).block(block), list_area);
 }
 
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
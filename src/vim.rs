@@ -0,0 +1,311 @@
+//! Optional vim-style modal layer on top of the regular editor. Only keys
+//! that aren't bound in the keymap are interpreted here, so ^O, ^X, ^P and
+//! friends keep working in every vim mode.
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use tui_textarea::CursorMove;
+use crate::app::App;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VimMode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+#[derive(Debug, Clone)]
+pub struct VimState {
+    pub mode: VimMode,
+    /// Operator or prefix waiting for its motion (`d`, `y`, `c`, `g`).
+    pending: Option<char>,
+    count: usize,
+}
+
+impl VimState {
+    pub fn new() -> Self {
+        Self {
+            mode: VimMode::Normal,
+            pending: None,
+            count: 0,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self.mode {
+            VimMode::Normal => "NORMAL",
+            VimMode::Insert => "INSERT",
+            VimMode::Visual => "VISUAL",
+        }
+    }
+
+    fn take_count(&mut self) -> usize {
+        std::mem::take(&mut self.count).max(1)
+    }
+}
+
+fn motion(c: char) -> Option<CursorMove> {
+    Some(match c {
+        'h' => CursorMove::Back,
+        'l' => CursorMove::Forward,
+        'j' => CursorMove::Down,
+        'k' => CursorMove::Up,
+        'w' => CursorMove::WordForward,
+        'b' => CursorMove::WordBack,
+        'e' => CursorMove::WordEnd,
+        '0' => CursorMove::Head,
+        '$' => CursorMove::End,
+        'G' => CursorMove::Bottom,
+        '{' => CursorMove::ParagraphBack,
+        '}' => CursorMove::ParagraphForward,
+        _ => return None,
+    })
+}
+
+fn is_navigation(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::Up | KeyCode::Down | KeyCode::Left | KeyCode::Right
+            | KeyCode::PageUp | KeyCode::PageDown | KeyCode::Home | KeyCode::End
+    )
+}
+
+/// Handle `key` if the vim layer wants it. Returns false to let the regular
+/// keymap/text input process it.
+pub fn handle_key(app: &mut App, key: KeyEvent) -> bool {
+    let Some(mut state) = app.vim.take() else { return false };
+    let consumed = handle(app, &mut state, key);
+    app.vim = Some(state);
+    consumed
+}
+
+fn handle(app: &mut App, state: &mut VimState, key: KeyEvent) -> bool {
+    if state.mode == VimMode::Insert {
+        if key.code == KeyCode::Esc {
+            state.mode = VimMode::Normal;
+            app.buffer.editor.move_cursor(CursorMove::Back);
+            return true;
+        }
+        return false;
+    }
+
+    if app.keymap.action_for(&key).is_some() {
+        return false;
+    }
+    if is_navigation(key.code) {
+        app.buffer.editor.input(key);
+        return true;
+    }
+    if key.code == KeyCode::Char('r') && key.modifiers.contains(KeyModifiers::CONTROL) {
+        app.redo();
+        return true;
+    }
+
+    let c = match key.code {
+        KeyCode::Char(c) if !key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) => c,
+        KeyCode::Esc => {
+            state.pending = None;
+            state.count = 0;
+            if state.mode == VimMode::Visual {
+                app.buffer.editor.cancel_selection();
+                state.mode = VimMode::Normal;
+            }
+            return true;
+        }
+        KeyCode::Enter => {
+            app.buffer.editor.move_cursor(CursorMove::Down);
+            app.buffer.editor.move_cursor(CursorMove::Head);
+            return true;
+        }
+        KeyCode::Backspace => {
+            app.buffer.editor.move_cursor(CursorMove::Back);
+            return true;
+        }
+        // Everything else is swallowed so it can't edit the buffer.
+        _ => return true,
+    };
+
+    if c.is_ascii_digit() && (c != '0' || state.count > 0) {
+        state.count = state.count * 10 + c.to_digit(10).unwrap_or(0) as usize;
+        return true;
+    }
+
+    if app.read_only && "xdcpPoOiaAIsu".contains(c) {
+        app.set_status("Buffer is read-only");
+        state.pending = None;
+        return true;
+    }
+
+    match state.mode {
+        VimMode::Visual => visual(app, state, c),
+        _ => normal(app, state, c),
+    }
+    true
+}
+
+fn normal(app: &mut App, state: &mut VimState, c: char) {
+    if let Some(op) = state.pending.take() {
+        operator(app, state, op, c);
+        return;
+    }
+
+    if let Some(m) = motion(c) {
+        for _ in 0..state.take_count() {
+            app.buffer.editor.move_cursor(m);
+        }
+        return;
+    }
+
+    match c {
+        'd' | 'y' | 'c' | 'g' => {
+            // Keep the count for the operator (3dd).
+            state.pending = Some(c);
+            return;
+        }
+        'i' => state.mode = VimMode::Insert,
+        'a' => {
+            app.buffer.editor.move_cursor(CursorMove::Forward);
+            state.mode = VimMode::Insert;
+        }
+        'A' => {
+            app.buffer.editor.move_cursor(CursorMove::End);
+            state.mode = VimMode::Insert;
+        }
+        'I' => {
+            app.buffer.editor.move_cursor(CursorMove::Head);
+            state.mode = VimMode::Insert;
+        }
+        'o' => {
+            app.buffer.editor.move_cursor(CursorMove::End);
+            app.buffer.editor.insert_newline();
+            app.mark_dirty();
+            state.mode = VimMode::Insert;
+        }
+        'O' => {
+            app.buffer.editor.move_cursor(CursorMove::Head);
+            app.buffer.editor.insert_newline();
+            app.buffer.editor.move_cursor(CursorMove::Up);
+            app.mark_dirty();
+            state.mode = VimMode::Insert;
+        }
+        'x' => {
+            for _ in 0..state.take_count() {
+                if app.buffer.editor.delete_next_char() {
+                    app.mark_dirty();
+                }
+            }
+        }
+        's' => {
+            if app.buffer.editor.delete_next_char() {
+                app.mark_dirty();
+            }
+            state.mode = VimMode::Insert;
+        }
+        'p' | 'P' => {
+            if c == 'p' {
+                app.buffer.editor.move_cursor(CursorMove::Forward);
+            }
+            for _ in 0..state.take_count() {
+                if app.buffer.editor.paste() {
+                    app.mark_dirty();
+                }
+            }
+        }
+        'u' => app.undo(),
+        'v' => {
+            app.buffer.editor.start_selection();
+            state.mode = VimMode::Visual;
+        }
+        _ => {}
+    }
+    state.count = 0;
+}
+
+/// Apply operator `op` (d, y, c) or prefix `g` with the following key.
+fn operator(app: &mut App, state: &mut VimState, op: char, c: char) {
+    let count = state.take_count();
+
+    if op == 'g' {
+        if c == 'g' {
+            app.buffer.editor.move_cursor(CursorMove::Top);
+        }
+        return;
+    }
+
+    // Doubled operator (dd, yy, cc) works on whole lines.
+    let linewise = c == op;
+    let origin = app.buffer.editor.cursor();
+    if linewise {
+        let last = app.buffer.editor.len_lines() - 1;
+        let end = origin.0 + count;
+        if end <= last {
+            app.buffer.editor.move_cursor(CursorMove::Head);
+            app.buffer.editor.start_selection();
+            app.buffer.editor.jump((end, 0));
+        } else {
+            // The range runs into EOF, where there is no newline after the
+            // last line: dd takes the one before the first line instead.
+            if op == 'd' && origin.0 > 0 {
+                let above = origin.0 - 1;
+                let width = app.buffer.editor.line_len(above);
+                app.buffer.editor.jump((above, width));
+            } else {
+                app.buffer.editor.move_cursor(CursorMove::Head);
+            }
+            app.buffer.editor.start_selection();
+            app.buffer.editor.jump((last, 0));
+            app.buffer.editor.move_cursor(CursorMove::End);
+        }
+    } else if let Some(m) = motion(c) {
+        app.buffer.editor.start_selection();
+        for _ in 0..count {
+            app.buffer.editor.move_cursor(m);
+        }
+    } else {
+        return;
+    }
+
+    match op {
+        'y' => {
+            app.buffer.editor.copy();
+            app.buffer.editor.jump((origin.0, origin.1));
+        }
+        _ => {
+            if app.buffer.editor.cut() {
+                app.mark_dirty();
+            }
+            if linewise && op == 'd' {
+                app.buffer.editor.move_cursor(CursorMove::Head);
+            }
+            if op == 'c' {
+                state.mode = VimMode::Insert;
+            }
+        }
+    }
+}
+
+fn visual(app: &mut App, state: &mut VimState, c: char) {
+    if let Some(m) = motion(c) {
+        for _ in 0..state.take_count() {
+            app.buffer.editor.move_cursor(m);
+        }
+        return;
+    }
+
+    match c {
+        'd' | 'x' | 'c' => {
+            if app.buffer.editor.cut() {
+                app.mark_dirty();
+            }
+            state.mode = if c == 'c' { VimMode::Insert } else { VimMode::Normal };
+        }
+        'y' => {
+            app.buffer.editor.copy();
+            state.mode = VimMode::Normal;
+        }
+        'v' => {
+            app.buffer.editor.cancel_selection();
+            state.mode = VimMode::Normal;
+        }
+        _ => {}
+    }
+    state.count = 0;
+}
@@ -0,0 +1,56 @@
+//! Soft wrapping: where long lines break into screen rows in the wrapped
+//! editor view, and moving the cursor by screen row.
+use crate::ui::display_columns;
+
+/// One screen row of the wrapped view: chars `start..end` of buffer `row`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenRow {
+    pub row: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Char index where each screen row of `line` starts when it wraps at
+/// `width` columns, breaking after whitespace where possible. Always
+/// starts with 0.
+pub fn row_starts(line: &str, width: usize, tab_len: usize) -> Vec<usize> {
+    let columns = display_columns(line, tab_len);
+    let chars: Vec<char> = line.chars().collect();
+    let width = width.max(1);
+    let mut starts = vec![0];
+    // Start column of the current screen row, and the last place after a
+    // space where it may break.
+    let mut row_col = 0;
+    let mut break_at = None;
+    for (i, &(start, w)) in columns.iter().enumerate() {
+        let row_start = *starts.last().unwrap();
+        // Whitespace may hang past the edge rather than start a row.
+        if start + w - row_col > width && i > row_start && !chars[i].is_whitespace() {
+            let at = break_at.filter(|&b| b > row_start).unwrap_or(i);
+            starts.push(at);
+            row_col = columns[at].0;
+            break_at = None;
+        }
+        if chars[i].is_whitespace() {
+            break_at = Some(i + 1);
+        }
+    }
+    starts
+}
+
+/// Screen rows of buffer `row`, given its `line`.
+pub fn screen_rows(row: usize, line: &str, width: usize, tab_len: usize) -> Vec<ScreenRow> {
+    let starts = row_starts(line, width, tab_len);
+    let len = line.chars().count();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| ScreenRow { row, start, end: starts.get(i + 1).copied().unwrap_or(len) })
+        .collect()
+}
+
+/// Index of the screen row of `rows` (one buffer line's) holding char
+/// `col`. The end of the line belongs to the last row.
+pub fn row_of(rows: &[ScreenRow], col: usize) -> usize {
+    rows.iter().rposition(|r| r.start <= col).unwrap_or(0)
+}